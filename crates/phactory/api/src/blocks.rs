@@ -3,7 +3,7 @@ use core::convert::TryFrom;
 use parity_scale_codec::{Decode, Encode, FullCodec};
 pub use sp_finality_grandpa::{AuthorityList, SetId};
 
-use sp_core::U256;
+use sp_core::{ecdsa, H256, U256};
 use sp_runtime::{generic::Header, traits::Hash as HashT};
 pub use phala_trie_storage::ser::StorageChanges;
 
@@ -36,6 +36,108 @@ pub struct GenesisBlockInfo {
     pub proof: StorageProof,
 }
 
+/// The BEEFY authority set with the id
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyAuthoritySet {
+    pub list: Vec<ecdsa::Public>,
+    pub id: u64,
+}
+
+/// The next BEEFY authority set with the storage proof (including both the next authority set and
+/// the current id)
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyAuthoritySetChange {
+    pub authority_set: BeefyAuthoritySet,
+    pub authority_proof: StorageProof,
+}
+
+/// The BEEFY authority set at the genesis block, to validate the headers with the BEEFY
+/// commitments instead of the GRANDPA justifications.
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyGenesisInfo {
+    pub authority_set: BeefyAuthoritySet,
+    pub proof: StorageProof,
+}
+
+/// The commitment signed by the BEEFY authorities, with the MMR root at `block_number` as the
+/// payload
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyCommitment {
+    pub payload: H256,
+    pub block_number: chain::BlockNumber,
+    pub validator_set_id: u64,
+}
+
+/// A BEEFY commitment with the signatures of the authorities, in the order of the authority set
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefySignedCommitment {
+    pub commitment: BeefyCommitment,
+    pub signatures: Vec<Option<ecdsa::Signature>>,
+}
+
+/// The next BEEFY authority set committed by an MMR leaf
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyNextAuthoritySet {
+    pub id: u64,
+    pub len: u32,
+    pub root: H256,
+}
+
+/// The leaf appended to the MMR at each block, committing to the parent block
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyMmrLeaf {
+    pub version: u8,
+    pub parent_number_and_hash: (chain::BlockNumber, H256),
+    pub beefy_next_authority_set: BeefyNextAuthoritySet,
+    pub parachain_heads: H256,
+}
+
+/// The proof of an MMR leaf against the MMR root of `leaf_count` leaves
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct MmrLeafProof {
+    pub leaf_index: u64,
+    pub leaf_count: u64,
+    pub items: Vec<H256>,
+}
+
+/// The BEEFY proof of the finality of a header, in place of the GRANDPA justification of the
+/// last header to sync.
+///
+/// The MMR leaf of the child block contains the header hash and is proven against the MMR root
+/// signed in the commitment. `authority_set_change` is the next BEEFY authority set read at the
+/// header, which signs the commitments after the current one.
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct BeefyFinalityProof {
+    pub signed_commitment: BeefySignedCommitment,
+    /// The SCALE encoded `BeefyMmrLeaf`, hashed as is in the MMR
+    pub mmr_leaf: Vec<u8>,
+    pub mmr_proof: MmrLeafProof,
+    pub authority_set_change: Option<BeefyAuthoritySetChange>,
+}
+
+/// A finalized header reached without an ancestry proof, justified by the authority set in effect
+/// at it. `authority_set_change` is the set enacted at the header, if any.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct WarpFragment {
+    pub header: HeaderToSync,
+    pub authority_set_change: Option<AuthoritySetChange>,
+}
+
+/// The finalized checkpoint a fresh pRuntime can start syncing from, instead of replaying the
+/// storage changes of every block since the genesis block.
+///
+/// The fragments lead from the genesis block to the checkpoint, one at each authority set change,
+/// and the last one is the checkpoint header on the (relay) chain. In parachain mode, the
+/// parachain header at the checkpoint is proven against the state root of that header.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct WarpPoint {
+    pub fragments: Vec<WarpFragment>,
+    pub para_header: Option<chain::Header>,
+    pub para_header_proof: StorageProof,
+    /// The full chain state at the checkpoint.
+    pub state: StorageState,
+}
+
 pub type RuntimeHasher = <chain::Runtime as frame_system::Config>::Hashing;
 pub type HeaderToSync = GenericHeaderToSync<chain::BlockNumber, RuntimeHasher>;
 pub type BlockHeaderWithChanges =
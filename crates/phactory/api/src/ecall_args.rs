@@ -1,6 +1,7 @@
 
 use parity_scale_codec::{Encode, Decode};
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Encode, Decode, Default, Clone)]
@@ -31,6 +32,12 @@ pub struct InitArgs {
 
     /// Skip corrupted checkpoint, and start to sync blocks from the beginning.
     pub skip_corrupted_checkpoint: bool,
+
+    /// Number of blocks to retain the ink! events emitted by pink contracts
+    pub ink_event_retention: u32,
+
+    /// Hex encoded ids of the pink clusters to keep the storage on disk
+    pub disk_backed_clusters: Vec<String>,
 }
 
 pub fn git_revision() -> String {
@@ -1,5 +1,6 @@
 use super::blocks::{
     AuthoritySetChange, BlockHeaderWithChanges, HeaderToSync, RuntimeHasher, StorageProof,
+    WarpFragment,
 };
 
 use alloc::collections::VecDeque;
@@ -48,6 +49,8 @@ pub enum Error {
     },
     /// Solo/Para mode mismatch
     ChainModeMismatch,
+    /// Warp sync requested after blocks have been synced
+    AlreadySynced,
 }
 
 pub trait BlockValidator {
@@ -60,12 +63,30 @@ pub trait BlockValidator {
         auhtority_set_change: Option<AuthoritySetChange>,
     ) -> Result<()>;
 
+    /// Submits a finalized header without proving that it descends from the last finalized one.
+    ///
+    /// Warp sync uses it to skip to the next authority set change, relying on the justification of
+    /// the current authority set alone.
+    fn submit_warp_fragment(
+        &mut self,
+        bridge_id: u64,
+        header: chain::Header,
+        grandpa_proof: Vec<u8>,
+        authority_set_change: Option<AuthoritySetChange>,
+    ) -> Result<()>;
+
     fn validate_storage_proof(
         &self,
         state_root: Hash,
         proof: StorageProof,
         items: &[(&[u8], &[u8])],
     ) -> Result<()>;
+
+    /// Whether the finality proof of a header is enough without the headers before it, which
+    /// allows to skip the headers where only the last state root is needed.
+    fn supports_header_gaps(&self) -> bool {
+        false
+    }
 }
 
 pub trait StorageSynchronizer {
@@ -126,17 +147,24 @@ where
     }
 
     /// Given chain headers in sequence, validate it and output the state_roots
+    ///
+    /// With `allow_gaps`, the headers may start after the next header number if the validator
+    /// supports it.
     pub fn sync_header(
         &mut self,
         headers: Vec<HeaderToSync>,
         authority_set_change: Option<AuthoritySetChange>,
         state_roots: &mut VecDeque<Hash>,
+        allow_gaps: bool,
     ) -> Result<chain::BlockNumber> {
         let first_header = match headers.first() {
             Some(header) => header,
             None => return Ok(self.header_number_next - 1),
         };
-        if first_header.header.number != self.header_number_next {
+        let gap_allowed = allow_gaps && self.validator.supports_header_gaps();
+        if first_header.header.number < self.header_number_next
+            || (first_header.header.number > self.header_number_next && !gap_allowed)
+        {
             return Err(Error::BlockNumberMismatch);
         }
 
@@ -184,6 +212,36 @@ where
         Ok(last_header.header.number)
     }
 
+    /// Skip to the header finalized by the last fragment, returning it.
+    ///
+    /// The storage of the blocks up to that header is never fed, so the caller has to set the
+    /// next block number and load the state at the corresponding block.
+    pub fn warp(&mut self, fragments: Vec<WarpFragment>) -> Result<chain::Header> {
+        if self.block_number_next != 1 {
+            return Err(Error::AlreadySynced);
+        }
+        let mut last_header = None;
+        for fragment in fragments {
+            let header = fragment.header.header;
+            if header.number < self.header_number_next {
+                return Err(Error::BlockNumberMismatch);
+            }
+            let justification = fragment
+                .header
+                .justification
+                .ok_or(Error::MissingJustification)?;
+            self.validator.submit_warp_fragment(
+                self.main_bridge,
+                header.clone(),
+                justification,
+                fragment.authority_set_change,
+            )?;
+            self.header_number_next = header.number + 1;
+            last_header = Some(header);
+        }
+        last_header.ok_or(Error::EmptyRequest)
+    }
+
     /// Feed a block and apply changes to storage if it's valid.
     pub fn feed_block(
         &mut self,
@@ -220,6 +278,18 @@ where
     }
 }
 
+fn check_state_root(header: &chain::Header, storage: &Storage) -> Result<()> {
+    let state_root = *storage.root();
+    if header.state_root != state_root {
+        return Err(Error::StateRootMismatch {
+            block: header.number,
+            expected: header.state_root,
+            actual: state_root,
+        });
+    }
+    Ok(())
+}
+
 #[derive(Default, Debug)]
 pub struct Counters {
     pub next_header_number: chain::BlockNumber,
@@ -241,6 +311,18 @@ impl<Validator: BlockValidator> SolochainSynchronizer<Validator> {
             state_roots: Default::default(),
         }
     }
+
+    /// Skip to the last header of the fragments, whose state is given in `storage`.
+    pub fn warp(
+        &mut self,
+        fragments: Vec<WarpFragment>,
+        storage: &Storage,
+    ) -> Result<chain::BlockNumber> {
+        let header = self.sync_state.warp(fragments)?;
+        check_state_root(&header, storage)?;
+        self.sync_state.block_number_next = header.number + 1;
+        Ok(header.number)
+    }
 }
 
 impl<Validator: BlockValidator> StorageSynchronizer for SolochainSynchronizer<Validator> {
@@ -258,7 +340,7 @@ impl<Validator: BlockValidator> StorageSynchronizer for SolochainSynchronizer<Va
         authority_set_change: Option<AuthoritySetChange>,
     ) -> Result<chain::BlockNumber> {
         self.sync_state
-            .sync_header(headers, authority_set_change, &mut self.state_roots)
+            .sync_header(headers, authority_set_change, &mut self.state_roots, false)
     }
 
     fn feed_block(
@@ -302,6 +384,28 @@ impl<Validator: BlockValidator> ParachainSynchronizer<Validator> {
             para_state_roots: Default::default(),
         }
     }
+
+    /// Skip to the parachain header included in the last relaychain header of the fragments,
+    /// whose state is given in `storage`.
+    pub fn warp(
+        &mut self,
+        fragments: Vec<WarpFragment>,
+        para_header: chain::Header,
+        proof: StorageProof,
+        storage_key: &[u8],
+        storage: &Storage,
+    ) -> Result<chain::BlockNumber> {
+        let relay_header = self.sync_state.warp(fragments)?;
+        self.sync_state.validator.validate_storage_proof(
+            relay_header.state_root,
+            proof,
+            &[(storage_key, para_header.encode().encode().as_slice())],
+        )?;
+        check_state_root(&para_header, storage)?;
+        self.para_header_number_next = para_header.number + 1;
+        self.sync_state.block_number_next = para_header.number + 1;
+        Ok(para_header.number)
+    }
 }
 
 impl<Validator: BlockValidator> StorageSynchronizer for ParachainSynchronizer<Validator> {
@@ -319,10 +423,11 @@ impl<Validator: BlockValidator> StorageSynchronizer for ParachainSynchronizer<Va
         headers: Vec<HeaderToSync>,
         authority_set_change: Option<AuthoritySetChange>,
     ) -> Result<chain::BlockNumber> {
+        // Only the last state root is needed, so the headers before it can be skipped
         let mut state_roots = Default::default();
         let last_header =
             self.sync_state
-                .sync_header(headers, authority_set_change, &mut state_roots)?;
+                .sync_header(headers, authority_set_change, &mut state_roots, true)?;
         self.last_relaychain_state_root = state_roots.pop_back();
         Ok(last_header)
     }
@@ -407,6 +512,26 @@ impl<Validator: BlockValidator> Synchronizer<Validator> {
         Self::Solo(SolochainSynchronizer::new(validator, main_bridge))
     }
 
+    /// Skip to the checkpoint at the end of the fragments, before syncing any header.
+    ///
+    /// `storage` holds the chain state at the checkpoint. In parachain mode, `para_header` is the
+    /// parachain header at the checkpoint with its proof under `storage_key`.
+    pub fn warp(
+        &mut self,
+        fragments: Vec<WarpFragment>,
+        para_header: Option<(chain::Header, StorageProof)>,
+        storage_key: &[u8],
+        storage: &Storage,
+    ) -> Result<chain::BlockNumber> {
+        match (self, para_header) {
+            (Self::Solo(s), None) => s.warp(fragments, storage),
+            (Self::Para(p), Some((header, proof))) => {
+                p.warp(fragments, header, proof, storage_key, storage)
+            }
+            _ => Err(Error::ChainModeMismatch),
+        }
+    }
+
     pub fn as_dyn(&self) -> &dyn StorageSynchronizer {
         match self {
             Self::Solo(s) => s,
@@ -45,5 +45,11 @@ pub trait ProtectedFileSystem {
     fn create_protected_file(&self, path: impl AsRef<Path>, key: &[u8]) -> Result<Self::WriteFile, Self::IoError>;
 }
 
-pub trait Platform: Sealing + RA + Machine + MemoryStats + ProtectedFileSystem + Clone {}
-impl<T: Sealing + RA + Machine + MemoryStats + ProtectedFileSystem + Clone> Platform for T {}
+pub trait Platform:
+    Sealing + RA + Machine + MemoryStats + ProtectedFileSystem + Clone + Send + Sync + 'static
+{
+}
+impl<T> Platform for T where
+    T: Sealing + RA + Machine + MemoryStats + ProtectedFileSystem + Clone + Send + Sync + 'static
+{
+}
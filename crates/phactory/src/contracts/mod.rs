@@ -1,6 +1,7 @@
 use crate::secret_channel::{KeyPair, SecretMessageChannel};
 use std::convert::TryFrom as _;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use crate::system::{TransactionError, TransactionResult};
 use crate::types::{deopaque_query, OpaqueError, OpaqueQuery, OpaqueReply};
@@ -8,6 +9,7 @@ use anyhow::{Context, Error, Result};
 use chain::AccountId;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::{MessageOrigin, SignedMessageChannel};
+use sp_core::Pair as _;
 
 pub mod assets;
 pub mod balances;
@@ -51,5 +53,41 @@ pub fn get_contract_id(contract_info: &ContractInfo<chain::Hash, chain::AccountI
     contract_address_to_id(&contract_address)
 }
 
+/// The native contracts built into pRuntime, registered by default.
+///
+/// The type names tag the saved contracts in checkpoints and must never change.
+fn builtin_native_contracts() -> Vec<Arc<dyn NativeContractFactory>> {
+    vec![
+        Arc::new(NativeContractType::new(DATA_PLAZA, "DataPlaza", |_| {
+            data_plaza::DataPlaza::new()
+        })),
+        Arc::new(NativeContractType::new(BALANCES, "Balances", |_| {
+            balances::Balances::new()
+        })),
+        Arc::new(NativeContractType::new(ASSETS, "Assets", |_| {
+            assets::Assets::new()
+        })),
+        Arc::new(NativeContractType::new(
+            WEB3_ANALYTICS,
+            "Web3Analytics",
+            |_| web3analytics::Web3Analytics::new(),
+        )),
+        Arc::new(NativeContractType::new(BTC_LOTTERY, "BtcLottery", |key| {
+            btc_lottery::BtcLottery::new(Some(key.to_raw_vec()))
+        })),
+        Arc::new(NativeContractType::new(GEOLOCATION, "Geolocation", |_| {
+            geolocation::Geolocation::new()
+        })),
+        Arc::new(NativeContractType::new(GUESS_NUMBER, "GuessNumber", |_| {
+            guess_number::GuessNumber::new()
+        })),
+        Arc::new(NativeContractType::new(
+            BTC_PRICE_BOT,
+            "BtcPriceBot",
+            |_| btc_price_bot::BtcPriceBot::new(),
+        )),
+    ]
+}
+
 pub use support::*;
 mod support;
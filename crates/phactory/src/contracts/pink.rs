@@ -2,25 +2,69 @@ use crate::contracts;
 use crate::system::{TransactionError, TransactionResult};
 use anyhow::{anyhow, Result};
 use parity_scale_codec::{Decode, Encode};
+use phala_crypto::ecdh::EcdhPublicKey;
 use phala_mq::{ContractClusterId, MessageOrigin};
-use pink::runtime::ExecSideEffects;
+use phala_types::contract::CommandReceipt;
+use pink::{
+    runtime::{ExecSideEffects, IncomingContractMessage, CONTRACT_MESSAGE_SELECTOR},
+    types::Weight,
+};
 use runtime::{AccountId, BlockNumber};
 
 use super::{contract_address_to_id, NativeContractMore};
 
+pub use node_store::ProtectedFileNodeStoreOpener;
+
+mod node_store;
+
+/// Gas limit of a contract query. Roughly 10 seconds of execution time.
+pub const QUERY_GAS_LIMIT: Weight = 10_000_000_000_000;
+/// Gas limit of a contract hook, and the cap of the gas limit of a command. Commands run while
+/// processing blocks, so the limit is tighter than queries to keep a single contract from stalling
+/// the worker.
+pub const COMMAND_GAS_LIMIT: Weight = 1_000_000_000_000;
+/// Cap of the gas limit of a contract constructor deployed from the chain.
+pub const INSTANTIATE_GAS_LIMIT: Weight = COMMAND_GAS_LIMIT;
+/// Blocks between two reports of the cluster state roots, compared on chain across the members.
+pub const STATE_ROOT_REPORT_INTERVAL: BlockNumber = 100;
+
 #[derive(Debug, Encode, Decode)]
 pub enum Command {
-    InkMessage { nonce: Vec<u8>, message: Vec<u8> },
+    InkMessage {
+        /// Identifies the receipt of the command.
+        nonce: Vec<u8>,
+        message: Vec<u8>,
+        /// Capped by `COMMAND_GAS_LIMIT`.
+        gas_limit: Weight,
+    },
+    /// A message sent by another pink contract via `pink_extension::send_contract_message`.
+    ContractMessage {
+        correlation_id: u64,
+        is_reply: bool,
+        /// The ecdh public key of the sender, for replying.
+        from_pubkey: EcdhPublicKey,
+        payload: Vec<u8>,
+    },
 }
 
 #[derive(Debug, Encode, Decode)]
 pub enum Query {
     InkMessage(Vec<u8>),
+    /// Simulate `Command::InkMessage` with the given message against the current cluster state.
+    DryRunInkMessage(Vec<u8>),
 }
 
 #[derive(Debug, Encode, Decode)]
 pub enum Response {
+    /// The SCALE encoded `ContractExecResult`, which also carries the gas consumed.
     InkMessageReturn(Vec<u8>),
+    DryRunReturn {
+        /// The SCALE encoded `ContractExecResult`, carrying the output, debug message and gas
+        /// consumed.
+        exec_result: Vec<u8>,
+        /// The side effects the command would have produced.
+        effects: ExecSideEffects,
+    },
 }
 
 #[derive(Debug, Encode, Decode)]
@@ -43,16 +87,18 @@ impl Pink {
         wasm_bin: Vec<u8>,
         input_data: Vec<u8>,
         salt: Vec<u8>,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
-    ) -> Result<(Self, ExecSideEffects)> {
-        let (instance, effects) = pink::Contract::new(
+    ) -> Result<(Self, ExecSideEffects, Weight)> {
+        let (instance, effects, gas_consumed) = pink::Contract::instantiate(
             storage,
             origin.clone(),
             wasm_bin,
             input_data,
             cluster_id.as_bytes().to_vec(),
             salt,
+            gas_limit,
             block_number,
             now,
         )
@@ -63,6 +109,7 @@ impl Pink {
                 instance,
             },
             effects,
+            gas_consumed,
         ))
     }
 
@@ -89,6 +136,14 @@ impl contracts::NativeContract for Pink {
         context: &mut contracts::QueryContext,
     ) -> Result<Response, QueryError> {
         let origin = origin.ok_or(QueryError::BadOrigin)?;
+        match &req {
+            Query::InkMessage(message) | Query::DryRunInkMessage(message)
+                if is_contract_message(message) =>
+            {
+                return Err(QueryError::BadOrigin);
+            }
+            _ => (),
+        }
         match req {
             Query::InkMessage(input_data) => {
                 let storage = cluster_storage(&mut context.contract_clusters, &self.cluster_id)
@@ -99,6 +154,7 @@ impl contracts::NativeContract for Pink {
                     origin.clone(),
                     input_data,
                     true,
+                    QUERY_GAS_LIMIT,
                     context.block_number,
                     context.now_ms,
                 );
@@ -107,6 +163,23 @@ impl contracts::NativeContract for Pink {
                 }
                 return Ok(Response::InkMessageReturn(ink_result.encode()));
             }
+            Query::DryRunInkMessage(message) => {
+                let storage = cluster_storage(&mut context.contract_clusters, &self.cluster_id)
+                    .expect("Pink cluster should always exists!");
+
+                let (ink_result, effects) = self.instance.dry_run(
+                    storage,
+                    origin.clone(),
+                    message,
+                    COMMAND_GAS_LIMIT,
+                    context.block_number,
+                    context.now_ms,
+                );
+                return Ok(Response::DryRunReturn {
+                    exec_result: ink_result.encode(),
+                    effects,
+                });
+            }
         }
     }
 
@@ -117,23 +190,40 @@ impl contracts::NativeContract for Pink {
         context: &mut contracts::NativeContext,
     ) -> TransactionResult {
         match cmd {
-            Command::InkMessage { nonce: _, message } => {
+            Command::InkMessage {
+                nonce,
+                message,
+                gas_limit,
+            } => {
                 let origin: runtime::AccountId = match origin {
                     MessageOrigin::AccountId(origin) => origin.0.into(),
                     _ => return Err(TransactionError::BadOrigin),
                 };
+                if is_contract_message(&message) {
+                    return Err(TransactionError::BadOrigin);
+                }
 
-                let storage = cluster_storage(&mut context.contract_clusters, &self.cluster_id)
+                let cluster = context
+                    .contract_clusters
+                    .get_cluster_mut(&self.cluster_id)
                     .expect("Pink cluster should always exists!");
 
                 let (result, effects) = self.instance.bare_call(
-                    storage,
+                    &mut cluster.storage,
                     origin.clone(),
                     message,
                     false,
+                    gas_limit.min(COMMAND_GAS_LIMIT),
                     context.block.block_number,
                     context.block.now_ms,
                 );
+                cluster.add_command_receipt(CommandReceipt {
+                    block_number: context.block.block_number,
+                    contract: self.id(),
+                    nonce,
+                    gas_consumed: result.gas_consumed,
+                    succeeded: result.result.is_ok(),
+                });
 
                 let ret = pink::transpose_contract_result(&result).map_err(|err| {
                     log::error!("Pink [{:?}] command exec error: {:?}", self.id(), err);
@@ -144,6 +234,49 @@ impl contracts::NativeContract for Pink {
                 let _ = ret;
                 Ok(effects)
             }
+            Command::ContractMessage {
+                correlation_id,
+                is_reply,
+                from_pubkey,
+                payload,
+            } => {
+                // Only contracts can send contract messages, the origin is authenticated by the MQ.
+                let from = match origin {
+                    MessageOrigin::Contract(id) => id,
+                    _ => return Err(TransactionError::BadOrigin),
+                };
+                let message = IncomingContractMessage {
+                    from: from.into(),
+                    from_pubkey,
+                    correlation_id,
+                    is_reply,
+                    payload,
+                };
+                let mut input = CONTRACT_MESSAGE_SELECTOR.to_vec();
+                message.encode_to(&mut input);
+
+                let storage = cluster_storage(&mut context.contract_clusters, &self.cluster_id)
+                    .expect("Pink cluster should always exists!");
+
+                let (result, effects) = self.instance.bare_call(
+                    storage,
+                    AccountId::new(from.into()),
+                    input,
+                    false,
+                    COMMAND_GAS_LIMIT,
+                    context.block.block_number,
+                    context.block.now_ms,
+                );
+                pink::transpose_contract_result(&result).map_err(|err| {
+                    log::error!(
+                        "Pink [{:?}] contract message exec error: {:?}",
+                        self.id(),
+                        err
+                    );
+                    TransactionError::Other(format!("Handle contract message failed: {:?}", err))
+                })?;
+                Ok(effects)
+            }
         }
     }
 
@@ -152,7 +285,12 @@ impl contracts::NativeContract for Pink {
             .expect("Pink cluster should always exists!");
         let effects = self
             .instance
-            .on_block_end(storage, context.block.block_number, context.block.now_ms)
+            .on_block_end(
+                storage,
+                COMMAND_GAS_LIMIT,
+                context.block.block_number,
+                context.block.now_ms,
+            )
             .map_err(|err| {
                 log::error!("Pink [{:?}] on_block_end exec error: {:?}", self.id(), err);
                 TransactionError::Other(format!("Call contract on_block_end failed: {:?}", err))
@@ -171,6 +309,12 @@ impl NativeContractMore for Pink {
     }
 }
 
+/// Contract messages carry the sender in the payload, so only `Command::ContractMessage`, whose
+/// sender is authenticated by the MQ, can call the contract message handler.
+fn is_contract_message(message: &[u8]) -> bool {
+    message.starts_with(&CONTRACT_MESSAGE_SELECTOR)
+}
+
 fn cluster_storage<'a>(
     clusters: &'a mut cluster::ClusterKeeper,
     cluster_id: &ContractClusterId,
@@ -181,27 +325,48 @@ fn cluster_storage<'a>(
 }
 
 pub mod cluster {
-    use super::Pink;
+    use super::{contract_address_to_id, Pink};
 
-    use anyhow::Result;
+    use anyhow::{anyhow, Result};
     use phala_mq::{ContractClusterId, ContractId};
     use phala_serde_more as more;
+    use phala_types::contract::{CommandReceipt, InkEvent};
     use pink::{
         runtime::ExecSideEffects,
-        types::{AccountId, Hash},
+        types::{AccountId, Hash, Weight},
+        NodeStoreOpener,
     };
     use runtime::BlockNumber;
     use serde::{Deserialize, Serialize};
     use sp_core::sr25519;
     use sp_runtime::DispatchError;
-    use std::collections::{BTreeMap, BTreeSet};
+    use std::collections::{BTreeMap, BTreeSet, VecDeque};
+    use std::sync::Arc;
 
     #[derive(Default, Serialize, Deserialize)]
     pub struct ClusterKeeper {
         clusters: BTreeMap<ContractClusterId, Cluster>,
+        /// Opens the node stores of the disk backed clusters.
+        #[serde(skip)]
+        node_store_opener: Option<Arc<dyn NodeStoreOpener>>,
+        /// The clusters to keep the storage on disk when they are created.
+        #[serde(skip)]
+        disk_backed_clusters: BTreeSet<ContractClusterId>,
     }
 
     impl ClusterKeeper {
+        /// Keep the storage of the given clusters on disk, opened via `opener`.
+        ///
+        /// Only affects the clusters created afterwards, existing clusters keep their backend.
+        pub fn set_node_store(
+            &mut self,
+            opener: Arc<dyn NodeStoreOpener>,
+            disk_backed_clusters: BTreeSet<ContractClusterId>,
+        ) {
+            self.node_store_opener = Some(opener);
+            self.disk_backed_clusters = disk_backed_clusters;
+        }
+
         pub fn instantiate_contract(
             &mut self,
             cluster_id: ContractClusterId,
@@ -210,21 +375,64 @@ pub mod cluster {
             input_data: Vec<u8>,
             salt: Vec<u8>,
             contract_key: &sr25519::Pair,
+            gas_limit: Weight,
             block_number: BlockNumber,
             now: u64,
-        ) -> Result<ExecSideEffects> {
+        ) -> Result<(ExecSideEffects, Weight)> {
             let cluster = self.get_cluster_or_default_mut(&cluster_id, contract_key);
-            let (_, effects) = Pink::instantiate(
+            // The key must be in place before the constructor runs.
+            let code_hash = sp_core::hashing::blake2_256(&wasm_bin);
+            let address = pink::contract_address(&origin, &code_hash, cluster_id.as_bytes(), &salt);
+            cluster.storage.set_contract_key(&address, contract_key);
+            let result = Pink::instantiate(
                 cluster_id,
                 &mut cluster.storage,
                 origin,
                 wasm_bin,
                 input_data,
                 salt,
+                gas_limit,
                 block_number,
                 now,
-            )?;
-            Ok(effects)
+            );
+            if result.is_err() {
+                // No contract is deployed at the address, don't leave its key behind.
+                cluster.storage.remove_contract_key(&address);
+            }
+            let (_, effects, gas_consumed) = result?;
+            Ok((effects, gas_consumed))
+        }
+
+        /// Replace the code of a contract in the cluster, keeping its storage and key.
+        pub fn upgrade_contract(
+            &mut self,
+            cluster_id: &ContractClusterId,
+            contract_id: &ContractId,
+            origin: AccountId,
+            code_hash: Hash,
+            migration: Option<Vec<u8>>,
+            gas_limit: Weight,
+            block_number: BlockNumber,
+            now: u64,
+        ) -> Result<ExecSideEffects> {
+            let cluster = self
+                .get_cluster_mut(cluster_id)
+                .ok_or(anyhow!("Cluster not found"))?;
+            if !cluster.contracts.contains(contract_id) {
+                return Err(anyhow!("Contract not found in the cluster"));
+            }
+            let mut instance = pink::Contract::from_address(AccountId::new(contract_id.0));
+            instance
+                .upgrade(
+                    &mut cluster.storage,
+                    origin,
+                    code_hash,
+                    migration,
+                    gas_limit,
+                    block_number,
+                    now,
+                )
+                .map_err(|err| anyhow!("Upgrade contract failed: {:?}", err))
         }
 
         pub fn get_cluster_storage_mut(
@@ -243,15 +451,70 @@ pub mod cluster {
             cluster_id: &ContractClusterId,
             contract_key: &sr25519::Pair,
         ) -> &mut Cluster {
-            self.clusters.entry(cluster_id.clone()).or_insert_with(|| {
+            if !self.clusters.contains_key(cluster_id) {
                 let mut cluster = Cluster {
-                    storage: Default::default(),
+                    storage: self.new_storage(cluster_id),
                     contracts: Default::default(),
                     key: contract_key.clone(),
+                    ink_events: Default::default(),
+                    command_receipts: Default::default(),
                 };
                 cluster.set_id(cluster_id);
-                cluster
-            })
+                cluster.storage.set_cluster_key(contract_key);
+                self.clusters.insert(cluster_id.clone(), cluster);
+            }
+            self.clusters
+                .get_mut(cluster_id)
+                .expect("The cluster was just inserted")
+        }
+
+        /// Create a cluster from the state dumped by another member of it.
+        pub fn restore_cluster(
+            &mut self,
+            cluster_id: &ContractClusterId,
+            cluster_key: &sr25519::Pair,
+            contracts: Vec<ContractId>,
+            dump: pink::StorageDump,
+        ) -> Result<&mut Cluster> {
+            if self.clusters.contains_key(cluster_id) {
+                return Err(anyhow!("Cluster already exists"));
+            }
+            let cluster = self.get_cluster_or_default_mut(cluster_id, cluster_key);
+            if !cluster.storage.load(dump) {
+                self.clusters.remove(cluster_id);
+                return Err(anyhow!("State root mismatch"));
+            }
+            let cluster = self
+                .clusters
+                .get_mut(cluster_id)
+                .expect("The cluster was just restored");
+            cluster.contracts.extend(contracts);
+            Ok(cluster)
+        }
+
+        /// The state root of each cluster, including the uncommitted changes.
+        pub fn state_roots(&self) -> Vec<(ContractClusterId, Hash)> {
+            self.clusters
+                .iter()
+                .map(|(id, cluster)| (*id, cluster.state_root()))
+                .collect()
+        }
+
+        /// Remove a cluster with all its state.
+        pub fn remove_cluster(&mut self, cluster_id: &ContractClusterId) -> Option<Cluster> {
+            self.clusters.remove(cluster_id)
+        }
+
+        fn new_storage(&self, cluster_id: &ContractClusterId) -> pink::Storage {
+            match &self.node_store_opener {
+                Some(opener) if self.disk_backed_clusters.contains(cluster_id) => {
+                    let name = hex::encode(cluster_id);
+                    log::info!("Keeping the storage of cluster {} on disk", name);
+                    let store = opener.open(&name);
+                    pink::Storage::on_disk(name, store)
+                }
+                _ => pink::Storage::in_memory(),
+            }
         }
 
         pub fn commit_changes(&mut self) -> anyhow::Result<()> {
@@ -260,6 +523,53 @@ pub mod cluster {
             }
             Ok(())
         }
+
+        /// Remove the trie nodes no retained checkpoint refers to from the disk-backed clusters.
+        pub fn collect_garbage(&mut self) {
+            for cluster in self.clusters.values_mut() {
+                cluster.storage.collect_garbage();
+            }
+        }
+
+        /// Drop the ink! events emitted and the command receipts recorded at or before
+        /// `block_number` in all clusters.
+        pub fn prune_ink_events(&mut self, block_number: BlockNumber) {
+            for cluster in self.clusters.values_mut() {
+                cluster.prune_ink_events(block_number);
+                cluster.prune_command_receipts(block_number);
+            }
+        }
+
+        /// The retained receipts of the commands sent to `contract` with the given nonce.
+        pub fn command_receipts(&self, contract: &ContractId, nonce: &[u8]) -> Vec<CommandReceipt> {
+            self.clusters
+                .values()
+                .flat_map(|cluster| cluster.command_receipts.iter())
+                .filter(|receipt| &receipt.contract == contract && receipt.nonce == nonce)
+                .cloned()
+                .collect()
+        }
+
+        /// The retained ink! events emitted since `from_block`, optionally filtered by the emitting
+        /// contract and a topic.
+        pub fn ink_events(
+            &self,
+            contract: Option<&ContractId>,
+            topic: Option<&Hash>,
+            from_block: BlockNumber,
+        ) -> Vec<InkEvent> {
+            let mut events: Vec<_> = self
+                .clusters
+                .values()
+                .flat_map(|cluster| cluster.ink_events.iter())
+                .filter(|event| event.block_number >= from_block)
+                .filter(|event| contract.map_or(true, |id| &event.contract == id))
+                .filter(|event| topic.map_or(true, |topic| event.topics.contains(topic)))
+                .cloned()
+                .collect();
+            events.sort_by_key(|event| event.block_number);
+            events
+        }
     }
 
     #[derive(Serialize, Deserialize)]
@@ -268,6 +578,10 @@ pub mod cluster {
         contracts: BTreeSet<ContractId>,
         #[serde(with = "more::key_bytes")]
         key: sr25519::Pair,
+        #[serde(default, with = "more::scale_bytes")]
+        ink_events: VecDeque<InkEvent>,
+        #[serde(default, with = "more::scale_bytes")]
+        command_receipts: VecDeque<CommandReceipt>,
     }
 
     impl Cluster {
@@ -276,15 +590,82 @@ pub mod cluster {
             self.contracts.insert(address)
         }
 
+        /// Remove a contract from the cluster and delete its storage. Returns false if the
+        /// contract is not in the cluster.
+        pub fn remove_contract(&mut self, id: &ContractId) -> Result<bool> {
+            if !self.contracts.remove(id) {
+                return Ok(false);
+            }
+            pink::Contract::from_address(AccountId::new(id.0))
+                .destroy(&mut self.storage)
+                .map_err(|err| anyhow!("Destroy contract failed: {:?}", err))?;
+            Ok(true)
+        }
+
+        pub fn contracts(&self) -> impl Iterator<Item = &ContractId> {
+            self.contracts.iter()
+        }
+
         pub fn key(&self) -> &sr25519::Pair {
             &self.key
         }
 
+        /// The state root including the uncommitted changes.
+        pub fn state_root(&self) -> Hash {
+            self.storage.changes_transaction().0
+        }
+
+        /// Commit the pending changes and dump the storage, to bootstrap another member with.
+        pub fn dump_storage(&mut self) -> pink::StorageDump {
+            self.storage.commit_changes();
+            self.storage.dump()
+        }
+
         pub fn commit_changes(&mut self) -> anyhow::Result<()> {
             self.storage.commit_changes();
             Ok(())
         }
 
+        /// Record the ink! events emitted by the contracts in the given block.
+        pub fn add_ink_events(
+            &mut self,
+            block_number: BlockNumber,
+            events: Vec<(AccountId, Vec<Hash>, Vec<u8>)>,
+        ) {
+            for (address, topics, payload) in events {
+                self.ink_events.push_back(InkEvent {
+                    block_number,
+                    contract: contract_address_to_id(&address),
+                    topics,
+                    payload,
+                });
+            }
+        }
+
+        /// Drop the ink! events emitted at or before `block_number`.
+        pub fn prune_ink_events(&mut self, block_number: BlockNumber) {
+            while let Some(event) = self.ink_events.front() {
+                if event.block_number > block_number {
+                    break;
+                }
+                self.ink_events.pop_front();
+            }
+        }
+
+        pub fn add_command_receipt(&mut self, receipt: CommandReceipt) {
+            self.command_receipts.push_back(receipt);
+        }
+
+        /// Drop the command receipts recorded at or before `block_number`.
+        pub fn prune_command_receipts(&mut self, block_number: BlockNumber) {
+            while let Some(receipt) = self.command_receipts.front() {
+                if receipt.block_number > block_number {
+                    break;
+                }
+                self.command_receipts.pop_front();
+            }
+        }
+
         pub fn set_id(&mut self, id: &ContractClusterId) {
             self.storage.set_cluster_id(id.as_bytes());
         }
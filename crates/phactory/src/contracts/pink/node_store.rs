@@ -0,0 +1,86 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use pink::{types::Hash, NodeStore, NodeStoreOpener};
+
+/// Keeps each trie node of a cluster in its own protected file, named after the node hash.
+pub struct ProtectedFileNodeStore<Platform> {
+    platform: Platform,
+    dir: PathBuf,
+    key: Vec<u8>,
+}
+
+impl<Platform: pal::Platform> ProtectedFileNodeStore<Platform> {
+    fn node_path(&self, key: &Hash) -> PathBuf {
+        let key = hex::encode(key);
+        // Spread the nodes over 256 sub directories to keep the directories small.
+        self.dir.join(&key[..2]).join(key)
+    }
+}
+
+impl<Platform: pal::Platform> NodeStore for ProtectedFileNodeStore<Platform> {
+    fn get(&self, key: &Hash) -> Result<Option<Vec<u8>>, String> {
+        let file = self
+            .platform
+            .open_protected_file(self.node_path(key), &self.key)
+            .map_err(|err| format!("{:?}", err))?;
+        let mut file = match file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut value = Vec::new();
+        file.read_to_end(&mut value)
+            .map_err(|err| err.to_string())?;
+        Ok(Some(value))
+    }
+
+    fn put(&self, key: &Hash, value: &[u8]) -> Result<(), String> {
+        let path = self.node_path(key);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        // Always rewrite the node in case an earlier write was interrupted.
+        let mut file = self
+            .platform
+            .create_protected_file(&path, &self.key)
+            .map_err(|err| format!("{:?}", err))?;
+        file.write_all(value).map_err(|err| err.to_string())?;
+        file.flush().map_err(|err| err.to_string())
+    }
+
+    fn remove(&self, key: &Hash) -> Result<(), String> {
+        match std::fs::remove_file(self.node_path(key)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Opens the node stores under `<sealing_path>/pink_nodes`, encrypted with the same key as the
+/// checkpoints.
+pub struct ProtectedFileNodeStoreOpener<Platform> {
+    platform: Platform,
+    root: PathBuf,
+    key: Vec<u8>,
+}
+
+impl<Platform> ProtectedFileNodeStoreOpener<Platform> {
+    pub fn new(platform: Platform, sealing_path: &str, key: Vec<u8>) -> Self {
+        Self {
+            platform,
+            root: PathBuf::from(sealing_path).join("pink_nodes"),
+            key,
+        }
+    }
+}
+
+impl<Platform: pal::Platform> NodeStoreOpener for ProtectedFileNodeStoreOpener<Platform> {
+    fn open(&self, name: &str) -> Arc<dyn NodeStore> {
+        Arc::new(ProtectedFileNodeStore {
+            platform: self.platform.clone(),
+            dir: self.root.join(name),
+            key: self.key.clone(),
+        })
+    }
+}
@@ -2,6 +2,7 @@ use phala_crypto::ecdh::EcdhPublicKey;
 use phala_mq::traits::MessageChannel;
 use runtime::BlockNumber;
 use serde::{Deserialize, Serialize};
+use sp_core::sr25519;
 
 use super::pink::cluster::ClusterKeeper;
 use super::*;
@@ -164,6 +165,34 @@ impl<Con: NativeContract> NativeCompatContract<Con> {
     }
 }
 
+impl<Con: NativeContract + NativeContractMore> NativeCompatContract<Con> {
+    /// Wrap `contract`, sending its messages signed with `contract_key` and receiving the commands
+    /// encrypted to `ecdh_key` from the dispatcher of `block`.
+    pub fn connect(
+        contract: Con,
+        contract_key: sr25519::Pair,
+        ecdh_key: KeyPair,
+        block: &mut BlockInfo,
+        cluster_id: phala_mq::ContractClusterId,
+    ) -> Self {
+        let contract_id = contract.id();
+        let sender = MessageOrigin::Contract(contract_id);
+        let send_mq = block.send_mq.channel(sender, contract_key.into());
+        let cmd_rcv_mq = SecretReceiver::new_secret(
+            block.recv_mq.subscribe(command_topic(contract_id)).into(),
+            ecdh_key.clone(),
+        );
+        Self::new(
+            contract,
+            send_mq,
+            cmd_rcv_mq,
+            ecdh_key,
+            cluster_id,
+            contract_id,
+        )
+    }
+}
+
 impl<Con: NativeContract + NativeContractMore> Contract for NativeCompatContract<Con> {
     fn id(&self) -> ContractId {
         self.contract_id
@@ -243,4 +272,6 @@ impl<Con: NativeContract + NativeContractMore> Contract for NativeCompatContract
 }
 
 pub use keeper::*;
+pub use registry::*;
 mod keeper;
+mod registry;
@@ -1,29 +1,93 @@
 use phala_mq::ContractId;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::{self, SerializeMap},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use std::collections::BTreeMap;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 
-use super::{Contract, NativeCompatContract, NativeContractWrapper};
-use crate::contracts::{
-    assets::Assets, balances::Balances, btc_lottery::BtcLottery, btc_price_bot::BtcPriceBot,
-    data_plaza::DataPlaza, geolocation::Geolocation, guess_number::GuessNumber, pink::Pink,
-    web3analytics::Web3Analytics,
+use super::{
+    native_contract_factory_by_name, Contract, NativeCompatContract, NativeContractInstance,
 };
+use crate::contracts::pink::Pink;
 
 type ContractMap = BTreeMap<ContractId, AnyContract>;
-type Compat<T> = NativeCompatContract<NativeContractWrapper<T>>;
 
-#[derive(Serialize, Deserialize)]
+const PINK_TYPE_NAME: &str = "Pink";
+
+/// A native contract created by a registered `NativeContractFactory`.
+pub struct NativeInstance {
+    type_name: &'static str,
+    instance: Box<dyn NativeContractInstance>,
+}
+
+impl NativeInstance {
+    pub fn new(type_name: &'static str, instance: Box<dyn NativeContractInstance>) -> Self {
+        NativeInstance {
+            type_name,
+            instance,
+        }
+    }
+}
+
+/// A contract stored in the keeper.
+///
+/// A contract is serialized as a single entry map from its type name to its state, the layout of
+/// an externally tagged enum. The type names of the builtin native contracts are the names of the
+/// variants they had before the registry, so older checkpoints can still be loaded.
 pub enum AnyContract {
     Pink(NativeCompatContract<Pink>),
-    DataPlaza(Compat<DataPlaza>),
-    Balances(Compat<Balances>),
-    Assets(Compat<Assets>),
-    Web3Analytics(Compat<Web3Analytics>),
-    BtcLottery(Compat<BtcLottery>),
-    Geolocation(Compat<Geolocation>),
-    GuessNumber(Compat<GuessNumber>),
-    BtcPriceBot(Compat<BtcPriceBot>),
+    Native(NativeInstance),
+}
+
+impl Serialize for AnyContract {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        match self {
+            AnyContract::Pink(c) => map.serialize_entry(PINK_TYPE_NAME, c)?,
+            AnyContract::Native(c) => {
+                let value = c.instance.save().map_err(ser::Error::custom)?;
+                map.serialize_entry(c.type_name, &value)?
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyContract {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AnyContractVisitor;
+
+        impl<'de> Visitor<'de> for AnyContractVisitor {
+            type Value = AnyContract;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a contract tagged with its type name")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<AnyContract, A::Error> {
+                let type_name: String = map
+                    .next_key()?
+                    .ok_or_else(|| de::Error::custom("Missing contract type name"))?;
+                if type_name == PINK_TYPE_NAME {
+                    return Ok(AnyContract::Pink(map.next_value()?));
+                }
+                let factory = native_contract_factory_by_name(&type_name).ok_or_else(|| {
+                    de::Error::custom(format!("Unknown native contract type {}", type_name))
+                })?;
+                let value: serde_cbor::Value = map.next_value()?;
+                let instance = factory.restore(value).map_err(de::Error::custom)?;
+                Ok(AnyContract::Native(NativeInstance::new(
+                    factory.type_name(),
+                    instance,
+                )))
+            }
+        }
+
+        deserializer.deserialize_map(AnyContractVisitor)
+    }
 }
 
 impl Deref for AnyContract {
@@ -32,14 +96,7 @@ impl Deref for AnyContract {
     fn deref(&self) -> &Self::Target {
         match self {
             AnyContract::Pink(c) => c,
-            AnyContract::DataPlaza(c) => c,
-            AnyContract::Balances(c) => c,
-            AnyContract::Assets(c) => c,
-            AnyContract::Web3Analytics(c) => c,
-            AnyContract::BtcLottery(c) => c,
-            AnyContract::Geolocation(c) => c,
-            AnyContract::GuessNumber(c) => c,
-            AnyContract::BtcPriceBot(c) => c,
+            AnyContract::Native(c) => c.instance.as_contract(),
         }
     }
 }
@@ -48,14 +105,7 @@ impl DerefMut for AnyContract {
     fn deref_mut(&mut self) -> &mut Self::Target {
         match self {
             AnyContract::Pink(c) => c,
-            AnyContract::DataPlaza(c) => c,
-            AnyContract::Balances(c) => c,
-            AnyContract::Assets(c) => c,
-            AnyContract::Web3Analytics(c) => c,
-            AnyContract::BtcLottery(c) => c,
-            AnyContract::Geolocation(c) => c,
-            AnyContract::GuessNumber(c) => c,
-            AnyContract::BtcPriceBot(c) => c,
+            AnyContract::Native(c) => c.instance.as_contract_mut(),
         }
     }
 }
@@ -66,51 +116,9 @@ impl From<NativeCompatContract<Pink>> for AnyContract {
     }
 }
 
-impl From<Compat<DataPlaza>> for AnyContract {
-    fn from(c: Compat<DataPlaza>) -> Self {
-        AnyContract::DataPlaza(c)
-    }
-}
-
-impl From<Compat<Balances>> for AnyContract {
-    fn from(c: Compat<Balances>) -> Self {
-        AnyContract::Balances(c)
-    }
-}
-
-impl From<Compat<Assets>> for AnyContract {
-    fn from(c: Compat<Assets>) -> Self {
-        AnyContract::Assets(c)
-    }
-}
-
-impl From<Compat<Web3Analytics>> for AnyContract {
-    fn from(c: Compat<Web3Analytics>) -> Self {
-        AnyContract::Web3Analytics(c)
-    }
-}
-
-impl From<Compat<BtcLottery>> for AnyContract {
-    fn from(c: Compat<BtcLottery>) -> Self {
-        AnyContract::BtcLottery(c)
-    }
-}
-
-impl From<Compat<Geolocation>> for AnyContract {
-    fn from(c: Compat<Geolocation>) -> Self {
-        AnyContract::Geolocation(c)
-    }
-}
-
-impl From<Compat<GuessNumber>> for AnyContract {
-    fn from(c: Compat<GuessNumber>) -> Self {
-        AnyContract::GuessNumber(c)
-    }
-}
-
-impl From<Compat<BtcPriceBot>> for AnyContract {
-    fn from(c: Compat<BtcPriceBot>) -> Self {
-        AnyContract::BtcPriceBot(c)
+impl From<NativeInstance> for AnyContract {
+    fn from(c: NativeInstance) -> Self {
+        AnyContract::Native(c)
     }
 }
 
@@ -127,6 +135,10 @@ impl ContractsKeeper {
         self.0.keys()
     }
 
+    pub fn remove(&mut self, id: &ContractId) -> Option<AnyContract> {
+        self.0.remove(id)
+    }
+
     pub fn get_mut(&mut self, id: &ContractId) -> Option<&mut AnyContract> {
         self.0.get_mut(id)
     }
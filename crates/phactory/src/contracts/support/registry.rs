@@ -0,0 +1,200 @@
+//! The registry of the native contract types pRuntime can instantiate.
+//!
+//! Each native contract type is registered with a `NativeContractFactory`, which creates the
+//! instances deployed with `CodeIndex::NativeCode(code_id)` and restores them from checkpoints.
+//! The contracts built into pRuntime are registered by default, and contracts living in other
+//! crates can be added with `phactory::register_native_contract` at startup, before any block is
+//! dispatched.
+
+use anyhow::{anyhow, Result};
+use phala_mq::ContractClusterId;
+use serde::{de::DeserializeOwned, Serialize};
+use sp_core::sr25519;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use super::{
+    Contract, NativeCompatContract, NativeContract, NativeContractMore, NativeContractWrapper,
+};
+use crate::contracts::{ContractId32, ContractInfo};
+use crate::secret_channel::KeyPair;
+use crate::types::BlockInfo;
+
+/// A native contract with its concrete type erased.
+pub trait NativeContractInstance: Send {
+    fn as_contract(&self) -> &dyn Contract;
+    fn as_contract_mut(&mut self) -> &mut dyn Contract;
+    /// Save the instance into a self-describing value, to be given back to
+    /// `NativeContractFactory::restore`.
+    fn save(&self) -> Result<serde_cbor::Value>;
+}
+
+impl<Con> NativeContractInstance for NativeCompatContract<Con>
+where
+    Con: NativeContract + NativeContractMore,
+    Self: Serialize + Send,
+{
+    fn as_contract(&self) -> &dyn Contract {
+        self
+    }
+
+    fn as_contract_mut(&mut self) -> &mut dyn Contract {
+        self
+    }
+
+    fn save(&self) -> Result<serde_cbor::Value> {
+        Ok(serde_cbor::value::to_value(self)?)
+    }
+}
+
+pub trait NativeContractFactory: Send + Sync {
+    /// The id the contract type is deployed with, as in `CodeIndex::NativeCode(code_id)`.
+    fn code_id(&self) -> ContractId32;
+    /// The name tagging the saved instances in checkpoints. It must never change once released.
+    fn type_name(&self) -> &'static str;
+    /// Create an instance for `contract_info`, connected to the message queues of `block`.
+    fn instantiate(
+        &self,
+        contract_info: &ContractInfo<chain::Hash, chain::AccountId>,
+        contract_key: &sr25519::Pair,
+        ecdh_key: KeyPair,
+        block: &mut BlockInfo,
+        cluster_id: ContractClusterId,
+    ) -> Box<dyn NativeContractInstance>;
+    /// Restore an instance saved by `NativeContractInstance::save`.
+    fn restore(&self, value: serde_cbor::Value) -> Result<Box<dyn NativeContractInstance>>;
+}
+
+type Compat<Con> = NativeCompatContract<NativeContractWrapper<Con>>;
+
+/// The factory of a `NativeContract` type, which is created from the contract key by `new`.
+pub struct NativeContractType<Con> {
+    code_id: ContractId32,
+    type_name: &'static str,
+    new: fn(&sr25519::Pair) -> Con,
+}
+
+impl<Con> NativeContractType<Con> {
+    pub fn new(
+        code_id: ContractId32,
+        type_name: &'static str,
+        new: fn(&sr25519::Pair) -> Con,
+    ) -> Self {
+        NativeContractType {
+            code_id,
+            type_name,
+            new,
+        }
+    }
+}
+
+impl<Con> NativeContractFactory for NativeContractType<Con>
+where
+    Con: NativeContract + 'static,
+    Compat<Con>: NativeContractInstance + DeserializeOwned,
+{
+    fn code_id(&self) -> ContractId32 {
+        self.code_id
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    fn instantiate(
+        &self,
+        contract_info: &ContractInfo<chain::Hash, chain::AccountId>,
+        contract_key: &sr25519::Pair,
+        ecdh_key: KeyPair,
+        block: &mut BlockInfo,
+        cluster_id: ContractClusterId,
+    ) -> Box<dyn NativeContractInstance> {
+        let contract = NativeContractWrapper::new((self.new)(contract_key), contract_info);
+        Box::new(NativeCompatContract::connect(
+            contract,
+            contract_key.clone(),
+            ecdh_key,
+            block,
+            cluster_id,
+        ))
+    }
+
+    fn restore(&self, value: serde_cbor::Value) -> Result<Box<dyn NativeContractInstance>> {
+        let contract: Compat<Con> = serde_cbor::value::from_value(value)?;
+        Ok(Box::new(contract))
+    }
+}
+
+type Factories = BTreeMap<ContractId32, Arc<dyn NativeContractFactory>>;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Factories> = RwLock::new({
+        let mut factories = Factories::new();
+        for factory in crate::contracts::builtin_native_contracts() {
+            add_factory(&mut factories, factory).expect("Conflicting builtin native contracts");
+        }
+        factories
+    });
+}
+
+fn add_factory(factories: &mut Factories, factory: Arc<dyn NativeContractFactory>) -> Result<()> {
+    if factories.contains_key(&factory.code_id()) {
+        return Err(anyhow!(
+            "Native contract code id {} is already registered",
+            factory.code_id()
+        ));
+    }
+    if factories
+        .values()
+        .any(|other| other.type_name() == factory.type_name())
+    {
+        return Err(anyhow!(
+            "Native contract type {} is already registered",
+            factory.type_name()
+        ));
+    }
+    factories.insert(factory.code_id(), factory);
+    Ok(())
+}
+
+/// Register a native contract type, failing if its code id or type name is taken.
+pub fn register_native_contract(factory: impl NativeContractFactory + 'static) -> Result<()> {
+    let mut factories = REGISTRY.write().expect("Native contract registry poisoned");
+    add_factory(&mut factories, Arc::new(factory))
+}
+
+pub fn native_contract_factory(code_id: ContractId32) -> Option<Arc<dyn NativeContractFactory>> {
+    let factories = REGISTRY.read().expect("Native contract registry poisoned");
+    factories.get(&code_id).cloned()
+}
+
+pub fn native_contract_factory_by_name(type_name: &str) -> Option<Arc<dyn NativeContractFactory>> {
+    let factories = REGISTRY.read().expect("Native contract registry poisoned");
+    factories
+        .values()
+        .find(|factory| factory.type_name() == type_name)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::{data_plaza::DataPlaza, BTC_LOTTERY, DATA_PLAZA};
+
+    #[test]
+    fn builtin_contracts_are_registered() {
+        let factory = native_contract_factory(BTC_LOTTERY).unwrap();
+        assert_eq!(factory.type_name(), "BtcLottery");
+        let factory = native_contract_factory_by_name("DataPlaza").unwrap();
+        assert_eq!(factory.code_id(), DATA_PLAZA);
+    }
+
+    #[test]
+    fn conflicting_registrations_are_rejected() {
+        let taken_id = NativeContractType::new(DATA_PLAZA, "AnotherPlaza", |_| DataPlaza::new());
+        assert!(register_native_contract(taken_id).is_err());
+        let taken_name = NativeContractType::new(10000, "DataPlaza", |_| DataPlaza::new());
+        assert!(register_native_contract(taken_name).is_err());
+        assert!(native_contract_factory(10000).is_none());
+    }
+}
@@ -18,7 +18,8 @@ use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
 };
 
-use crate::light_validation::LightValidation;
+use crate::light_validation::{BeefyLightValidation, LightValidation};
+use std::collections::BTreeSet;
 use std::marker::PhantomData;
 use std::path::PathBuf;
 use std::str;
@@ -51,9 +52,13 @@ use std::time::Instant;
 use types::Error;
 
 pub use contracts::pink;
+pub use contracts::{
+    register_native_contract, Contract, NativeContext, NativeContract, NativeContractFactory,
+    NativeContractInstance, NativeContractType, QueryContext,
+};
 pub use side_task::SideTaskManager;
-pub use storage::{Storage, StorageExt};
-pub use system::gk;
+pub use storage::{AnyValidator, Storage, StorageExt};
+pub use system::{gk, TransactionError, TransactionResult};
 pub use types::BlockInfo;
 
 pub mod benchmark;
@@ -63,6 +68,7 @@ mod contracts;
 mod cryptography;
 mod light_validation;
 mod prpc_service;
+mod query_subscription;
 mod rpc_types;
 mod secret_channel;
 mod side_task;
@@ -82,7 +88,7 @@ struct RuntimeState {
     recv_mq: MessageDispatcher,
 
     // chain storage synchonizing
-    storage_synchronizer: Synchronizer<LightValidation<chain::Runtime>>,
+    storage_synchronizer: Synchronizer<AnyValidator>,
 
     // TODO.kevin: use a better serialization approach
     chain_storage: Storage,
@@ -111,7 +117,8 @@ const RUNTIME_SEALED_DATA_FILE: &str = "runtime-data.seal";
 const CHECKPOINT_FILE: &str = "checkpoint.seal";
 const TMP_CHECKPOINT_FILE: &str = "checkpoint.seal.tmp";
 const BACKUP_CHECKPOINT_FILE: &str = "checkpoint.seal.bak";
-const CHECKPOINT_VERSION: u32 = 1;
+/// Version 2 changed the layout of the pink cluster storages, which still load version 1.
+const CHECKPOINT_VERSION: u32 = 2;
 
 fn maybe_remove_checkpoints(basedir: &str) {
     for filename in [CHECKPOINT_FILE, BACKUP_CHECKPOINT_FILE].iter() {
@@ -124,6 +131,23 @@ fn maybe_remove_checkpoints(basedir: &str) {
     }
 }
 
+/// The pink clusters configured to keep their storage on disk. Malformed ids are ignored.
+fn disk_backed_clusters(args: &InitArgs) -> BTreeSet<H256> {
+    args.disk_backed_clusters
+        .iter()
+        .filter_map(|id| {
+            let bytes = hex::decode(id.trim_start_matches("0x")).ok();
+            match bytes {
+                Some(bytes) if bytes.len() == 32 => Some(H256::from_slice(&bytes)),
+                _ => {
+                    warn!("Ignoring invalid disk backed cluster id: {}", id);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 #[derive(Encode, Decode, Clone, Debug)]
 struct PersistentRuntimeData {
     genesis_block_hash: H256,
@@ -169,6 +193,9 @@ pub struct Phactory<Platform> {
     #[serde(skip)]
     #[serde(default = "Instant::now")]
     last_checkpoint: Instant,
+
+    #[serde(skip)]
+    query_subscriptions: query_subscription::QuerySubscriptions,
 }
 
 impl<Platform: pal::Platform> Phactory<Platform> {
@@ -185,6 +212,7 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             system: None,
             side_task_man: Default::default(),
             last_checkpoint: Instant::now(),
+            query_subscriptions: Default::default(),
         }
     }
 
@@ -209,6 +237,8 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         if let Some(system) = &mut self.system {
             system.sealing_path = self.args.sealing_path.clone();
             system.geoip_city_db = self.args.geoip_city_db.clone();
+            system.ink_event_retention = self.args.ink_event_retention;
+            system.set_disk_backed_clusters(disk_backed_clusters(&self.args));
         }
     }
 
@@ -321,6 +351,10 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         }
         info!("Checkpoint saved to {:?}", checkpoint_file);
         self.last_checkpoint = Instant::now();
+        // The dropped pink trie nodes can only be removed once the new checkpoint is in place.
+        if let Some(system) = self.system.as_mut() {
+            system.collect_garbage();
+        }
         Ok(())
     }
 
@@ -360,7 +394,16 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             }
         };
 
-        let loader: PhactoryLoader<_> = serde_cbor::de::from_reader(file)?;
+        // The disk backed pink clusters reopen their node stores while being deserialized.
+        let mut node_store_opener = pink::ProtectedFileNodeStoreOpener::new(
+            platform.clone(),
+            sealing_path,
+            runtime_data.sk.to_vec(),
+        );
+        let loader: PhactoryLoader<_> =
+            ::pink::using_node_store_opener(&mut node_store_opener, || {
+                serde_cbor::de::from_reader(file)
+            })?;
         Ok(Some(loader.0))
     }
 
@@ -0,0 +1,476 @@
+//! Header validation with the BEEFY commitments and the MMR leaf proofs.
+//!
+//! The BEEFY authorities sign commitments to the root of the MMR of all the blocks. Each MMR leaf
+//! contains the hash of the parent block, so a header is proven finalized by the leaf of its
+//! child block and a single signed commitment, no matter how many headers are in between.
+//!
+//! Not wired into pherry yet: it neither sends `encoded_beefy_genesis_info` nor relays the
+//! `BeefyFinalityProof`s, so it's only used by relayers built for it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::Result;
+use mmr_lib::{leaf_index_to_mmr_size, leaf_index_to_pos, Merge, MerkleProof};
+use parity_scale_codec::{Decode, Encode};
+use phactory_api::blocks::{
+    BeefyAuthoritySet, BeefyFinalityProof, BeefyMmrLeaf, BeefySignedCommitment, MmrLeafProof,
+};
+use phala_serde_more as more;
+use serde::{Deserialize, Serialize};
+use sp_core::{ecdsa, hashing::keccak_256, H256};
+
+use super::storage_proof::{StorageProof, StorageProofChecker};
+use super::utils::storage_prefix;
+use super::BridgeId;
+
+type Hashing = <chain::Runtime as frame_system::Config>::Hashing;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BeefyBridgeInfo {
+    last_finalized_block_header: chain::Header,
+    #[serde(with = "more::scale_bytes")]
+    current_set: BeefyAuthoritySet,
+}
+
+// The fields are named apart from `LightValidation` to be told apart in `AnyValidator`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct BeefyLightValidation {
+    num_bridges: BridgeId,
+    beefy_bridges: BTreeMap<BridgeId, BeefyBridgeInfo>,
+}
+
+impl BeefyLightValidation {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn initialize_bridge(
+        &mut self,
+        block_header: chain::Header,
+        authority_set: BeefyAuthoritySet,
+        proof: StorageProof,
+    ) -> Result<BridgeId> {
+        check_authority_set_proof(
+            block_header.state_root,
+            proof,
+            "Authorities",
+            &authority_set,
+            authority_set.id,
+        )?;
+
+        let new_bridge_id = self.num_bridges + 1;
+        self.beefy_bridges.insert(
+            new_bridge_id,
+            BeefyBridgeInfo {
+                last_finalized_block_header: block_header,
+                current_set: authority_set,
+            },
+        );
+        self.num_bridges = new_bridge_id;
+
+        Ok(new_bridge_id)
+    }
+
+    /// Submits a block header proven finalized by a BEEFY commitment
+    ///
+    /// The header doesn't need to be the child of the last finalized one. Optionally the proof
+    /// carries the next authority set, which is expected to sign the following commitments.
+    pub fn submit_finalized_header(
+        &mut self,
+        bridge_id: BridgeId,
+        header: chain::Header,
+        finality_proof: BeefyFinalityProof,
+    ) -> Result<()> {
+        let bridge = self
+            .beefy_bridges
+            .get_mut(&bridge_id)
+            .ok_or_else(|| anyhow::Error::msg(Error::NoSuchBridgeExists))?;
+        if header.number <= bridge.last_finalized_block_header.number {
+            return Err(anyhow::Error::msg(Error::HeaderAlreadyFinalized));
+        }
+
+        let BeefyFinalityProof {
+            signed_commitment,
+            mmr_leaf,
+            mmr_proof,
+            authority_set_change,
+        } = finality_proof;
+
+        verify_signed_commitment(&signed_commitment, &bridge.current_set)?;
+
+        // The leaf of the child block commits to the header
+        let leaf = BeefyMmrLeaf::decode(&mut &mmr_leaf[..])
+            .map_err(|_| anyhow::Error::msg(Error::InvalidMmrLeaf))?;
+        if leaf.parent_number_and_hash != (header.number, header.hash()) {
+            return Err(anyhow::Error::msg(Error::MmrLeafMismatch));
+        }
+        verify_mmr_proof(signed_commitment.commitment.payload, &mmr_leaf, mmr_proof)?;
+
+        if let Some(change) = authority_set_change {
+            if change.authority_set.id != bridge.current_set.id + 1 {
+                return Err(anyhow::Error::msg(Error::UnexpectedValidatorSetId));
+            }
+            check_authority_set_proof(
+                header.state_root,
+                change.authority_proof,
+                "NextAuthorities",
+                &change.authority_set,
+                bridge.current_set.id,
+            )?;
+            bridge.current_set = change.authority_set;
+        }
+        bridge.last_finalized_block_header = header;
+
+        Ok(())
+    }
+
+    pub fn validate_storage_proof(
+        &self,
+        state_root: H256,
+        proof: StorageProof,
+        items: &[(&[u8], &[u8])], // &[(key, value)]
+    ) -> Result<()> {
+        let checker = StorageProofChecker::<Hashing>::new(state_root, proof)?;
+        for (k, v) in items {
+            let actual_value = checker
+                .read_value(k)?
+                .ok_or_else(|| anyhow::Error::msg(Error::StorageValueUnavailable))?;
+            if actual_value.as_slice() != *v {
+                return Err(anyhow::Error::msg(Error::StorageValueMismatch));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NoSuchBridgeExists,
+    HeaderAlreadyFinalized,
+    UnexpectedValidatorSetId,
+    SignatureCountMismatch,
+    InvalidSignature,
+    NotEnoughSignatures,
+    InvalidMmrLeaf,
+    MmrLeafMismatch,
+    InvalidMmrProof,
+    StorageValueUnavailable,
+    StorageValueMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSuchBridgeExists => write!(f, "no such bridge exists"),
+            Error::HeaderAlreadyFinalized => write!(f, "header already finalized"),
+            Error::UnexpectedValidatorSetId => write!(f, "unexpected validator set id"),
+            Error::SignatureCountMismatch => write!(f, "signature count mismatch"),
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::NotEnoughSignatures => write!(f, "not enough signatures"),
+            Error::InvalidMmrLeaf => write!(f, "invalid mmr leaf"),
+            Error::MmrLeafMismatch => write!(f, "mmr leaf mismatch"),
+            Error::InvalidMmrProof => write!(f, "invalid mmr proof"),
+            Error::StorageValueUnavailable => write!(f, "storage value unavailable"),
+            Error::StorageValueMismatch => write!(f, "storage value mismatch"),
+        }
+    }
+}
+
+/// Checks the authority set read from `Beefy::<authorities>`, with `Beefy::ValidatorSetId` being
+/// `set_id`, against the state root.
+fn check_authority_set_proof(
+    state_root: H256,
+    proof: StorageProof,
+    authorities: &str,
+    authority_set: &BeefyAuthoritySet,
+    set_id: u64,
+) -> Result<()> {
+    let checker = StorageProofChecker::<Hashing>::new(state_root, proof)?;
+    let items = [
+        (
+            storage_prefix("Beefy", authorities),
+            authority_set.list.encode(),
+        ),
+        (storage_prefix("Beefy", "ValidatorSetId"), set_id.encode()),
+    ];
+    for (key, value) in items.iter() {
+        let actual_value = checker
+            .read_value(key)?
+            .ok_or_else(|| anyhow::Error::msg(Error::StorageValueUnavailable))?;
+        if &actual_value != value {
+            return Err(anyhow::Error::msg(Error::StorageValueMismatch));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the commitment is signed by more than 2/3 of the authorities
+fn verify_signed_commitment(
+    signed_commitment: &BeefySignedCommitment,
+    authority_set: &BeefyAuthoritySet,
+) -> Result<()> {
+    let commitment = &signed_commitment.commitment;
+    if commitment.validator_set_id != authority_set.id {
+        return Err(anyhow::Error::msg(Error::UnexpectedValidatorSetId));
+    }
+    if signed_commitment.signatures.len() != authority_set.list.len() {
+        return Err(anyhow::Error::msg(Error::SignatureCountMismatch));
+    }
+    let message = keccak_256(&commitment.encode());
+    let mut num_signatures = 0;
+    for (signature, public) in signed_commitment.signatures.iter().zip(&authority_set.list) {
+        if let Some(signature) = signature {
+            if !ecdsa::Pair::verify_prehashed(signature, &message, public) {
+                return Err(anyhow::Error::msg(Error::InvalidSignature));
+            }
+            num_signatures += 1;
+        }
+    }
+    let threshold = authority_set.list.len() * 2 / 3 + 1;
+    if num_signatures < threshold {
+        return Err(anyhow::Error::msg(Error::NotEnoughSignatures));
+    }
+    Ok(())
+}
+
+struct Keccak256Merge;
+
+impl Merge for Keccak256Merge {
+    type Item = H256;
+
+    fn merge(left: &H256, right: &H256) -> H256 {
+        let mut concat = left.as_bytes().to_vec();
+        concat.extend_from_slice(right.as_bytes());
+        keccak_256(&concat).into()
+    }
+}
+
+/// Checks the encoded leaf is in the MMR with the root
+fn verify_mmr_proof(root: H256, leaf: &[u8], proof: MmrLeafProof) -> Result<()> {
+    if proof.leaf_index >= proof.leaf_count {
+        return Err(anyhow::Error::msg(Error::InvalidMmrProof));
+    }
+    let mmr_size = leaf_index_to_mmr_size(proof.leaf_count - 1);
+    let leaf_position = leaf_index_to_pos(proof.leaf_index);
+    let leaf_hash = H256::from(keccak_256(leaf));
+    let valid = MerkleProof::<H256, Keccak256Merge>::new(mmr_size, proof.items)
+        .verify(root, vec![(leaf_position, leaf_hash)])
+        .map_err(|_| anyhow::Error::msg(Error::InvalidMmrProof))?;
+    if !valid {
+        return Err(anyhow::Error::msg(Error::InvalidMmrProof));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phactory_api::blocks::{BeefyAuthoritySetChange, BeefyCommitment, BeefyNextAuthoritySet};
+    use sp_core::Pair;
+    use trie::{trie_types::TrieDBMutV0, MemoryDB, TrieMut};
+
+    fn authority_set(pairs: &[ecdsa::Pair]) -> BeefyAuthoritySet {
+        BeefyAuthoritySet {
+            list: pairs.iter().map(|pair| pair.public()).collect(),
+            id: 1,
+        }
+    }
+
+    fn sign(pairs: &[ecdsa::Pair], num_signers: usize) -> BeefySignedCommitment {
+        let commitment = BeefyCommitment {
+            payload: H256::repeat_byte(1),
+            block_number: 10,
+            validator_set_id: 1,
+        };
+        let message = keccak_256(&commitment.encode());
+        let signatures = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, pair)| (i < num_signers).then(|| pair.sign_prehashed(&message)))
+            .collect();
+        BeefySignedCommitment {
+            commitment,
+            signatures,
+        }
+    }
+
+    /// A state root with the given storage items, and the proof of all of them
+    fn state_with(items: &[(Vec<u8>, Vec<u8>)]) -> (H256, StorageProof) {
+        let mut root = Default::default();
+        let mut db = MemoryDB::<Hashing>::default();
+        {
+            let mut trie = TrieDBMutV0::new(&mut db, &mut root);
+            for (key, value) in items {
+                trie.insert(key, value).unwrap();
+            }
+        }
+        let proof = db.drain().into_iter().map(|(_, (node, _))| node).collect();
+        (root, proof)
+    }
+
+    fn beefy_state(
+        authorities: &str,
+        set: &BeefyAuthoritySet,
+        set_id: u64,
+    ) -> (H256, StorageProof) {
+        state_with(&[
+            (storage_prefix("Beefy", authorities), set.list.encode()),
+            (storage_prefix("Beefy", "ValidatorSetId"), set_id.encode()),
+        ])
+    }
+
+    fn header(number: chain::BlockNumber, state_root: H256) -> chain::Header {
+        chain::Header {
+            parent_hash: Default::default(),
+            number,
+            state_root,
+            extrinsics_root: Default::default(),
+            digest: Default::default(),
+        }
+    }
+
+    /// The proof of `header` with a single leaf MMR, signed by `pairs` of the set `set_id`
+    fn finality_proof(
+        header: &chain::Header,
+        pairs: &[ecdsa::Pair],
+        set_id: u64,
+        authority_set_change: Option<BeefyAuthoritySetChange>,
+    ) -> BeefyFinalityProof {
+        let mmr_leaf = BeefyMmrLeaf {
+            version: 0,
+            parent_number_and_hash: (header.number, header.hash()),
+            beefy_next_authority_set: BeefyNextAuthoritySet {
+                id: set_id + 1,
+                len: pairs.len() as u32,
+                root: Default::default(),
+            },
+            parachain_heads: Default::default(),
+        }
+        .encode();
+        // The only leaf is the root
+        let commitment = BeefyCommitment {
+            payload: keccak_256(&mmr_leaf).into(),
+            block_number: header.number + 1,
+            validator_set_id: set_id,
+        };
+        let message = keccak_256(&commitment.encode());
+        BeefyFinalityProof {
+            signed_commitment: BeefySignedCommitment {
+                commitment,
+                signatures: pairs
+                    .iter()
+                    .map(|pair| Some(pair.sign_prehashed(&message)))
+                    .collect(),
+            },
+            mmr_leaf,
+            mmr_proof: MmrLeafProof {
+                leaf_index: 0,
+                leaf_count: 1,
+                items: vec![],
+            },
+            authority_set_change,
+        }
+    }
+
+    #[test]
+    fn submit_finalized_header_hands_over_authority_set() {
+        let pairs = |seed: u8| -> Vec<_> {
+            (0..4u8)
+                .map(|i| ecdsa::Pair::from_seed(&[seed + i; 32]))
+                .collect()
+        };
+        let (pairs1, pairs2) = (pairs(1), pairs(11));
+        let set1 = authority_set(&pairs1);
+        let set2 = BeefyAuthoritySet {
+            id: 2,
+            ..authority_set(&pairs2)
+        };
+
+        let mut validator = BeefyLightValidation::new();
+        let (root, proof) = beefy_state("Authorities", &set1, 1);
+        let bridge_id = validator
+            .initialize_bridge(header(0, root), set1.clone(), proof)
+            .unwrap();
+
+        // The header where the next set is read from, signed by the current set
+        let (root, proof) = beefy_state("NextAuthorities", &set2, 1);
+        let handover = header(5, root);
+        let change = BeefyAuthoritySetChange {
+            authority_set: set2.clone(),
+            authority_proof: proof,
+        };
+        let mut tampered = change.clone();
+        tampered.authority_set.list.pop();
+        assert!(validator
+            .submit_finalized_header(
+                bridge_id,
+                handover.clone(),
+                finality_proof(&handover, &pairs1, 1, Some(tampered)),
+            )
+            .is_err());
+        validator
+            .submit_finalized_header(
+                bridge_id,
+                handover.clone(),
+                finality_proof(&handover, &pairs1, 1, Some(change)),
+            )
+            .unwrap();
+        assert!(validator
+            .submit_finalized_header(
+                bridge_id,
+                handover.clone(),
+                finality_proof(&handover, &pairs1, 1, None),
+            )
+            .is_err());
+
+        // The new set signs the following headers, and the old one no longer can
+        let next = header(10, H256::repeat_byte(2));
+        assert!(validator
+            .submit_finalized_header(
+                bridge_id,
+                next.clone(),
+                finality_proof(&next, &pairs1, 1, None),
+            )
+            .is_err());
+        validator
+            .submit_finalized_header(
+                bridge_id,
+                next.clone(),
+                finality_proof(&next, &pairs2, 2, None),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn commitment_needs_two_thirds_of_signatures() {
+        let pairs: Vec<_> = (0..4u8)
+            .map(|i| ecdsa::Pair::from_seed(&[i + 1; 32]))
+            .collect();
+        let set = authority_set(&pairs);
+        assert!(verify_signed_commitment(&sign(&pairs, 3), &set).is_ok());
+        assert!(verify_signed_commitment(&sign(&pairs, 2), &set).is_err());
+
+        let mut tampered = sign(&pairs, 4);
+        tampered.commitment.block_number += 1;
+        assert!(verify_signed_commitment(&tampered, &set).is_err());
+    }
+
+    #[test]
+    fn mmr_proof_of_leaf() {
+        // Three leaves: the peaks are the hash of the first two and the third leaf
+        let leaves: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i]).collect();
+        let hashes: Vec<H256> = leaves.iter().map(|l| keccak_256(l).into()).collect();
+        let left_peak = Keccak256Merge::merge(&hashes[0], &hashes[1]);
+        // The peaks are bagged from the right
+        let root = Keccak256Merge::merge(&hashes[2], &left_peak);
+
+        let proof = MmrLeafProof {
+            leaf_index: 0,
+            leaf_count: 3,
+            items: vec![hashes[1], hashes[2]],
+        };
+        assert!(verify_mmr_proof(root, &leaves[0], proof.clone()).is_ok());
+        assert!(verify_mmr_proof(root, &leaves[1], proof).is_err());
+    }
+}
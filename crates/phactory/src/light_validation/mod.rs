@@ -34,6 +34,7 @@
 // // Ensure we're `no_std` when compiling for Wasm.
 // #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod beefy;
 mod error;
 mod justification;
 pub mod storage_proof;
@@ -58,6 +59,7 @@ use sp_finality_grandpa::{AuthorityId, AuthorityWeight, SetId};
 use sp_runtime::traits::{Block as BlockT, Header, NumberFor};
 use sp_runtime::EncodedJustification;
 
+pub use beefy::BeefyLightValidation;
 pub use types::{AuthoritySet, AuthoritySetChange};
 
 #[derive(Encode, Decode, Clone, PartialEq, Serialize, Deserialize)]
@@ -155,6 +157,43 @@ where
         let last_header = &bridge.last_finalized_block_header;
         verify_ancestry(ancestry_proof, last_header.hash(), &header)?;
 
+        self.finalize_header(bridge_id, header, grandpa_proof, auhtority_set_change)
+    }
+
+    /// Submits a finalized block header to the light client, skipping the ancestry check
+    ///
+    /// The header is trusted as long as the current authority set justifies it. It allows to warp
+    /// from one authority set change to the next without the headers in between, as GRANDPA
+    /// never finalizes two forks under the same authority set.
+    pub fn submit_warp_fragment(
+        &mut self,
+        bridge_id: BridgeId,
+        header: T::Header,
+        grandpa_proof: EncodedJustification,
+        auhtority_set_change: Option<AuthoritySetChange>,
+    ) -> Result<()> {
+        let bridge = self
+            .tracked_bridges
+            .get(&bridge_id)
+            .ok_or_else(|| anyhow::Error::msg(Error::NoSuchBridgeExists))?;
+        if header.number() <= bridge.last_finalized_block_header.number() {
+            return Err(anyhow::Error::msg(Error::InvalidAncestryProof));
+        }
+        self.finalize_header(bridge_id, header, grandpa_proof, auhtority_set_change)
+    }
+
+    fn finalize_header(
+        &mut self,
+        bridge_id: BridgeId,
+        header: T::Header,
+        grandpa_proof: EncodedJustification,
+        auhtority_set_change: Option<AuthoritySetChange>,
+    ) -> Result<()> {
+        let bridge = self
+            .tracked_bridges
+            .get(&bridge_id)
+            .ok_or_else(|| anyhow::Error::msg(Error::NoSuchBridgeExists))?;
+
         let block_hash = header.hash();
         let block_num = *header.number();
 
@@ -1,6 +1,7 @@
 use crate::system::System;
 
 use super::*;
+use crate::query_subscription;
 use pb::{
     phactory_api_server::{PhactoryApi, PhactoryApiServer},
     server::Error as RpcError,
@@ -20,6 +21,9 @@ fn from_debug(e: impl core::fmt::Debug) -> RpcError {
 
 pub const VERSION: u32 = 1;
 
+/// The maximum number of queries in a `ContractBatchQuery`.
+const MAX_BATCH_QUERIES: usize = 100;
+
 fn now() -> u64 {
     use std::time::SystemTime;
     let now = SystemTime::now()
@@ -44,6 +48,33 @@ fn fit_size(mut messages: pb::EgressMessages, size: usize) -> pb::EgressMessages
     messages
 }
 
+/// Run the queries of a batch in order, keeping the error of each query in its own result.
+fn batch_query(
+    queries: Vec<pb::ContractQueryRequest>,
+    mut query: impl FnMut(pb::ContractQueryRequest) -> RpcResult<pb::ContractQueryResponse>,
+) -> RpcResult<pb::ContractBatchQueryResponse> {
+    if queries.len() > MAX_BATCH_QUERIES {
+        return Err(from_display(format!(
+            "Too many queries in a batch, at most {} allowed",
+            MAX_BATCH_QUERIES
+        )));
+    }
+    let results = queries
+        .into_iter()
+        .map(|request| match query(request) {
+            Ok(response) => pb::ContractBatchQueryResult {
+                response: Some(response),
+                error: String::new(),
+            },
+            Err(err) => pb::ContractBatchQueryResult {
+                response: None,
+                error: err.to_string(),
+            },
+        })
+        .collect();
+    Ok(pb::ContractBatchQueryResponse { results })
+}
+
 impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform> {
     fn runtime_state(&mut self) -> RpcResult<&mut RuntimeState> {
         self.runtime_state
@@ -224,6 +255,9 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             self.poll_side_tasks(block.block_header.number)?;
             last_block = block.block_header.number;
         }
+        if last_block != 0 {
+            self.update_query_subscriptions(last_block);
+        }
 
         if let Err(e) = self.maybe_take_checkpoint() {
             error!("Failed to take checkpoint: {:?}", e);
@@ -253,6 +287,8 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         genesis_state: blocks::StorageState,
         operator: Option<chain::AccountId>,
         debug_set_key: ::core::option::Option<Vec<u8>>,
+        warp_point: Option<blocks::WarpPoint>,
+        beefy_genesis: Option<blocks::BeefyGenesisInfo>,
     ) -> RpcResult<pb::InitRuntimeResponse> {
         if self.system.is_some() {
             return Err(from_display("Runtime already initialized"));
@@ -319,10 +355,27 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
 
         // Initialize bridge
         let next_headernum = genesis.block_header.number + 1;
-        let mut light_client = LightValidation::new();
-        let main_bridge = light_client
-            .initialize_bridge(genesis.block_header, genesis.authority_set, genesis.proof)
-            .expect("Bridge initialize failed");
+        let (light_client, main_bridge) = match beefy_genesis {
+            None => {
+                let mut light_client = LightValidation::new();
+                let main_bridge = light_client
+                    .initialize_bridge(genesis.block_header, genesis.authority_set, genesis.proof)
+                    .expect("Bridge initialize failed");
+                (AnyValidator::Grandpa(light_client), main_bridge)
+            }
+            Some(beefy_genesis) => {
+                let mut light_client = BeefyLightValidation::new();
+                let main_bridge = light_client
+                    .initialize_bridge(
+                        genesis.block_header,
+                        beefy_genesis.authority_set,
+                        beefy_genesis.proof,
+                    )
+                    .map_err(from_display)?;
+                info!("Validating headers with BEEFY");
+                (AnyValidator::Beefy(light_client), main_bridge)
+            }
+        };
 
         let storage_synchronizer = if is_parachain {
             Synchronizer::new_parachain(light_client, main_bridge, next_headernum)
@@ -344,24 +397,64 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         };
 
         // Initialize other states
-        runtime_state.chain_storage.load(genesis_state.into_iter());
-
-        info!(
-            "Genesis state loaded: {:?}",
-            runtime_state.chain_storage.root()
-        );
+        let warped_to = match warp_point {
+            None => {
+                runtime_state.chain_storage.load(genesis_state.into_iter());
+                info!(
+                    "Genesis state loaded: {:?}",
+                    runtime_state.chain_storage.root()
+                );
+                None
+            }
+            Some(warp_point) => {
+                // The genesis state is not needed, the state at the checkpoint replaces it.
+                let chain_storage = &mut runtime_state.chain_storage;
+                chain_storage.load(warp_point.state.into_iter());
+                let storage_key = match chain_storage.para_id() {
+                    Some(para_id) => light_validation::utils::storage_map_prefix_twox_64_concat(
+                        b"Paras", b"Heads", &para_id,
+                    ),
+                    None => Vec::new(),
+                };
+                let para_header = warp_point
+                    .para_header
+                    .map(|header| (header, warp_point.para_header_proof));
+                let warped_to = runtime_state
+                    .storage_synchronizer
+                    .warp(
+                        warp_point.fragments,
+                        para_header,
+                        &storage_key,
+                        chain_storage,
+                    )
+                    .map_err(from_display)?;
+                info!(
+                    "Warped to block {}, state loaded: {:?}",
+                    warped_to,
+                    chain_storage.root()
+                );
+                Some(warped_to)
+            }
+        };
 
-        let system = system::System::new(
+        let mut system = system::System::new(
             self.platform.clone(),
             self.args.sealing_path.clone(),
             false,
             self.args.geoip_city_db.clone(),
+            self.args.ink_event_retention,
             identity_key,
             ecdh_key,
             &runtime_state.send_mq,
             &mut runtime_state.recv_mq,
             contracts,
         );
+        system.set_disk_backed_clusters(crate::disk_backed_clusters(&self.args));
+        if warped_to.is_some() {
+            system
+                .warp_sync(&runtime_state.chain_storage)
+                .map_err(from_display)?;
+        }
 
         let resp = pb::InitRuntimeResponse::new(
             runtime_info,
@@ -439,9 +532,135 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         &mut self,
         request: pb::ContractQueryRequest,
     ) -> RpcResult<pb::ContractQueryResponse> {
+        let current_block = self.get_info().blocknum.saturating_sub(1);
+        self.contract_query_at(request, current_block)
+    }
+
+    fn contract_batch_query(
+        &mut self,
+        request: pb::ContractBatchQueryRequest,
+    ) -> RpcResult<pb::ContractBatchQueryResponse> {
+        // No block can be dispatched while we hold the phactory, so all the queries see the
+        // same state.
+        let current_block = self.get_info().blocknum.saturating_sub(1);
+        batch_query(request.queries, |query| {
+            self.contract_query_at(query, current_block)
+        })
+    }
+
+    fn contract_query_subscribe(
+        &mut self,
+        request: pb::ContractQueryRequest,
+    ) -> RpcResult<pb::ContractQuerySubscription> {
+        if self.query_subscriptions.len() >= query_subscription::MAX_SUBSCRIPTIONS {
+            return Err(from_display("Too many query subscriptions"));
+        }
+        // Run it once, so that a bad query fails here rather than in the first update.
+        let current_block = self.get_info().blocknum.saturating_sub(1);
+        let (response_data, remote_pubkey, origin) =
+            self.run_contract_query(&request, current_block)?;
+        if self.query_subscriptions.count_of(origin.as_ref())
+            >= query_subscription::MAX_SUBSCRIPTIONS_PER_ORIGIN
+        {
+            return Err(from_display("Too many query subscriptions of the origin"));
+        }
+        let response = self.encrypt_query_response(&remote_pubkey, &response_data)?;
+        let id = self
+            .query_subscriptions
+            .subscribe(origin, request, current_block);
+        self.query_subscriptions.result_changed(id, &response_data);
+        self.query_subscriptions.push_update(
+            id,
+            pb::ContractQueryUpdate {
+                block_number: current_block,
+                response: Some(response),
+                error: String::new(),
+            },
+        );
+        Ok(pb::ContractQuerySubscription { id })
+    }
+
+    fn contract_query_poll(
+        &mut self,
+        request: pb::ContractQueryPollRequest,
+    ) -> RpcResult<pb::ContractQueryUpdates> {
+        let current_block = self.get_info().blocknum.saturating_sub(1);
+        let (updates, closed) = self
+            .query_subscriptions
+            .poll(request.subscription_id, current_block)
+            .ok_or_else(|| from_display("Query subscription not found"))?;
+        Ok(pb::ContractQueryUpdates { updates, closed })
+    }
+
+    fn contract_query_unsubscribe(
+        &mut self,
+        request: pb::ContractQuerySubscription,
+    ) -> RpcResult<()> {
+        if !self.query_subscriptions.unsubscribe(request.id) {
+            return Err(from_display("Query subscription not found"));
+        }
+        Ok(())
+    }
+
+    /// Re-evaluate the subscribed queries in turn, buffering the changed results.
+    ///
+    /// At most `MAX_EVALUATIONS_PER_DISPATCH` queries are run, to bound the delay they add to the
+    /// dispatch of blocks.
+    fn update_query_subscriptions(&mut self, block_number: chain::BlockNumber) {
+        self.query_subscriptions.prune_idle(block_number);
+        let due = self
+            .query_subscriptions
+            .due_requests(query_subscription::MAX_EVALUATIONS_PER_DISPATCH);
+        for (id, request) in due {
+            let update = match self.run_contract_query(&request, block_number) {
+                Ok((response_data, remote_pubkey, _)) => {
+                    if !self.query_subscriptions.result_changed(id, &response_data) {
+                        continue;
+                    }
+                    match self.encrypt_query_response(&remote_pubkey, &response_data) {
+                        Ok(response) => pb::ContractQueryUpdate {
+                            block_number,
+                            response: Some(response),
+                            error: String::new(),
+                        },
+                        Err(err) => pb::ContractQueryUpdate {
+                            block_number,
+                            response: None,
+                            error: err.to_string(),
+                        },
+                    }
+                }
+                Err(err) => pb::ContractQueryUpdate {
+                    block_number,
+                    response: None,
+                    error: err.to_string(),
+                },
+            };
+            self.query_subscriptions.push_update(id, update);
+        }
+    }
+
+    fn contract_query_at(
+        &mut self,
+        request: pb::ContractQueryRequest,
+        current_block: chain::BlockNumber,
+    ) -> RpcResult<pb::ContractQueryResponse> {
+        let (response_data, remote_pubkey, _) = self.run_contract_query(&request, current_block)?;
+        self.encrypt_query_response(&remote_pubkey, &response_data)
+    }
+
+    /// Run a query, returning the plain response, the public key to encrypt it to and the origin.
+    fn run_contract_query(
+        &mut self,
+        request: &pb::ContractQueryRequest,
+        current_block: chain::BlockNumber,
+    ) -> RpcResult<(
+        Vec<u8>,
+        crypto::ecdh::EcdhPublicKey,
+        Option<chain::AccountId>,
+    )> {
         // Validate signature
         let origin = if let Some(sig) = &request.signature {
-            let current_block = self.get_info().blocknum - 1;
             // At most two level cert chain supported
             match sig.verify(&request.encoded_encrypted_data, current_block, 2) {
                 Ok(key_chain) => match &key_chain[..] {
@@ -475,15 +694,28 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         let head = contract::ContractQueryHead::decode(&mut data_cursor)?;
         let data_cursor = data_cursor;
 
-        // Origin
-        let accid_origin = match origin {
-            Some(origin) => {
+        // Origin, where a query signed in a session is on behalf of the account of the session
+        let session = if request.encoded_session.is_empty() {
+            None
+        } else {
+            Some(
+                contract::QuerySession::decode(&mut &request.encoded_session[..])
+                    .map_err(|_| from_display("Bad query session"))?,
+            )
+        };
+        let accid_origin = match (&session, origin) {
+            (Some(session), _) => {
+                let account = session.verify(&head.id, &head.nonce, data_cursor, current_block)?;
+                info!("Query signed in a session of {}", hex::encode(&account));
+                Some(chain::AccountId::from(account))
+            }
+            (None, Some(origin)) => {
                 use core::convert::TryFrom;
                 let accid = chain::AccountId::try_from(origin.as_slice())
                     .map_err(|_| from_display("Bad account id"))?;
                 Some(accid)
             }
-            None => None,
+            (None, None) => None,
         };
 
         // Dispatch
@@ -498,20 +730,57 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
             nonce: head.nonce,
             result: contract::Data(res),
         };
-        let response_data = response.encode();
+        Ok((response.encode(), encrypted_req.pubkey, accid_origin))
+    }
 
-        // Encrypt
+    fn encrypt_query_response(
+        &mut self,
+        remote_pubkey: &crypto::ecdh::EcdhPublicKey,
+        response_data: &[u8],
+    ) -> RpcResult<pb::ContractQueryResponse> {
+        let ecdh_key = &self.system()?.ecdh_key;
         let encrypted_resp = crypto::EncryptedData::encrypt(
-            &ecdh_key,
-            &encrypted_req.pubkey,
+            ecdh_key,
+            remote_pubkey,
             crate::generate_random_iv(),
-            &response_data,
+            response_data,
         )
         .map_err(from_debug)?;
 
         Ok(pb::ContractQueryResponse::new(encrypted_resp))
     }
 
+    fn get_contract_events(
+        &mut self,
+        request: pb::GetContractEventsRequest,
+    ) -> RpcResult<pb::ContractEvents> {
+        fn optional_h256(bytes: &[u8], what: &str) -> RpcResult<Option<H256>> {
+            match bytes.len() {
+                0 => Ok(None),
+                32 => Ok(Some(H256::from_slice(bytes))),
+                _ => Err(from_display(format!("Bad {}", what))),
+            }
+        }
+        let contract = optional_h256(&request.contract_id, "contract id")?;
+        let topic = optional_h256(&request.topic, "topic")?;
+        let events =
+            self.system()?
+                .ink_events(contract.as_ref(), topic.as_ref(), request.from_block);
+        Ok(pb::ContractEvents::new(events))
+    }
+
+    fn get_command_receipts(
+        &mut self,
+        request: pb::GetCommandReceiptsRequest,
+    ) -> RpcResult<pb::CommandReceipts> {
+        if request.contract_id.len() != 32 {
+            return Err(from_display("Bad contract id"));
+        }
+        let contract = H256::from_slice(&request.contract_id);
+        let receipts = self.system()?.command_receipts(&contract, &request.nonce);
+        Ok(pb::CommandReceipts::new(receipts))
+    }
+
     #[allow(unused_unsafe)]
     pub unsafe fn dispatch_prpc_request(
         &mut self,
@@ -705,6 +974,8 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi
             request.decode_genesis_state()?,
             request.decode_operator()?,
             request.debug_set_key,
+            request.decode_warp_point()?,
+            request.decode_beefy_genesis_info()?,
         )
     }
 
@@ -727,6 +998,40 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi
         self.phactory.contract_query(request)
     }
 
+    /// Subscribe to the results of a contract query, which is re-evaluated after each dispatch
+    /// of blocks.
+    fn contract_query_subscribe(
+        &mut self,
+        request: pb::ContractQueryRequest,
+    ) -> RpcResult<pb::ContractQuerySubscription> {
+        self.phactory.contract_query_subscribe(request)
+    }
+
+    /// Take the changed results of a query subscription.
+    ///
+    /// Answered at once here. The waiting of a long poll is done by the host, outside the enclave.
+    fn contract_query_poll(
+        &mut self,
+        request: pb::ContractQueryPollRequest,
+    ) -> RpcResult<pb::ContractQueryUpdates> {
+        self.phactory.contract_query_poll(request)
+    }
+
+    fn contract_query_unsubscribe(
+        &mut self,
+        request: pb::ContractQuerySubscription,
+    ) -> RpcResult<()> {
+        self.phactory.contract_query_unsubscribe(request)
+    }
+
+    /// Run several contract queries against the same block, reporting the errors per query.
+    fn contract_batch_query(
+        &mut self,
+        request: pb::ContractBatchQueryRequest,
+    ) -> RpcResult<pb::ContractBatchQueryResponse> {
+        self.phactory.contract_batch_query(request)
+    }
+
     fn get_worker_state(
         &mut self,
         request: pb::GetWorkerStateRequest,
@@ -748,8 +1053,84 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> PhactoryApi
         Ok(state)
     }
 
+    /// Get the ink! events emitted by pink contracts in the retention window.
+    ///
+    /// Like ink! events on a public chain, the events are public: anyone can read them without
+    /// signing the request. Contracts must not put secrets in the events, or should encrypt them.
+    fn get_contract_events(
+        &mut self,
+        request: pb::GetContractEventsRequest,
+    ) -> RpcResult<pb::ContractEvents> {
+        self.phactory.get_contract_events(request)
+    }
+
+    /// Get the receipts of the pink commands with the given nonce in the retention window.
+    ///
+    /// The receipts are public like the ink! events.
+    fn get_command_receipts(
+        &mut self,
+        request: pb::GetCommandReceiptsRequest,
+    ) -> RpcResult<pb::CommandReceipts> {
+        self.phactory.get_command_receipts(request)
+    }
+
     fn echo(&mut self, request: pb::EchoMessage) -> RpcResult<pb::EchoMessage> {
         let echo_msg = request.echo_msg;
         Ok(pb::EchoMessage { echo_msg })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_request(n: u8) -> pb::ContractQueryRequest {
+        pb::ContractQueryRequest {
+            encoded_encrypted_data: vec![n],
+            ..Default::default()
+        }
+    }
+
+    /// Echo the queries, failing the odd ones
+    fn echo_even(request: pb::ContractQueryRequest) -> RpcResult<pb::ContractQueryResponse> {
+        let n = request.encoded_encrypted_data[0];
+        if n % 2 == 1 {
+            return Err(from_display(format!("query {} failed", n)));
+        }
+        Ok(pb::ContractQueryResponse {
+            encoded_encrypted_data: vec![n],
+        })
+    }
+
+    #[test]
+    fn batch_query_isolates_errors_in_order() {
+        let queries = (0..5).map(query_request).collect();
+        let response = batch_query(queries, echo_even).unwrap();
+        assert_eq!(response.results.len(), 5);
+        for (n, result) in response.results.iter().enumerate() {
+            if n % 2 == 1 {
+                assert!(result.response.is_none());
+                assert!(result.error.contains(&format!("query {} failed", n)));
+            } else {
+                let response = result.response.as_ref().unwrap();
+                assert_eq!(response.encoded_encrypted_data, vec![n as u8]);
+                assert!(result.error.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn batch_query_rejects_too_many_queries() {
+        let queries = (0..MAX_BATCH_QUERIES).map(|_| query_request(0)).collect();
+        assert!(batch_query(queries, echo_even).is_ok());
+
+        let mut ran = 0;
+        let queries = (0..=MAX_BATCH_QUERIES).map(|_| query_request(0)).collect();
+        let result = batch_query(queries, |request| {
+            ran += 1;
+            echo_even(request)
+        });
+        assert!(result.is_err());
+        assert_eq!(ran, 0);
+    }
+}
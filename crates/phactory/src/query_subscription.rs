@@ -0,0 +1,235 @@
+//! The contract queries subscribed by the clients, re-evaluated after each dispatch of blocks.
+//!
+//! The subscriptions live in the memory only. They are lost when pRuntime restarts, after which
+//! the polls fail and the clients are expected to subscribe again.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use phactory_api::prpc as pb;
+
+/// The maximum number of living subscriptions.
+pub const MAX_SUBSCRIPTIONS: usize = 1000;
+/// The maximum number of living subscriptions of an origin. Unsigned queries share one origin.
+pub const MAX_SUBSCRIPTIONS_PER_ORIGIN: usize = 16;
+/// The maximum number of subscriptions re-evaluated after a dispatch of blocks. The rest are
+/// re-evaluated in turn after the following dispatches.
+pub const MAX_EVALUATIONS_PER_DISPATCH: usize = 64;
+/// The maximum number of updates buffered for a subscription, beyond which the oldest are dropped.
+const MAX_BUFFERED_UPDATES: usize = 16;
+/// A subscription not polled for this many blocks is dropped.
+const SUBSCRIPTION_IDLE_BLOCKS: chain::BlockNumber = 50;
+
+struct Subscription {
+    origin: Option<chain::AccountId>,
+    request: pb::ContractQueryRequest,
+    /// The hash of the last plain response, to tell whether the result has changed.
+    last_result: Option<[u8; 32]>,
+    updates: VecDeque<pb::ContractQueryUpdate>,
+    /// Set after an error, which ends the subscription once the error is polled.
+    closed: bool,
+    last_polled: chain::BlockNumber,
+}
+
+#[derive(Default)]
+pub struct QuerySubscriptions {
+    subscriptions: BTreeMap<u64, Subscription>,
+    /// The id from which the next re-evaluation starts.
+    next_evaluation: u64,
+}
+
+impl QuerySubscriptions {
+    pub fn len(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// The number of living subscriptions of an origin.
+    pub fn count_of(&self, origin: Option<&chain::AccountId>) -> usize {
+        self.subscriptions
+            .values()
+            .filter(|subscription| subscription.origin.as_ref() == origin)
+            .count()
+    }
+
+    /// Add a subscription, returning its id.
+    pub fn subscribe(
+        &mut self,
+        origin: Option<chain::AccountId>,
+        request: pb::ContractQueryRequest,
+        block_number: chain::BlockNumber,
+    ) -> u64 {
+        // Random ids, so that a client can't guess and cancel the subscriptions of others.
+        let id = loop {
+            let id = rand::random();
+            if !self.subscriptions.contains_key(&id) {
+                break id;
+            }
+        };
+        self.subscriptions.insert(
+            id,
+            Subscription {
+                origin,
+                request,
+                last_result: None,
+                updates: VecDeque::new(),
+                closed: false,
+                last_polled: block_number,
+            },
+        );
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) -> bool {
+        self.subscriptions.remove(&id).is_some()
+    }
+
+    /// Take the buffered updates of a subscription.
+    ///
+    /// Returns the updates and whether the subscription has been closed, or None if there is no
+    /// such subscription.
+    pub fn poll(
+        &mut self,
+        id: u64,
+        block_number: chain::BlockNumber,
+    ) -> Option<(Vec<pb::ContractQueryUpdate>, bool)> {
+        let subscription = self.subscriptions.get_mut(&id)?;
+        subscription.last_polled = block_number;
+        let updates = subscription.updates.drain(..).collect();
+        let closed = subscription.closed;
+        if closed {
+            self.subscriptions.remove(&id);
+        }
+        Some((updates, closed))
+    }
+
+    /// Drop the subscriptions the clients have stopped polling.
+    pub fn prune_idle(&mut self, block_number: chain::BlockNumber) {
+        self.subscriptions.retain(|id, subscription| {
+            let alive = subscription.last_polled + SUBSCRIPTION_IDLE_BLOCKS >= block_number;
+            if !alive {
+                info!("Dropping idle query subscription {}", id);
+            }
+            alive
+        });
+    }
+
+    /// The next at most `budget` open subscriptions to be re-evaluated, in turn across calls.
+    pub fn due_requests(&mut self, budget: usize) -> Vec<(u64, pb::ContractQueryRequest)> {
+        let start = self.next_evaluation;
+        let due: Vec<_> = self
+            .subscriptions
+            .range(start..)
+            .chain(self.subscriptions.range(..start))
+            .filter(|(_, subscription)| !subscription.closed)
+            .take(budget)
+            .map(|(id, subscription)| (*id, subscription.request.clone()))
+            .collect();
+        if let Some((last, _)) = due.last() {
+            self.next_evaluation = last.wrapping_add(1);
+        }
+        due
+    }
+
+    /// Whether the plain response of a subscription differs from the last one, remembering it.
+    pub fn result_changed(&mut self, id: u64, response_data: &[u8]) -> bool {
+        let subscription = match self.subscriptions.get_mut(&id) {
+            Some(subscription) => subscription,
+            None => return false,
+        };
+        let hash = sp_core::blake2_256(response_data);
+        if subscription.last_result == Some(hash) {
+            return false;
+        }
+        subscription.last_result = Some(hash);
+        true
+    }
+
+    /// Buffer an update of a subscription. An update carrying an error closes it.
+    pub fn push_update(&mut self, id: u64, update: pb::ContractQueryUpdate) {
+        let subscription = match self.subscriptions.get_mut(&id) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        if !update.error.is_empty() {
+            subscription.closed = true;
+        }
+        if subscription.updates.len() >= MAX_BUFFERED_UPDATES {
+            subscription.updates.pop_front();
+        }
+        subscription.updates.push_back(update);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(block_number: chain::BlockNumber, error: &str) -> pb::ContractQueryUpdate {
+        pb::ContractQueryUpdate {
+            block_number,
+            response: None,
+            error: error.into(),
+        }
+    }
+
+    #[test]
+    fn test_poll_and_close() {
+        let mut subscriptions = QuerySubscriptions::default();
+        let id = subscriptions.subscribe(None, Default::default(), 1);
+        assert!(subscriptions.result_changed(id, b"a"));
+        assert!(!subscriptions.result_changed(id, b"a"));
+        assert!(subscriptions.result_changed(id, b"b"));
+
+        for block in 0..MAX_BUFFERED_UPDATES as u32 + 2 {
+            subscriptions.push_update(id, update(block, ""));
+        }
+        let (updates, closed) = subscriptions.poll(id, 2).unwrap();
+        assert!(!closed);
+        // The oldest updates are dropped
+        assert_eq!(updates.len(), MAX_BUFFERED_UPDATES);
+        assert_eq!(updates[0].block_number, 2);
+        assert!(subscriptions.poll(id, 2).unwrap().0.is_empty());
+
+        // An error closes the subscription, which is removed once the error is polled
+        subscriptions.push_update(id, update(3, "failed"));
+        assert!(subscriptions.due_requests(usize::MAX).is_empty());
+        let (updates, closed) = subscriptions.poll(id, 3).unwrap();
+        assert!(closed);
+        assert_eq!(updates.len(), 1);
+        assert!(subscriptions.poll(id, 3).is_none());
+        assert_eq!(subscriptions.len(), 0);
+    }
+
+    #[test]
+    fn test_prune_idle() {
+        let mut subscriptions = QuerySubscriptions::default();
+        let idle = subscriptions.subscribe(None, Default::default(), 1);
+        let polled = subscriptions.subscribe(None, Default::default(), 1);
+        subscriptions.poll(polled, 10);
+        subscriptions.prune_idle(1 + SUBSCRIPTION_IDLE_BLOCKS);
+        assert_eq!(subscriptions.len(), 2);
+        subscriptions.prune_idle(2 + SUBSCRIPTION_IDLE_BLOCKS);
+        assert!(subscriptions.poll(idle, 100).is_none());
+        assert!(subscriptions.poll(polled, 100).is_some());
+    }
+
+    #[test]
+    fn test_due_requests_in_turn() {
+        let mut subscriptions = QuerySubscriptions::default();
+        let alice = chain::AccountId::new([1; 32]);
+        for _ in 0..5 {
+            subscriptions.subscribe(Some(alice.clone()), Default::default(), 1);
+        }
+        assert_eq!(subscriptions.count_of(Some(&alice)), 5);
+        assert_eq!(subscriptions.count_of(None), 0);
+
+        let mut evaluated: Vec<u64> = (0..5)
+            .flat_map(|_| subscriptions.due_requests(2))
+            .map(|(id, _)| id)
+            .collect();
+        // Every subscription is evaluated twice in 5 rounds of 2
+        evaluated.sort_unstable();
+        evaluated.dedup();
+        assert_eq!(evaluated.len(), 5);
+        assert_eq!(subscriptions.due_requests(10).len(), 5);
+    }
+}
@@ -1,6 +1,9 @@
-use crate::light_validation::{storage_proof::StorageProof, LightValidation};
-use std::string::ToString;
+use crate::light_validation::{storage_proof::StorageProof, BeefyLightValidation, LightValidation};
+use parity_scale_codec::Decode;
+use phactory_api::blocks::{AuthoritySetChange, BeefyFinalityProof};
 use phactory_api::storage_sync::{BlockValidator, Error as SyncError, Result};
+use serde::{Deserialize, Serialize};
+use std::string::ToString;
 
 pub use storage_ext::{Storage, StorageExt};
 
@@ -23,6 +26,17 @@ impl BlockValidator for LightValidation<chain::Runtime> {
         .map_err(|e| SyncError::HeaderValidateFailed(e.to_string()))
     }
 
+    fn submit_warp_fragment(
+        &mut self,
+        bridge_id: u64,
+        header: chain::Header,
+        grandpa_proof: Vec<u8>,
+        authority_set_change: Option<phactory_api::blocks::AuthoritySetChange>,
+    ) -> Result<()> {
+        self.submit_warp_fragment(bridge_id, header, grandpa_proof, authority_set_change)
+            .map_err(|e| SyncError::HeaderValidateFailed(e.to_string()))
+    }
+
     fn validate_storage_proof(
         &self,
         state_root: chain::Hash,
@@ -34,6 +48,94 @@ impl BlockValidator for LightValidation<chain::Runtime> {
     }
 }
 
+/// The header validator pRuntime is initialized with.
+///
+/// It's untagged to keep loading the checkpoints taken before BEEFY was supported.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AnyValidator {
+    Grandpa(LightValidation<chain::Runtime>),
+    Beefy(BeefyLightValidation),
+}
+
+impl BlockValidator for AnyValidator {
+    /// With BEEFY, `grandpa_proof` is the encoded `BeefyFinalityProof` of the header, which
+    /// carries the authority set change on its own.
+    fn submit_finalized_headers(
+        &mut self,
+        bridge_id: u64,
+        header: chain::Header,
+        ancestry_proof: Vec<chain::Header>,
+        grandpa_proof: Vec<u8>,
+        auhtority_set_change: Option<AuthoritySetChange>,
+    ) -> Result<()> {
+        match self {
+            AnyValidator::Grandpa(validator) => BlockValidator::submit_finalized_headers(
+                validator,
+                bridge_id,
+                header,
+                ancestry_proof,
+                grandpa_proof,
+                auhtority_set_change,
+            ),
+            AnyValidator::Beefy(validator) => {
+                if auhtority_set_change.is_some() {
+                    return Err(SyncError::HeaderValidateFailed(
+                        "GRANDPA authority set change submitted to the BEEFY validator".into(),
+                    ));
+                }
+                let finality_proof = BeefyFinalityProof::decode(&mut &grandpa_proof[..])
+                    .map_err(|e| SyncError::HeaderValidateFailed(e.to_string()))?;
+                validator
+                    .submit_finalized_header(bridge_id, header, finality_proof)
+                    .map_err(|e| SyncError::HeaderValidateFailed(e.to_string()))
+            }
+        }
+    }
+
+    fn submit_warp_fragment(
+        &mut self,
+        bridge_id: u64,
+        header: chain::Header,
+        grandpa_proof: Vec<u8>,
+        authority_set_change: Option<AuthoritySetChange>,
+    ) -> Result<()> {
+        match self {
+            AnyValidator::Grandpa(validator) => BlockValidator::submit_warp_fragment(
+                validator,
+                bridge_id,
+                header,
+                grandpa_proof,
+                authority_set_change,
+            ),
+            // A single BEEFY proof reaches any finalized header, no fragments needed
+            AnyValidator::Beefy(_) => Err(SyncError::HeaderValidateFailed(
+                "Warp fragments are not supported by the BEEFY validator".into(),
+            )),
+        }
+    }
+
+    fn validate_storage_proof(
+        &self,
+        state_root: chain::Hash,
+        proof: StorageProof,
+        items: &[(&[u8], &[u8])],
+    ) -> Result<()> {
+        match self {
+            AnyValidator::Grandpa(validator) => {
+                BlockValidator::validate_storage_proof(validator, state_root, proof, items)
+            }
+            AnyValidator::Beefy(validator) => validator
+                .validate_storage_proof(state_root, proof, items)
+                .map_err(|e| SyncError::StorageProofFailed(e.to_string())),
+        }
+    }
+
+    fn supports_header_gaps(&self) -> bool {
+        matches!(self, AnyValidator::Beefy(_))
+    }
+}
+
 mod storage_ext {
     use crate::chain;
     use crate::light_validation::utils::storage_prefix;
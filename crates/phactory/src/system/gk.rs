@@ -10,9 +10,10 @@ use phala_types::{
     contract::messaging::ContractEvent,
     contract::ContractId,
     messaging::{
-        ContractKeyDistribution, GatekeeperEvent, KeyDistribution, MessageOrigin,
-        MiningInfoUpdateEvent, MiningReportEvent, RandomNumber, RandomNumberEvent, SettleInfo,
-        SystemEvent, WorkerEvent, WorkerEventWithKey,
+        ContractKeyDistribution, DispatchContractKeyEvent, DispatchMasterKeyEvent, GatekeeperEvent,
+        KeyDistribution, MessageOrigin, MiningInfoUpdateEvent, MiningReportEvent,
+        NewGatekeeperEvent, RandomNumber, RandomNumberEvent, SettleInfo, SystemEvent, WorkerEvent,
+        WorkerEventWithKey,
     },
     EcdhPublicKey, WorkerPublicKey,
 };
@@ -100,10 +101,23 @@ impl WorkerInfo {
     }
 }
 
+/// A master key replaced by a rotation
+#[derive(Serialize, Deserialize)]
+struct RetiredMasterKey {
+    #[serde(with = "more::key_bytes")]
+    master_key: sr25519::Pair,
+    /// The block the next master key took over in
+    retired_at: chain::BlockNumber,
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Gatekeeper<MsgChan> {
     #[serde(with = "more::key_bytes")]
     master_key: sr25519::Pair,
+    /// The master keys replaced by rotations, oldest first, still used to verify the earlier
+    /// messages
+    #[serde(default)]
+    retired_master_keys: Vec<RetiredMasterKey>,
     master_pubkey_on_chain: bool,
     registered_on_chain: bool,
     egress: MsgChan, // TODO.kevin: syncing the egress state while migrating.
@@ -128,6 +142,7 @@ where
 
         Self {
             master_key,
+            retired_master_keys: Vec::new(),
             master_pubkey_on_chain: false,
             registered_on_chain: false,
             egress: egress.clone(),
@@ -163,7 +178,6 @@ where
         self.registered_on_chain = true;
     }
 
-    #[allow(unused)]
     pub fn unregister_on_chain(&mut self) {
         info!("Gatekeeper: unregister on chain");
         self.egress.set_dummy(true);
@@ -178,13 +192,57 @@ where
         self.master_pubkey_on_chain = true;
     }
 
-    pub fn share_master_key(
+    /// Switch to the rotated master key once its pubkey is published on chain.
+    ///
+    /// `egress` must be signed by the new master key.
+    pub fn rotate_master_key(
+        &mut self,
+        master_key: sr25519::Pair,
+        egress: MsgChan,
+        block_number: chain::BlockNumber,
+    ) {
+        info!(
+            "Gatekeeper: rotate master key to {} in block {}",
+            hex::encode(master_key.public()),
+            block_number
+        );
+        let retired_master_key = std::mem::replace(&mut self.master_key, master_key);
+        self.retired_master_keys.push(RetiredMasterKey {
+            master_key: retired_master_key,
+            retired_at: block_number,
+        });
+        self.egress = egress.clone();
+        self.mining_economics.egress = egress;
+    }
+
+    /// The master key in use at `block_number`
+    fn master_key_at(&self, block_number: chain::BlockNumber) -> &sr25519::Pair {
+        self.retired_master_keys
+            .iter()
+            .find(|retired| block_number < retired.retired_at)
+            .map(|retired| &retired.master_key)
+            .unwrap_or(&self.master_key)
+    }
+
+    /// The master key the gatekeepers launched with.
+    ///
+    /// Contract and cluster keys are always derived from it, so that they stay the same across
+    /// master key rotations.
+    fn genesis_master_key(&self) -> &sr25519::Pair {
+        self.retired_master_keys
+            .first()
+            .map(|retired| &retired.master_key)
+            .unwrap_or(&self.master_key)
+    }
+
+    /// Encrypt `master_keys`, oldest first and the current one last, to the given worker
+    fn encrypt_master_keys(
         &mut self,
+        master_keys: &[sr25519::Pair],
         pubkey: &WorkerPublicKey,
         ecdh_pubkey: &EcdhPublicKey,
         block_number: chain::BlockNumber,
-    ) {
-        info!("Gatekeeper: try dispatch master key");
+    ) -> DispatchMasterKeyEvent {
         let derived_key = self
             .master_key
             .derive_sr25519_pair(&[&crate::generate_random_info()])
@@ -195,20 +253,60 @@ where
         let secret = ecdh::agree(&my_ecdh_key, &ecdh_pubkey.0)
             .expect("should never fail with valid ecdh key; qed.");
         let iv = self.generate_iv(block_number);
-        let mut data = self.master_key.dump_secret_key().to_vec();
+        let mut data: Vec<u8> = master_keys
+            .iter()
+            .flat_map(|master_key| master_key.dump_secret_key().to_vec())
+            .collect();
 
         aead::encrypt(&iv, &secret, &mut data).expect("Failed to encrypt master key");
+        DispatchMasterKeyEvent {
+            dest: *pubkey,
+            ecdh_pubkey: my_ecdh_key
+                .public()
+                .as_ref()
+                .try_into()
+                .expect("should never fail given pubkey with correct length; qed;"),
+            encrypted_master_key: data,
+            iv,
+        }
+    }
+
+    /// Share all the master keys ever used with the given gatekeeper
+    pub fn share_master_key(
+        &mut self,
+        master_keys: &[sr25519::Pair],
+        pubkey: &WorkerPublicKey,
+        ecdh_pubkey: &EcdhPublicKey,
+        block_number: chain::BlockNumber,
+    ) {
+        info!("Gatekeeper: try dispatch master key");
+        let event = self.encrypt_master_keys(master_keys, pubkey, ecdh_pubkey, block_number);
         self.egress
-            .push_message(&KeyDistribution::master_key_distribution(
-                *pubkey,
-                my_ecdh_key
-                    .public()
-                    .as_ref()
-                    .try_into()
-                    .expect("should never fail given pubkey with correct length; qed;"),
-                data,
-                iv,
-            ));
+            .push_message(&KeyDistribution::MasterKeyDistribution(event));
+    }
+
+    /// Share the master keys, with the newly generated one at last, with all the gatekeepers
+    pub fn share_rotated_master_key(
+        &mut self,
+        rotation_id: u64,
+        master_keys: &[sr25519::Pair],
+        gatekeepers: &[NewGatekeeperEvent],
+        block_number: chain::BlockNumber,
+    ) {
+        info!(
+            "Gatekeeper: dispatch rotated master key to {} gatekeepers",
+            gatekeepers.len()
+        );
+        for gatekeeper in gatekeepers {
+            let event = self.encrypt_master_keys(
+                master_keys,
+                &gatekeeper.pubkey,
+                &gatekeeper.ecdh_pubkey,
+                block_number,
+            );
+            self.egress
+                .push_message(&KeyDistribution::master_key_rotation(rotation_id, event));
+        }
     }
 
     pub fn process_messages(&mut self, block: &BlockInfo<'_>) {
@@ -268,6 +366,8 @@ where
             ContractEvent::InstantiateCode {
                 contract_info,
                 deploy_workers,
+                expiration,
+                gas_limit,
             } => {
                 if !origin.is_pallet() {
                     error!("Attempt to instantiate pink from bad origin");
@@ -276,7 +376,7 @@ where
 
                 // first, update the on-chain ContractPubkey
                 let contract_id = get_contract_id(&contract_info);
-                let contract_key = get_contract_key(&self.master_key, &contract_id);
+                let contract_key = get_contract_key(self.genesis_master_key(), &contract_id);
                 self.egress
                     .push_message(&ContractRegistryEvent::PubkeyAvailable {
                         contract_id: contract_id.clone(),
@@ -289,17 +389,56 @@ where
                     .derive_ecdh_key()
                     .expect("should never fail with valid master key; qed.");
                 let secret_mq = SecretMessageChannel::new(&ecdh_key, &self.egress);
-                // TODO.shelven: set up expiration
                 for worker in deploy_workers.iter() {
                     secret_mq
                         .bind_remote_key(Some(&worker.ecdh_pubkey.0))
                         .push_message(&ContractKeyDistribution::contract_key_distribution(
                             contract_key.dump_secret_key(),
                             contract_info.clone(),
-                            0,
+                            expiration,
+                            gas_limit,
                         ));
                 }
             }
+            ContractEvent::ClusterWorkerAdded {
+                cluster_id,
+                worker,
+                key_contract,
+                contracts,
+            } => {
+                if !origin.is_pallet() {
+                    error!("Attempt to add cluster worker from bad origin");
+                    return Err(TransactionError::BadOrigin);
+                }
+
+                let contracts = contracts
+                    .into_iter()
+                    .map(|(contract_info, expiration)| {
+                        let contract_id = get_contract_id(&contract_info);
+                        let contract_key =
+                            get_contract_key(self.genesis_master_key(), &contract_id);
+                        DispatchContractKeyEvent {
+                            secret_key: contract_key.dump_secret_key(),
+                            contract_info,
+                            expiration,
+                            // The joining worker gets the state instead of running the constructor
+                            gas_limit: 0,
+                        }
+                    })
+                    .collect();
+                let cluster_key = get_contract_key(self.genesis_master_key(), &key_contract);
+                let ecdh_key = self
+                    .master_key
+                    .derive_ecdh_key()
+                    .expect("should never fail with valid master key; qed.");
+                SecretMessageChannel::new(&ecdh_key, &self.egress)
+                    .bind_remote_key(Some(&worker.ecdh_pubkey.0))
+                    .push_message(&ContractKeyDistribution::cluster_key_distribution(
+                        cluster_id,
+                        cluster_key.dump_secret_key(),
+                        contracts,
+                    ));
+            }
         }
         Ok(())
     }
@@ -312,7 +451,7 @@ where
         };
 
         let expect_random = next_random_number(
-            &self.master_key,
+            self.master_key_at(event.block_number),
             event.block_number,
             event.last_random_number,
         );
@@ -1154,7 +1293,11 @@ pub mod tests {
     use parity_scale_codec::{Decode, Encode};
     use phala_mq::{BindTopic, Message, MessageDispatcher, MessageOrigin, Path};
     use phala_types::{messaging as msg, WorkerPublicKey};
-    use std::cell::RefCell;
+    use sp_core::{sr25519, Pair};
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
 
     type MiningInfoUpdateEvent = super::MiningInfoUpdateEvent<chain::BlockNumber>;
 
@@ -1176,9 +1319,10 @@ pub mod tests {
         }
     }
 
-    #[derive(Default)]
+    #[derive(Default, Clone)]
     struct CollectChannel {
-        messages: RefCell<Vec<Message>>,
+        messages: Rc<RefCell<Vec<Message>>>,
+        dummy: Rc<Cell<bool>>,
     }
 
     impl CollectChannel {
@@ -1210,6 +1354,9 @@ pub mod tests {
 
     impl MessageChannel for CollectChannel {
         fn push_data(&self, data: Vec<u8>, to: impl Into<Path>) {
+            if self.dummy.get() {
+                return;
+            }
             let message = Message {
                 sender: MessageOrigin::Gatekeeper,
                 destination: to.into().into(),
@@ -1217,6 +1364,10 @@ pub mod tests {
             };
             self.messages.borrow_mut().push(message);
         }
+
+        fn set_dummy(&self, dummy: bool) {
+            self.dummy.set(dummy);
+        }
     }
 
     struct Roles {
@@ -1932,6 +2083,128 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn gk_should_verify_random_numbers_across_master_key_rotation() {
+        let mut mq = MessageDispatcher::new();
+        let egress = CollectChannel::default();
+        let mut gk = super::Gatekeeper::new(
+            sr25519::Pair::from_seed(&[1u8; 32]),
+            &mut mq,
+            egress.clone(),
+        );
+        gk.master_pubkey_uploaded();
+        gk.register_on_chain();
+
+        gk.emit_random_number(5);
+        gk.rotate_master_key(sr25519::Pair::from_seed(&[2u8; 32]), egress.clone(), 7);
+        gk.emit_random_number(10);
+        gk.rotate_master_key(sr25519::Pair::from_seed(&[3u8; 32]), egress.clone(), 12);
+        gk.emit_random_number(15);
+
+        let events: Vec<msg::GatekeeperEvent> = egress.drain_decode();
+        assert_eq!(events.len(), 3);
+        // Would panic if any of them is verified with the wrong master key
+        with_block(16, |block| {
+            for event in events {
+                mq.dispatch_bound(&MessageOrigin::Gatekeeper, event);
+            }
+            gk.process_messages(block);
+        });
+    }
+
+    #[test]
+    fn gk_should_keep_cluster_key_across_master_key_rotation() {
+        use crate::contracts::get_contract_id;
+        use crate::secret_channel::{Payload, Peeler, SecretPeeler};
+        use phala_crypto::sr25519::{Persistence, KDF};
+        use phala_types::{
+            contract::{messaging::ContractEvent, CodeIndex, ContractInfo},
+            messaging::ContractKeyDistribution,
+            EcdhPublicKey, WorkerIdentity,
+        };
+
+        type KeyDistribution =
+            ContractKeyDistribution<chain::Hash, chain::BlockNumber, chain::AccountId>;
+
+        let genesis_master_key = sr25519::Pair::from_seed(&[1u8; 32]);
+        let mut mq = MessageDispatcher::new();
+        let egress = CollectChannel::default();
+        let mut gk = super::Gatekeeper::new(genesis_master_key.clone(), &mut mq, egress.clone());
+        gk.master_pubkey_uploaded();
+        gk.register_on_chain();
+        gk.rotate_master_key(sr25519::Pair::from_seed(&[2u8; 32]), egress.clone(), 7);
+
+        let contract_info = ContractInfo {
+            deployer: chain::AccountId::new([1u8; 32]),
+            code_index: CodeIndex::WasmCode(Default::default()),
+            salt: vec![],
+            cluster_id: Default::default(),
+            instantiate_data: vec![],
+        };
+        let key_contract = get_contract_id(&contract_info);
+        let worker_ecdh_key = sr25519::Pair::from_seed(&[9u8; 32])
+            .derive_ecdh_key()
+            .unwrap();
+        let worker = WorkerIdentity {
+            pubkey: WorkerPublicKey::from_raw([9u8; 32]),
+            ecdh_pubkey: EcdhPublicKey(worker_ecdh_key.public()),
+        };
+        with_block(8, |block| {
+            mq.dispatch_bound(
+                &MessageOrigin::Pallet(b"PhalaFatContracts".to_vec()),
+                ContractEvent::ClusterWorkerAdded {
+                    cluster_id: Default::default(),
+                    worker,
+                    key_contract,
+                    contracts: vec![(contract_info, 0)],
+                },
+            );
+            gk.process_messages(block);
+        });
+
+        let payloads: Vec<Payload<KeyDistribution>> = egress
+            .drain()
+            .into_iter()
+            .filter(|m| &m.destination.path()[..] == &KeyDistribution::topic())
+            .map(|m| Decode::decode(&mut &m.payload[..]).unwrap())
+            .collect();
+        assert_eq!(payloads.len(), 1);
+        let peeler = SecretPeeler::<KeyDistribution>::new(worker_ecdh_key);
+        let event = match peeler.peel(payloads.into_iter().next().unwrap()).unwrap() {
+            ContractKeyDistribution::ClusterKeyDistribution(event) => event,
+            _ => panic!("Expected a cluster key distribution"),
+        };
+        // The keys are still derived from the master key the cluster was created with
+        let expected_key = super::get_contract_key(&genesis_master_key, &key_contract);
+        assert_eq!(event.cluster_key, expected_key.dump_secret_key());
+        assert_eq!(event.contracts.len(), 1);
+        assert_eq!(
+            event.contracts[0].secret_key,
+            expected_key.dump_secret_key()
+        );
+    }
+
+    #[test]
+    fn gk_should_stop_emitting_messages_after_unregistered() {
+        let mut mq = MessageDispatcher::new();
+        let egress = CollectChannel::default();
+        let mut gk = super::Gatekeeper::new(
+            sr25519::Pair::from_seed(&[1u8; 32]),
+            &mut mq,
+            egress.clone(),
+        );
+        gk.master_pubkey_uploaded();
+
+        gk.register_on_chain();
+        gk.emit_random_number(5);
+        assert_eq!(egress.drain_decode::<msg::GatekeeperEvent>().len(), 1);
+
+        gk.unregister_on_chain();
+        assert!(!gk.registered_on_chain());
+        gk.emit_random_number(10);
+        assert!(egress.drain().is_empty());
+    }
+
     #[test]
     fn test_update_p_instant() {
         let mut info = super::TokenomicInfo {
@@ -19,37 +19,60 @@ struct PersistentMasterKey {
 #[derive(Debug, Encode, Decode)]
 enum MasterKeySeal {
     V1(PersistentMasterKey),
+    /// The master keys ever used, oldest first and the current one last
+    V2(Vec<PersistentMasterKey>),
 }
 
 fn master_key_file_path(sealing_path: String) -> PathBuf {
     PathBuf::from(&sealing_path).join(MASTER_KEY_FILE)
 }
 
-/// Seal master key seed with signature to ensure integrity
+/// Seal master key seeds with signature to ensure integrity
+///
+/// `master_keys` are all the master keys ever used, oldest first and the current one last.
 pub fn seal(
     sealing_path: String,
-    master_key: &sr25519::Pair,
+    master_keys: &[sr25519::Pair],
     identity_key: &sr25519::Pair,
     sys: &impl Sealing,
 ) {
-    let secret = master_key.dump_secret_key();
-    let signature = identity_key.sign_data(&secret);
+    let keys = master_keys
+        .iter()
+        .map(|master_key| {
+            let secret = master_key.dump_secret_key();
+            let signature = identity_key.sign_data(&secret);
+            PersistentMasterKey { secret, signature }
+        })
+        .collect();
 
-    let data = MasterKeySeal::V1(PersistentMasterKey { secret, signature });
+    let data = MasterKeySeal::V2(keys);
     let filepath = master_key_file_path(sealing_path);
     info!("Seal master key to {}", filepath.as_path().display());
     sys.seal_data(filepath, &data.encode())
         .expect("Seal master key failed");
 }
 
-/// Unseal local master key seed and verify signature
+/// Remove the sealed master keys
+pub fn wipe(sealing_path: String) {
+    let filepath = master_key_file_path(sealing_path);
+    info!("Wipe master key {}", filepath.as_path().display());
+    if filepath.exists() {
+        if let Err(err) = std::fs::remove_file(&filepath) {
+            error!("Failed to remove {}: {}", filepath.display(), err);
+        }
+    }
+}
+
+/// Unseal local master key seeds and verify signature
+///
+/// Returns all the master keys ever used, oldest first and the current one last.
 ///
 /// This function could panic a lot.
 pub fn try_unseal(
     sealing_path: String,
     identity_key: &sr25519::Pair,
     sys: &impl Sealing,
-) -> Option<sr25519::Pair> {
+) -> Vec<sr25519::Pair> {
     let filepath = master_key_file_path(sealing_path);
     info!("Unseal master key from {}", filepath.as_path().display());
     let sealed_data = match sys
@@ -59,22 +82,25 @@ pub fn try_unseal(
         Some(data) => data,
         None => {
             warn!("No sealed master key");
-            return None;
+            return Vec::new();
         }
     };
 
     let versioned_data =
         MasterKeySeal::decode(&mut &sealed_data[..]).expect("Failed to decode sealed master key");
 
-    #[allow(clippy::infallible_destructuring_match)]
-    let data = match versioned_data {
-        MasterKeySeal::V1(data) => data,
+    let keys = match versioned_data {
+        MasterKeySeal::V1(data) => vec![data],
+        MasterKeySeal::V2(keys) => keys,
     };
 
-    assert!(
-        identity_key.verify_data(&data.signature, &data.secret),
-        "Broken sealed master key"
-    );
-
-    Some(sr25519::Pair::restore_from_secret_key(&data.secret))
+    keys.iter()
+        .map(|data| {
+            assert!(
+                identity_key.verify_data(&data.signature, &data.secret),
+                "Broken sealed master key"
+            );
+            sr25519::Pair::restore_from_secret_key(&data.secret)
+        })
+        .collect()
 }
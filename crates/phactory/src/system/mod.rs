@@ -7,16 +7,20 @@ use crate::{
     contracts::{
         pink::cluster::Cluster, ContractsKeeper, ExecuteEnv, NativeContract, NativeContractMore,
     },
-    pink::{cluster::ClusterKeeper, Pink},
-    secret_channel::{ecdh_serde, SecretReceiver},
+    pink::{
+        cluster::ClusterKeeper, Pink, ProtectedFileNodeStoreOpener, INSTANTIATE_GAS_LIMIT,
+        STATE_ROOT_REPORT_INTERVAL,
+    },
+    secret_channel::{ecdh_serde, SecretMessageChannel, SecretReceiver},
     types::{BlockInfo, OpaqueError, OpaqueQuery, OpaqueReply},
 };
 use anyhow::{anyhow, Context, Result};
 use core::fmt;
 use log::info;
-use pink::runtime::ExecSideEffects;
+use pink::{runtime::ExecSideEffects, types::Weight};
 use runtime::BlockNumber;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
 use crate::contracts;
 use crate::pal;
@@ -26,21 +30,28 @@ pub use phactory_api::prpc::{GatekeeperRole, GatekeeperStatus};
 use phala_crypto::{
     aead,
     ecdh::{self, EcdhKey},
-    sr25519::{Persistence, KDF},
+    sr25519::{Persistence, Sr25519SecretKey, KDF},
 };
 use phala_mq::{
-    traits::MessageChannel, BadOrigin, BindTopic, ContractId, MessageDispatcher, MessageOrigin,
-    MessageSendQueue, SignedMessageChannel, TypedReceiver,
+    traits::MessageChannel, BadOrigin, BindTopic, ContractClusterId, ContractId, Message,
+    MessageDispatcher, MessageOrigin, MessageReceiver, MessageSendQueue, SignedMessageChannel,
+    TypedReceiver,
 };
 use phala_serde_more as more;
 use phala_types::{
-    contract::{self, messaging::ContractOperation, CodeIndex},
+    contract::{
+        self,
+        messaging::{ClusterStateTransfer, ContractOperation},
+        CodeIndex, CommandReceipt, InkEvent,
+    },
     messaging::{
-        ContractKeyDistribution, DispatchContractKeyEvent, DispatchMasterKeyEvent,
-        GatekeeperChange, GatekeeperLaunch, HeartbeatChallenge, KeyDistribution, MiningReportEvent,
-        NewGatekeeperEvent, SystemEvent, WorkerContractReport, WorkerEvent,
+        ContractKeyDistribution, DispatchClusterKeyEvent, DispatchContractKeyEvent,
+        DispatchMasterKeyEvent, DispatchRotatedMasterKeyEvent, GatekeeperChange, GatekeeperLaunch,
+        HeartbeatChallenge, KeyDistribution, MasterKeyRotationAbortedEvent,
+        MasterPubkeyRotatedEvent, MiningReportEvent, NewGatekeeperEvent, RemoveGatekeeperEvent,
+        RotateMasterKeyEvent, SystemEvent, WorkerContractReport, WorkerEvent,
     },
-    EcdhPublicKey, WorkerPublicKey,
+    EcdhPublicKey, WorkerIdentity, WorkerPublicKey,
 };
 use serde::{Deserialize, Serialize};
 use side_tasks::geo_probe;
@@ -383,6 +394,84 @@ impl WorkerIdentityKey {
 #[serde(transparent)]
 pub(crate) struct ContractKey(#[serde(with = "more::key_bytes")] sr25519::Pair);
 
+type ContractKeyEvent = DispatchContractKeyEvent<chain::Hash, chain::BlockNumber, chain::AccountId>;
+
+/// The max size of the state chunks sent to a worker joining a cluster.
+const CLUSTER_STATE_CHUNK_SIZE: usize = 256 * 1024;
+
+/// The max size of the state of a cluster to send to a joining worker, which goes through the
+/// chain. The join fails if the state is larger.
+const MAX_CLUSTER_STATE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A rotated master key waiting for its pubkey to be published on chain.
+#[derive(Serialize, Deserialize)]
+struct PendingMasterKey {
+    rotation_id: u64,
+    #[serde(with = "more::key_bytes")]
+    master_key: sr25519::Pair,
+}
+
+/// A cluster this worker is joining, waiting for the state from an existing member.
+#[derive(Serialize, Deserialize)]
+struct ClusterJoin {
+    /// The key the cluster was created with
+    #[serde(with = "more::key_bytes")]
+    cluster_key: sr25519::Pair,
+    /// The keys of the contracts in the cluster from the gatekeeper
+    #[serde(with = "more::scale_bytes")]
+    keys: Vec<ContractKeyEvent>,
+    /// The member sending the state, once assigned by the chain
+    source: Option<WorkerPublicKey>,
+    /// The subscriptions to the commands of the contracts since the state was taken
+    command_receivers: Vec<MessageReceiver<Message>>,
+    /// The blocks processed so far, replayed once the state is installed
+    held_blocks: Vec<HeldBlock>,
+    /// The chunks of the encoded state received so far
+    state: Vec<u8>,
+    state_chunks: u32,
+}
+
+/// The commands received in a block by the contracts of a cluster being joined.
+#[derive(Serialize, Deserialize)]
+struct HeldBlock {
+    block_number: chain::BlockNumber,
+    now_ms: u64,
+    commands: Vec<Message>,
+}
+
+impl ClusterJoin {
+    /// Hold the commands received in this block, which would be dropped at the end of the block.
+    fn hold_commands(&mut self, block: &BlockInfo) {
+        if self.source.is_none() {
+            return;
+        }
+        let mut commands = Vec::new();
+        for receiver in self.command_receivers.iter_mut() {
+            while let Ok(Some((_, message))) = receiver.try_next() {
+                commands.push(message);
+            }
+        }
+        self.held_blocks.push(HeldBlock {
+            block_number: block.block_number,
+            now_ms: block.now_ms,
+            commands,
+        });
+    }
+
+    /// Start over with the state from `source`, whose commands are held from this block on.
+    fn wait_state_from(
+        &mut self,
+        source: Option<WorkerPublicKey>,
+        command_receivers: Vec<MessageReceiver<Message>>,
+    ) {
+        self.source = source;
+        self.command_receivers = command_receivers;
+        self.held_blocks.clear();
+        self.state.clear();
+        self.state_chunks = 0;
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct System<Platform> {
     platform: Platform,
@@ -390,6 +479,8 @@ pub struct System<Platform> {
     pub(crate) sealing_path: String,
     enable_geoprobing: bool,
     pub(crate) geoip_city_db: String,
+    /// Number of blocks to retain the ink! events emitted by pink contracts.
+    pub(crate) ink_event_retention: BlockNumber,
     // Messageing
     egress: SignedMessageChannel,
     system_events: TypedReceiver<SystemEvent>,
@@ -399,6 +490,7 @@ pub struct System<Platform> {
     contract_key_distribution_events:
         SecretReceiver<ContractKeyDistribution<chain::Hash, chain::BlockNumber, chain::AccountId>>,
     contract_operation_events: TypedReceiver<ContractOperation<chain::AccountId>>,
+    cluster_state_events: SecretReceiver<ClusterStateTransfer>,
     // Worker
     pub(crate) identity_key: WorkerIdentityKey,
     #[serde(with = "ecdh_serde")]
@@ -407,11 +499,25 @@ pub struct System<Platform> {
     // Gatekeeper
     #[serde(with = "more::option_key_bytes")]
     master_key: Option<sr25519::Pair>,
+    /// The master keys replaced by rotations, oldest first
+    #[serde(default, with = "more::vec_key_bytes")]
+    master_key_history: Vec<sr25519::Pair>,
+    /// The master key of the ongoing rotation, not in use nor sealed until it's on chain
+    #[serde(default)]
+    pending_master_key: Option<PendingMasterKey>,
     pub(crate) gatekeeper: Option<gk::Gatekeeper<SignedMessageChannel>>,
 
     pub(crate) contracts: ContractsKeeper,
     contract_clusters: ClusterKeeper,
     contract_keys: BTreeMap<ContractId, ContractKey>,
+    /// The last block the key of each time-limited contract is valid in
+    #[serde(default)]
+    contract_key_expirations: BTreeMap<ContractId, chain::BlockNumber>,
+    #[serde(default)]
+    cluster_joins: BTreeMap<ContractClusterId, ClusterJoin>,
+    /// The clusters to send the state of to a joining worker at the end of the current block
+    #[serde(skip)]
+    cluster_state_requests: Vec<(ContractClusterId, WorkerIdentity)>,
 
     // Cached for query
     block_number: BlockNumber,
@@ -424,6 +530,7 @@ impl<Platform: pal::Platform> System<Platform> {
         sealing_path: String,
         enable_geoprobing: bool,
         geoip_city_db: String,
+        ink_event_retention: BlockNumber,
         identity_key: sr25519::Pair,
         ecdh_key: EcdhKey,
         send_mq: &MessageSendQueue,
@@ -433,13 +540,16 @@ impl<Platform: pal::Platform> System<Platform> {
         let identity_key = WorkerIdentityKey(identity_key);
         let pubkey = identity_key.public();
         let sender = MessageOrigin::Worker(pubkey);
-        let master_key = master_key::try_unseal(sealing_path.clone(), &identity_key.0, &platform);
+        let mut master_key_history =
+            master_key::try_unseal(sealing_path.clone(), &identity_key.0, &platform);
+        let master_key = master_key_history.pop();
 
         System {
             platform,
             sealing_path,
             enable_geoprobing,
             geoip_city_db,
+            ink_event_retention,
             egress: send_mq.channel(sender, identity_key.clone().0.into()),
             system_events: recv_mq.subscribe_bound(),
             gatekeeper_launch_events: recv_mq.subscribe_bound(),
@@ -456,14 +566,23 @@ impl<Platform: pal::Platform> System<Platform> {
                 ecdh_key.clone(),
             ),
             contract_operation_events: recv_mq.subscribe_bound(),
+            cluster_state_events: SecretReceiver::new_secret(
+                recv_mq.subscribe(ClusterStateTransfer::topic()).into(),
+                ecdh_key.clone(),
+            ),
             identity_key,
             ecdh_key,
             worker_state: WorkerState::new(pubkey),
             master_key,
+            master_key_history,
+            pending_master_key: None,
             gatekeeper: None,
             contracts,
             contract_clusters: Default::default(),
             contract_keys: Default::default(),
+            contract_key_expirations: Default::default(),
+            cluster_joins: Default::default(),
+            cluster_state_requests: Default::default(),
             block_number: 0,
             now_ms: 0,
         }
@@ -510,6 +629,9 @@ impl<Platform: pal::Platform> System<Platform> {
             (event, origin) = self.contract_operation_events => {
                 self.process_contract_operation_event(block, origin, event)?
             },
+            (event, origin) = self.cluster_state_events => {
+                self.process_cluster_state_transfer(block, origin, event)?
+            },
         };
         Ok(ok.is_none())
     }
@@ -541,18 +663,33 @@ impl<Platform: pal::Platform> System<Platform> {
         }
         self.worker_state
             .on_block_processed(block, &mut WorkerSMDelegate(&self.egress));
+        self.expire_contracts(block.block_number);
 
         if let Some(gatekeeper) = &mut self.gatekeeper {
             gatekeeper.process_messages(block);
             gatekeeper.emit_random_number(block.block_number);
         }
 
-        // Iterate over all contracts to handle their incoming commands.
-        //
+        let contract_ids: Vec<_> = self.contracts.keys().cloned().collect();
+        self.process_contract_messages(block, contract_ids);
+
+        self.contract_clusters
+            .prune_ink_events(block.block_number.saturating_sub(self.ink_event_retention));
+
+        if block.block_number % STATE_ROOT_REPORT_INTERVAL == 0 {
+            self.report_cluster_state_roots(block.block_number);
+        }
+        self.send_cluster_states(block.block_number);
+        for join in self.cluster_joins.values_mut() {
+            join.hold_commands(block);
+        }
+    }
+
+    /// Let the given contracts handle their incoming commands and the end of the block.
+    fn process_contract_messages(&mut self, block: &mut BlockInfo, contract_ids: Vec<ContractId>) {
         // Since the wasm contracts can instantiate new contracts, it means that it will mutate the `self.contracts`.
         // So we can not directly iterate over the self.contracts.values_mut() which would keep borrowing on `self.contracts`
         // in the scope of entire `for loop` body.
-        let contract_ids: Vec<_> = self.contracts.keys().cloned().collect();
         'outer: for key in contract_ids {
             // Inner loop to handle commands. One command per iteration and apply the command side-effects to make it
             // availabe for next command.
@@ -600,55 +737,151 @@ impl<Platform: pal::Platform> System<Platform> {
         }
     }
 
+    /// Report the state roots of the clusters, for the chain to check the members agree.
+    fn report_cluster_state_roots(&mut self, block_number: chain::BlockNumber) {
+        for (cluster_id, root) in self.contract_clusters.state_roots() {
+            self.egress
+                .push_message(&WorkerContractReport::ClusterStateRoot {
+                    cluster_id,
+                    block_number,
+                    root,
+                });
+        }
+    }
+
+    /// Send the state of the clusters requested in this block to the joining workers.
+    ///
+    /// The state is taken after the contracts processed the commands of this block, and the
+    /// joining workers hold the commands from the next block on.
+    fn send_cluster_states(&mut self, block_number: chain::BlockNumber) {
+        for (cluster_id, dest) in std::mem::take(&mut self.cluster_state_requests) {
+            let cluster = match self.contract_clusters.get_cluster_mut(&cluster_id) {
+                Some(cluster) => cluster,
+                None => {
+                    error!("Cluster {} to transfer not found", cluster_id);
+                    continue;
+                }
+            };
+            let contracts: Vec<_> = cluster.contracts().cloned().collect();
+            let storage = cluster.dump_storage().encode();
+            if storage.len() > MAX_CLUSTER_STATE_SIZE {
+                error!(
+                    "The state of cluster {} is too large to transfer: {} bytes",
+                    cluster_id,
+                    storage.len()
+                );
+                self.egress
+                    .push_message(&WorkerContractReport::ClusterStateTooLarge {
+                        cluster_id,
+                        size: storage.len() as u64,
+                    });
+                continue;
+            }
+            let chunk_count = storage.chunks(CLUSTER_STATE_CHUNK_SIZE).count() as u32;
+            let channel = SecretMessageChannel::new(&self.ecdh_key, &self.egress);
+            let channel = channel.bind_remote_key(Some(&dest.ecdh_pubkey.0));
+            for (chunk_index, chunk) in storage.chunks(CLUSTER_STATE_CHUNK_SIZE).enumerate() {
+                channel.push_message(&ClusterStateTransfer {
+                    cluster_id,
+                    block_number,
+                    contracts: contracts.clone(),
+                    chunk_index: chunk_index as u32,
+                    chunk_count,
+                    storage: chunk.to_vec(),
+                });
+            }
+            info!(
+                "Sent the state of cluster {} to worker {:?} in {} chunks",
+                cluster_id, dest.pubkey, chunk_count
+            );
+        }
+    }
+
+    /// The ink! events emitted by pink contracts in the retention window.
+    pub fn ink_events(
+        &self,
+        contract: Option<&ContractId>,
+        topic: Option<&pink::types::Hash>,
+        from_block: BlockNumber,
+    ) -> Vec<InkEvent> {
+        self.contract_clusters
+            .ink_events(contract, topic, from_block)
+    }
+
+    /// The receipts of the pink commands sent to `contract` with the given nonce, in the same
+    /// retention window as the ink! events.
+    pub fn command_receipts(&self, contract: &ContractId, nonce: &[u8]) -> Vec<CommandReceipt> {
+        self.contract_clusters.command_receipts(contract, nonce)
+    }
+
+    /// Keep the storage of the given pink clusters on disk under the sealing path.
+    ///
+    /// Only affects the clusters created afterwards.
+    pub fn set_disk_backed_clusters(&mut self, clusters: BTreeSet<phala_mq::ContractClusterId>) {
+        let opener = ProtectedFileNodeStoreOpener::new(
+            self.platform.clone(),
+            &self.sealing_path,
+            self.identity_key.dump_secret_key().to_vec(),
+        );
+        self.contract_clusters
+            .set_node_store(Arc::new(opener), clusters);
+    }
+
     fn process_system_event(&mut self, block: &BlockInfo, event: &SystemEvent) {
         self.worker_state
             .process_event(block, event, &mut WorkerSMDelegate(&self.egress), true);
     }
 
-    fn set_master_key(&mut self, master_key: sr25519::Pair, need_restart: bool) {
+    /// All the master keys ever used, oldest first and the current one last
+    fn master_keys(&self) -> Vec<sr25519::Pair> {
+        self.master_key_history
+            .iter()
+            .chain(&self.master_key)
+            .cloned()
+            .collect()
+    }
+
+    /// Set the master keys, oldest first and the current one last
+    fn set_master_key(&mut self, mut master_keys: Vec<sr25519::Pair>, need_restart: bool) {
+        let master_key = master_keys.pop().expect("At least one master key; qed.");
         if self.master_key.is_none() {
+            self.master_key_history = master_keys;
+            self.master_key = Some(master_key);
             master_key::seal(
                 self.sealing_path.clone(),
-                &master_key,
+                &self.master_keys(),
                 &self.identity_key,
                 &self.platform,
             );
-            self.master_key = Some(master_key);
 
             if need_restart {
                 crate::maybe_remove_checkpoints(&self.sealing_path);
                 panic!("Received master key, please restart pRuntime and pherry");
             }
         } else if let Some(my_master_key) = &self.master_key {
-            // TODO(shelven): remove this assertion after we enable master key rotation
             assert_eq!(my_master_key.to_raw_vec(), master_key.to_raw_vec());
         }
     }
 
     fn init_gatekeeper(&mut self, block: &mut BlockInfo) {
-        assert!(
-            self.master_key.is_some(),
-            "Gatekeeper initialization without master key"
-        );
         assert!(
             self.gatekeeper.is_none(),
             "Duplicated gatekeeper initialization"
         );
+        // The gatekeepers launch before any rotation, so start with the oldest master key and
+        // follow the rotations published on chain.
+        let master_key = self
+            .master_keys()
+            .into_iter()
+            .next()
+            .expect("Gatekeeper initialization without master key");
 
         let gatekeeper = gk::Gatekeeper::new(
-            self.master_key
-                .as_ref()
-                .expect("checked master key above; qed.")
-                .clone(),
+            master_key.clone(),
             block.recv_mq,
-            block.send_mq.channel(
-                MessageOrigin::Gatekeeper,
-                self.master_key
-                    .as_ref()
-                    .expect("checked master key above; qed.")
-                    .clone()
-                    .into(),
-            ),
+            block
+                .send_mq
+                .channel(MessageOrigin::Gatekeeper, master_key.into()),
         );
         self.gatekeeper = Some(gatekeeper);
     }
@@ -673,6 +906,129 @@ impl<Platform: pal::Platform> System<Platform> {
                     gatekeeper.master_pubkey_uploaded();
                 }
             }
+            GatekeeperLaunch::RotateMasterKey(rotate_master_key_event) => {
+                self.process_rotate_master_key_event(block, origin, rotate_master_key_event)
+            }
+            GatekeeperLaunch::MasterPubkeyRotated(master_pubkey_rotated_event) => {
+                self.process_master_pubkey_rotated_event(block, origin, master_pubkey_rotated_event)
+            }
+            GatekeeperLaunch::MasterKeyRotationAborted(master_key_rotation_aborted_event) => self
+                .process_master_key_rotation_aborted_event(
+                    origin,
+                    master_key_rotation_aborted_event,
+                ),
+        }
+    }
+
+    /// Generate a new master key and share it with all the gatekeepers if this is the first one
+    fn process_rotate_master_key_event(
+        &mut self,
+        block: &mut BlockInfo,
+        origin: MessageOrigin,
+        event: RotateMasterKeyEvent,
+    ) {
+        if !origin.is_pallet() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return;
+        }
+
+        let my_pubkey = self.identity_key.public();
+        if event.gk_identities.first().map(|gk| gk.pubkey) != Some(my_pubkey) {
+            return;
+        }
+        let mut master_keys = self.master_keys();
+        if let Some(gatekeeper) = &mut self.gatekeeper {
+            info!(
+                "Gatekeeper: generate master key for rotation {}",
+                event.rotation_id
+            );
+            master_keys.push(crate::new_sr25519_key());
+            gatekeeper.share_rotated_master_key(
+                event.rotation_id,
+                &master_keys,
+                &event.gk_identities,
+                block.block_number,
+            );
+        }
+    }
+
+    /// Switch to the rotated master key and sign the gatekeeper messages with it from now on
+    fn process_master_pubkey_rotated_event(
+        &mut self,
+        block: &mut BlockInfo,
+        origin: MessageOrigin,
+        event: MasterPubkeyRotatedEvent,
+    ) {
+        if !origin.is_pallet() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return;
+        }
+
+        let confirmed = matches!(
+            &self.pending_master_key,
+            Some(pending) if pending.rotation_id == event.rotation_id
+                && pending.master_key.public() == event.master_pubkey
+        );
+        if confirmed {
+            let pending = self.pending_master_key.take().expect("Checked above; qed.");
+            info!(
+                "Gatekeeper: switch to master key {} of rotation {}",
+                hex::encode(event.master_pubkey),
+                event.rotation_id
+            );
+            if let Some(master_key) = self.master_key.replace(pending.master_key) {
+                self.master_key_history.push(master_key);
+            }
+            master_key::seal(
+                self.sealing_path.clone(),
+                &self.master_keys(),
+                &self.identity_key,
+                &self.platform,
+            );
+        }
+
+        let master_key = self
+            .master_keys()
+            .into_iter()
+            .find(|master_key| master_key.public() == event.master_pubkey);
+        if let Some(gatekeeper) = &mut self.gatekeeper {
+            let master_key = match master_key {
+                Some(master_key) => master_key,
+                None => {
+                    error!(
+                        "Fatal error: Unknown rotated master pubkey {:?}",
+                        event.master_pubkey
+                    );
+                    panic!("GK state poisoned");
+                }
+            };
+            let egress = block
+                .send_mq
+                .channel(MessageOrigin::Gatekeeper, master_key.clone().into());
+            gatekeeper.rotate_master_key(master_key, egress, block.block_number);
+        }
+    }
+
+    /// Discard the master key of the aborted rotation
+    fn process_master_key_rotation_aborted_event(
+        &mut self,
+        origin: MessageOrigin,
+        event: MasterKeyRotationAbortedEvent,
+    ) {
+        if !origin.is_pallet() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return;
+        }
+
+        if let Some(pending) = &self.pending_master_key {
+            if pending.rotation_id == event.rotation_id {
+                info!(
+                    "Gatekeeper: discard master key {} of aborted rotation {}",
+                    hex::encode(pending.master_key.public()),
+                    event.rotation_id
+                );
+                self.pending_master_key = None;
+            }
         }
     }
 
@@ -705,7 +1061,7 @@ impl<Platform: pal::Platform> System<Platform> {
             // generate master key as the first gatekeeper
             // no need to restart
             let master_key = crate::new_sr25519_key();
-            self.set_master_key(master_key.clone(), false);
+            self.set_master_key(vec![master_key.clone()], false);
             // upload the master key on chain via worker egress
             info!(
                 "Gatekeeper: upload master key {} on chain",
@@ -741,7 +1097,47 @@ impl<Platform: pal::Platform> System<Platform> {
             GatekeeperChange::GatekeeperRegistered(new_gatekeeper_event) => {
                 self.process_new_gatekeeper_event(block, origin, new_gatekeeper_event)
             }
+            GatekeeperChange::GatekeeperUnregistered(remove_gatekeeper_event) => {
+                self.process_remove_gatekeeper_event(block, origin, remove_gatekeeper_event)
+            }
+        }
+    }
+
+    /// Wipe the master keys and stop the gatekeeper if the removed gatekeeper is this worker
+    fn process_remove_gatekeeper_event(
+        &mut self,
+        block: &mut BlockInfo,
+        origin: MessageOrigin,
+        event: RemoveGatekeeperEvent,
+    ) {
+        if !origin.is_pallet() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return;
+        }
+
+        // double check the gatekeeper is removed on chain
+        if chain_state::is_gatekeeper(&event.pubkey, block.storage) {
+            error!("Fatal error: Invalid gatekeeper unregistration {:?}", event);
+            panic!("System state poisoned");
         }
+
+        if self.identity_key.public() != event.pubkey {
+            return;
+        }
+        info!(
+            "Gatekeeper: unregistered in block {}, wipe the master key",
+            block.block_number
+        );
+        if let Some(mut gatekeeper) = self.gatekeeper.take() {
+            gatekeeper.unregister_on_chain();
+        }
+        self.master_key = None;
+        self.master_key_history.clear();
+        self.pending_master_key = None;
+        master_key::wipe(self.sealing_path.clone());
+        // The checkpoints hold a copy of the master keys
+        crate::maybe_remove_checkpoints(&self.sealing_path);
+        self.egress.push_message(&RegistryEvent::GatekeeperDemoted);
     }
 
     /// Share the master key to the newly-registered gatekeeper
@@ -766,8 +1162,14 @@ impl<Platform: pal::Platform> System<Platform> {
             panic!("System state poisoned");
         }
 
+        let master_keys = self.master_keys();
         if let Some(gatekeeper) = &mut self.gatekeeper {
-            gatekeeper.share_master_key(&event.pubkey, &event.ecdh_pubkey, block.block_number);
+            gatekeeper.share_master_key(
+                &master_keys,
+                &event.pubkey,
+                &event.ecdh_pubkey,
+                block.block_number,
+            );
 
             let my_pubkey = self.identity_key.public();
             if my_pubkey == event.pubkey {
@@ -790,6 +1192,13 @@ impl<Platform: pal::Platform> System<Platform> {
                     error!("Failed to process master key distribution event: {:?}", err);
                 };
             }
+            KeyDistribution::MasterKeyRotation(dispatch_rotated_master_key_event) => {
+                if let Err(err) =
+                    self.process_master_key_rotation(origin, dispatch_rotated_master_key_event)
+                {
+                    error!("Failed to process master key rotation event: {:?}", err);
+                };
+            }
         }
     }
 
@@ -819,12 +1228,221 @@ impl<Platform: pal::Platform> System<Platform> {
                     self.egress.push_message(&message);
                 }
             }
+            ContractKeyDistribution::ClusterKeyDistribution(event) => {
+                if let Err(err) = self.process_cluster_key_distribution(origin, event) {
+                    error!(
+                        "Failed to process cluster key distribution event: {:?}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Keep the keys of a cluster this worker is joining, and ask for the state of the cluster.
+    fn process_cluster_key_distribution(
+        &mut self,
+        origin: MessageOrigin,
+        event: DispatchClusterKeyEvent<chain::Hash, chain::BlockNumber, chain::AccountId>,
+    ) -> anyhow::Result<()> {
+        if !origin.is_gatekeeper() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return Err(TransactionError::BadOrigin.into());
+        }
+        let cluster_id = event.cluster_id;
+        if self
+            .contract_clusters
+            .get_cluster_mut(&cluster_id)
+            .is_some()
+        {
+            info!("Already a member of cluster {}", cluster_id);
+            return Ok(());
+        }
+        self.cluster_joins.insert(
+            cluster_id,
+            ClusterJoin {
+                cluster_key: sr25519::Pair::restore_from_secret_key(&event.cluster_key),
+                keys: event.contracts,
+                source: None,
+                command_receivers: Vec::new(),
+                held_blocks: Vec::new(),
+                state: Vec::new(),
+                state_chunks: 0,
+            },
+        );
+        self.egress
+            .push_message(&WorkerContractReport::ClusterStateRequested { cluster_id });
+        info!("Joining cluster {}, waiting for the state", cluster_id);
+        Ok(())
+    }
+
+    /// Collect the state of a cluster this worker is joining, and install it once complete.
+    fn process_cluster_state_transfer(
+        &mut self,
+        block: &mut BlockInfo,
+        origin: MessageOrigin,
+        event: ClusterStateTransfer,
+    ) -> anyhow::Result<()> {
+        let cluster_id = event.cluster_id;
+        let join = match self.cluster_joins.get_mut(&cluster_id) {
+            Some(join) => join,
+            // Not for this worker
+            None => return Ok(()),
+        };
+        let source = match join.source {
+            Some(source) if MessageOrigin::Worker(source) == origin => source,
+            _ => anyhow::bail!("Invalid origin {:?} sent the state of a cluster", origin),
+        };
+        let max_chunks = MAX_CLUSTER_STATE_SIZE / CLUSTER_STATE_CHUNK_SIZE;
+        if event.chunk_index != join.state_chunks || event.chunk_count as usize > max_chunks {
+            self.reject_cluster_state(cluster_id, source);
+            anyhow::bail!(
+                "Unexpected chunk {} of the state of cluster {}",
+                event.chunk_index,
+                cluster_id
+            );
+        }
+        join.state.extend(event.storage.iter());
+        join.state_chunks += 1;
+        if join.state_chunks < event.chunk_count {
+            return Ok(());
+        }
+
+        let mut join = self
+            .cluster_joins
+            .remove(&cluster_id)
+            .expect("checked; qed.");
+        let result = if self
+            .contract_clusters
+            .get_cluster_mut(&cluster_id)
+            .is_some()
+        {
+            Err(anyhow!("Already a member of cluster {}", cluster_id))
+        } else {
+            self.install_cluster_state(block, &mut join, &event)
+                .map_err(|err| {
+                    self.remove_cluster(&cluster_id);
+                    err
+                })
+        };
+        if let Err(err) = result {
+            self.cluster_joins.insert(cluster_id, join);
+            self.reject_cluster_state(cluster_id, source);
+            return Err(err.context("Failed to install the state of a cluster"));
+        }
+        self.egress
+            .push_message(&WorkerContractReport::ClusterJoined { cluster_id });
+        info!(
+            "Joined cluster {} with the state after block {}",
+            cluster_id, event.block_number
+        );
+        Ok(())
+    }
+
+    /// Ask the chain for the state of a cluster from another member than `source`.
+    fn reject_cluster_state(&mut self, cluster_id: ContractClusterId, source: WorkerPublicKey) {
+        if let Some(join) = self.cluster_joins.get_mut(&cluster_id) {
+            join.wait_state_from(None, Vec::new());
+        }
+        self.egress
+            .push_message(&WorkerContractReport::ClusterStateRejected { cluster_id, source });
+    }
+
+    /// Install the state of a cluster this worker is joining, and replay the blocks held since.
+    fn install_cluster_state(
+        &mut self,
+        block: &mut BlockInfo,
+        join: &mut ClusterJoin,
+        event: &ClusterStateTransfer,
+    ) -> anyhow::Result<()> {
+        let cluster_id = event.cluster_id;
+        let dump = pink::StorageDump::decode(&mut &std::mem::take(&mut join.state)[..])
+            .context("Invalid cluster state")?;
+        let cluster_key = join.cluster_key.clone();
+        let ecdh_key = cluster_key
+            .derive_ecdh_key()
+            .or(Err(anyhow!("Invalid cluster key")))?;
+        self.contract_clusters.restore_cluster(
+            &cluster_id,
+            &cluster_key,
+            event.contracts.clone(),
+            dump,
+        )?;
+
+        let mut native_contracts = BTreeSet::new();
+        for key in join.keys.iter() {
+            let contract_id = contracts::get_contract_id(&key.contract_info);
+            if key.expiration != 0 {
+                self.contract_key_expirations
+                    .insert(contract_id, key.expiration);
+            }
+            match key.contract_info.code_index {
+                CodeIndex::NativeCode(_) => {
+                    native_contracts.insert(contract_id);
+                }
+                CodeIndex::WasmCode(_) => {
+                    let contract_key = sr25519::Pair::restore_from_secret_key(&key.secret_key);
+                    self.contract_keys
+                        .insert(contract_id, ContractKey(contract_key));
+                }
+            }
+        }
+        let mut installed = Vec::new();
+        for contract_id in event.contracts.iter() {
+            if native_contracts.contains(contract_id) {
+                warn!(
+                    "The state of native contract {:?} can't be transferred",
+                    contract_id
+                );
+                continue;
+            }
+            let pink = Pink::from_address(pink::types::AccountId::new(contract_id.0), cluster_id);
+            installed.push(install_contract(
+                &mut self.contracts,
+                pink,
+                cluster_key.clone(),
+                ecdh_key.clone(),
+                block,
+                cluster_id,
+            )?);
+        }
+
+        // The commands of the current block go along with the ones of the other contracts.
+        let mut commands = Vec::new();
+        for receiver in join.command_receivers.iter_mut() {
+            while let Ok(Some((_, message))) = receiver.try_next() {
+                commands.push(message);
+            }
+        }
+        join.command_receivers.clear();
+        // The blocks since the state was taken run with their own block number and time, but with
+        // the chain storage of the current block.
+        for held in std::mem::take(&mut join.held_blocks) {
+            if held.block_number <= event.block_number {
+                continue;
+            }
+            let mut held_block = BlockInfo {
+                block_number: held.block_number,
+                now_ms: held.now_ms,
+                storage: block.storage,
+                send_mq: block.send_mq,
+                recv_mq: &mut *block.recv_mq,
+                side_task_man: &mut *block.side_task_man,
+            };
+            for message in held.commands {
+                held_block.recv_mq.dispatch(message);
+            }
+            self.process_contract_messages(&mut held_block, installed.clone());
+        }
+        for message in commands {
+            block.recv_mq.dispatch(message);
         }
+        Ok(())
     }
 
     fn process_contract_operation_event(
         &mut self,
-        _block: &mut BlockInfo,
+        block: &mut BlockInfo,
         sender: MessageOrigin,
         event: ContractOperation<chain::AccountId>,
     ) -> anyhow::Result<()> {
@@ -849,41 +1467,305 @@ impl<Platform: pal::Platform> System<Platform> {
                     cluster_id, hash
                 );
             }
-        }
-        Ok(())
-    }
-
-    /// Process encrypted master key from mq
-    fn process_master_key_distribution(
-        &mut self,
-        origin: MessageOrigin,
-        event: DispatchMasterKeyEvent,
-    ) -> Result<(), TransactionError> {
-        if !origin.is_gatekeeper() {
-            error!("Invalid origin {:?} sent a {:?}", origin, event);
-            return Err(TransactionError::BadOrigin);
-        }
-
-        let my_pubkey = self.identity_key.public();
-        if my_pubkey == event.dest {
-            let my_ecdh_key = self
-                .identity_key
-                .derive_ecdh_key()
-                .expect("Should never failed with valid identity key; qed.");
-            let secret = ecdh::agree(&my_ecdh_key, &event.ecdh_pubkey.0)
-                .expect("Should never failed with valid ecdh key; qed.");
-
-            let mut master_key_buff = event.encrypted_master_key.clone();
-            let master_key = aead::decrypt(&event.iv, &secret, &mut master_key_buff[..])
-                .expect("Failed to decrypt dispatched master key");
-
-            let master_pair = sr25519::Pair::from_seed_slice(master_key)
-                .expect("Master key seed must be correct; qed.");
-            info!("Gatekeeper: successfully decrypt received master key");
-            self.set_master_key(master_pair, true);
-        }
-        Ok(())
-    }
+            ContractOperation::UpgradeContract {
+                origin,
+                contract_id,
+                cluster_id,
+                code_hash,
+                migration,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin {:?} trying to upgrade contract", sender);
+                }
+                if self
+                    .contract_clusters
+                    .get_cluster_mut(&cluster_id)
+                    .is_none()
+                {
+                    // Not a member of the cluster
+                    return Ok(());
+                }
+                let result = self.contract_clusters.upgrade_contract(
+                    &cluster_id,
+                    &contract_id,
+                    origin,
+                    code_hash,
+                    migration,
+                    INSTANTIATE_GAS_LIMIT,
+                    block.block_number,
+                    block.now_ms,
+                );
+                let report = match result {
+                    Ok(effects) => {
+                        info!(
+                            "Upgraded contract {:?} to code_hash={:?}",
+                            contract_id, code_hash
+                        );
+                        let cluster = self
+                            .contract_clusters
+                            .get_cluster_mut(&cluster_id)
+                            .expect("checked; qed.");
+                        apply_pink_side_effects(
+                            effects,
+                            cluster_id,
+                            &mut self.contracts,
+                            cluster,
+                            block,
+                            &self.egress,
+                            None,
+                        );
+                        WorkerContractReport::ContractUpgraded {
+                            id: contract_id,
+                            cluster_id,
+                            code_hash,
+                        }
+                    }
+                    Err(err) => {
+                        error!("Failed to upgrade contract {:?}: {:?}", contract_id, err);
+                        WorkerContractReport::ContractUpgradeFailed {
+                            id: contract_id,
+                            cluster_id,
+                            code_hash,
+                        }
+                    }
+                };
+                self.egress.push_message(&report);
+            }
+            ContractOperation::Destroy {
+                contract_id,
+                cluster_id,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin {:?} trying to destroy contract", sender);
+                }
+                if self.uninstall_contract(&contract_id)? {
+                    info!(
+                        "Destroyed contract {:?} in cluster {}",
+                        contract_id, cluster_id
+                    );
+                }
+            }
+            ContractOperation::RenewContract {
+                contract_id,
+                cluster_id,
+                expiration,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin {:?} trying to renew contract", sender);
+                }
+                if self.contracts.get(&contract_id).is_none() {
+                    // Not deployed on this worker
+                    return Ok(());
+                }
+                if expiration == 0 {
+                    self.contract_key_expirations.remove(&contract_id);
+                } else {
+                    self.contract_key_expirations
+                        .insert(contract_id, expiration);
+                }
+                info!(
+                    "Renewed contract {:?} in cluster {}, expiration={}",
+                    contract_id, cluster_id, expiration
+                );
+            }
+            ContractOperation::TransferClusterState {
+                cluster_id,
+                source,
+                dest,
+                contracts,
+            } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!("Invalid origin {:?} trying to transfer cluster", sender);
+                }
+                let my_pubkey = self.identity_key.public();
+                if source == my_pubkey {
+                    if self
+                        .contract_clusters
+                        .get_cluster_mut(&cluster_id)
+                        .is_none()
+                    {
+                        anyhow::bail!("Not a member of cluster {}", cluster_id);
+                    }
+                    self.cluster_state_requests.push((cluster_id, dest));
+                } else if dest.pubkey == my_pubkey {
+                    let join = self
+                        .cluster_joins
+                        .get_mut(&cluster_id)
+                        .context("Not joining the cluster")?;
+                    // The commands of this block are already dispatched, and covered by the state.
+                    let command_receivers = contracts
+                        .into_iter()
+                        .map(|id| block.recv_mq.subscribe(contract::command_topic(id)))
+                        .collect();
+                    join.wait_state_from(Some(source), command_receivers);
+                }
+            }
+            ContractOperation::RemoveClusterWorker { cluster_id, worker } => {
+                if !sender.is_pallet() {
+                    anyhow::bail!(
+                        "Invalid origin {:?} trying to remove cluster worker",
+                        sender
+                    );
+                }
+                if worker != self.identity_key.public() {
+                    return Ok(());
+                }
+                self.cluster_joins.remove(&cluster_id);
+                if self.remove_cluster(&cluster_id) {
+                    info!("Removed from cluster {}", cluster_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the keys and state of a cluster along with its contracts.
+    ///
+    /// Returns false if this worker is not a member of the cluster.
+    fn remove_cluster(&mut self, cluster_id: &ContractClusterId) -> bool {
+        let cluster = match self.contract_clusters.remove_cluster(cluster_id) {
+            Some(cluster) => cluster,
+            None => return false,
+        };
+        for contract_id in cluster.contracts() {
+            self.contracts.remove(contract_id);
+            self.contract_keys.remove(contract_id);
+            self.contract_key_expirations.remove(contract_id);
+        }
+        true
+    }
+
+    /// Forget the key of a contract and remove it with its storage.
+    ///
+    /// Returns false if the contract is not deployed on this worker.
+    fn uninstall_contract(&mut self, contract_id: &ContractId) -> anyhow::Result<bool> {
+        self.contract_keys.remove(contract_id);
+        self.contract_key_expirations.remove(contract_id);
+        let contract = match self.contracts.remove(contract_id) {
+            Some(contract) => contract,
+            None => return Ok(false),
+        };
+        if let Some(cluster) = self
+            .contract_clusters
+            .get_cluster_mut(&contract.cluster_id())
+        {
+            cluster.remove_contract(contract_id)?;
+        }
+        Ok(true)
+    }
+
+    /// Uninstall the contracts whose keys are no longer valid after `block_number`.
+    fn expire_contracts(&mut self, block_number: chain::BlockNumber) {
+        let expired: Vec<_> = self
+            .contract_key_expirations
+            .iter()
+            .filter(|(_, expiration)| **expiration < block_number)
+            .map(|(contract_id, _)| *contract_id)
+            .collect();
+        for contract_id in expired {
+            match self.uninstall_contract(&contract_id) {
+                Ok(_) => info!(
+                    "Contract {:?} expired at block {}",
+                    contract_id, block_number
+                ),
+                Err(err) => error!(
+                    "Failed to uninstall expired contract {:?}: {:?}",
+                    contract_id, err
+                ),
+            }
+        }
+    }
+
+    /// Process encrypted master key from mq
+    fn process_master_key_distribution(
+        &mut self,
+        origin: MessageOrigin,
+        event: DispatchMasterKeyEvent,
+    ) -> Result<(), TransactionError> {
+        if !origin.is_gatekeeper() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return Err(TransactionError::BadOrigin);
+        }
+
+        let my_pubkey = self.identity_key.public();
+        if my_pubkey == event.dest {
+            let master_keys = self.decrypt_master_keys(&event);
+            info!("Gatekeeper: successfully decrypt received master key");
+            self.set_master_key(master_keys, true);
+        }
+        Ok(())
+    }
+
+    /// Keep the rotated master key as pending and report its pubkey
+    ///
+    /// The new key is only used and sealed once its pubkey is published on chain, and discarded if
+    /// the rotation is aborted. The old master keys are kept to decrypt the historical data.
+    fn process_master_key_rotation(
+        &mut self,
+        origin: MessageOrigin,
+        event: DispatchRotatedMasterKeyEvent,
+    ) -> Result<(), TransactionError> {
+        if !origin.is_gatekeeper() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return Err(TransactionError::BadOrigin);
+        }
+
+        let my_pubkey = self.identity_key.public();
+        if my_pubkey != event.dispatch.dest {
+            return Ok(());
+        }
+        if self.master_key.is_none() {
+            error!("Received rotated master key without the master key");
+            return Err(TransactionError::BadSecret);
+        }
+        let new_master_key = self
+            .decrypt_master_keys(&event.dispatch)
+            .pop()
+            .ok_or(TransactionError::BadSecret)?;
+        let known = self
+            .master_keys()
+            .iter()
+            .any(|key| key.public() == new_master_key.public());
+        if !known {
+            info!(
+                "Gatekeeper: received rotated master key {} for rotation {}",
+                hex::encode(new_master_key.public()),
+                event.rotation_id
+            );
+            self.pending_master_key = Some(PendingMasterKey {
+                rotation_id: event.rotation_id,
+                master_key: new_master_key.clone(),
+            });
+        }
+        self.egress
+            .push_message(&RegistryEvent::RotatedMasterPubkey {
+                rotation_id: event.rotation_id,
+                master_pubkey: new_master_key.public(),
+            });
+        Ok(())
+    }
+
+    /// Decrypt the master keys dispatched to this worker, oldest first and the current one last
+    fn decrypt_master_keys(&self, event: &DispatchMasterKeyEvent) -> Vec<sr25519::Pair> {
+        let my_ecdh_key = self
+            .identity_key
+            .derive_ecdh_key()
+            .expect("Should never failed with valid identity key; qed.");
+        let secret = ecdh::agree(&my_ecdh_key, &event.ecdh_pubkey.0)
+            .expect("Should never failed with valid ecdh key; qed.");
+
+        let mut master_key_buff = event.encrypted_master_key.clone();
+        let master_keys = aead::decrypt(&event.iv, &secret, &mut master_key_buff[..])
+            .expect("Failed to decrypt dispatched master key");
+
+        master_keys
+            .chunks(std::mem::size_of::<Sr25519SecretKey>())
+            .map(|master_key| {
+                sr25519::Pair::from_seed_slice(master_key)
+                    .expect("Master key seed must be correct; qed.")
+            })
+            .collect()
+    }
 
     fn process_contract_key_distribution(
         &mut self,
@@ -896,11 +1778,11 @@ impl<Platform: pal::Platform> System<Platform> {
             return Err(TransactionError::BadOrigin.into());
         }
 
-        // TODO(shelven): forget contract key after expiration time
         let keypair = sr25519::Pair::restore_from_secret_key(&event.secret_key);
         let contract_key = ContractKey(keypair);
         let contract_info = event.contract_info;
         let cluster_id = contract_info.cluster_id;
+        let expiring_contract = contracts::get_contract_id(&contract_info);
 
         match contract_info.code_index {
             CodeIndex::NativeCode(contract_id) => {
@@ -912,47 +1794,22 @@ impl<Platform: pal::Platform> System<Platform> {
                     .derive_ecdh_key()
                     .or(Err(anyhow::anyhow!("Invalid contract key")))?;
 
-                macro_rules! match_and_install_contract {
-                    ($(($id: path => $contract: expr)),*) => {{
-                        match contract_id {
-                            $(
-                                $id => {
-                                    let contract = NativeContractWrapper::new(
-                                        $contract,
-                                        &contract_info
-                                    );
-                                    install_contract(
-                                        &mut self.contracts,
-                                        contract,
-                                        contract_key.0.clone(),
-                                        ecdh_key,
-                                        block,
-                                        cluster_id,
-                                    )?
-                                }
-                            )*
-                            _ => {
-                                anyhow::bail!(
-                                    "Invalid contract id: {:?}",
-                                    contract_id
-                                );
-                            }
-                        }
-                    }};
+                let factory = native_contract_factory(contract_id)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid contract id: {:?}", contract_id))?;
+                let contract_id = get_contract_id(&contract_info);
+                if self.contracts.get(&contract_id).is_some() {
+                    return Err(anyhow::anyhow!("Contract already exists"));
                 }
-
                 let ecdh_pubkey = ecdh_key.public();
-
-                let contract_id = match_and_install_contract! {
-                    (DATA_PLAZA => data_plaza::DataPlaza::new()),
-                    (BALANCES => balances::Balances::new()),
-                    (ASSETS => assets::Assets::new()),
-                    (BTC_LOTTERY => btc_lottery::BtcLottery::new(Some(contract_key.0.to_raw_vec()))),
-                    (WEB3_ANALYTICS => web3analytics::Web3Analytics::new()),
-                    (GEOLOCATION => geolocation::Geolocation::new()),
-                    (GUESS_NUMBER => guess_number::GuessNumber::new()),
-                    (BTC_PRICE_BOT => btc_price_bot::BtcPriceBot::new())
-                };
+                let instance = factory.instantiate(
+                    &contract_info,
+                    &contract_key.0,
+                    ecdh_key,
+                    block,
+                    cluster_id,
+                );
+                self.contracts
+                    .insert(NativeInstance::new(factory.type_name(), instance));
 
                 self.contract_clusters
                     .get_cluster_or_default_mut(&cluster_id, &contract_key.0)
@@ -963,6 +1820,7 @@ impl<Platform: pal::Platform> System<Platform> {
                     cluster_id,
                     deployer,
                     pubkey: EcdhPublicKey(ecdh_pubkey),
+                    gas_consumed: None,
                 };
                 info!("Native contract instantiate status: {:?}", message);
                 self.egress.push_message(&message);
@@ -987,7 +1845,7 @@ impl<Platform: pal::Platform> System<Platform> {
                 self.contract_keys.insert(contract_id, contract_key.clone());
                 let code = code.expect("checked; qed.");
                 let deployer = contract_info.deployer;
-                let effects = self
+                let (effects, gas_consumed) = self
                     .contract_clusters
                     .instantiate_contract(
                         cluster_id,
@@ -996,6 +1854,7 @@ impl<Platform: pal::Platform> System<Platform> {
                         contract_info.instantiate_data,
                         contract_info.salt,
                         &contract_key,
+                        event.gas_limit.min(INSTANTIATE_GAS_LIMIT),
                         block.block_number,
                         block.now_ms,
                     )
@@ -1012,9 +1871,15 @@ impl<Platform: pal::Platform> System<Platform> {
                     cluster,
                     block,
                     &self.egress,
+                    Some((contract_id, gas_consumed)),
                 );
             }
         }
+        // Only the installed contracts expire
+        if event.expiration != 0 {
+            self.contract_key_expirations
+                .insert(expiring_contract, event.expiration);
+        }
         Ok(())
     }
 
@@ -1022,6 +1887,42 @@ impl<Platform: pal::Platform> System<Platform> {
         self.worker_state.registered
     }
 
+    /// Catch up with the worker state on chain after a warp sync, which skipped the events
+    /// before the checkpoint.
+    ///
+    /// The states of a gatekeeper or a mining worker are built from the skipped events and can't
+    /// be recovered from the chain state, so they have to sync from the genesis block.
+    pub fn warp_sync(&mut self, chain_storage: &crate::storage::Storage) -> Result<()> {
+        let pubkey = self.identity_key.public();
+        if self.master_key.is_some() || chain_state::is_gatekeeper(&pubkey, chain_storage) {
+            return Err(anyhow!("Gatekeepers must sync from the genesis block"));
+        }
+        if !self.contract_keys.is_empty() || chain_state::is_cluster_worker(&pubkey, chain_storage)
+        {
+            return Err(anyhow!("Cluster workers must sync from the genesis block"));
+        }
+        if let Some(state) = chain_state::miner_state(&pubkey, chain_storage) {
+            use chain::pallet_mining::MinerState;
+            if !matches!(state, MinerState::Ready | MinerState::MiningCoolingDown) {
+                return Err(anyhow!(
+                    "Worker in mining state {:?} must sync from the genesis block",
+                    state
+                ));
+            }
+        }
+        // The messages sent before the warp were accepted on chain, so continue from where the
+        // ingress expects, or the new messages would be rejected as replays.
+        let sequence =
+            chain_state::offchain_ingress_sequence(&MessageOrigin::Worker(pubkey), chain_storage);
+        self.egress.set_sequence(sequence);
+        self.worker_state.registered = chain_state::is_worker_registered(&pubkey, chain_storage);
+        info!(
+            "Warp synced, registered={}, egress sequence={}",
+            self.worker_state.registered, sequence
+        );
+        Ok(())
+    }
+
     pub fn gatekeeper_status(&self) -> GatekeeperStatus {
         let active = match &self.gatekeeper {
             Some(gk) => gk.registered_on_chain(),
@@ -1047,6 +1948,11 @@ impl<Platform: pal::Platform> System<Platform> {
     pub fn commit_changes(&mut self) -> anyhow::Result<()> {
         self.contract_clusters.commit_changes()
     }
+
+    /// Remove the pink trie nodes no retained checkpoint refers to. Called after each checkpoint.
+    pub fn collect_garbage(&mut self) {
+        self.contract_clusters.collect_garbage()
+    }
 }
 
 pub fn handle_contract_command_result(
@@ -1074,9 +1980,13 @@ pub fn handle_contract_command_result(
         }
         Some(cluster) => cluster,
     };
-    apply_pink_side_effects(effects, cluster_id, contracts, cluster, block, egress);
+    apply_pink_side_effects(effects, cluster_id, contracts, cluster, block, egress, None);
 }
 
+/// Apply the side effects of a pink execution.
+///
+/// `constructor` is the contract deployed from the chain by the execution, if any, with the gas
+/// consumed by its constructor.
 pub fn apply_pink_side_effects(
     effects: ExecSideEffects,
     cluster_id: phala_mq::ContractClusterId,
@@ -1084,12 +1994,15 @@ pub fn apply_pink_side_effects(
     cluster: &mut Cluster,
     block: &mut BlockInfo,
     egress: &SignedMessageChannel,
+    constructor: Option<(ContractId, Weight)>,
 ) {
     let contract_key = cluster.key().clone();
     let ecdh_key = contract_key
         .derive_ecdh_key()
         .expect("Derive ecdh_key should not fail");
 
+    cluster.add_ink_events(block.block_number, effects.ink_events);
+
     for (deployer, address) in effects.instantiated {
         let pink = Pink::from_address(address.clone(), cluster_id);
         let id = install_contract(
@@ -1119,6 +2032,9 @@ pub fn apply_pink_side_effects(
             cluster_id,
             deployer: phala_types::messaging::AccountId(deployer.into()),
             pubkey: EcdhPublicKey(ecdh_key.public()),
+            gas_consumed: constructor
+                .filter(|(contract_id, _)| contract_id == &id)
+                .map(|(_, gas_consumed)| gas_consumed),
         };
 
         info!("pink instantiate status: {:?}", message);
@@ -1151,6 +2067,19 @@ pub fn apply_pink_side_effects(
             PinkEvent::OnBlockEndSelector(selector) => {
                 contract.set_on_block_end_selector(selector);
             }
+            PinkEvent::ContractMessage(message) => {
+                let command = crate::pink::Command::ContractMessage {
+                    correlation_id: message.correlation_id,
+                    is_reply: message.is_reply,
+                    from_pubkey: ecdh_key.public(),
+                    payload: message.payload,
+                };
+                contract.push_osp_message(
+                    command.encode(),
+                    contract::command_topic(message.target.into()),
+                    Some(&message.target_pubkey),
+                );
+            }
         }
     }
 }
@@ -1173,22 +2102,12 @@ where
     if contracts.get(&contract_id).is_some() {
         return Err(anyhow::anyhow!("Contract already exists"));
     }
-    let sender = MessageOrigin::Contract(contract_id);
-    let mq = block.send_mq.channel(sender, contract_key.into());
-    let cmd_mq = SecretReceiver::new_secret(
-        block
-            .recv_mq
-            .subscribe(contract::command_topic(contract_id))
-            .into(),
-        ecdh_key.clone(),
-    );
-    let wrapped = contracts::NativeCompatContract::new(
+    let wrapped = contracts::NativeCompatContract::connect(
         contract,
-        mq,
-        cmd_mq,
-        ecdh_key.clone(),
+        contract_key,
+        ecdh_key,
+        block,
         cluster_id,
-        contract_id,
     );
     contracts.insert(wrapped);
     Ok(contract_id)
@@ -1230,6 +2149,62 @@ pub mod chain_state {
         gatekeepers.contains(pubkey)
     }
 
+    pub fn is_worker_registered(pubkey: &WorkerPublicKey, chain_storage: &Storage) -> bool {
+        let key = storage_map_prefix_twox_64_concat(b"PhalaRegistry", b"Workers", pubkey);
+        chain_storage.get(&key).is_some()
+    }
+
+    /// The state of the miner the worker is bound to, if any
+    pub fn miner_state(
+        pubkey: &WorkerPublicKey,
+        chain_storage: &Storage,
+    ) -> Option<chain::pallet_mining::MinerState> {
+        let key = storage_map_prefix_twox_64_concat(b"PhalaMining", b"WorkerBindings", pubkey);
+        let miner = chain_storage.get(&key).map(|v| {
+            chain::AccountId::decode(&mut &v[..])
+                .expect("Decode value of WorkerBindings Failed. (This should not happen)")
+        })?;
+        let key = storage_map_prefix_twox_64_concat(b"PhalaMining", b"Miners", &miner);
+        chain_storage.get(&key).map(|v| {
+            chain::pallet_mining::MinerInfo::decode(&mut &v[..])
+                .expect("Decode value of Miners Failed. (This should not happen)")
+                .state
+        })
+    }
+
+    /// Whether the worker is a member of, or joining, any contract cluster
+    pub fn is_cluster_worker(pubkey: &WorkerPublicKey, chain_storage: &Storage) -> bool {
+        let members = chain_storage
+            .pairs(storage_prefix("PhalaFatContracts", "ClusterWorkers"))
+            .into_iter()
+            .any(|(_, v)| {
+                Vec::<WorkerPublicKey>::decode(&mut &v[..])
+                    .expect("Decode value of ClusterWorkers Failed. (This should not happen)")
+                    .contains(pubkey)
+            });
+        members
+            || chain_storage
+                .pairs(storage_prefix("PhalaFatContracts", "ClusterJoining"))
+                .into_iter()
+                .any(|(_, v)| {
+                    WorkerPublicKey::decode(&mut &v[..])
+                        .expect("Decode value of ClusterJoining Failed. (This should not happen)")
+                        == *pubkey
+                })
+    }
+
+    /// The sequence of the next message the chain accepts from the sender
+    pub fn offchain_ingress_sequence(sender: &MessageOrigin, chain_storage: &Storage) -> u64 {
+        let key = storage_map_prefix_twox_64_concat(b"PhalaMq", b"OffchainIngress", sender);
+        chain_storage
+            .get(&key)
+            .map(|v| {
+                u64::decode(&mut &v[..])
+                    .expect("Decode value of OffchainIngress Failed. (This should not happen)")
+            })
+            .unwrap_or(0)
+    }
+
     pub fn read_contract_code(chain_storage: &Storage, code_hash: chain::Hash) -> Option<Vec<u8>> {
         let key =
             storage_map_prefix_twox_64_concat(b"PhalaFatContracts", b"ContractCode", &code_hash);
@@ -1254,7 +2229,7 @@ mod tests {
         let mut keeper = ClusterKeeper::default();
         let wasm_bin = pink::load_test_wasm("hooks_test");
         let cluster_id = phala_mq::ContractClusterId(Default::default());
-        let effects = keeper
+        let (effects, _) = keeper
             .instantiate_contract(
                 cluster_id,
                 ALICE,
@@ -1262,6 +2237,7 @@ mod tests {
                 vec![0xed, 0x4b, 0x9d, 0x1b],
                 Default::default(),
                 &contract_key,
+                INSTANTIATE_GAS_LIMIT,
                 1,
                 1,
             )
@@ -1283,6 +2259,7 @@ mod tests {
             cluster,
             &mut block_info,
             &egress,
+            None,
         );
 
         insta::assert_display_snapshot!(contracts.len());
@@ -1305,4 +2282,515 @@ mod tests {
             .collect();
         insta::assert_debug_snapshot!(messages);
     }
+
+    #[test]
+    fn test_contract_message_routing() {
+        use pink::runtime::{ContractMessage, PinkEvent};
+
+        let contract_key = sp_core::Pair::from_seed(&Default::default());
+        let mut contracts = ContractsKeeper::default();
+        let mut keeper = ClusterKeeper::default();
+        let cluster_id = phala_mq::ContractClusterId(Default::default());
+        let (effects, _) = keeper
+            .instantiate_contract(
+                cluster_id,
+                ALICE,
+                pink::load_test_wasm("hooks_test"),
+                vec![0xed, 0x4b, 0x9d, 0x1b],
+                Default::default(),
+                &contract_key,
+                INSTANTIATE_GAS_LIMIT,
+                1,
+                1,
+            )
+            .unwrap();
+        let sender = effects.instantiated[0].1.clone();
+
+        let mut builder = BlockInfo::builder().block_number(1).now_ms(1);
+        let signer = sr25519::Pair::from_seed(&Default::default());
+        let egress = builder
+            .send_mq
+            .channel(MessageOrigin::Gatekeeper, signer.into());
+        let mut block_info = builder.build();
+        let cluster = keeper.get_cluster_mut(&cluster_id).unwrap();
+        apply_pink_side_effects(
+            effects,
+            cluster_id,
+            &mut contracts,
+            cluster,
+            &mut block_info,
+            &egress,
+            None,
+        );
+
+        let target = [0xcc; 32];
+        let target_pubkey = contract_key.derive_ecdh_key().unwrap().public();
+        let effects = ExecSideEffects {
+            pink_events: vec![(
+                sender.clone(),
+                PinkEvent::ContractMessage(ContractMessage {
+                    target,
+                    target_pubkey,
+                    correlation_id: 1,
+                    is_reply: false,
+                    payload: b"ping".to_vec(),
+                }),
+            )],
+            ..Default::default()
+        };
+        let cluster = keeper.get_cluster_mut(&cluster_id).unwrap();
+        apply_pink_side_effects(
+            effects,
+            cluster_id,
+            &mut contracts,
+            cluster,
+            &mut block_info,
+            &egress,
+            None,
+        );
+
+        let messages = builder.send_mq.all_messages();
+        let message = messages
+            .iter()
+            .map(|msg| &msg.message)
+            .find(|msg| msg.destination.path() == &contract::command_topic(target.into()))
+            .expect("The contract message should be sent to the command topic of the target");
+        assert_eq!(
+            message.sender,
+            MessageOrigin::Contract(contracts::contract_address_to_id(&sender))
+        );
+    }
+
+    #[test]
+    fn test_contract_message_rejects_non_contract_origins() {
+        let contract_key = sp_core::Pair::from_seed(&Default::default());
+        let mut keeper = ClusterKeeper::default();
+        let cluster_id = phala_mq::ContractClusterId(Default::default());
+        let (effects, _) = keeper
+            .instantiate_contract(
+                cluster_id,
+                ALICE,
+                pink::load_test_wasm("hooks_test"),
+                vec![0xed, 0x4b, 0x9d, 0x1b],
+                Default::default(),
+                &contract_key,
+                INSTANTIATE_GAS_LIMIT,
+                1,
+                1,
+            )
+            .unwrap();
+        let address = effects.instantiated[0].1.clone();
+        let mut pink = Pink::from_address(address.clone(), cluster_id);
+        let self_id = contracts::contract_address_to_id(&address);
+
+        let mut builder = BlockInfo::builder().block_number(1).now_ms(1);
+        let signer = sr25519::Pair::from_seed(&Default::default());
+        let mq = builder
+            .send_mq
+            .channel(MessageOrigin::Contract(self_id), signer.into());
+        let ecdh_key = contract_key.derive_ecdh_key().unwrap();
+        let mut block_info = builder.build();
+        let mut context = contracts::NativeContext {
+            block: &mut block_info,
+            mq: &mq,
+            secret_mq: SecretMessageChannel::new(&ecdh_key, &mq),
+            contract_clusters: &mut keeper,
+            self_id,
+        };
+        let user = MessageOrigin::AccountId(phala_types::messaging::AccountId(ALICE.into()));
+
+        // Contract messages can't be sent by accounts
+        let command = crate::pink::Command::ContractMessage {
+            correlation_id: 1,
+            is_reply: false,
+            from_pubkey: ecdh_key.public(),
+            payload: b"ping".to_vec(),
+        };
+        let result = pink.handle_command(user.clone(), command, &mut context);
+        assert!(matches!(result, Err(TransactionError::BadOrigin)));
+
+        // nor forged by calling the handler with an ink! message.
+        let command = crate::pink::Command::InkMessage {
+            nonce: vec![],
+            message: pink::runtime::CONTRACT_MESSAGE_SELECTOR.to_vec(),
+            gas_limit: crate::pink::COMMAND_GAS_LIMIT,
+        };
+        let result = pink.handle_command(user, command, &mut context);
+        assert!(matches!(result, Err(TransactionError::BadOrigin)));
+    }
+
+    #[derive(Clone)]
+    struct TestPlatform;
+
+    impl pal::Sealing for TestPlatform {
+        type SealError = std::io::Error;
+        type UnsealError = std::io::Error;
+
+        fn seal_data(
+            &self,
+            path: impl AsRef<std::path::Path>,
+            data: &[u8],
+        ) -> Result<(), Self::SealError> {
+            std::fs::write(path, data)
+        }
+
+        fn unseal_data(
+            &self,
+            path: impl AsRef<std::path::Path>,
+        ) -> Result<Option<Vec<u8>>, Self::UnsealError> {
+            match std::fs::read(path) {
+                Ok(data) => Ok(Some(data)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    impl pal::RA for TestPlatform {
+        type Error = anyhow::Error;
+
+        fn create_attestation_report(
+            &self,
+            _data: &[u8],
+        ) -> Result<(String, String, String), Self::Error> {
+            Err(anyhow!("No RA in tests"))
+        }
+
+        fn quote_test(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl pal::Machine for TestPlatform {
+        fn machine_id(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn cpu_core_num(&self) -> u32 {
+            1
+        }
+
+        fn cpu_feature_level(&self) -> u32 {
+            1
+        }
+    }
+
+    impl pal::MemoryStats for TestPlatform {
+        fn memory_usage(&self) -> pal::MemoryUsage {
+            pal::MemoryUsage {
+                total_peak_used: 0,
+                rust_used: 0,
+                rust_peak_used: 0,
+            }
+        }
+    }
+
+    impl pal::ProtectedFileSystem for TestPlatform {
+        type IoError = std::io::Error;
+        type ReadFile = std::fs::File;
+        type WriteFile = std::fs::File;
+
+        fn open_protected_file(
+            &self,
+            path: impl AsRef<std::path::Path>,
+            _key: &[u8],
+        ) -> Result<Option<Self::ReadFile>, Self::IoError> {
+            match std::fs::File::open(path) {
+                Ok(file) => Ok(Some(file)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+
+        fn create_protected_file(
+            &self,
+            path: impl AsRef<std::path::Path>,
+            _key: &[u8],
+        ) -> Result<Self::WriteFile, Self::IoError> {
+            std::fs::File::create(path)
+        }
+    }
+
+    /// A sealing directory for a test, removed when dropped.
+    struct SealingDir(std::path::PathBuf);
+
+    impl SealingDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("phactory-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            SealingDir(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_str().unwrap().into()
+        }
+    }
+
+    impl Drop for SealingDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_system(
+        sealing_path: String,
+        identity_key: sr25519::Pair,
+        builder: &mut crate::types::BlockInfoBuilder,
+    ) -> System<TestPlatform> {
+        let ecdh_key = identity_key.derive_ecdh_key().unwrap();
+        System::new(
+            TestPlatform,
+            sealing_path,
+            false,
+            String::new(),
+            0,
+            identity_key,
+            ecdh_key,
+            &builder.send_mq,
+            &mut builder.recv_mq,
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_contract_expiration() {
+        let sealing_dir = SealingDir::new("contract-expiration");
+        let mut builder = BlockInfo::builder().block_number(1).now_ms(1);
+        let mut system = test_system(
+            sealing_dir.path(),
+            sr25519::Pair::from_seed(&[1; 32]),
+            &mut builder,
+        );
+        let mut block = builder.build();
+
+        let cluster_id = ContractClusterId(Default::default());
+        let contract_info = contract::ContractInfo {
+            deployer: ALICE,
+            code_index: CodeIndex::NativeCode(contract::DATA_PLAZA),
+            salt: vec![],
+            cluster_id,
+            instantiate_data: vec![],
+        };
+        let contract_id = contracts::get_contract_id(&contract_info);
+
+        // A contract failing to install doesn't expire
+        let missing_code = contract::ContractInfo {
+            code_index: CodeIndex::WasmCode(Default::default()),
+            ..contract_info.clone()
+        };
+        assert!(system
+            .process_contract_key_distribution(
+                &mut block,
+                MessageOrigin::Gatekeeper,
+                DispatchContractKeyEvent {
+                    secret_key: sr25519::Pair::from_seed(&[3; 32]).dump_secret_key(),
+                    contract_info: missing_code,
+                    expiration: 10,
+                    gas_limit: 0,
+                },
+            )
+            .is_err());
+        assert!(system.contract_key_expirations.is_empty());
+
+        system
+            .process_contract_key_distribution(
+                &mut block,
+                MessageOrigin::Gatekeeper,
+                DispatchContractKeyEvent {
+                    secret_key: sr25519::Pair::from_seed(&[2; 32]).dump_secret_key(),
+                    contract_info,
+                    expiration: 10,
+                    gas_limit: 0,
+                },
+            )
+            .unwrap();
+        assert!(system.contracts.get(&contract_id).is_some());
+
+        let renew = |expiration| ContractOperation::RenewContract {
+            contract_id,
+            cluster_id,
+            expiration,
+        };
+        let pallet = MessageOrigin::Pallet(b"PhalaFatContracts".to_vec());
+        assert!(system
+            .process_contract_operation_event(&mut block, MessageOrigin::Gatekeeper, renew(0))
+            .is_err());
+        system
+            .process_contract_operation_event(&mut block, pallet.clone(), renew(20))
+            .unwrap();
+
+        // The key is valid till the end of the renewed expiration
+        system.expire_contracts(20);
+        assert!(system.contracts.get(&contract_id).is_some());
+        system.expire_contracts(21);
+        assert!(system.contracts.get(&contract_id).is_none());
+        assert!(system.contract_key_expirations.is_empty());
+
+        // Renewing an uninstalled contract is ignored
+        system
+            .process_contract_operation_event(&mut block, pallet, renew(30))
+            .unwrap();
+        assert!(system.contract_key_expirations.is_empty());
+    }
+
+    #[test]
+    fn test_unregistered_gatekeeper_wipes_master_key() {
+        let sealing_dir = SealingDir::new("remove-gatekeeper");
+        let sealing_path = sealing_dir.path();
+        let identity_key = sr25519::Pair::from_seed(&[1; 32]);
+        let master_key = sr25519::Pair::from_seed(&[2; 32]);
+        master_key::seal(
+            sealing_path.clone(),
+            &[master_key],
+            &identity_key,
+            &TestPlatform,
+        );
+        for filename in [crate::CHECKPOINT_FILE, crate::BACKUP_CHECKPOINT_FILE].iter() {
+            std::fs::write(sealing_dir.0.join(filename), b"checkpoint").unwrap();
+        }
+        let sealed_files = || {
+            [
+                master_key::MASTER_KEY_FILE,
+                crate::CHECKPOINT_FILE,
+                crate::BACKUP_CHECKPOINT_FILE,
+            ]
+            .iter()
+            .filter(|filename| sealing_dir.0.join(filename).exists())
+            .count()
+        };
+
+        let mut builder = BlockInfo::builder().block_number(1).now_ms(1);
+        let mut system = test_system(sealing_path, identity_key.clone(), &mut builder);
+        assert!(system.master_key.is_some());
+        let pallet = MessageOrigin::Pallet(b"PhalaRegistry".to_vec());
+        {
+            let mut block = builder.build();
+            // Another gatekeeper removed
+            system.process_remove_gatekeeper_event(
+                &mut block,
+                pallet.clone(),
+                RemoveGatekeeperEvent {
+                    pubkey: WorkerPublicKey::from_raw([3; 32]),
+                },
+            );
+            assert!(system.master_key.is_some());
+            assert_eq!(sealed_files(), 3);
+
+            system.process_remove_gatekeeper_event(
+                &mut block,
+                pallet,
+                RemoveGatekeeperEvent {
+                    pubkey: identity_key.public(),
+                },
+            );
+            assert!(system.master_key.is_none());
+            assert!(system.master_key_history.is_empty());
+            assert_eq!(sealed_files(), 0);
+        }
+
+        let messages = builder.send_mq.all_messages();
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0].message;
+        assert_eq!(message.sender, MessageOrigin::Worker(identity_key.public()));
+        assert!(matches!(
+            message.decode_payload::<RegistryEvent>(),
+            Some(RegistryEvent::GatekeeperDemoted)
+        ));
+    }
+
+    fn rotated_master_key_event(
+        identity_key: &sr25519::Pair,
+        rotation_id: u64,
+        master_keys: &[sr25519::Pair],
+    ) -> DispatchRotatedMasterKeyEvent {
+        let sender_key = sr25519::Pair::from_seed(&[9; 32])
+            .derive_ecdh_key()
+            .unwrap();
+        let dest_key = identity_key.derive_ecdh_key().unwrap();
+        let secret = ecdh::agree(&sender_key, &dest_key.public()).unwrap();
+        let iv = [0; 12];
+        let mut encrypted_master_key: Vec<u8> = master_keys
+            .iter()
+            .flat_map(|master_key| master_key.dump_secret_key().to_vec())
+            .collect();
+        aead::encrypt(&iv, &secret, &mut encrypted_master_key).unwrap();
+        DispatchRotatedMasterKeyEvent {
+            rotation_id,
+            dispatch: DispatchMasterKeyEvent {
+                dest: identity_key.public(),
+                ecdh_pubkey: EcdhPublicKey(sender_key.public()),
+                encrypted_master_key,
+                iv,
+            },
+        }
+    }
+
+    #[test]
+    fn test_rotated_master_key_waits_for_chain() {
+        let sealing_dir = SealingDir::new("master-key-rotation");
+        let sealing_path = sealing_dir.path();
+        let identity_key = sr25519::Pair::from_seed(&[1; 32]);
+        let master_key = sr25519::Pair::from_seed(&[2; 32]);
+        master_key::seal(
+            sealing_path.clone(),
+            &[master_key.clone()],
+            &identity_key,
+            &TestPlatform,
+        );
+        let sealed_pubkeys = || -> Vec<_> {
+            master_key::try_unseal(sealing_path.clone(), &identity_key, &TestPlatform)
+                .iter()
+                .map(|master_key| master_key.public())
+                .collect()
+        };
+
+        let mut builder = BlockInfo::builder().block_number(1).now_ms(1);
+        let mut system = test_system(sealing_path.clone(), identity_key.clone(), &mut builder);
+        let mut block = builder.build();
+        let pallet = MessageOrigin::Pallet(b"PhalaRegistry".to_vec());
+        let current_pubkey =
+            |system: &System<TestPlatform>| system.master_key.as_ref().map(|key| key.public());
+
+        // The key of an aborted rotation is never used
+        let aborted_key = sr25519::Pair::from_seed(&[3; 32]);
+        system
+            .process_master_key_rotation(
+                MessageOrigin::Gatekeeper,
+                rotated_master_key_event(&identity_key, 1, &[master_key.clone(), aborted_key]),
+            )
+            .unwrap();
+        assert!(system.pending_master_key.is_some());
+        assert_eq!(current_pubkey(&system), Some(master_key.public()));
+        assert_eq!(sealed_pubkeys(), vec![master_key.public()]);
+        system.process_master_key_rotation_aborted_event(
+            pallet.clone(),
+            MasterKeyRotationAbortedEvent { rotation_id: 1 },
+        );
+        assert!(system.pending_master_key.is_none());
+
+        // The next rotation takes effect once the new pubkey is on chain
+        let new_key = sr25519::Pair::from_seed(&[4; 32]);
+        system
+            .process_master_key_rotation(
+                MessageOrigin::Gatekeeper,
+                rotated_master_key_event(&identity_key, 2, &[master_key.clone(), new_key.clone()]),
+            )
+            .unwrap();
+        assert_eq!(current_pubkey(&system), Some(master_key.public()));
+        system.process_master_pubkey_rotated_event(
+            &mut block,
+            pallet,
+            MasterPubkeyRotatedEvent {
+                rotation_id: 2,
+                master_pubkey: new_key.public(),
+            },
+        );
+        assert!(system.pending_master_key.is_none());
+        assert_eq!(current_pubkey(&system), Some(new_key.public()));
+        assert_eq!(
+            sealed_pubkeys(),
+            vec![master_key.public(), new_key.public()]
+        );
+    }
 }
@@ -19,7 +19,9 @@ mod simple_mpsc;
 pub mod checkpoint_helper;
 
 #[cfg(feature = "dispatcher")]
-pub use dispatcher::{MessageDispatcher, TypedReceiveError, TypedReceiver};
+pub use dispatcher::{
+    MessageDispatcher, Receiver as MessageReceiver, TypedReceiveError, TypedReceiver,
+};
 #[cfg(feature = "queue")]
 pub use send_queue::{MessageChannel, MessageSendQueue};
 #[cfg(any(feature = "queue", feature = "dispatcher"))]
@@ -87,6 +87,13 @@ impl MessageSendQueue {
         entry.dummy = dummy;
     }
 
+    /// Set the sequence of the next message from the sender, e.g. to resume after a warp sync.
+    pub fn set_sequence(&self, sender: SenderId, sequence: u64) {
+        let mut inner = self.inner.lock();
+        let entry = inner.entry(sender).or_default();
+        entry.sequence = sequence;
+    }
+
     pub fn all_messages(&self) -> Vec<SignedMessage> {
         let inner = self.inner.lock();
         inner
@@ -151,6 +158,11 @@ mod msg_channel {
                 signer,
             }
         }
+
+        /// Set the sequence of the next message pushed into the channel.
+        pub fn set_sequence(&self, sequence: u64) {
+            self.queue.set_sequence(self.sender.clone(), sequence);
+        }
     }
 
     impl<Si: MessageSigner + Clone> MessageChannel<Si> {
@@ -10,4 +10,7 @@ pub mod key_bytes;
 pub mod option_key_bytes;
 
 #[cfg(feature = "crypto")]
-pub mod pubkey_bytes;
\ No newline at end of file
+pub mod pubkey_bytes;
+
+#[cfg(feature = "crypto")]
+pub mod vec_key_bytes;
@@ -32,6 +32,25 @@ pub enum DeployTarget {
     NewGroup(Vec<WorkerPublicKey>),
 }
 
+/// An ink! event emitted by a pink contract.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
+pub struct InkEvent {
+    pub block_number: u32,
+    pub contract: ContractId,
+    pub topics: Vec<sp_core::H256>,
+    pub payload: Vec<u8>,
+}
+
+/// The outcome of a pink command, looked up by the nonce the sender put in the command.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Debug, TypeInfo)]
+pub struct CommandReceipt {
+    pub block_number: u32,
+    pub contract: ContractId,
+    pub nonce: Vec<u8>,
+    pub gas_consumed: u64,
+    pub succeeded: bool,
+}
+
 impl<CodeHash: AsRef<[u8]>> CodeIndex<CodeHash> {
     pub fn code_hash(&self) -> Vec<u8> {
         match self {
@@ -45,17 +64,29 @@ pub mod messaging {
     use alloc::vec::Vec;
     use codec::{Decode, Encode};
 
-    use super::{ContractClusterId, ContractInfo};
-    use crate::WorkerIdentity;
+    use super::{ContractClusterId, ContractId, ContractInfo};
+    use crate::{WorkerIdentity, WorkerPublicKey};
     use phala_mq::bind_topic;
 
     bind_topic!(ContractEvent<CodeHash, AccountId>, b"phala/contract/event");
     #[derive(Encode, Decode, Debug)]
     pub enum ContractEvent<CodeHash, AccountId> {
-        // TODO.shelven: enable add and remove workers
         InstantiateCode {
             contract_info: ContractInfo<CodeHash, AccountId>,
             deploy_workers: Vec<WorkerIdentity>,
+            /// The last block the contract key is valid in, or 0 if it never expires.
+            expiration: u32,
+            /// The gas limit of the constructor.
+            gas_limit: u64,
+        },
+        /// A worker joined an existing cluster and needs the keys of the cluster.
+        ClusterWorkerAdded {
+            cluster_id: ContractClusterId,
+            worker: WorkerIdentity,
+            /// The contract whose key the cluster was created with, which may be destroyed.
+            key_contract: ContractId,
+            /// The contracts of the cluster with their expirations, in deployment order.
+            contracts: Vec<(ContractInfo<CodeHash, AccountId>, u32)>,
         },
     }
 
@@ -63,10 +94,28 @@ pub mod messaging {
         pub fn instantiate_code(
             contract_info: ContractInfo<CodeHash, AccountId>,
             deploy_workers: Vec<WorkerIdentity>,
+            expiration: u32,
+            gas_limit: u64,
         ) -> Self {
             ContractEvent::InstantiateCode {
                 contract_info,
                 deploy_workers,
+                expiration,
+                gas_limit,
+            }
+        }
+
+        pub fn cluster_worker_added(
+            cluster_id: ContractClusterId,
+            worker: WorkerIdentity,
+            key_contract: ContractId,
+            contracts: Vec<(ContractInfo<CodeHash, AccountId>, u32)>,
+        ) -> Self {
+            ContractEvent::ClusterWorkerAdded {
+                cluster_id,
+                worker,
+                key_contract,
+                contracts,
             }
         }
     }
@@ -79,6 +128,61 @@ pub mod messaging {
             code: Vec<u8>,
             cluster_id: ContractClusterId,
         },
+        /// Replace the code of a pink contract, keeping its storage and key.
+        UpgradeContract {
+            origin: AccountId,
+            contract_id: ContractId,
+            cluster_id: ContractClusterId,
+            /// The hash of the new code, which must have been uploaded to the cluster.
+            code_hash: sp_core::H256,
+            /// The input of a message called on the new code right after the replacement.
+            migration: Option<Vec<u8>>,
+        },
+        /// Remove a contract and its storage from the cluster.
+        Destroy {
+            contract_id: ContractId,
+            cluster_id: ContractClusterId,
+        },
+        /// Move the expiration of a contract key, with 0 meaning it never expires.
+        RenewContract {
+            contract_id: ContractId,
+            cluster_id: ContractClusterId,
+            expiration: u32,
+        },
+        /// Ask `source` to send the state of the cluster to `dest`, which is joining it.
+        ///
+        /// The state is taken after the block of this operation is processed.
+        TransferClusterState {
+            cluster_id: ContractClusterId,
+            source: WorkerPublicKey,
+            dest: WorkerIdentity,
+            /// The contracts of the cluster, whose commands `dest` holds until the state arrives.
+            contracts: Vec<ContractId>,
+        },
+        /// Remove a worker from the cluster, which forgets the keys and state of the cluster.
+        RemoveClusterWorker {
+            cluster_id: ContractClusterId,
+            worker: WorkerPublicKey,
+        },
+    }
+
+    bind_topic!(ClusterStateTransfer, b"phala/cluster/state");
+    /// A chunk of the state of a cluster sent by a member to a joining worker, encrypted to the
+    /// latter.
+    ///
+    /// The encoded storage dump is split into chunks sent in order, to keep each message small.
+    #[derive(Encode, Decode, Debug)]
+    pub struct ClusterStateTransfer {
+        pub cluster_id: ContractClusterId,
+        /// The block after which the state is taken.
+        pub block_number: u32,
+        pub contracts: Vec<ContractId>,
+        /// The index of this chunk.
+        pub chunk_index: u32,
+        /// The number of chunks the storage dump is split into.
+        pub chunk_count: u32,
+        /// A chunk of the encoded dump of the cluster storage.
+        pub storage: Vec<u8>,
     }
 }
 
@@ -109,6 +213,97 @@ pub struct ContractQueryHead {
     pub nonce: [u8; 32],
 }
 
+/// A certificate by which an account authorizes a session key to sign contract queries on its
+/// behalf, so that a dApp can sign many queries with the wallet prompted only once.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, TypeInfo)]
+pub struct SessionCertificate {
+    /// The sr25519 public key of the authorizing account.
+    pub account: [u8; 32],
+    /// The sr25519 public key of the session.
+    pub session_key: [u8; 32],
+    /// The last block number at which the session is valid.
+    pub expiration: u32,
+    /// The contracts the session can query, or any contract if empty.
+    pub contracts: Vec<ContractId>,
+}
+
+/// The domain separation prefix of a signed `SessionCertificate`.
+pub const SESSION_CERTIFICATE_CONTEXT: &[u8] = b"phala/contract/session:";
+/// The domain separation prefix of a query signed by a session key.
+pub const SESSION_QUERY_CONTEXT: &[u8] = b"phala/contract/query:";
+
+impl SessionCertificate {
+    /// The message the account signs to authorize the session.
+    ///
+    /// Wallets signing raw bytes usually wrap them in `<Bytes>...</Bytes>`, which is accepted
+    /// as well.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = SESSION_CERTIFICATE_CONTEXT.to_vec();
+        self.encode_to(&mut payload);
+        payload
+    }
+}
+
+/// The message the session key signs for a query of `data` to contract `id`.
+pub fn session_query_payload(id: &ContractId, nonce: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let mut payload = SESSION_QUERY_CONTEXT.to_vec();
+    payload.extend_from_slice(id.as_ref());
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(data);
+    payload
+}
+
+/// The authorization of a query signed by a session key.
+///
+/// It is carried SCALE encoded in the `encoded_session` field of the prpc `ContractQueryRequest`,
+/// and takes precedence over the prpc level signature as the origin of the query.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, TypeInfo)]
+pub struct QuerySession {
+    pub certificate: SessionCertificate,
+    /// The sr25519 signature of `certificate.signing_payload()` by the account.
+    pub certificate_signature: Vec<u8>,
+    /// The sr25519 signature of `session_query_payload(..)` by the session key.
+    pub signature: Vec<u8>,
+}
+
+#[cfg(feature = "full_crypto")]
+impl QuerySession {
+    /// Verify the session authorizes querying `data` from contract `id` at `current_block`,
+    /// returning the authorizing account.
+    pub fn verify(
+        &self,
+        id: &ContractId,
+        nonce: &[u8; 32],
+        data: &[u8],
+        current_block: u32,
+    ) -> Result<[u8; 32], ContractQueryError> {
+        use sp_core::{sr25519, Pair};
+
+        let cert = &self.certificate;
+        if cert.expiration < current_block {
+            return Err(ContractQueryError::SessionExpired);
+        }
+        if !cert.contracts.is_empty() && !cert.contracts.contains(id) {
+            return Err(ContractQueryError::SessionNotAuthorized);
+        }
+        let payload = cert.signing_payload();
+        let mut wrapped = b"<Bytes>".to_vec();
+        wrapped.extend_from_slice(&payload);
+        wrapped.extend_from_slice(b"</Bytes>");
+        let account_signed = [payload, wrapped]
+            .iter()
+            .any(|msg| sr25519::Pair::verify_weak(&self.certificate_signature, msg, &cert.account));
+        if !account_signed {
+            return Err(ContractQueryError::InvalidSignature);
+        }
+        let payload = session_query_payload(id, nonce, data);
+        if !sr25519::Pair::verify_weak(&self.signature, &payload, &cert.session_key) {
+            return Err(ContractQueryError::InvalidSignature);
+        }
+        Ok(cert.account)
+    }
+}
+
 /// Contract query response, to be encrypted.
 #[derive(Encode, Decode, Debug)]
 pub struct ContractQueryResponse<Data> {
@@ -140,6 +335,10 @@ pub enum ContractQueryError {
     DecodeError,
     /// Other errors reported during the contract query execution.
     OtherError(String),
+    /// The session certificate has expired.
+    SessionExpired,
+    /// The session is not authorized to query the contract.
+    SessionNotAuthorized,
 }
 
 impl From<ContractQueryError> for prpc::server::Error {
@@ -153,3 +352,96 @@ pub fn command_topic(id: ContractId) -> Vec<u8> {
         .as_bytes()
         .to_vec()
 }
+
+#[cfg(all(test, feature = "full_crypto"))]
+mod tests {
+    use super::*;
+    use sp_core::{sr25519, Pair};
+
+    fn signed_session(
+        account: &sr25519::Pair,
+        session: &sr25519::Pair,
+        contracts: Vec<ContractId>,
+        wrap_bytes: bool,
+        query: (&ContractId, &[u8; 32], &[u8]),
+    ) -> QuerySession {
+        let certificate = SessionCertificate {
+            account: account.public().0,
+            session_key: session.public().0,
+            expiration: 100,
+            contracts,
+        };
+        let mut payload = certificate.signing_payload();
+        if wrap_bytes {
+            payload = [&b"<Bytes>"[..], &payload, &b"</Bytes>"[..]].concat();
+        }
+        let certificate_signature = account.sign(&payload).0.to_vec();
+        let (id, nonce, data) = query;
+        let signature = session
+            .sign(&session_query_payload(id, nonce, data))
+            .0
+            .to_vec();
+        QuerySession {
+            certificate,
+            certificate_signature,
+            signature,
+        }
+    }
+
+    #[test]
+    fn test_query_session_verify() {
+        let account = sr25519::Pair::from_seed(&[1; 32]);
+        let session_key = sr25519::Pair::from_seed(&[2; 32]);
+        let id = ContractId::from_low_u64_be(1);
+        let other_id = ContractId::from_low_u64_be(2);
+        let nonce = [3; 32];
+        let data: &[u8] = b"query";
+
+        for wrap_bytes in [false, true] {
+            let session = signed_session(
+                &account,
+                &session_key,
+                vec![],
+                wrap_bytes,
+                (&id, &nonce, data),
+            );
+            assert_eq!(
+                session.verify(&id, &nonce, data, 100).unwrap(),
+                account.public().0
+            );
+            // Expired
+            assert!(matches!(
+                session.verify(&id, &nonce, data, 101),
+                Err(ContractQueryError::SessionExpired)
+            ));
+            // Tampered query
+            assert!(matches!(
+                session.verify(&id, &nonce, b"other", 100),
+                Err(ContractQueryError::InvalidSignature)
+            ));
+        }
+
+        // Scoped to some contracts
+        let session = signed_session(
+            &account,
+            &session_key,
+            vec![id],
+            false,
+            (&other_id, &nonce, data),
+        );
+        assert!(matches!(
+            session.verify(&other_id, &nonce, data, 1),
+            Err(ContractQueryError::SessionNotAuthorized)
+        ));
+        let session = signed_session(&account, &session_key, vec![id], false, (&id, &nonce, data));
+        assert!(session.verify(&id, &nonce, data, 1).is_ok());
+
+        // Certificate not signed by the account
+        let mut session = session;
+        session.certificate.account = session_key.public().0;
+        assert!(matches!(
+            session.verify(&id, &nonce, data, 1),
+            Err(ContractQueryError::InvalidSignature)
+        ));
+    }
+}
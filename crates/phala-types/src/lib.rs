@@ -17,7 +17,7 @@ pub mod messaging {
     use codec::{Decode, Encode};
     use core::fmt::Debug;
     use scale_info::TypeInfo;
-    use sp_core::U256;
+    use sp_core::{H256, U256};
 
     #[cfg(feature = "enable_serde")]
     use serde::{Deserialize, Serialize};
@@ -391,6 +391,9 @@ pub mod messaging {
     pub enum GatekeeperLaunch {
         FirstGatekeeper(NewGatekeeperEvent),
         MasterPubkeyOnChain(MasterPubkeyEvent),
+        RotateMasterKey(RotateMasterKeyEvent),
+        MasterPubkeyRotated(MasterPubkeyRotatedEvent),
+        MasterKeyRotationAborted(MasterKeyRotationAbortedEvent),
     }
 
     impl GatekeeperLaunch {
@@ -407,6 +410,32 @@ pub mod messaging {
         pub fn master_pubkey_on_chain(master_pubkey: MasterPublicKey) -> GatekeeperLaunch {
             GatekeeperLaunch::MasterPubkeyOnChain(MasterPubkeyEvent { master_pubkey })
         }
+
+        pub fn rotate_master_key(
+            rotation_id: u64,
+            gk_identities: Vec<NewGatekeeperEvent>,
+        ) -> GatekeeperLaunch {
+            GatekeeperLaunch::RotateMasterKey(RotateMasterKeyEvent {
+                rotation_id,
+                gk_identities,
+            })
+        }
+
+        pub fn master_pubkey_rotated(
+            rotation_id: u64,
+            master_pubkey: MasterPublicKey,
+        ) -> GatekeeperLaunch {
+            GatekeeperLaunch::MasterPubkeyRotated(MasterPubkeyRotatedEvent {
+                rotation_id,
+                master_pubkey,
+            })
+        }
+
+        pub fn master_key_rotation_aborted(rotation_id: u64) -> GatekeeperLaunch {
+            GatekeeperLaunch::MasterKeyRotationAborted(MasterKeyRotationAbortedEvent {
+                rotation_id,
+            })
+        }
     }
 
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
@@ -422,11 +451,30 @@ pub mod messaging {
         pub master_pubkey: MasterPublicKey,
     }
 
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct RotateMasterKeyEvent {
+        pub rotation_id: u64,
+        /// The gatekeepers to share the new master key with, the first one generates the key
+        pub gk_identities: Vec<NewGatekeeperEvent>,
+    }
+
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct MasterPubkeyRotatedEvent {
+        pub rotation_id: u64,
+        pub master_pubkey: MasterPublicKey,
+    }
+
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct MasterKeyRotationAbortedEvent {
+        pub rotation_id: u64,
+    }
+
     // Messages: Gatekeeper change
     bind_topic!(GatekeeperChange, b"phala/gatekeeper/change");
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
     pub enum GatekeeperChange {
         GatekeeperRegistered(NewGatekeeperEvent),
+        GatekeeperUnregistered(RemoveGatekeeperEvent),
     }
 
     impl GatekeeperChange {
@@ -439,6 +487,16 @@ pub mod messaging {
                 ecdh_pubkey,
             })
         }
+
+        pub fn gatekeeper_unregistered(pubkey: WorkerPublicKey) -> GatekeeperChange {
+            GatekeeperChange::GatekeeperUnregistered(RemoveGatekeeperEvent { pubkey })
+        }
+    }
+
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct RemoveGatekeeperEvent {
+        /// The public key of removed gatekeeper
+        pub pubkey: WorkerPublicKey,
     }
 
     // Messages: Distribution of master key and contract keys
@@ -446,6 +504,7 @@ pub mod messaging {
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
     pub enum KeyDistribution {
         MasterKeyDistribution(DispatchMasterKeyEvent),
+        MasterKeyRotation(DispatchRotatedMasterKeyEvent),
     }
 
     impl KeyDistribution {
@@ -462,12 +521,23 @@ pub mod messaging {
                 iv,
             })
         }
+
+        pub fn master_key_rotation(
+            rotation_id: u64,
+            dispatch: DispatchMasterKeyEvent,
+        ) -> KeyDistribution {
+            KeyDistribution::MasterKeyRotation(DispatchRotatedMasterKeyEvent {
+                rotation_id,
+                dispatch,
+            })
+        }
     }
 
     bind_topic!(ContractKeyDistribution<CodeHash, BlockNumber, AccountId>, b"phala/contract/key");
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
     pub enum ContractKeyDistribution<CodeHash, BlockNumber, AccountId> {
         ContractKeyDistribution(DispatchContractKeyEvent<CodeHash, BlockNumber, AccountId>),
+        ClusterKeyDistribution(DispatchClusterKeyEvent<CodeHash, BlockNumber, AccountId>),
     }
 
     impl<CodeHash, BlockNumber, AccountId> ContractKeyDistribution<CodeHash, BlockNumber, AccountId> {
@@ -475,11 +545,25 @@ pub mod messaging {
             secret_key: Sr25519SecretKey,
             contract_info: ContractInfo<CodeHash, AccountId>,
             expiration: BlockNumber,
+            gas_limit: u64,
         ) -> ContractKeyDistribution<CodeHash, BlockNumber, AccountId> {
             ContractKeyDistribution::ContractKeyDistribution(DispatchContractKeyEvent {
                 secret_key,
                 contract_info,
                 expiration,
+                gas_limit,
+            })
+        }
+
+        pub fn cluster_key_distribution(
+            cluster_id: ContractClusterId,
+            cluster_key: Sr25519SecretKey,
+            contracts: Vec<DispatchContractKeyEvent<CodeHash, BlockNumber, AccountId>>,
+        ) -> ContractKeyDistribution<CodeHash, BlockNumber, AccountId> {
+            ContractKeyDistribution::ClusterKeyDistribution(DispatchClusterKeyEvent {
+                cluster_id,
+                cluster_key,
+                contracts,
             })
         }
     }
@@ -494,16 +578,38 @@ pub mod messaging {
         /// The ecdh public key of master key source
         pub ecdh_pubkey: EcdhPublicKey,
         /// Master key encrypted with aead key
+        ///
+        /// The plaintext is the secret keys of all the master keys ever used, oldest first and
+        /// the current one last.
         pub encrypted_master_key: Vec<u8>,
         /// Aead IV
         pub iv: AeadIV,
     }
 
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq, TypeInfo)]
+    pub struct DispatchRotatedMasterKeyEvent {
+        pub rotation_id: u64,
+        /// The master keys with the newly generated one at last
+        pub dispatch: DispatchMasterKeyEvent,
+    }
+
     #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug)]
     pub struct DispatchContractKeyEvent<CodeHash, BlockNumber, AccountId> {
         pub secret_key: Sr25519SecretKey,
         pub contract_info: ContractInfo<CodeHash, AccountId>,
+        /// The last block the key is valid in, or 0 if it never expires
         pub expiration: BlockNumber,
+        /// The gas limit of the constructor, if the receiver instantiates the contract
+        pub gas_limit: u64,
+    }
+
+    #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug)]
+    pub struct DispatchClusterKeyEvent<CodeHash, BlockNumber, AccountId> {
+        pub cluster_id: ContractClusterId,
+        /// The key the cluster was created with, which is the key of its first contract
+        pub cluster_key: Sr25519SecretKey,
+        /// The keys of the contracts in deployment order
+        pub contracts: Vec<DispatchContractKeyEvent<CodeHash, BlockNumber, AccountId>>,
     }
 
     // Messages: Gatekeeper
@@ -570,12 +676,46 @@ pub mod messaging {
             cluster_id: ContractClusterId,
             deployer: AccountId,
             pubkey: EcdhPublicKey,
+            /// The gas consumed by the constructor, or None for native contracts and the ones
+            /// instantiated by other contracts, which pay for it.
+            gas_consumed: Option<u64>,
         },
         ContractInstantiationFailed {
             id: ContractId,
             cluster_id: ContractClusterId,
             deployer: AccountId,
         },
+        ContractUpgraded {
+            id: ContractId,
+            cluster_id: ContractClusterId,
+            code_hash: H256,
+        },
+        ContractUpgradeFailed {
+            id: ContractId,
+            cluster_id: ContractClusterId,
+            code_hash: H256,
+        },
+        /// The worker got the keys of a cluster it's joining and waits for the state.
+        ClusterStateRequested { cluster_id: ContractClusterId },
+        /// The worker installed the state of a cluster it's joining.
+        ClusterJoined { cluster_id: ContractClusterId },
+        /// The state root of a cluster after the worker processed the given block.
+        ClusterStateRoot {
+            cluster_id: ContractClusterId,
+            block_number: u32,
+            root: H256,
+        },
+        /// The worker failed to install the state of a cluster it's joining from `source`, and
+        /// waits for the state from another member.
+        ClusterStateRejected {
+            cluster_id: ContractClusterId,
+            source: WorkerPublicKey,
+        },
+        /// The state of a cluster is too large to be sent to the joining worker.
+        ClusterStateTooLarge {
+            cluster_id: ContractClusterId,
+            size: u64,
+        },
     }
 }
 
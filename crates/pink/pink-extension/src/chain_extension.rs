@@ -0,0 +1,148 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use ink_lang as ink;
+use scale::{Decode, Encode};
+
+/// Function ids of the pink chain extension.
+///
+/// Keep syncing with the dispatcher in `pink::runtime::extension`.
+pub mod func_ids {
+    pub const HTTP_REQUEST: u32 = 0xff000001;
+    pub const SIGN: u32 = 0xff000002;
+    pub const VERIFY: u32 = 0xff000003;
+    pub const DERIVE_SR25519_KEY: u32 = 0xff000004;
+    pub const GET_PUBLIC_KEY: u32 = 0xff000005;
+    pub const GETRANDOM: u32 = 0xff000006;
+}
+
+/// The max number of bytes `getrandom` returns in a call.
+pub const MAX_RANDOM_LENGTH: u32 = 64 * 1024;
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct HttpRequest {
+    pub url: String,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(url: impl Into<String>, method: impl Into<String>) -> Self {
+        HttpRequest {
+            url: url.into(),
+            method: method.into(),
+            headers: Default::default(),
+            body: Default::default(),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub reason_phrase: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// A response representing a request that the runtime failed to send.
+    pub fn failed(reason: impl Into<String>) -> Self {
+        HttpResponse {
+            status_code: 0,
+            reason_phrase: reason.into(),
+            headers: Default::default(),
+            body: Default::default(),
+        }
+    }
+}
+
+/// The signature schemes supported by the signing functions.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum SigType {
+    /// Keys are 32 bytes seeds.
+    Ed25519,
+    /// Keys are 64 bytes secret keys.
+    Sr25519,
+    /// Keys are 32 bytes seeds, signatures are 65 bytes recoverable signatures.
+    Ecdsa,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct SignArgs {
+    pub sigtype: SigType,
+    pub key: Vec<u8>,
+    pub message: Vec<u8>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct VerifyArgs {
+    pub sigtype: SigType,
+    pub pubkey: Vec<u8>,
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct PublicKeyForArgs {
+    pub sigtype: SigType,
+    pub key: Vec<u8>,
+}
+
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum ErrorCode {}
+
+impl ink_env::chain_extension::FromStatusCode for ErrorCode {
+    fn from_status_code(status_code: u32) -> Result<(), Self> {
+        match status_code {
+            0 => Ok(()),
+            _ => panic!("encountered unknown status code"),
+        }
+    }
+}
+
+/// The chain extension exposed by the pink runtime.
+#[ink::chain_extension]
+pub trait PinkExt {
+    type ErrorCode = ErrorCode;
+
+    /// Send a HTTP request to the outside world and wait for the response.
+    ///
+    /// Only available in query context.
+    #[ink(extension = 0xff000001, handle_status = false, returns_result = false)]
+    fn http_request(request: HttpRequest) -> HttpResponse;
+
+    /// Sign a message with the given private key.
+    ///
+    /// Sr25519 signatures are randomized, so they can only be made in query context.
+    #[ink(extension = 0xff000002, handle_status = false, returns_result = false)]
+    fn sign(args: SignArgs) -> Vec<u8>;
+
+    /// Verify a signature against the given public key.
+    #[ink(extension = 0xff000003, handle_status = false, returns_result = false)]
+    fn verify(args: VerifyArgs) -> bool;
+
+    /// Derive a sr25519 private key from the key of the calling contract.
+    ///
+    /// The same salt always derives the same key for the same contract.
+    #[ink(extension = 0xff000004, handle_status = false, returns_result = false)]
+    fn derive_sr25519_key(salt: Vec<u8>) -> Vec<u8>;
+
+    /// Get the public key of the given private key.
+    #[ink(extension = 0xff000005, handle_status = false, returns_result = false)]
+    fn get_public_key(args: PublicKeyForArgs) -> Vec<u8>;
+
+    /// Get `length` random bytes, at most `MAX_RANDOM_LENGTH`.
+    ///
+    /// Returns enclave generated randomness in query context. In transaction context the bytes are
+    /// derived from the contract key and the block number, so every worker gets the same result.
+    #[ink(extension = 0xff000006, handle_status = false, returns_result = false)]
+    fn getrandom(length: u32) -> Vec<u8>;
+}
@@ -4,14 +4,18 @@ extern crate alloc;
 
 use alloc::vec::Vec;
 use ink_env::{emit_event, topics::state::HasRemainingTopics, Environment, Topics};
+use ink_lang::ChainExtensionInstance;
 
 use scale::{Decode, Encode};
 
 #[cfg(feature = "runtime_utils")]
 use ::{ink_env::test::EmittedEvent, std::convert::TryInto};
 
+pub use chain_extension::{HttpRequest, HttpResponse, SigType};
 pub use pink_extension_macro::contract;
 
+pub mod chain_extension;
+
 const PINK_EVENT_TOPIC: &[u8] = b"phala.pink.event";
 
 pub type EcdhPublicKey = [u8; 32];
@@ -29,6 +33,43 @@ pub struct OspMessage {
     pub remote_pubkey: Option<EcdhPublicKey>,
 }
 
+/// The selector of the ink message receiving the messages sent by other contracts.
+///
+/// A contract accepts contract messages by declaring a message like:
+///
+/// ```ignore
+/// #[ink(message, selector = 0x706d7367)]
+/// pub fn on_contract_message(&mut self, message: pink_extension::IncomingContractMessage) {}
+/// ```
+pub const CONTRACT_MESSAGE_SELECTOR: [u8; 4] = *b"pmsg";
+
+/// A message to a contract, possibly living in another cluster or on another worker
+#[derive(Encode, Decode, Debug)]
+pub struct ContractMessage {
+    /// The id of the target contract
+    pub target: Hash,
+    /// The ecdh public key of the target contract, the message is encrypted with it
+    pub target_pubkey: EcdhPublicKey,
+    /// Chosen by the sender, the reply carries the same id
+    pub correlation_id: u64,
+    /// Whether this message is a reply to the message with the same correlation id
+    pub is_reply: bool,
+    pub payload: Vec<u8>,
+}
+
+/// A message from another contract, delivered to the `CONTRACT_MESSAGE_SELECTOR` handler
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct IncomingContractMessage {
+    /// The id of the sender contract
+    pub from: Hash,
+    /// The ecdh public key to reply to the sender with
+    pub from_pubkey: EcdhPublicKey,
+    pub correlation_id: u64,
+    pub is_reply: bool,
+    pub payload: Vec<u8>,
+}
+
 #[derive(Encode, Decode, Debug)]
 pub enum PinkEvent {
     /// Contract pushed a raw message
@@ -37,6 +78,8 @@ pub enum PinkEvent {
     OspMessage(OspMessage),
     /// Contract has an on_block_end ink message and will emit this event on instantiation.
     OnBlockEndSelector(u32),
+    /// Contract sent a message to another contract
+    ContractMessage(ContractMessage),
 }
 
 impl Topics for PinkEvent {
@@ -81,12 +124,102 @@ pub fn push_osp_message(payload: Vec<u8>, topic: Vec<u8>, remote_pubkey: Option<
     }))
 }
 
+/// Send a message to another contract, which may live in another cluster
+///
+/// The message is routed through the on-chain MQ, encrypted with `target_pubkey`, and delivered
+/// to the `CONTRACT_MESSAGE_SELECTOR` handler of the target. Only takes effect in transactions.
+pub fn send_contract_message(
+    target: Hash,
+    target_pubkey: EcdhPublicKey,
+    correlation_id: u64,
+    payload: Vec<u8>,
+) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::ContractMessage(ContractMessage {
+        target,
+        target_pubkey,
+        correlation_id,
+        is_reply: false,
+        payload,
+    }))
+}
+
+/// Reply to a message received from another contract
+pub fn reply_contract_message(message: &IncomingContractMessage, payload: Vec<u8>) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::ContractMessage(ContractMessage {
+        target: message.from,
+        target_pubkey: message.from_pubkey,
+        correlation_id: message.correlation_id,
+        is_reply: true,
+        payload,
+    }))
+}
+
 /// Turn on on_block_end feature and set it's selector
 ///
 pub fn set_on_block_end_selector(selector: u32) {
     emit_event::<PinkEnvironment, _>(PinkEvent::OnBlockEndSelector(selector))
 }
 
+/// Send a HTTP request to the outside world and wait for the response.
+///
+/// Only available in query context. Requests issued while handling a transaction would make the
+/// execution nondeterministic, so the runtime rejects them.
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    ext().http_request(request)
+}
+
+/// Sign a message with the given private key.
+///
+/// Sr25519 signatures are randomized, so they can only be made in query context.
+pub fn sign(sigtype: SigType, key: &[u8], message: &[u8]) -> Vec<u8> {
+    ext().sign(chain_extension::SignArgs {
+        sigtype,
+        key: key.to_vec(),
+        message: message.to_vec(),
+    })
+}
+
+/// Verify a signature against the given public key.
+pub fn verify(sigtype: SigType, pubkey: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    ext().verify(chain_extension::VerifyArgs {
+        sigtype,
+        pubkey: pubkey.to_vec(),
+        message: message.to_vec(),
+        signature: signature.to_vec(),
+    })
+}
+
+/// Derive a sr25519 private key from the key of the calling contract.
+///
+/// The derived key never leaves the contract unless the contract exposes it. Use different salts
+/// to get independent child keys.
+pub fn derive_sr25519_key(salt: &[u8]) -> Vec<u8> {
+    ext().derive_sr25519_key(salt.to_vec())
+}
+
+/// Get the public key of the given private key.
+pub fn get_public_key(sigtype: SigType, key: &[u8]) -> Vec<u8> {
+    ext().get_public_key(chain_extension::PublicKeyForArgs {
+        sigtype,
+        key: key.to_vec(),
+    })
+}
+
+/// Get `length` random bytes.
+///
+/// Returns enclave generated randomness in query context. In transaction context the bytes are
+/// derived from the contract key and the block number. They are unpredictable to anyone outside
+/// the workers, but deterministic across workers so that the contract state stays consistent.
+///
+/// The call fails if `length` exceeds `chain_extension::MAX_RANDOM_LENGTH`.
+pub fn getrandom(length: u32) -> Vec<u8> {
+    ext().getrandom(length)
+}
+
+fn ext() -> <chain_extension::PinkExt as ChainExtensionInstance>::Instance {
+    <chain_extension::PinkExt as ChainExtensionInstance>::instantiate()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub enum PinkEnvironment {}
@@ -100,7 +233,7 @@ impl Environment for PinkEnvironment {
     type BlockNumber = <ink_env::DefaultEnvironment as Environment>::BlockNumber;
     type Timestamp = <ink_env::DefaultEnvironment as Environment>::Timestamp;
 
-    type ChainExtension = <ink_env::DefaultEnvironment as Environment>::ChainExtension;
+    type ChainExtension = chain_extension::PinkExt;
 }
 
 #[cfg(feature = "runtime_utils")]
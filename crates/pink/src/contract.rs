@@ -1,18 +1,19 @@
+use frame_support::storage::with_transaction;
 use pallet_contracts_primitives::StorageDeposit;
 use scale::{Decode, Encode};
 use sp_core::hashing;
 use sp_core::Hasher as _;
-use sp_runtime::DispatchError;
+use sp_runtime::{DispatchError, TransactionOutcome};
 
 use crate::{
-    runtime::{Contracts, ExecSideEffects, System, Timestamp},
+    runtime::{CallMode, Contracts, ExecSideEffects, Pink, System, Timestamp},
     storage,
-    types::{AccountId, BlockNumber, Hashing, GAS_LIMIT},
+    types::{AccountId, BlockNumber, Hash, Hashing, Weight},
 };
 
 type ContractExecResult = pallet_contracts_primitives::ContractExecResult<crate::types::Balance>;
 
-pub type Storage = storage::Storage<storage::InMemoryBackend>;
+pub type Storage = storage::Storage<storage::ClusterBackend>;
 
 #[derive(Debug)]
 pub struct ExecError {
@@ -50,7 +51,7 @@ pub struct Contract {
 
 impl Contract {
     pub fn new_storage() -> Storage {
-        Storage::new(Default::default())
+        Storage::in_memory()
     }
 
     pub fn from_address(address: AccountId) -> Self {
@@ -68,6 +69,7 @@ impl Contract {
     /// * `code`: The contract code to deploy in raw bytes.
     /// * `input_data`: The input data to pass to the contract constructor.
     /// * `salt`: Used for the address derivation.
+    /// * `gas_limit`: The constructor is aborted once it consumed more gas than this.
     pub fn new(
         storage: &mut Storage,
         origin: AccountId,
@@ -75,9 +77,36 @@ impl Contract {
         input_data: Vec<u8>,
         cluster_id: Vec<u8>,
         salt: Vec<u8>,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> Result<(Self, ExecSideEffects), ExecError> {
+        let (contract, effects, _gas_consumed) = Self::instantiate(
+            storage,
+            origin,
+            code,
+            input_data,
+            cluster_id,
+            salt,
+            gas_limit,
+            block_number,
+            now,
+        )?;
+        Ok((contract, effects))
+    }
+
+    /// Like `new`, but also returns the gas consumed by the constructor.
+    pub fn instantiate(
+        storage: &mut Storage,
+        origin: AccountId,
+        code: Vec<u8>,
+        input_data: Vec<u8>,
+        cluster_id: Vec<u8>,
+        salt: Vec<u8>,
+        gas_limit: Weight,
+        block_number: BlockNumber,
+        now: u64,
+    ) -> Result<(Self, ExecSideEffects, Weight), ExecError> {
         if origin == Default::default() {
             return Err(ExecError {
                 source: DispatchError::BadOrigin,
@@ -87,20 +116,21 @@ impl Contract {
 
         let code_hash = Hashing::hash(&code);
 
-        let (address, effects) = storage.execute_with(false, move || -> Result<_, ExecError> {
+        let (result, effects) = storage.execute_with(false, move || -> Result<_, ExecError> {
             System::set_block_number(block_number);
             Timestamp::set_timestamp(now);
 
             let result = Contracts::bare_instantiate(
                 origin.clone(),
                 0,
-                GAS_LIMIT,
+                gas_limit,
                 None,
                 pallet_contracts_primitives::Code::Upload(code.into()),
                 input_data,
                 salt.clone(),
                 true,
             );
+            log::debug!(target: "pink", "Instantiate consumed {} gas", result.gas_consumed);
             match result.result {
                 Err(err) => {
                     return Err(ExecError {
@@ -110,14 +140,16 @@ impl Contract {
                 }
                 Ok(_) => (),
             }
-            Ok(contract_address(
+            let address = contract_address(
                 &origin,
                 code_hash.as_ref(),
                 cluster_id.as_ref(),
                 salt.as_ref(),
-            ))
+            );
+            Ok((address, result.gas_consumed))
         });
-        Ok((Self::from_address(address?), effects))
+        let (address, gas_consumed) = result?;
+        Ok((Self::from_address(address), effects, gas_consumed))
     }
 
     pub fn new_with_selector(
@@ -128,6 +160,7 @@ impl Contract {
         args: impl Encode,
         cluster_id: Vec<u8>,
         salt: Vec<u8>,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> Result<(Self, ExecSideEffects), ExecError> {
@@ -141,6 +174,7 @@ impl Contract {
             input_data,
             cluster_id,
             salt,
+            gas_limit,
             block_number,
             now,
         )
@@ -150,14 +184,69 @@ impl Contract {
     ///
     /// # Parameters
     /// * `input_data`: The SCALE encoded arguments including the 4-bytes selector as prefix.
+    /// * `gas_limit`: The call is aborted once it consumed more gas than this.
     /// # Return
-    /// Returns the SCALE encoded method return value.
+    /// Returns the SCALE encoded method return value along with the gas consumed.
     pub fn bare_call(
         &mut self,
         storage: &mut Storage,
         origin: AccountId,
         input_data: Vec<u8>,
         rollback: bool,
+        gas_limit: Weight,
+        block_number: BlockNumber,
+        now: u64,
+    ) -> (ContractExecResult, ExecSideEffects) {
+        let mode = if rollback {
+            CallMode::Query
+        } else {
+            CallMode::Command
+        };
+        self.checked_call(
+            storage,
+            origin,
+            input_data,
+            mode,
+            rollback,
+            gas_limit,
+            block_number,
+            now,
+        )
+    }
+
+    /// Simulate a transaction calling a contract method without keeping any of its changes.
+    ///
+    /// Unlike a query, the contract is executed in transaction context, so the result, gas
+    /// consumed and side effects are what the same input would produce if sent on-chain.
+    pub fn dry_run(
+        &mut self,
+        storage: &mut Storage,
+        origin: AccountId,
+        input_data: Vec<u8>,
+        gas_limit: Weight,
+        block_number: BlockNumber,
+        now: u64,
+    ) -> (ContractExecResult, ExecSideEffects) {
+        self.checked_call(
+            storage,
+            origin,
+            input_data,
+            CallMode::Command,
+            true,
+            gas_limit,
+            block_number,
+            now,
+        )
+    }
+
+    fn checked_call(
+        &mut self,
+        storage: &mut Storage,
+        origin: AccountId,
+        input_data: Vec<u8>,
+        mode: CallMode,
+        rollback: bool,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> (ContractExecResult, ExecSideEffects) {
@@ -173,7 +262,16 @@ impl Contract {
                 ExecSideEffects::default(),
             );
         }
-        self.unchecked_bare_call(storage, origin, input_data, rollback, block_number, now)
+        self.unchecked_bare_call(
+            storage,
+            origin,
+            input_data,
+            mode,
+            rollback,
+            gas_limit,
+            block_number,
+            now,
+        )
     }
 
     fn unchecked_bare_call(
@@ -181,15 +279,17 @@ impl Contract {
         storage: &mut Storage,
         origin: AccountId,
         input_data: Vec<u8>,
+        mode: CallMode,
         rollback: bool,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> (ContractExecResult, ExecSideEffects) {
         let addr = self.address.clone();
-        storage.execute_with(rollback, move || {
+        storage.execute_in_mode(mode, rollback, move || {
             System::set_block_number(block_number);
             Timestamp::set_timestamp(now);
-            Contracts::bare_call(origin, addr, 0, GAS_LIMIT, None, input_data, true)
+            Contracts::bare_call(origin, addr, 0, gas_limit, None, input_data, true)
         })
     }
 
@@ -201,14 +301,22 @@ impl Contract {
         selector: [u8; 4],
         args: impl Encode,
         rollback: bool,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> Result<(RV, ExecSideEffects), ExecError> {
         let mut input_data = vec![];
         selector.encode_to(&mut input_data);
         args.encode_to(&mut input_data);
-        let (result, effects) =
-            self.bare_call(storage, origin, input_data, rollback, block_number, now);
+        let (result, effects) = self.bare_call(
+            storage,
+            origin,
+            input_data,
+            rollback,
+            gas_limit,
+            block_number,
+            now,
+        );
         let mut rv = transpose_contract_result(&result)?;
         Ok((
             Decode::decode(&mut rv).or(Err(ExecError {
@@ -219,10 +327,53 @@ impl Contract {
         ))
     }
 
+    /// Replace the code of the contract with an uploaded code, keeping its storage and key.
+    ///
+    /// If `migration` is given, it's sent to the new code as the input of a message, selector
+    /// included, right after the replacement. The whole upgrade is reverted if the migration fails.
+    pub fn upgrade(
+        &mut self,
+        storage: &mut Storage,
+        origin: AccountId,
+        code_hash: Hash,
+        migration: Option<Vec<u8>>,
+        gas_limit: Weight,
+        block_number: BlockNumber,
+        now: u64,
+    ) -> Result<ExecSideEffects, ExecError> {
+        let address = self.address.clone();
+        let (result, effects) = storage.execute_with(false, move || {
+            System::set_block_number(block_number);
+            Timestamp::set_timestamp(now);
+            with_transaction(move || {
+                let result = replace_code(address, origin, code_hash, migration, gas_limit);
+                if result.is_ok() {
+                    TransactionOutcome::Commit(result)
+                } else {
+                    TransactionOutcome::Rollback(result)
+                }
+            })
+        });
+        result.map(|()| effects)
+    }
+
+    /// Remove the contract from the cluster, deleting its storage and key.
+    pub fn destroy(self, storage: &mut Storage) -> Result<(), ExecError> {
+        let address = self.address;
+        storage
+            .execute_with(false, move || Pink::remove_contract(&address))
+            .0
+            .map_err(|err| ExecError {
+                source: err,
+                message: "Failed to remove the contract".into(),
+            })
+    }
+
     /// Called by on each block end by the runtime
     pub fn on_block_end(
         &mut self,
         storage: &mut Storage,
+        gas_limit: Weight,
         block_number: BlockNumber,
         now: u64,
     ) -> Result<ExecSideEffects, ExecError> {
@@ -234,7 +385,9 @@ impl Contract {
                 storage,
                 Default::default(),
                 input_data,
+                CallMode::Command,
                 false,
+                gas_limit,
                 block_number,
                 now,
             );
@@ -250,6 +403,30 @@ impl Contract {
     }
 }
 
+fn replace_code(
+    address: AccountId,
+    origin: AccountId,
+    code_hash: Hash,
+    migration: Option<Vec<u8>>,
+    gas_limit: Weight,
+) -> Result<(), ExecError> {
+    Pink::set_code_hash(&address, code_hash).map_err(|err| ExecError {
+        source: err,
+        message: "Failed to replace the contract code".into(),
+    })?;
+    if let Some(input_data) = migration {
+        let result = Contracts::bare_call(origin, address, 0, gas_limit, None, input_data, true);
+        transpose_contract_result(&result)?;
+        if result.result.as_ref().map_or(false, |v| v.did_revert()) {
+            return Err(ExecError {
+                source: DispatchError::Other("Migration reverted"),
+                message: String::from_utf8_lossy(&result.debug_message).to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
 pub fn transpose_contract_result(result: &ContractExecResult) -> Result<&[u8], ExecError> {
     result
         .result
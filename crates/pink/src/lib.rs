@@ -5,5 +5,8 @@ pub mod runtime;
 
 pub mod types;
 
-pub use contract::{Contract, ContractFile, Storage, transpose_contract_result, contract_address};
+pub use contract::{
+    contract_address, transpose_contract_result, Contract, ContractFile, ExecError, Storage,
+};
 pub use export_fixtures::load_test_wasm;
+pub use storage::{using_node_store_opener, NodeStore, NodeStoreOpener, StorageDump};
@@ -15,8 +15,14 @@ use sp_runtime::{
     Perbill,
 };
 
-pub use extension::{get_side_effects, ExecSideEffects};
-pub use pink_extension::{Message, OspMessage, PinkEvent};
+pub use extension::{
+    get_side_effects, set_http_backend, using_call_mode, CallMode, DefaultHttpBackend,
+    ExecSideEffects, HttpBackend,
+};
+pub use pink_extension::{
+    ContractMessage, IncomingContractMessage, Message, OspMessage, PinkEvent,
+    CONTRACT_MESSAGE_SELECTOR,
+};
 
 type UncheckedExtrinsic = frame_system::mocking::MockUncheckedExtrinsic<PinkRuntime>;
 type Block = frame_system::mocking::MockBlock<PinkRuntime>;
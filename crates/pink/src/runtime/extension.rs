@@ -1,17 +1,32 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::time::Duration;
+
 use frame_support::log::error;
 use pallet_contracts::chain_extension::{
-    ChainExtension, Environment, Ext, InitState, RetVal, SysConfig, UncheckedFrom,
+    BufInBufOutState, ChainExtension, Environment, Ext, InitState, RetVal, SysConfig, UncheckedFrom,
+};
+use phala_crypto::sr25519::{Persistence, KDF};
+use pink_extension::{
+    chain_extension::{
+        func_ids, HttpRequest, HttpResponse, PublicKeyForArgs, SigType, SignArgs, VerifyArgs,
+        MAX_RANDOM_LENGTH,
+    },
+    PinkEvent,
 };
-use pink_extension::PinkEvent;
-use scale::Decode;
+use rand::RngCore as _;
+use scale::{Decode, Encode};
+use sp_core::{ecdsa, ed25519, sr25519, Pair};
 use sp_runtime::DispatchError;
 
-use crate::types::AccountId;
+use crate::types::{AccountId, Hash};
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Encode, Decode)]
 pub struct ExecSideEffects {
     pub pink_events: Vec<(AccountId, PinkEvent)>,
     pub instantiated: Vec<(AccountId, AccountId)>,
+    /// Events emitted by ink! contracts other than pink events, as (contract, topics, data).
+    pub ink_events: Vec<(AccountId, Vec<Hash>, Vec<u8>)>,
 }
 
 pub fn get_side_effects() -> ExecSideEffects {
@@ -28,10 +43,9 @@ pub fn get_side_effects() -> ExecSideEffects {
                     contract: address,
                     data,
                 } => {
-                    if event.topics.len() != 1 {
-                        continue;
-                    }
-                    if event.topics[0].0 == pink_extension::PinkEvent::event_topic() {
+                    let is_pink_event = event.topics.len() == 1
+                        && event.topics[0].0 == pink_extension::PinkEvent::event_topic();
+                    if is_pink_event {
                         match pink_extension::PinkEvent::decode(&mut &data[..]) {
                             Ok(event) => {
                                 result.pink_events.push((address, event));
@@ -40,6 +54,8 @@ pub fn get_side_effects() -> ExecSideEffects {
                                 error!("Contract emitted an invalid pink event");
                             }
                         }
+                    } else {
+                        result.ink_events.push((address, event.topics, data));
                     }
                 }
                 _ => (),
@@ -49,15 +65,251 @@ pub fn get_side_effects() -> ExecSideEffects {
     result
 }
 
+/// The context a contract is executed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallMode {
+    /// Off-chain query. State changes are discarded after the call.
+    Query,
+    /// On-chain transaction. Must be deterministic across workers.
+    Command,
+}
+
+environmental::environmental!(call_mode: CallMode);
+
+/// Run `f` with `mode` visible to the chain extension.
+pub fn using_call_mode<R>(mut mode: CallMode, f: impl FnOnce() -> R) -> R {
+    call_mode::using(&mut mode, f)
+}
+
+fn current_call_mode() -> CallMode {
+    call_mode::with(|mode| *mode).unwrap_or(CallMode::Command)
+}
+
+/// The host side of `http_request` issued by contracts.
+pub trait HttpBackend {
+    fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, String>;
+}
+
+thread_local! {
+    static HTTP_BACKEND: RefCell<Box<dyn HttpBackend>> = RefCell::new(Box::new(DefaultHttpBackend));
+}
+
+/// Replace the HTTP backend of the current thread. Returns the previous one.
+pub fn set_http_backend(backend: Box<dyn HttpBackend>) -> Box<dyn HttpBackend> {
+    HTTP_BACKEND.with(|current| current.replace(backend))
+}
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends the requests to the network.
+pub struct DefaultHttpBackend;
+
+impl HttpBackend for DefaultHttpBackend {
+    fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, String> {
+        use http_req::{
+            request::{Method, Request},
+            uri::Uri,
+        };
+
+        let uri = Uri::try_from(request.url.as_str()).map_err(|err| format!("{}", err))?;
+        let method = match request.method.to_ascii_uppercase().as_str() {
+            "GET" => Method::GET,
+            "HEAD" => Method::HEAD,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "DELETE" => Method::DELETE,
+            "OPTIONS" => Method::OPTIONS,
+            "PATCH" => Method::PATCH,
+            _ => return Err(format!("Unsupported method: {}", request.method)),
+        };
+
+        let mut body = Vec::new();
+        let mut req = Request::new(&uri);
+        req.method(method).timeout(Some(HTTP_TIMEOUT));
+        for (key, value) in request.headers.iter() {
+            req.header(key, value);
+        }
+        if !request.body.is_empty() {
+            req.header("Content-Length", &request.body.len());
+            req.body(&request.body);
+        }
+        let response = req.send(&mut body).map_err(|err| format!("{}", err))?;
+
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Ok(HttpResponse {
+            status_code: response.status_code().into(),
+            reason_phrase: response.reason().into(),
+            headers,
+            body,
+        })
+    }
+}
+
 /// Contract extension for `pink contracts`
 pub struct PinkExtension;
 
 impl ChainExtension<super::PinkRuntime> for PinkExtension {
-    fn call<E: Ext>(func_id: u32, _env: Environment<E, InitState>) -> Result<RetVal, DispatchError>
+    fn call<E: Ext>(func_id: u32, env: Environment<E, InitState>) -> Result<RetVal, DispatchError>
     where
         <E::T as SysConfig>::AccountId: UncheckedFrom<<E::T as SysConfig>::Hash> + AsRef<[u8]>,
     {
-        error!(target: "pink", "Called an unregistered `func_id`: {:}", func_id);
-        return Err(DispatchError::Other("Unimplemented func_id"));
+        let mut env = env.buf_in_buf_out();
+        let output = match func_id {
+            func_ids::HTTP_REQUEST => {
+                if current_call_mode() != CallMode::Query {
+                    return Err(DispatchError::Other(
+                        "http_request is only allowed in query context",
+                    ));
+                }
+                let request: HttpRequest = read_input(&env)?;
+                let response = HTTP_BACKEND
+                    .with(|backend| backend.borrow().http_request(request))
+                    .unwrap_or_else(|err| {
+                        error!(target: "pink", "HTTP request failed: {}", err);
+                        HttpResponse::failed(err)
+                    });
+                response.encode()
+            }
+            func_ids::SIGN => {
+                let args: SignArgs = read_input(&env)?;
+                if args.sigtype == SigType::Sr25519 && current_call_mode() != CallMode::Query {
+                    return Err(DispatchError::Other(
+                        "sr25519 signing is only allowed in query context",
+                    ));
+                }
+                sign(args)?.encode()
+            }
+            func_ids::VERIFY => {
+                let args: VerifyArgs = read_input(&env)?;
+                verify(args)?.encode()
+            }
+            func_ids::DERIVE_SR25519_KEY => {
+                let salt: Vec<u8> = read_input(&env)?;
+                let address = caller_address(&env)?;
+                let contract_key = contract_key_of(&address)?;
+                let derived = contract_key
+                    .derive_sr25519_pair(&[address.as_ref(), &salt[..]])
+                    .or(Err(DispatchError::Other("Failed to derive sr25519 key")))?;
+                derived.dump_secret_key().to_vec().encode()
+            }
+            func_ids::GET_PUBLIC_KEY => {
+                let args: PublicKeyForArgs = read_input(&env)?;
+                get_public_key(args)?.encode()
+            }
+            func_ids::GETRANDOM => {
+                let length: u32 = read_input(&env)?;
+                if length > MAX_RANDOM_LENGTH {
+                    return Err(DispatchError::Other("getrandom length exceeds the limit"));
+                }
+                let mut buffer = vec![0u8; length as usize];
+                match current_call_mode() {
+                    CallMode::Query => rand::thread_rng().fill_bytes(&mut buffer),
+                    CallMode::Command => {
+                        let address = caller_address(&env)?;
+                        let block_number = super::System::block_number();
+                        let nonce = super::Pink::take_random_nonce();
+                        let seed = contract_key_of(&address)?
+                            .derive_sr25519_pair(&[
+                                b"random",
+                                address.as_ref(),
+                                &block_number.to_le_bytes(),
+                                &nonce.to_le_bytes(),
+                            ])
+                            .or(Err(DispatchError::Other("Failed to derive random seed")))?
+                            .dump_secret_key();
+                        fill_deterministic(&seed, &mut buffer);
+                    }
+                }
+                buffer.encode()
+            }
+            _ => {
+                error!(target: "pink", "Called an unregistered `func_id`: {:}", func_id);
+                return Err(DispatchError::Other("Unimplemented func_id"));
+            }
+        };
+        env.write(&output, false, None)
+            .or(Err(DispatchError::Other("Failed to return result")))?;
+        Ok(RetVal::Converging(0))
+    }
+}
+
+fn caller_address<E: Ext>(
+    env: &Environment<E, BufInBufOutState>,
+) -> Result<AccountId, DispatchError>
+where
+    <E::T as SysConfig>::AccountId: UncheckedFrom<<E::T as SysConfig>::Hash> + AsRef<[u8]>,
+{
+    let address: [u8; 32] = TryFrom::try_from(env.ext().address().as_ref())
+        .or(Err(DispatchError::Other("Invalid contract address")))?;
+    Ok(AccountId::new(address))
+}
+
+fn contract_key_of(address: &AccountId) -> Result<sr25519::Pair, DispatchError> {
+    super::Pink::contract_key(address).ok_or(DispatchError::Other("No key for the contract"))
+}
+
+/// Expand `seed` into `buffer` by hashing it with an increasing counter.
+fn fill_deterministic(seed: &[u8], buffer: &mut [u8]) {
+    for (i, chunk) in buffer.chunks_mut(32).enumerate() {
+        let block = sp_core::hashing::blake2_256(&(seed, i as u32).encode());
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+fn pair_from_key<P: Pair>(key: &[u8]) -> Result<P, DispatchError> {
+    P::from_seed_slice(key).or(Err(DispatchError::Other("Invalid key")))
+}
+
+fn sign(args: SignArgs) -> Result<Vec<u8>, DispatchError> {
+    fn sign_with<P: Pair>(key: &[u8], message: &[u8]) -> Result<Vec<u8>, DispatchError> {
+        Ok(pair_from_key::<P>(key)?.sign(message).as_ref().to_vec())
+    }
+    match args.sigtype {
+        SigType::Ed25519 => sign_with::<ed25519::Pair>(&args.key, &args.message),
+        SigType::Sr25519 => sign_with::<sr25519::Pair>(&args.key, &args.message),
+        SigType::Ecdsa => sign_with::<ecdsa::Pair>(&args.key, &args.message),
+    }
+}
+
+fn verify(args: VerifyArgs) -> Result<bool, DispatchError> {
+    macro_rules! verify_with {
+        ($sigtype: ident) => {{
+            let pubkey = $sigtype::Public::try_from(&args.pubkey[..])
+                .or(Err(DispatchError::Other("Invalid public key")))?;
+            // A malformed signature simply doesn't verify.
+            let signature = match $sigtype::Signature::try_from(&args.signature[..]) {
+                Ok(signature) => signature,
+                Err(_) => return Ok(false),
+            };
+            Ok($sigtype::Pair::verify(&signature, &args.message, &pubkey))
+        }};
     }
+    match args.sigtype {
+        SigType::Ed25519 => verify_with!(ed25519),
+        SigType::Sr25519 => verify_with!(sr25519),
+        SigType::Ecdsa => verify_with!(ecdsa),
+    }
+}
+
+fn get_public_key(args: PublicKeyForArgs) -> Result<Vec<u8>, DispatchError> {
+    fn public_of<P: Pair>(key: &[u8]) -> Result<Vec<u8>, DispatchError> {
+        Ok(pair_from_key::<P>(key)?.public().as_ref().to_vec())
+    }
+    match args.sigtype {
+        SigType::Ed25519 => public_of::<ed25519::Pair>(&args.key),
+        SigType::Sr25519 => public_of::<sr25519::Pair>(&args.key),
+        SigType::Ecdsa => public_of::<ecdsa::Pair>(&args.key),
+    }
+}
+
+fn read_input<T: Decode, E: Ext>(env: &Environment<E, BufInBufOutState>) -> Result<T, DispatchError>
+where
+    <E::T as SysConfig>::AccountId: UncheckedFrom<<E::T as SysConfig>::Hash> + AsRef<[u8]>,
+{
+    let input = env.read(env.in_len())?;
+    T::decode(&mut &input[..]).or(Err(DispatchError::Other("Invalid chain extension input")))
 }
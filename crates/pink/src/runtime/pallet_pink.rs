@@ -2,19 +2,56 @@ pub use pallet::*;
 
 #[frame_support::pallet]
 pub mod pallet {
-    use frame_support::pallet_prelude::*;
+    use frame_support::{
+        pallet_prelude::*,
+        storage::{child, storage_prefix, unhashed},
+        StorageHasher,
+    };
     use pallet_contracts::AddressGenerator;
-    use sp_core::crypto::UncheckedFrom;
+    use phala_crypto::sr25519::{Persistence, Sr25519SecretKey};
+    use sp_core::{crypto::UncheckedFrom, sr25519};
     use sp_runtime::traits::Hash as _;
 
     type CodeHash<T> = <T as frame_system::Config>::Hash;
 
+    /// Mirror of the contract info kept by `pallet_contracts`, which is not exposed.
+    #[derive(Encode, Decode)]
+    struct RawContractInfo<CodeHash> {
+        trie_id: Vec<u8>,
+        code_hash: CodeHash,
+        storage_deposit: crate::types::Balance,
+    }
+
+    /// Mirror of the code owner info kept by `pallet_contracts`, holding the number of contracts
+    /// using the code.
+    #[derive(Encode, Decode)]
+    struct RawOwnerInfo<AccountId> {
+        owner: AccountId,
+        #[codec(compact)]
+        deposit: crate::types::Balance,
+        #[codec(compact)]
+        refcount: u64,
+    }
+
     #[pallet::config]
     pub trait Config: frame_system::Config {}
 
     #[pallet::storage]
     pub(crate) type ClusterId<T: Config> = StorageValue<_, Vec<u8>, ValueQuery>;
 
+    /// The key of the cluster. Used by contracts that don't have a dedicated key.
+    #[pallet::storage]
+    pub(crate) type ClusterKey<T: Config> = StorageValue<_, Sr25519SecretKey>;
+
+    /// Dedicated keys of contracts assigned by the gatekeeper.
+    #[pallet::storage]
+    pub(crate) type ContractKeys<T: Config> =
+        StorageMap<_, Twox64Concat, T::AccountId, Sr25519SecretKey>;
+
+    /// Number of random values drawn by transactions, mixed into the next one.
+    #[pallet::storage]
+    pub(crate) type RandomNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
     #[pallet::pallet]
     pub struct Pallet<T>(PhantomData<T>);
 
@@ -46,5 +83,100 @@ pub mod pallet {
         pub fn set_cluster_id(cluster_id: &[u8]) {
             <ClusterId<T>>::put(cluster_id.to_vec());
         }
+
+        pub fn set_cluster_key(key: Sr25519SecretKey) {
+            <ClusterKey<T>>::put(key);
+        }
+
+        pub fn set_contract_key(address: &T::AccountId, key: Sr25519SecretKey) {
+            <ContractKeys<T>>::insert(address, key);
+        }
+
+        pub fn remove_contract_key(address: &T::AccountId) {
+            <ContractKeys<T>>::remove(address);
+        }
+
+        /// Returns the current random nonce and increases it.
+        pub fn take_random_nonce() -> u64 {
+            <RandomNonce<T>>::mutate(|nonce| {
+                let current = *nonce;
+                *nonce = nonce.wrapping_add(1);
+                current
+            })
+        }
+
+        /// Replace the code of a deployed contract, keeping its storage.
+        ///
+        /// `pallet_contracts` only offers this to the contract itself with `seal_set_code_hash`, so
+        /// its contract info is patched in place, moving the code reference counts the same way.
+        pub fn set_code_hash(address: &T::AccountId, code_hash: CodeHash<T>) -> DispatchResult {
+            let code_key = [
+                &storage_prefix(b"Contracts", b"CodeStorage")[..],
+                code_hash.as_ref(),
+            ]
+            .concat();
+            ensure!(
+                unhashed::exists(&code_key),
+                DispatchError::Other("Code not found")
+            );
+            let info_key = contract_info_key(address);
+            let mut info: RawContractInfo<CodeHash<T>> =
+                unhashed::get(&info_key).ok_or(DispatchError::Other("Contract not found"))?;
+            if info.code_hash == code_hash {
+                return Ok(());
+            }
+            update_code_refcount::<T>(&code_hash, |refcount| refcount.saturating_add(1))?;
+            update_code_refcount::<T>(&info.code_hash, |refcount| refcount.saturating_sub(1))?;
+            info.code_hash = code_hash;
+            unhashed::put(&info_key, &info);
+            Ok(())
+        }
+
+        /// Remove a deployed contract along with its storage and key.
+        ///
+        /// Like `set_code_hash`, this bypasses `pallet_contracts`. The code is left in place, with
+        /// the contract no longer counted as its user, so its owner can remove it.
+        pub fn remove_contract(address: &T::AccountId) -> DispatchResult {
+            let info_key = contract_info_key(address);
+            let info: RawContractInfo<CodeHash<T>> =
+                unhashed::get(&info_key).ok_or(DispatchError::Other("Contract not found"))?;
+            update_code_refcount::<T>(&info.code_hash, |refcount| refcount.saturating_sub(1))?;
+            let _ = child::kill_storage(&child::ChildInfo::new_default(&info.trie_id), None);
+            unhashed::kill(&info_key);
+            <ContractKeys<T>>::remove(address);
+            Ok(())
+        }
+
+        /// The key of the given contract, falling back to the cluster key.
+        pub fn contract_key(address: &T::AccountId) -> Option<sr25519::Pair> {
+            let key = <ContractKeys<T>>::get(address).or_else(<ClusterKey<T>>::get)?;
+            Some(sr25519::Pair::restore_from_secret_key(&key))
+        }
+    }
+
+    /// Update the number of contracts using the code in `pallet_contracts`.
+    fn update_code_refcount<T: Config>(
+        code_hash: &CodeHash<T>,
+        f: impl FnOnce(u64) -> u64,
+    ) -> DispatchResult {
+        let key = [
+            &storage_prefix(b"Contracts", b"OwnerInfoOf")[..],
+            code_hash.as_ref(),
+        ]
+        .concat();
+        let mut info: RawOwnerInfo<T::AccountId> =
+            unhashed::get(&key).ok_or(DispatchError::Other("Code owner info not found"))?;
+        info.refcount = f(info.refcount);
+        unhashed::put(&key, &info);
+        Ok(())
+    }
+
+    /// The storage key of the contract info of `address` in `pallet_contracts`.
+    fn contract_info_key(address: &impl Encode) -> Vec<u8> {
+        [
+            &storage_prefix(b"Contracts", b"ContractInfoOf")[..],
+            &Twox64Concat::hash(&address.encode()),
+        ]
+        .concat()
     }
 }
@@ -1,12 +1,22 @@
 use crate::{
-    runtime::ExecSideEffects,
+    runtime::{CallMode, ExecSideEffects},
     types::{AccountId, Hash, Hashing},
 };
+use phala_crypto::sr25519::Persistence;
 use phala_trie_storage::{deserialize_trie_backend, serialize_trie_backend};
+use scale::{Decode, Encode};
 use serde::{Deserialize, Serialize};
+use sp_core::{
+    sr25519,
+    storage::{well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX, ChildInfo},
+};
 use sp_runtime::DispatchError;
 use sp_state_machine::{Backend as StorageBackend, Ext, OverlayedChanges, StorageTransactionCache};
 
+pub use disk::{using_node_store_opener, ClusterBackend, NodeStorage, NodeStore, NodeStoreOpener};
+
+mod disk;
+
 pub type InMemoryBackend = sp_state_machine::InMemoryBackend<Hashing>;
 
 pub trait CommitTransaction: StorageBackend<Hashing> {
@@ -19,7 +29,17 @@ impl CommitTransaction for InMemoryBackend {
     }
 }
 
-#[derive(Default)]
+type KeyValues = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The committed state of a storage, to bootstrap another storage with.
+#[derive(Encode, Decode, Debug)]
+pub struct StorageDump {
+    pub root: Hash,
+    top: KeyValues,
+    /// The child tries by their storage keys.
+    children: Vec<(Vec<u8>, KeyValues)>,
+}
+
 pub struct Storage<Backend> {
     backend: Backend,
     overlay: OverlayedChanges,
@@ -40,6 +60,22 @@ where
         &mut self,
         rollback: bool,
         f: impl FnOnce() -> R,
+    ) -> (R, ExecSideEffects) {
+        // The changes of a query are always rolled back.
+        let mode = if rollback {
+            CallMode::Query
+        } else {
+            CallMode::Command
+        };
+        self.execute_in_mode(mode, rollback, f)
+    }
+
+    /// Like `execute_with`, but lets the caller choose the context seen by the contracts.
+    pub fn execute_in_mode<R>(
+        &mut self,
+        mode: CallMode,
+        rollback: bool,
+        f: impl FnOnce() -> R,
     ) -> (R, ExecSideEffects) {
         let backend = self.backend.as_trie_backend().expect("No trie backend?");
 
@@ -47,9 +83,11 @@ where
         let mut cache = StorageTransactionCache::default();
         let mut ext = Ext::new(&mut self.overlay, &mut cache, backend, None);
         let r = sp_externalities::set_and_run_with_externalities(&mut ext, move || {
-            crate::runtime::System::reset_events();
-            let r = f();
-            (r, crate::runtime::get_side_effects())
+            crate::runtime::using_call_mode(mode, move || {
+                crate::runtime::System::reset_events();
+                let r = f();
+                (r, crate::runtime::get_side_effects())
+            })
         });
         if rollback {
             self.overlay.rollback_transaction()
@@ -90,12 +128,81 @@ where
         self.clear_changes();
     }
 
+    /// Dump the committed state, leaving out the uncommitted changes.
+    pub fn dump(&self) -> StorageDump {
+        let backend = self.backend.as_trie_backend().expect("No trie backend?");
+        let mut top = Vec::new();
+        let mut children = Vec::new();
+        for (key, value) in backend.pairs() {
+            match key.strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+                // The child roots are recalculated from the child tries on load.
+                Some(storage_key) => {
+                    let child_info = ChildInfo::new_default(storage_key);
+                    let pairs = backend
+                        .child_keys(&child_info, &[])
+                        .into_iter()
+                        .filter_map(|key| {
+                            let value = backend.child_storage(&child_info, &key).ok()??;
+                            Some((key, value))
+                        })
+                        .collect();
+                    children.push((storage_key.to_vec(), pairs));
+                }
+                None => top.push((key, value)),
+            }
+        }
+        StorageDump {
+            root: *backend.root(),
+            top,
+            children,
+        }
+    }
+
+    /// Write the state in `dump` on top of the storage and commit it.
+    ///
+    /// Returns false if the resulting state root differs from the dumped one, in which case the
+    /// storage is left with whatever was written.
+    pub fn load(&mut self, dump: StorageDump) -> bool {
+        for (key, value) in dump.top {
+            self.overlay.set_storage(key, Some(value));
+        }
+        for (storage_key, pairs) in dump.children {
+            let child_info = ChildInfo::new_default(&storage_key);
+            for (key, value) in pairs {
+                self.overlay
+                    .set_child_storage(&child_info, key, Some(value));
+            }
+        }
+        let (root, transaction) = self.changes_transaction();
+        self.commit_transaction(root, transaction);
+        self.clear_changes();
+        root == dump.root
+    }
+
     pub fn set_cluster_id(&mut self, cluster_id: &[u8]) {
         self.execute_with(false, || {
             crate::runtime::Pink::set_cluster_id(cluster_id);
         });
     }
 
+    pub fn set_cluster_key(&mut self, key: &sr25519::Pair) {
+        self.execute_with(false, || {
+            crate::runtime::Pink::set_cluster_key(key.dump_secret_key());
+        });
+    }
+
+    pub fn set_contract_key(&mut self, address: &AccountId, key: &sr25519::Pair) {
+        self.execute_with(false, || {
+            crate::runtime::Pink::set_contract_key(address, key.dump_secret_key());
+        });
+    }
+
+    pub fn remove_contract_key(&mut self, address: &AccountId) {
+        self.execute_with(false, || {
+            crate::runtime::Pink::remove_contract_key(address);
+        });
+    }
+
     pub fn upload_code(
         &mut self,
         account: AccountId,
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use hash_db::Prefix;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sp_core::Hasher as _;
+use sp_state_machine::{DBValue, TrieBackend, TrieBackendStorage};
+use sp_trie::{HashDBT as _, MemoryDB, EMPTY_PREFIX};
+
+use super::{CommitTransaction, Storage};
+use crate::types::{Hash, Hashing};
+
+/// A key-value store keeping the trie nodes of a cluster outside of the memory.
+///
+/// Nodes are content addressed. The reference counts are kept by `DiskNodes` instead.
+pub trait NodeStore: Send + Sync {
+    fn get(&self, key: &Hash) -> Result<Option<Vec<u8>>, String>;
+    fn put(&self, key: &Hash, value: &[u8]) -> Result<(), String>;
+    /// Remove a node. Removing a missing node is not an error.
+    fn remove(&self, key: &Hash) -> Result<(), String>;
+}
+
+/// Opens the node store of the given name.
+pub trait NodeStoreOpener: Send + Sync {
+    fn open(&self, name: &str) -> Arc<dyn NodeStore>;
+}
+
+environmental::environmental!(node_store_opener: trait NodeStoreOpener);
+
+/// Run `f` with `opener` used to reopen the node stores of the storages being deserialized.
+pub fn using_node_store_opener<R>(
+    opener: &mut (dyn NodeStoreOpener + 'static),
+    f: impl FnOnce() -> R,
+) -> R {
+    node_store_opener::using(opener, f)
+}
+
+/// Where the trie nodes of a cluster are kept.
+#[derive(Clone)]
+pub enum NodeStorage {
+    InMemory(MemoryDB<Hashing>),
+    OnDisk(DiskNodes),
+}
+
+/// The trie nodes kept in a node store, with their reference counts.
+///
+/// The reference counts live in the checkpoints rather than in the store, so the blocks replayed
+/// after restoring a checkpoint count from the same numbers. For the same reason, a node dropped
+/// by the trie is only removed from the store once no retained checkpoint can refer to it. See
+/// `collect_garbage`.
+#[derive(Clone)]
+pub struct DiskNodes {
+    name: String,
+    store: Arc<dyn NodeStore>,
+    /// Reference counts of the nodes, which can go negative like in `MemoryDB`.
+    refs: BTreeMap<Hash, i32>,
+    /// The nodes no longer referenced, with the generation they were dropped in.
+    garbage: BTreeMap<Hash, u32>,
+    /// The number of garbage collections so far.
+    generation: u32,
+}
+
+impl DiskNodes {
+    fn new(name: String, store: Arc<dyn NodeStore>) -> Self {
+        Self {
+            name,
+            store,
+            refs: Default::default(),
+            garbage: Default::default(),
+            generation: 0,
+        }
+    }
+
+    fn apply(&mut self, mut transaction: MemoryDB<Hashing>) {
+        for (key, (value, rc)) in transaction.drain() {
+            if rc == 0 {
+                continue;
+            }
+            let before = self.refs.get(&key).copied().unwrap_or(0);
+            let after = before + rc;
+            if after == 0 {
+                self.refs.remove(&key);
+            } else {
+                self.refs.insert(key, after);
+            }
+            match (before > 0, after > 0) {
+                (false, true) => {
+                    self.garbage.remove(&key);
+                    let name = &self.name;
+                    self.store.put(&key, &value).unwrap_or_else(|err| {
+                        panic!("Failed to write node to the store of {}: {}", name, err)
+                    });
+                }
+                (true, false) => {
+                    self.garbage.insert(key, self.generation);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Remove the dropped nodes from the store. To be called right after a checkpoint is saved.
+    ///
+    /// The node files are shared by the saved checkpoint and the backup one. A node dropped after
+    /// generation `g` may still be referenced by the checkpoint saved at `g`, so it is only
+    /// removed once both retained checkpoints are newer than that.
+    fn collect_garbage(&mut self) {
+        let generation = self.generation;
+        let mut removed = 0;
+        let store = &self.store;
+        let name = &self.name;
+        self.garbage.retain(|key, dropped_at| {
+            if *dropped_at + 1 > generation {
+                return true;
+            }
+            if let Err(err) = store.remove(key) {
+                log::error!("Failed to remove node from the store of {}: {}", name, err);
+                return true;
+            }
+            removed += 1;
+            false
+        });
+        self.generation += 1;
+        log::info!("Removed {} nodes from the store of {}", removed, self.name);
+    }
+}
+
+impl TrieBackendStorage<Hashing> for NodeStorage {
+    type Overlay = MemoryDB<Hashing>;
+
+    fn get(&self, key: &Hash, prefix: Prefix) -> Result<Option<DBValue>, String> {
+        match self {
+            NodeStorage::InMemory(db) => TrieBackendStorage::get(db, key, prefix),
+            NodeStorage::OnDisk(nodes) => {
+                // The null node is implied by every trie and never written to the store.
+                if *key == empty_trie_root() {
+                    return Ok(Some(vec![0u8]));
+                }
+                nodes.store.get(key)
+            }
+        }
+    }
+}
+
+pub type ClusterBackend = TrieBackend<NodeStorage, Hashing>;
+
+fn empty_trie_root() -> Hash {
+    Hashing::hash(&[0u8][..])
+}
+
+impl CommitTransaction for ClusterBackend {
+    fn commit_transaction(&mut self, root: Hash, transaction: Self::Transaction) {
+        let placeholder = TrieBackend::new(NodeStorage::InMemory(Default::default()), root);
+        let storage = match core::mem::replace(self, placeholder).into_storage() {
+            NodeStorage::InMemory(mut db) => {
+                db.consolidate(transaction);
+                db.purge();
+                NodeStorage::InMemory(db)
+            }
+            NodeStorage::OnDisk(mut nodes) => {
+                nodes.apply(transaction);
+                NodeStorage::OnDisk(nodes)
+            }
+        };
+        *self = TrieBackend::new(storage, root);
+    }
+}
+
+impl Storage<ClusterBackend> {
+    /// Create an empty storage keeping the trie nodes in memory.
+    pub fn in_memory() -> Self {
+        Self::new(TrieBackend::new(
+            NodeStorage::InMemory(Default::default()),
+            empty_trie_root(),
+        ))
+    }
+
+    /// Create an empty storage keeping the trie nodes in the given node store.
+    pub fn on_disk(name: String, store: Arc<dyn NodeStore>) -> Self {
+        Self::new(TrieBackend::new(
+            NodeStorage::OnDisk(DiskNodes::new(name, store)),
+            empty_trie_root(),
+        ))
+    }
+
+    /// Remove the trie nodes no retained checkpoint refers to from the node store, if any.
+    pub fn collect_garbage(&mut self) {
+        let root = *self.backend.root();
+        let placeholder = TrieBackend::new(NodeStorage::InMemory(Default::default()), root);
+        let mut storage = core::mem::replace(&mut self.backend, placeholder).into_storage();
+        if let NodeStorage::OnDisk(nodes) = &mut storage {
+            nodes.collect_garbage();
+        }
+        self.backend = TrieBackend::new(storage, root);
+    }
+}
+
+impl Default for Storage<ClusterBackend> {
+    fn default() -> Self {
+        Self::in_memory()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum NodesDump {
+    /// All the nodes with their keys and reference counts.
+    InMemory(Vec<(Hash, Vec<u8>, i32)>),
+    /// The nodes are already in the store, only their reference counts are dumped.
+    OnDisk {
+        name: String,
+        refs: Vec<(Hash, i32)>,
+        garbage: Vec<(Hash, u32)>,
+        generation: u32,
+    },
+}
+
+/// The serialized storage, in any of the checkpoint versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StorageDumpVersions {
+    /// Since checkpoint version 2.
+    V2(Hash, NodesDump),
+    /// Checkpoint version 1, with the nodes in memory and without their keys.
+    V1(Hash, Vec<(Vec<u8>, i32)>),
+}
+
+impl Serialize for Storage<ClusterBackend> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let trie = &self.backend;
+        let nodes = match trie.backend_storage() {
+            NodeStorage::InMemory(db) => NodesDump::InMemory(
+                db.clone()
+                    .drain()
+                    .into_iter()
+                    .map(|(key, (value, rc))| (key, value, rc))
+                    .collect(),
+            ),
+            NodeStorage::OnDisk(nodes) => NodesDump::OnDisk {
+                name: nodes.name.clone(),
+                refs: nodes.refs.iter().map(|(k, v)| (*k, *v)).collect(),
+                garbage: nodes.garbage.iter().map(|(k, v)| (*k, *v)).collect(),
+                generation: nodes.generation,
+            },
+        };
+        (trie.root(), nodes).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Storage<ClusterBackend> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (root, nodes) = match Deserialize::deserialize(deserializer)? {
+            StorageDumpVersions::V2(root, nodes) => (root, nodes),
+            StorageDumpVersions::V1(root, nodes) => {
+                // Version 1 had no keys, so the pending removals can't be restored.
+                let nodes = nodes
+                    .into_iter()
+                    .filter(|(_, rc)| *rc > 0)
+                    .map(|(value, rc)| (Hashing::hash(&value), value, rc))
+                    .collect();
+                (root, NodesDump::InMemory(nodes))
+            }
+        };
+        let storage = match nodes {
+            NodesDump::InMemory(nodes) => {
+                let mut db = MemoryDB::default();
+                for (key, value, rc) in nodes {
+                    if rc > 0 {
+                        for _ in 0..rc {
+                            db.emplace(key, EMPTY_PREFIX, value.clone());
+                        }
+                    } else {
+                        // Nodes removed before being inserted, keep them pending.
+                        for _ in rc..0 {
+                            db.remove(&key, EMPTY_PREFIX);
+                        }
+                    }
+                }
+                NodeStorage::InMemory(db)
+            }
+            NodesDump::OnDisk {
+                name,
+                refs,
+                garbage,
+                generation,
+            } => {
+                let store = node_store_opener::with(|opener| opener.open(&name))
+                    .ok_or_else(|| de::Error::custom("No node store opener available"))?;
+                NodeStorage::OnDisk(DiskNodes {
+                    name,
+                    store,
+                    refs: refs.into_iter().collect(),
+                    garbage: garbage.into_iter().collect(),
+                    generation,
+                })
+            }
+        };
+        Ok(Self::new(TrieBackend::new(storage, root)))
+    }
+}
@@ -0,0 +1,54 @@
+(module
+	(import "seal0" "seal_input" (func $seal_input (param i32 i32)))
+	(import "seal0" "seal_return" (func $seal_return (param i32 i32 i32)))
+	(import "seal0" "seal_call_chain_extension"
+		(func $seal_call_chain_extension (param i32 i32 i32 i32 i32) (result i32))
+	)
+	(import "env" "memory" (memory 16 16))
+
+	(func $assert (param i32)
+		(block $ok
+			(br_if $ok
+				(get_local 0)
+			)
+			(unreachable)
+		)
+	)
+
+	(func (export "deploy"))
+
+	;; Forwards the input to the chain extension and returns its output.
+	;;
+	;; Layout of the input:
+	;;  [0, 4) func_id of the chain extension function, little endian
+	;;  [4, ..) input of the chain extension function
+	;;
+	;; Layout of the memory:
+	;;  [0, 4) size of the input buffer
+	;;  [4, 8) size of the output buffer
+	;;  [8, 32768) input buffer
+	;;  [32768, 65536) output buffer
+	(func (export "call")
+		(i32.store (i32.const 0) (i32.const 32760))
+		(call $seal_input (i32.const 8) (i32.const 0))
+
+		(i32.store (i32.const 4) (i32.const 32768))
+		(call $assert
+			(i32.eqz
+				(call $seal_call_chain_extension
+					(i32.load (i32.const 8)) ;; func_id
+					(i32.const 12) ;; input_ptr
+					(i32.sub (i32.load (i32.const 0)) (i32.const 4)) ;; input_len
+					(i32.const 32768) ;; output_ptr
+					(i32.const 4) ;; output_len_ptr
+				)
+			)
+		)
+
+		(call $seal_return
+			(i32.const 0) ;; flags
+			(i32.const 32768) ;; data_ptr
+			(i32.load (i32.const 4)) ;; data_len
+		)
+	)
+)
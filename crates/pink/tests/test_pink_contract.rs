@@ -1,11 +1,66 @@
 use frame_support::assert_ok;
 use hex_literal::hex;
-use pink::Contract;
-use pink_extension::PinkEvent;
+use pink::{
+    types::{Hash, GAS_LIMIT},
+    using_node_store_opener, Contract, NodeStore, NodeStoreOpener,
+};
+use pink_extension::{
+    chain_extension::{
+        func_ids, HttpRequest, HttpResponse, PublicKeyForArgs, SigType, SignArgs, VerifyArgs,
+        MAX_RANDOM_LENGTH,
+    },
+    PinkEvent,
+};
+use scale::{Decode, Encode};
 use sp_runtime::AccountId32;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 pub const ALICE: AccountId32 = AccountId32::new([1u8; 32]);
 
+/// Deploy the chain extension proxy contract defined in fixtures/chain_extension.wat
+fn deploy_chain_extension_proxy(storage: &mut pink::Storage) -> Contract {
+    let wasm = wat::parse_bytes(include_bytes!("./fixtures/chain_extension.wat"))
+        .unwrap()
+        .into_owned();
+    Contract::new(
+        storage,
+        ALICE.clone(),
+        wasm,
+        vec![],
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        1,
+        0,
+    )
+    .unwrap()
+    .0
+}
+
+/// Call the chain extension function `func_id` via the proxy contract
+fn call_chain_extension<R: Decode>(
+    contract: &mut Contract,
+    storage: &mut pink::Storage,
+    func_id: u32,
+    input: impl Encode,
+    rollback: bool,
+) -> Result<R, pink::ExecError> {
+    let mut input_data = func_id.encode();
+    input.encode_to(&mut input_data);
+    let (result, _) = contract.bare_call(
+        storage,
+        ALICE.clone(),
+        input_data,
+        rollback,
+        GAS_LIMIT,
+        1,
+        0,
+    );
+    let mut output = pink::transpose_contract_result(&result)?;
+    Ok(Decode::decode(&mut output).expect("Failed to decode chain extension output"))
+}
+
 #[test]
 fn test_ink_flip() {
     let mut storage = Contract::new_storage();
@@ -17,6 +72,7 @@ fn test_ink_flip() {
         true,
         vec![],
         vec![],
+        GAS_LIMIT,
         0,
         0,
     )
@@ -30,6 +86,7 @@ fn test_ink_flip() {
             hex!("2f865bd9"), // get
             (),
             false,
+            GAS_LIMIT,
             0,
             0,
         )
@@ -45,6 +102,7 @@ fn test_ink_flip() {
             hex!("633aa551"), // flip
             (),
             false,
+            GAS_LIMIT,
             0,
             0,
         )
@@ -58,6 +116,7 @@ fn test_ink_flip() {
             hex!("2f865bd9"), // get
             (),
             false,
+            GAS_LIMIT,
             0,
             0,
         )
@@ -73,6 +132,7 @@ fn test_ink_flip() {
             hex!("f7dff04c"), // echo
             (42u32, 24u128),
             false,
+            GAS_LIMIT,
             0,
             0,
         )
@@ -81,6 +141,117 @@ fn test_ink_flip() {
     assert_eq!(result, (42, 24));
 }
 
+#[test]
+fn test_gas_limit() {
+    let mut storage = Contract::new_storage();
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+
+    let (result, _) = contract.bare_call(
+        &mut storage,
+        ALICE.clone(),
+        hex!("633aa551").to_vec(), // flip
+        false,
+        GAS_LIMIT,
+        0,
+        0,
+    );
+    assert!(result.result.is_ok());
+    assert!(result.gas_consumed > 0);
+
+    // Aborted when running out of gas, and the state is left untouched
+    let (result, _) = contract.bare_call(
+        &mut storage,
+        ALICE.clone(),
+        hex!("633aa551").to_vec(), // flip
+        false,
+        result.gas_consumed / 2,
+        0,
+        0,
+    );
+    assert!(result.result.is_err());
+
+    let value: bool = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("2f865bd9"), // get
+            (),
+            true,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    assert_eq!(value, false);
+}
+
+#[test]
+fn test_dry_run() {
+    let mut storage = Contract::new_storage();
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+
+    let (result, _) = contract.dry_run(
+        &mut storage,
+        ALICE.clone(),
+        hex!("633aa551").to_vec(), // flip
+        GAS_LIMIT,
+        0,
+        0,
+    );
+    assert!(result.result.is_ok());
+    assert!(result.gas_consumed > 0);
+
+    // The changes are discarded
+    let value: bool = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("2f865bd9"), // get
+            (),
+            true,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    assert_eq!(value, true);
+
+    // Dry runs are executed in transaction context
+    let mut proxy = deploy_chain_extension_proxy(&mut storage);
+    let mut input_data = func_ids::HTTP_REQUEST.encode();
+    HttpRequest::new("http://localhost", "GET").encode_to(&mut input_data);
+    let (result, _) = proxy.dry_run(&mut storage, ALICE.clone(), input_data, GAS_LIMIT, 1, 0);
+    assert!(result.result.is_err());
+}
+
 #[test]
 fn test_load_contract_file() {
     assert_ok!(pink::ContractFile::load(include_bytes!(
@@ -99,6 +270,7 @@ fn test_ink_cross_contract_instanciate() {
         true,
         vec![],
         vec![],
+        GAS_LIMIT,
         0,
         0,
     )
@@ -112,6 +284,7 @@ fn test_ink_cross_contract_instanciate() {
         (),
         vec![],
         vec![],
+        GAS_LIMIT,
         0,
         0,
     )
@@ -125,6 +298,7 @@ fn test_ink_cross_contract_instanciate() {
             hex!("c3220014"), // get
             (),
             false,
+            GAS_LIMIT,
             0,
             0,
         )
@@ -145,6 +319,7 @@ fn test_mq_egress() {
         (),
         vec![],
         vec![],
+        GAS_LIMIT,
         1,
         0,
     )
@@ -159,6 +334,7 @@ fn test_mq_egress() {
             hex!("6495da7f"), // push_message
             (b"\x42\x42".to_vec(), b"\x24\x24".to_vec()),
             false,
+            GAS_LIMIT,
             1,
             0,
         )
@@ -172,6 +348,7 @@ fn test_mq_egress() {
             hex!("d09d68e0"), // push_osp_message
             (b"\x42\x42".to_vec(), b"\x24\x24".to_vec(), Some([0u8; 32])),
             false,
+            GAS_LIMIT,
             1,
             0,
         )
@@ -190,6 +367,7 @@ fn test_on_block_end() {
         (),
         vec![],
         vec![],
+        GAS_LIMIT,
         1,
         0,
     )
@@ -207,7 +385,525 @@ fn test_on_block_end() {
         }
     }
 
-    let effects = contract.on_block_end(&mut storage, 1, 1).unwrap();
+    let effects = contract
+        .on_block_end(&mut storage, GAS_LIMIT, 1, 1)
+        .unwrap();
 
     insta::assert_debug_snapshot!(effects);
 }
+
+#[test]
+fn test_http_request() {
+    struct EchoBackend;
+    impl pink::runtime::HttpBackend for EchoBackend {
+        fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, String> {
+            Ok(HttpResponse {
+                status_code: 200,
+                reason_phrase: "OK".into(),
+                headers: vec![("X-Method".into(), request.method)],
+                body: request.body,
+            })
+        }
+    }
+    pink::runtime::set_http_backend(Box::new(EchoBackend));
+
+    let mut storage = Contract::new_storage();
+    let mut contract = deploy_chain_extension_proxy(&mut storage);
+
+    let request = HttpRequest {
+        url: "http://localhost/echo".into(),
+        method: "POST".into(),
+        headers: vec![],
+        body: b"hello".to_vec(),
+    };
+    let response: HttpResponse = call_chain_extension(
+        &mut contract,
+        &mut storage,
+        func_ids::HTTP_REQUEST,
+        &request,
+        true,
+    )
+    .unwrap();
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.headers, vec![("X-Method".into(), "POST".into())]);
+    assert_eq!(response.body, b"hello".to_vec());
+
+    // Not allowed in transactions
+    let result: Result<HttpResponse, _> = call_chain_extension(
+        &mut contract,
+        &mut storage,
+        func_ids::HTTP_REQUEST,
+        &request,
+        false,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_sign_and_verify() {
+    use sp_core::Pair as _;
+
+    let mut storage = Contract::new_storage();
+    storage.set_cluster_key(&sp_core::sr25519::Pair::from_seed(&[1u8; 32]));
+    let mut contract = deploy_chain_extension_proxy(&mut storage);
+
+    let derive = |contract: &mut Contract, storage: &mut pink::Storage, salt: &[u8]| -> Vec<u8> {
+        call_chain_extension(
+            contract,
+            storage,
+            func_ids::DERIVE_SR25519_KEY,
+            salt.to_vec(),
+            false,
+        )
+        .unwrap()
+    };
+    let key = derive(&mut contract, &mut storage, b"a");
+    assert_eq!(key.len(), 64);
+    assert_eq!(derive(&mut contract, &mut storage, b"a"), key);
+    assert_ne!(derive(&mut contract, &mut storage, b"b"), key);
+
+    let message = b"hello".to_vec();
+    for (sigtype, key) in [
+        (SigType::Sr25519, key.clone()),
+        (SigType::Ed25519, vec![2u8; 32]),
+        (SigType::Ecdsa, vec![3u8; 32]),
+    ] {
+        let pubkey: Vec<u8> = call_chain_extension(
+            &mut contract,
+            &mut storage,
+            func_ids::GET_PUBLIC_KEY,
+            PublicKeyForArgs {
+                sigtype,
+                key: key.clone(),
+            },
+            true,
+        )
+        .unwrap();
+        let signature: Vec<u8> = call_chain_extension(
+            &mut contract,
+            &mut storage,
+            func_ids::SIGN,
+            SignArgs {
+                sigtype,
+                key: key.clone(),
+                message: message.clone(),
+            },
+            true,
+        )
+        .unwrap();
+        let verify = |contract: &mut Contract, storage: &mut pink::Storage, message: &[u8]| {
+            call_chain_extension::<bool>(
+                contract,
+                storage,
+                func_ids::VERIFY,
+                VerifyArgs {
+                    sigtype,
+                    pubkey: pubkey.clone(),
+                    message: message.to_vec(),
+                    signature: signature.clone(),
+                },
+                false,
+            )
+            .unwrap()
+        };
+        assert!(verify(&mut contract, &mut storage, &message));
+        assert!(!verify(&mut contract, &mut storage, b"world"));
+    }
+
+    // Randomized sr25519 signatures are not allowed in transactions
+    let result: Result<Vec<u8>, _> = call_chain_extension(
+        &mut contract,
+        &mut storage,
+        func_ids::SIGN,
+        SignArgs {
+            sigtype: SigType::Sr25519,
+            key,
+            message,
+        },
+        false,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_getrandom() {
+    use sp_core::Pair as _;
+
+    let cluster_key = sp_core::sr25519::Pair::from_seed(&[1u8; 32]);
+    let deploy = || {
+        let mut storage = Contract::new_storage();
+        storage.set_cluster_key(&cluster_key);
+        let contract = deploy_chain_extension_proxy(&mut storage);
+        (storage, contract)
+    };
+    let (mut storage1, mut contract1) = deploy();
+    let (mut storage2, mut contract2) = deploy();
+
+    let getrandom = |contract: &mut Contract, storage: &mut pink::Storage, rollback| {
+        call_chain_extension::<Vec<u8>>(contract, storage, func_ids::GETRANDOM, 40u32, rollback)
+            .unwrap()
+    };
+
+    // Transactions on different workers get the same randomness
+    let random1 = getrandom(&mut contract1, &mut storage1, false);
+    let random2 = getrandom(&mut contract2, &mut storage2, false);
+    assert_eq!(random1.len(), 40);
+    assert_eq!(random1, random2);
+    // but the next draw differs
+    assert_ne!(getrandom(&mut contract1, &mut storage1, false), random1);
+
+    // Queries get enclave randomness
+    let query1 = getrandom(&mut contract1, &mut storage1, true);
+    let query2 = getrandom(&mut contract2, &mut storage2, true);
+    assert_eq!(query1.len(), 40);
+    assert_ne!(query1, query2);
+
+    // Longer than a u8, up to the limit
+    for (length, rollback) in [(MAX_RANDOM_LENGTH, false), (MAX_RANDOM_LENGTH, true)] {
+        let random = call_chain_extension::<Vec<u8>>(
+            &mut contract1,
+            &mut storage1,
+            func_ids::GETRANDOM,
+            length,
+            rollback,
+        )
+        .unwrap();
+        assert_eq!(random.len(), length as usize);
+        assert!(call_chain_extension::<Vec<u8>>(
+            &mut contract1,
+            &mut storage1,
+            func_ids::GETRANDOM,
+            length + 1,
+            rollback,
+        )
+        .is_err());
+    }
+}
+
+#[derive(Default)]
+struct MemNodeStore(Mutex<HashMap<Hash, Vec<u8>>>);
+
+impl NodeStore for MemNodeStore {
+    fn get(&self, key: &Hash) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &Hash, value: &[u8]) -> Result<(), String> {
+        self.0.lock().unwrap().insert(*key, value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &Hash) -> Result<(), String> {
+        self.0.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+struct MemNodeStoreOpener(Arc<MemNodeStore>);
+
+impl NodeStoreOpener for MemNodeStoreOpener {
+    fn open(&self, name: &str) -> Arc<dyn NodeStore> {
+        assert_eq!(name, "test");
+        self.0.clone()
+    }
+}
+
+#[test]
+fn test_disk_backed_storage() {
+    let store = Arc::new(MemNodeStore::default());
+    let mut storage = pink::Storage::on_disk("test".into(), store.clone());
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+    let _: () = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("633aa551"), // flip
+            (),
+            false,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    storage.commit_changes();
+    assert!(!store.0.lock().unwrap().is_empty());
+
+    // The nodes stay in the store, so the checkpoint can't be loaded without it.
+    let dump = serde_json::to_vec(&storage).unwrap();
+    assert!(serde_json::from_slice::<pink::Storage>(&dump).is_err());
+
+    let mut opener = MemNodeStoreOpener(store);
+    let mut storage: pink::Storage =
+        using_node_store_opener(&mut opener, || serde_json::from_slice(&dump)).unwrap();
+    let result: bool = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("2f865bd9"), // get
+            (),
+            true,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    assert_eq!(result, false);
+}
+
+#[test]
+fn test_disk_backed_storage_gc() {
+    let store = Arc::new(MemNodeStore::default());
+    let mut storage = pink::Storage::on_disk("test".into(), store.clone());
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+    storage.commit_changes();
+    let mut flip = |storage: &mut pink::Storage| {
+        let _: () = contract
+            .call_with_selector(
+                storage,
+                ALICE.clone(),
+                hex!("633aa551"), // flip
+                (),
+                false,
+                GAS_LIMIT,
+                0,
+                0,
+            )
+            .unwrap()
+            .0;
+        storage.commit_changes();
+    };
+    let node_count = || store.0.lock().unwrap().len();
+
+    // The nodes dropped by the flip are kept for the checkpoints taken before it
+    flip(&mut storage);
+    let count = node_count();
+    storage.collect_garbage();
+    assert_eq!(node_count(), count);
+    // and removed once both retained checkpoints are taken after it.
+    storage.collect_garbage();
+    assert!(node_count() < count);
+
+    // Flip back and forth, the nodes of the current state are referenced again and kept.
+    flip(&mut storage);
+    flip(&mut storage);
+    let count = node_count();
+    storage.collect_garbage();
+    storage.collect_garbage();
+    storage.collect_garbage();
+    assert!(node_count() < count);
+    let result: bool = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("2f865bd9"), // get
+            (),
+            true,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    assert_eq!(result, false);
+}
+
+#[test]
+fn test_upgrade() {
+    let mut storage = Contract::new_storage();
+    let code = include_bytes!("./fixtures/flip/flip.wasm").to_vec();
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        code.clone(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+    let code_hash = storage.upload_code(ALICE.clone(), code).unwrap();
+
+    let get = |contract: &mut Contract, storage: &mut pink::Storage| -> bool {
+        contract
+            .call_with_selector(
+                storage,
+                ALICE.clone(),
+                hex!("2f865bd9"), // get
+                (),
+                true,
+                GAS_LIMIT,
+                0,
+                0,
+            )
+            .unwrap()
+            .0
+    };
+
+    // Unknown code
+    let result = contract.upgrade(
+        &mut storage,
+        ALICE.clone(),
+        Hash::zero(),
+        None,
+        GAS_LIMIT,
+        1,
+        0,
+    );
+    assert!(result.is_err());
+
+    // A failed migration reverts the upgrade
+    let result = contract.upgrade(
+        &mut storage,
+        ALICE.clone(),
+        code_hash,
+        Some(hex!("deadbeef").to_vec()),
+        GAS_LIMIT,
+        1,
+        0,
+    );
+    assert!(result.is_err());
+    assert_eq!(get(&mut contract, &mut storage), true);
+
+    // The storage is kept and the migration is applied
+    let result = contract.upgrade(
+        &mut storage,
+        ALICE.clone(),
+        code_hash,
+        Some(hex!("633aa551").to_vec()), // flip
+        GAS_LIMIT,
+        1,
+        0,
+    );
+    assert!(result.is_ok());
+    assert_eq!(get(&mut contract, &mut storage), false);
+}
+
+#[test]
+fn test_destroy() {
+    let mut storage = Contract::new_storage();
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+    let address = contract.address.clone();
+    contract.destroy(&mut storage).unwrap();
+
+    let mut contract = Contract::from_address(address);
+    let result = contract.call_with_selector::<bool>(
+        &mut storage,
+        ALICE.clone(),
+        hex!("2f865bd9"), // get
+        (),
+        true,
+        GAS_LIMIT,
+        0,
+        0,
+    );
+    assert!(result.is_err());
+    assert!(contract.destroy(&mut storage).is_err());
+}
+
+#[test]
+fn test_storage_dump_and_load() {
+    let mut storage = Contract::new_storage();
+    let mut contract = Contract::new_with_selector(
+        &mut storage,
+        ALICE.clone(),
+        include_bytes!("./fixtures/flip/flip.wasm").to_vec(),
+        hex!("9bae9d5e"), // init_value
+        true,
+        vec![],
+        vec![],
+        GAS_LIMIT,
+        0,
+        0,
+    )
+    .unwrap()
+    .0;
+    storage.commit_changes();
+    let _: () = contract
+        .call_with_selector(
+            &mut storage,
+            ALICE.clone(),
+            hex!("633aa551"), // flip
+            (),
+            false,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    storage.commit_changes();
+
+    // Round trip through the encoding sent to the joining workers
+    let dump = storage.dump();
+    let root = dump.root;
+    let dump = pink::StorageDump::decode(&mut &dump.encode()[..]).unwrap();
+    let mut restored = Contract::new_storage();
+    assert!(restored.load(dump));
+    assert_eq!(restored.dump().root, root);
+
+    let mut contract = Contract::from_address(contract.address.clone());
+    let result: bool = contract
+        .call_with_selector(
+            &mut restored,
+            ALICE.clone(),
+            hex!("2f865bd9"), // get
+            (),
+            true,
+            GAS_LIMIT,
+            0,
+            0,
+        )
+        .unwrap()
+        .0;
+    assert_eq!(result, false);
+
+    // A dump not matching its root is rejected
+    let mut dump = storage.dump();
+    dump.root = Hash::default();
+    assert!(!Contract::new_storage().load(dump));
+}
@@ -227,6 +227,7 @@ describe('A full stack', function () {
     describe('Contract', () => {
         let wasm_file = './res/flipper.wasm';
         let init_selector = hex('0xed4b9d1b'); // for default() function
+        let gasLimit = 1e12; // the cap of the constructor gas limit in pRuntime
         let code_hash;
         let contract_ids = [];
         let cluster_id;
@@ -253,7 +254,7 @@ describe('A full stack', function () {
             const code_index = api.createType('CodeIndex', { 'WasmCode': code_hash });
             const deploy_to = api.createType('DeployTarget', { 'NewGroup': [hex(info.publicKey)] });
             const { events } = await assert.txAccepted(
-                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 0, deploy_to),
+                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 0, deploy_to, null, gasLimit),
                 alice,
             );
             assertEvents(events, [
@@ -284,7 +285,7 @@ describe('A full stack', function () {
             const code_index = api.createType('CodeIndex', { 'WasmCode': code_hash });
             const deploy_to = api.createType('DeployTarget', { 'Cluster': cluster_id });
             await assert.txFailed(
-                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 0, deploy_to),
+                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 0, deploy_to, null, gasLimit),
                 alice,
             );
         });
@@ -293,7 +294,7 @@ describe('A full stack', function () {
             const code_index = api.createType('CodeIndex', { 'WasmCode': code_hash });
             const deploy_to = api.createType('DeployTarget', { 'Cluster': cluster_id });
             const { events } = await assert.txAccepted(
-                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 1, deploy_to),
+                api.tx.phalaFatContracts.instantiateContract(code_index, init_selector, 1, deploy_to, null, gasLimit),
                 alice,
             );
             assertEvents(events, [
@@ -7,7 +7,7 @@ pub mod pallet {
 	use frame_support::{dispatch::DispatchResult, pallet_prelude::*, traits::StorageVersion};
 	use frame_system::pallet_prelude::*;
 	use sp_core::H256;
-	use sp_runtime::traits::Hash;
+	use sp_runtime::{traits::Hash, SaturatedConversion};
 	use sp_std::prelude::*;
 	use sp_std::vec;
 
@@ -36,7 +36,10 @@ pub mod pallet {
 		type Event: From<Event<Self>> + IsType<<Self as frame_system::Config>::Event>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(1);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(2);
+
+	/// The number of blocks the reported cluster state roots are kept for comparison
+	pub const CLUSTER_STATE_ROOT_RETENTION: u32 = 1000;
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -62,14 +65,87 @@ pub mod pallet {
 	pub type ClusterWorkers<T> =
 		StorageMap<_, Twox64Concat, ContractClusterId, Vec<WorkerPublicKey>>;
 
+	/// The worker joining each cluster, until it reports the cluster state installed
+	#[pallet::storage]
+	pub type ClusterJoining<T> = StorageMap<_, Twox64Concat, ContractClusterId, WorkerPublicKey>;
+
+	/// The contract whose key each cluster was created with
+	///
+	/// Backfilled with the first contract of the clusters created before this record.
+	#[pallet::storage]
+	pub type ClusterKeyContracts<T> = StorageMap<_, Twox64Concat, ContractClusterId, ContractId>;
+
+	/// The first state root of a cluster reported after each block, with the reporting worker
+	///
+	/// The later reports of the block are checked against it. Kept for
+	/// `CLUSTER_STATE_ROOT_RETENTION` blocks.
+	#[pallet::storage]
+	pub type ClusterStateRoots<T> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		ContractClusterId,
+		Twox64Concat,
+		u32,
+		(WorkerPublicKey, H256),
+	>;
+
+	/// The clusters whose workers reported different state roots, with the block of the first
+	/// divergence
+	#[pallet::storage]
+	pub type DivergedClusters<T> = StorageMap<_, Twox64Concat, ContractClusterId, u32>;
+
+	/// Accounts allowed to upgrade a contract besides its deployer
+	#[pallet::storage]
+	pub type ContractAdmins<T: Config> = StorageMap<_, Twox64Concat, ContractId, T::AccountId>;
+
+	/// The code hash of the requested upgrade of a contract, until a worker reports the result
+	#[pallet::storage]
+	pub type PendingUpgrades<T> = StorageMap<_, Twox64Concat, ContractId, H256>;
+
+	/// The last block the key of a time-limited contract is valid in
+	#[pallet::storage]
+	pub type ContractExpiration<T: Config> =
+		StorageMap<_, Twox64Concat, ContractId, T::BlockNumber>;
+
+	/// The time-limited contracts by the last block they are valid in, to be removed after it
+	#[pallet::storage]
+	pub type ExpiringContracts<T: Config> =
+		StorageMap<_, Twox64Concat, T::BlockNumber, Vec<ContractId>, ValueQuery>;
+
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event<T: Config> {
 		CodeUploaded(CodeHash<T>),
 		PubkeyAvailable(ContractId, ContractPublicKey),
 		Instantiating(ContractId, ContractClusterId, T::AccountId),
-		Instantiated(ContractId, ContractClusterId, H256),
+		/// A worker instantiated the contract, with the gas consumed by the constructor
+		Instantiated(ContractId, ContractClusterId, H256, Option<u64>),
 		InstantiationFailed(ContractId, ContractClusterId, H256),
+		ContractAdminChanged(ContractId, Option<T::AccountId>),
+		Upgrading(ContractId, ContractClusterId, H256),
+		Upgraded(ContractId, ContractClusterId, H256),
+		UpgradeFailed(ContractId, ContractClusterId, H256),
+		Destroyed(ContractId, ContractClusterId),
+		/// The key of a time-limited contract expired, and the workers uninstalled it
+		Expired(ContractId, ContractClusterId),
+		Renewed(ContractId, ContractClusterId, Option<T::BlockNumber>),
+		ClusterWorkerAdded(ContractClusterId, WorkerPublicKey),
+		ClusterStateTransferring(ContractClusterId, WorkerPublicKey, WorkerPublicKey),
+		ClusterWorkerJoined(ContractClusterId, WorkerPublicKey),
+		ClusterWorkerRemoved(ContractClusterId, WorkerPublicKey),
+		ClusterJoinCancelled(ContractClusterId, WorkerPublicKey),
+		/// A member refused to send the state of a cluster of the given size, cancelling the join
+		ClusterStateTooLarge(ContractClusterId, WorkerPublicKey, u64),
+		/// Two workers of a cluster reported different state roots after the same block
+		ClusterStateDiverged(
+			ContractClusterId,
+			u32,
+			WorkerPublicKey,
+			H256,
+			WorkerPublicKey,
+			H256,
+		),
+		ClusterDivergenceCleared(ContractClusterId),
 	}
 
 	#[pallet::error]
@@ -81,10 +157,69 @@ pub mod pallet {
 		NoWorkerSpecified,
 		InvalidSender,
 		WorkerNotFound,
+		ContractNotFound,
+		NotUpgradable,
+		NotContractAdmin,
+		InvalidExpiration,
+		ContractExpired,
+		DuplicatedClusterWorker,
+		ClusterWorkerNotFound,
+		CannotRemoveLastClusterWorker,
+		ClusterJoinPending,
+		NoClusterJoinPending,
+		ClusterNotDiverged,
+		ClusterKeyNotFound,
 	}
 
 	type CodeHash<T> = <T as frame_system::Config>::Hash;
 
+	#[pallet::hooks]
+	impl<T: Config> Hooks<T::BlockNumber> for Pallet<T>
+	where
+		T: crate::mq::Config + crate::registry::Config,
+	{
+		fn on_finalize(n: T::BlockNumber) {
+			Self::remove_expired_contracts(n);
+		}
+
+		fn on_runtime_upgrade() -> Weight {
+			let mut w = 0;
+			let old = Self::on_chain_storage_version();
+			w += T::DbWeight::get().reads(1);
+
+			if old < 2 {
+				w += migrations::backfill_cluster_key_contracts::<T>();
+				STORAGE_VERSION.put::<super::Pallet<T>>();
+				w += T::DbWeight::get().writes(1);
+			}
+			w
+		}
+	}
+
+	mod migrations {
+		use super::{ClusterKeyContracts, Config, ContractClusters};
+		use frame_support::pallet_prelude::*;
+
+		/// Record the first contract of the existing clusters as their key contract, which is the
+		/// one the workers created them with.
+		pub fn backfill_cluster_key_contracts<T: Config>() -> Weight {
+			log::info!("phala_pallet::fat: backfill_cluster_key_contracts()");
+			let mut reads = 0;
+			let mut writes = 0;
+			for (cluster_id, contracts) in ContractClusters::<T>::iter() {
+				reads += 2;
+				if ClusterKeyContracts::<T>::contains_key(&cluster_id) {
+					continue;
+				}
+				if let Some(key_contract) = contracts.first() {
+					ClusterKeyContracts::<T>::insert(&cluster_id, key_contract);
+					writes += 1;
+				}
+			}
+			T::DbWeight::get().reads_writes(reads, writes)
+		}
+	}
+
 	#[pallet::call]
 	impl<T: Config> Pallet<T>
 	where
@@ -119,13 +254,15 @@ pub mod pallet {
 		pub fn instantiate_contract(
 			origin: OriginFor<T>,
 			// #[pallet::compact] endowment: BalanceOf<T>,
-			// #[pallet::compact] gas_limit: Weight,
 			code_index: CodeIndex<CodeHash<T>>,
 			data: Vec<u8>,
 			salt: Vec<u8>,
 			deploy_to: DeployTarget,
+			expiration: Option<T::BlockNumber>,
+			#[pallet::compact] gas_limit: Weight,
 		) -> DispatchResult {
 			let deployer = ensure_signed(origin)?;
+			Self::ensure_valid_expiration(expiration)?;
 
 			match code_index {
 				CodeIndex::NativeCode(_) => {}
@@ -137,12 +274,18 @@ pub mod pallet {
 				}
 			}
 
+			let new_cluster = matches!(deploy_to, DeployTarget::NewGroup(_));
 			let (cluster_id, deploy_workers) = match deploy_to {
 				DeployTarget::Cluster(cluster_id) => {
 					ensure!(
 						ClusterWorkers::<T>::contains_key(cluster_id),
 						Error::<T>::ContractClusterNotFound
 					);
+					// The joining worker has no state to instantiate the contract on
+					ensure!(
+						!ClusterJoining::<T>::contains_key(cluster_id),
+						Error::<T>::ClusterJoinPending
+					);
 					let workers = ClusterWorkers::<T>::get(cluster_id).expect("checked; qed.");
 					(cluster_id, workers)
 				}
@@ -207,14 +350,21 @@ pub mod pallet {
 				instantiate_data: data,
 			};
 			Contracts::<T>::insert(&contract_id, &contract_info);
+			Self::set_expiration(&contract_id, expiration);
 
 			let mut cluster = ContractClusters::<T>::try_get(&cluster_id).unwrap_or(vec![]);
 			cluster.push(contract_id);
 			ContractClusters::<T>::insert(&cluster_id, cluster);
+			// The workers create the cluster with the key of its first contract
+			if new_cluster {
+				ClusterKeyContracts::<T>::insert(&cluster_id, contract_id);
+			}
 
 			Self::push_message(ContractEvent::instantiate_code(
 				contract_info.clone(),
 				workers,
+				Self::encode_expiration(expiration),
+				gas_limit,
 			));
 			Self::deposit_event(Event::Instantiating(
 				contract_id,
@@ -224,12 +374,363 @@ pub mod pallet {
 
 			Ok(())
 		}
+
+		/// Allow `admin` to upgrade the contract besides the deployer, or revoke it with `None`
+		#[pallet::weight(0)]
+		pub fn set_contract_admin(
+			origin: OriginFor<T>,
+			contract_id: ContractId,
+			admin: Option<T::AccountId>,
+		) -> DispatchResult {
+			let origin: T::AccountId = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(&contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				contract_info.deployer == origin,
+				Error::<T>::NotContractAdmin
+			);
+			match &admin {
+				Some(admin) => ContractAdmins::<T>::insert(&contract_id, admin),
+				None => ContractAdmins::<T>::remove(&contract_id),
+			}
+			Self::deposit_event(Event::ContractAdminChanged(contract_id, admin));
+			Ok(())
+		}
+
+		/// Replace the code of a pink contract, keeping its storage and key
+		///
+		/// The new code must have been uploaded to the cluster with `upload_code_to_cluster`.
+		/// `migration`, if given, is the input of a message called on the new code right after
+		/// the replacement. The workers report the result with `WorkerContractReport`.
+		#[pallet::weight(0)]
+		pub fn upgrade_contract(
+			origin: OriginFor<T>,
+			contract_id: ContractId,
+			code_hash: H256,
+			migration: Option<Vec<u8>>,
+		) -> DispatchResult {
+			let origin: T::AccountId = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(&contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				matches!(contract_info.code_index, CodeIndex::WasmCode(_)),
+				Error::<T>::NotUpgradable
+			);
+			ensure!(
+				contract_info.deployer == origin
+					|| ContractAdmins::<T>::get(&contract_id).as_ref() == Some(&origin),
+				Error::<T>::NotContractAdmin
+			);
+			let cluster_id = contract_info.cluster_id;
+			PendingUpgrades::<T>::insert(&contract_id, code_hash);
+			Self::push_message(ContractOperation::UpgradeContract {
+				origin,
+				contract_id,
+				cluster_id,
+				code_hash,
+				migration,
+			});
+			Self::deposit_event(Event::Upgrading(contract_id, cluster_id, code_hash));
+			Ok(())
+		}
+
+		/// Remove a contract from its cluster, deleting its storage in the workers
+		#[pallet::weight(0)]
+		pub fn destroy_contract(origin: OriginFor<T>, contract_id: ContractId) -> DispatchResult {
+			let origin: T::AccountId = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(&contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				contract_info.deployer == origin
+					|| ContractAdmins::<T>::get(&contract_id).as_ref() == Some(&origin),
+				Error::<T>::NotContractAdmin
+			);
+			let cluster_id = contract_info.cluster_id;
+			Self::remove_contract(&contract_id, &cluster_id);
+			Self::push_message(ContractOperation::Destroy {
+				contract_id,
+				cluster_id,
+			});
+			Self::deposit_event(Event::Destroyed(contract_id, cluster_id));
+			Ok(())
+		}
+
+		/// Move the expiration of a time-limited contract, or lift it with `None`
+		///
+		/// The workers drop the key of a contract and uninstall it once the chain passes its
+		/// expiration, so an expired contract can't be renewed.
+		#[pallet::weight(0)]
+		pub fn renew_contract(
+			origin: OriginFor<T>,
+			contract_id: ContractId,
+			expiration: Option<T::BlockNumber>,
+		) -> DispatchResult {
+			let origin: T::AccountId = ensure_signed(origin)?;
+			let contract_info =
+				Contracts::<T>::get(&contract_id).ok_or(Error::<T>::ContractNotFound)?;
+			ensure!(
+				contract_info.deployer == origin
+					|| ContractAdmins::<T>::get(&contract_id).as_ref() == Some(&origin),
+				Error::<T>::NotContractAdmin
+			);
+			if let Some(current) = ContractExpiration::<T>::get(&contract_id) {
+				ensure!(
+					current >= frame_system::Pallet::<T>::block_number(),
+					Error::<T>::ContractExpired
+				);
+			}
+			Self::ensure_valid_expiration(expiration)?;
+			Self::set_expiration(&contract_id, expiration);
+			let cluster_id = contract_info.cluster_id;
+			Self::push_message(ContractOperation::RenewContract {
+				contract_id,
+				cluster_id,
+				expiration: Self::encode_expiration(expiration),
+			});
+			Self::deposit_event(Event::Renewed(contract_id, cluster_id, expiration));
+			Ok(())
+		}
+
+		/// Add a worker to an existing cluster
+		///
+		/// The gatekeeper sends the keys of the cluster to the worker, which then gets the state
+		/// of the cluster from an existing member. No contract can be instantiated in the cluster
+		/// until the worker reports it joined.
+		#[pallet::weight(0)]
+		pub fn add_cluster_worker(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			worker: WorkerPublicKey,
+		) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			let mut workers =
+				ClusterWorkers::<T>::get(&cluster_id).ok_or(Error::<T>::ContractClusterNotFound)?;
+			ensure!(
+				!workers.contains(&worker),
+				Error::<T>::DuplicatedClusterWorker
+			);
+			ensure!(
+				!ClusterJoining::<T>::contains_key(&cluster_id),
+				Error::<T>::ClusterJoinPending
+			);
+			let worker_info =
+				registry::Workers::<T>::try_get(&worker).or(Err(Error::<T>::WorkerNotFound))?;
+
+			let key_contract =
+				ClusterKeyContracts::<T>::get(&cluster_id).ok_or(Error::<T>::ClusterKeyNotFound)?;
+			let cluster_contracts = ContractClusters::<T>::get(&cluster_id).unwrap_or_default();
+			let contracts: Vec<_> = cluster_contracts
+				.into_iter()
+				.filter_map(|contract_id| {
+					let contract_info = Contracts::<T>::get(&contract_id)?;
+					let expiration =
+						Self::encode_expiration(ContractExpiration::<T>::get(&contract_id));
+					Some((contract_info, expiration))
+				})
+				.collect();
+			workers.push(worker);
+			ClusterWorkers::<T>::insert(&cluster_id, workers);
+			ClusterJoining::<T>::insert(&cluster_id, worker);
+			Self::push_message(ContractEvent::cluster_worker_added(
+				cluster_id,
+				WorkerIdentity {
+					pubkey: worker_info.pubkey,
+					ecdh_pubkey: worker_info.ecdh_pubkey,
+				},
+				key_contract,
+				contracts,
+			));
+			Self::deposit_event(Event::ClusterWorkerAdded(cluster_id, worker));
+			Ok(())
+		}
+
+		/// Give up adding the joining worker to a cluster, if it can't get the cluster state
+		///
+		/// The worker is removed from the cluster and forgets the keys of the cluster.
+		#[pallet::weight(0)]
+		pub fn cancel_cluster_join(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+		) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			Self::do_cancel_cluster_join(cluster_id)
+		}
+
+		/// Remove a worker from a cluster, which then forgets the keys and state of the cluster
+		#[pallet::weight(0)]
+		pub fn remove_cluster_worker(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+			worker: WorkerPublicKey,
+		) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			let mut workers =
+				ClusterWorkers::<T>::get(&cluster_id).ok_or(Error::<T>::ContractClusterNotFound)?;
+			ensure!(workers.contains(&worker), Error::<T>::ClusterWorkerNotFound);
+			ensure!(workers.len() > 1, Error::<T>::CannotRemoveLastClusterWorker);
+			// The state transfer to the joining worker may come from any other member
+			ensure!(
+				!ClusterJoining::<T>::contains_key(&cluster_id),
+				Error::<T>::ClusterJoinPending
+			);
+			workers.retain(|w| w != &worker);
+			ClusterWorkers::<T>::insert(&cluster_id, workers);
+			Self::push_message(ContractOperation::RemoveClusterWorker { cluster_id, worker });
+			Self::deposit_event(Event::ClusterWorkerRemoved(cluster_id, worker));
+			Ok(())
+		}
+
+		/// Clear the divergence flag of a cluster once the operators have dealt with it
+		#[pallet::weight(0)]
+		pub fn clear_cluster_divergence(
+			origin: OriginFor<T>,
+			cluster_id: ContractClusterId,
+		) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			ensure!(
+				DivergedClusters::<T>::contains_key(&cluster_id),
+				Error::<T>::ClusterNotDiverged
+			);
+			DivergedClusters::<T>::remove(&cluster_id);
+			Self::deposit_event(Event::ClusterDivergenceCleared(cluster_id));
+			Ok(())
+		}
 	}
 
 	impl<T: Config> Pallet<T>
 	where
 		T: crate::mq::Config + crate::registry::Config,
 	{
+		fn ensure_valid_expiration(expiration: Option<T::BlockNumber>) -> DispatchResult {
+			if let Some(expiration) = expiration {
+				ensure!(
+					expiration >= frame_system::Pallet::<T>::block_number(),
+					Error::<T>::InvalidExpiration
+				);
+			}
+			Ok(())
+		}
+
+		/// Ask a member of the cluster to send its state to the joining worker `dest`
+		///
+		/// The member is the one following `failed_source` in the cluster, or the first one.
+		fn transfer_cluster_state(
+			cluster_id: ContractClusterId,
+			dest: &WorkerPublicKey,
+			failed_source: Option<&WorkerPublicKey>,
+		) -> DispatchResult {
+			ensure!(
+				ClusterJoining::<T>::get(&cluster_id).as_ref() == Some(dest),
+				Error::<T>::InvalidSender
+			);
+			let worker_info =
+				registry::Workers::<T>::try_get(dest).or(Err(Error::<T>::WorkerNotFound))?;
+			let members: Vec<_> = ClusterWorkers::<T>::get(&cluster_id)
+				.unwrap_or_default()
+				.into_iter()
+				.filter(|w| w != dest)
+				.collect();
+			let next = failed_source
+				.and_then(|failed| members.iter().position(|w| w == failed))
+				.map_or(0, |i| i + 1);
+			let source = *members
+				.get(next % members.len().max(1))
+				.ok_or(Error::<T>::ClusterWorkerNotFound)?;
+			Self::push_message(ContractOperation::TransferClusterState {
+				cluster_id,
+				source,
+				dest: WorkerIdentity {
+					pubkey: worker_info.pubkey,
+					ecdh_pubkey: worker_info.ecdh_pubkey,
+				},
+				contracts: ContractClusters::<T>::get(&cluster_id).unwrap_or_default(),
+			});
+			Self::deposit_event(Event::ClusterStateTransferring(cluster_id, source, *dest));
+			Ok(())
+		}
+
+		/// Remove the joining worker from a cluster
+		fn do_cancel_cluster_join(cluster_id: ContractClusterId) -> DispatchResult {
+			let worker =
+				ClusterJoining::<T>::take(&cluster_id).ok_or(Error::<T>::NoClusterJoinPending)?;
+			ClusterWorkers::<T>::mutate(&cluster_id, |workers| {
+				if let Some(workers) = workers {
+					workers.retain(|w| w != &worker);
+				}
+			});
+			Self::push_message(ContractOperation::RemoveClusterWorker { cluster_id, worker });
+			Self::deposit_event(Event::ClusterJoinCancelled(cluster_id, worker));
+			Ok(())
+		}
+
+		/// Set or lift the expiration of a contract, keeping `ExpiringContracts` in sync
+		fn set_expiration(contract_id: &ContractId, expiration: Option<T::BlockNumber>) {
+			if let Some(current) = ContractExpiration::<T>::take(contract_id) {
+				ExpiringContracts::<T>::mutate(current, |contracts| {
+					contracts.retain(|id| id != contract_id)
+				});
+			}
+			if let Some(expiration) = expiration {
+				ContractExpiration::<T>::insert(contract_id, expiration);
+				ExpiringContracts::<T>::append(expiration, *contract_id);
+			}
+		}
+
+		/// Remove the on-chain records of a destroyed or expired contract
+		fn remove_contract(contract_id: &ContractId, cluster_id: &ContractClusterId) {
+			Self::set_expiration(contract_id, None);
+			Contracts::<T>::remove(contract_id);
+			ContractAdmins::<T>::remove(contract_id);
+			PendingUpgrades::<T>::remove(contract_id);
+			registry::ContractKeys::<T>::remove(contract_id);
+			ContractClusters::<T>::mutate(cluster_id, |contracts| {
+				if let Some(contracts) = contracts {
+					contracts.retain(|id| id != contract_id);
+				}
+			});
+		}
+
+		/// Remove the contracts whose keys are no longer valid after block `n`
+		///
+		/// The workers uninstall them on their own when processing the next block.
+		fn remove_expired_contracts(n: T::BlockNumber) {
+			for contract_id in ExpiringContracts::<T>::take(n) {
+				if let Some(contract_info) = Contracts::<T>::get(&contract_id) {
+					let cluster_id = contract_info.cluster_id;
+					Self::remove_contract(&contract_id, &cluster_id);
+					Self::deposit_event(Event::Expired(contract_id, cluster_id));
+				}
+			}
+		}
+
+		/// The expiration in the messages to the workers, where 0 means never
+		fn encode_expiration(expiration: Option<T::BlockNumber>) -> u32 {
+			expiration.map_or(0, |expiration| expiration.saturated_into())
+		}
+
+		/// Take the pending upgrade a cluster worker reported the result of.
+		///
+		/// Returns false if the upgrade was already reported by another worker.
+		fn take_pending_upgrade(
+			worker: &WorkerPublicKey,
+			contract_id: &ContractId,
+			cluster_id: &ContractClusterId,
+			code_hash: H256,
+		) -> Result<bool, DispatchError> {
+			let contract_cluster = Contracts::<T>::get(contract_id).map(|info| info.cluster_id);
+			ensure!(
+				contract_cluster.as_ref() == Some(cluster_id),
+				Error::<T>::ContractNotFound
+			);
+			let workers = ClusterWorkers::<T>::get(cluster_id).unwrap_or_default();
+			ensure!(workers.contains(worker), Error::<T>::InvalidSender);
+			if PendingUpgrades::<T>::get(contract_id) != Some(code_hash) {
+				return Ok(false);
+			}
+			PendingUpgrades::<T>::remove(contract_id);
+			Ok(true)
+		}
+
 		pub fn on_contract_message_received(
 			message: DecodedMessage<ContractRegistryEvent>,
 		) -> DispatchResult {
@@ -252,7 +753,7 @@ pub mod pallet {
 		pub fn on_worker_contract_message_received(
 			message: DecodedMessage<WorkerContractReport>,
 		) -> DispatchResult {
-			let _worker_pubkey = match &message.sender {
+			let worker_pubkey = match &message.sender {
 				MessageOrigin::Worker(worker_pubkey) => worker_pubkey,
 				_ => return Err(Error::<T>::InvalidSender.into()),
 			};
@@ -262,8 +763,14 @@ pub mod pallet {
 					cluster_id,
 					deployer,
 					pubkey: _,
+					gas_consumed,
 				} => {
-					Self::deposit_event(Event::Instantiated(id, cluster_id, deployer));
+					Self::deposit_event(Event::Instantiated(
+						id,
+						cluster_id,
+						deployer,
+						gas_consumed,
+					));
 				}
 				WorkerContractReport::ContractInstantiationFailed {
 					id,
@@ -273,6 +780,102 @@ pub mod pallet {
 					Self::deposit_event(Event::InstantiationFailed(id, cluster_id, deployer));
 					// TODO.shelven: some cleanup?
 				}
+				WorkerContractReport::ContractUpgraded {
+					id,
+					cluster_id,
+					code_hash,
+				} => {
+					if Self::take_pending_upgrade(worker_pubkey, &id, &cluster_id, code_hash)? {
+						Self::deposit_event(Event::Upgraded(id, cluster_id, code_hash));
+					}
+				}
+				WorkerContractReport::ContractUpgradeFailed {
+					id,
+					cluster_id,
+					code_hash,
+				} => {
+					if Self::take_pending_upgrade(worker_pubkey, &id, &cluster_id, code_hash)? {
+						Self::deposit_event(Event::UpgradeFailed(id, cluster_id, code_hash));
+					}
+				}
+				WorkerContractReport::ClusterStateRequested { cluster_id } => {
+					Self::transfer_cluster_state(cluster_id, worker_pubkey, None)?;
+				}
+				WorkerContractReport::ClusterStateRejected { cluster_id, source } => {
+					// Try the next member, in case the state of `source` is broken
+					Self::transfer_cluster_state(cluster_id, worker_pubkey, Some(&source))?;
+				}
+				WorkerContractReport::ClusterStateTooLarge { cluster_id, size } => {
+					let workers = ClusterWorkers::<T>::get(&cluster_id).unwrap_or_default();
+					let joining = ClusterJoining::<T>::get(&cluster_id);
+					ensure!(
+						workers.contains(worker_pubkey) && joining.as_ref() != Some(worker_pubkey),
+						Error::<T>::InvalidSender
+					);
+					Self::deposit_event(Event::ClusterStateTooLarge(
+						cluster_id,
+						*worker_pubkey,
+						size,
+					));
+					// No member can send it either
+					Self::do_cancel_cluster_join(cluster_id)?;
+				}
+				WorkerContractReport::ClusterJoined { cluster_id } => {
+					ensure!(
+						ClusterJoining::<T>::get(&cluster_id).as_ref() == Some(worker_pubkey),
+						Error::<T>::InvalidSender
+					);
+					ClusterJoining::<T>::remove(&cluster_id);
+					Self::deposit_event(Event::ClusterWorkerJoined(cluster_id, *worker_pubkey));
+				}
+				WorkerContractReport::ClusterStateRoot {
+					cluster_id,
+					block_number,
+					root,
+				} => {
+					let workers = ClusterWorkers::<T>::get(&cluster_id).unwrap_or_default();
+					ensure!(
+						workers.contains(worker_pubkey),
+						Error::<T>::ClusterWorkerNotFound
+					);
+					let now: u32 = frame_system::Pallet::<T>::block_number().saturated_into();
+					let oldest_kept = now.saturating_sub(CLUSTER_STATE_ROOT_RETENTION);
+					if block_number < oldest_kept {
+						// Too late to be compared with the other reports
+						return Ok(());
+					}
+					match ClusterStateRoots::<T>::get(&cluster_id, block_number) {
+						Some((worker, reported_root)) => {
+							if reported_root != root {
+								if !DivergedClusters::<T>::contains_key(&cluster_id) {
+									DivergedClusters::<T>::insert(&cluster_id, block_number);
+								}
+								Self::deposit_event(Event::ClusterStateDiverged(
+									cluster_id,
+									block_number,
+									worker,
+									reported_root,
+									*worker_pubkey,
+									root,
+								));
+							}
+						}
+						None => {
+							let expired: Vec<u32> =
+								ClusterStateRoots::<T>::iter_key_prefix(&cluster_id)
+									.filter(|reported_at| *reported_at < oldest_kept)
+									.collect();
+							for reported_at in expired {
+								ClusterStateRoots::<T>::remove(&cluster_id, reported_at);
+							}
+							ClusterStateRoots::<T>::insert(
+								&cluster_id,
+								block_number,
+								(*worker_pubkey, root),
+							);
+						}
+					}
+				}
 			}
 			Ok(())
 		}
@@ -281,4 +884,433 @@ pub mod pallet {
 	impl<T: Config + crate::mq::Config> MessageOriginInfo for Pallet<T> {
 		type Config = T;
 	}
+
+	#[cfg(test)]
+	mod test {
+		use frame_support::{assert_noop, assert_ok};
+
+		use super::*;
+		use crate::mock::{
+			new_test_ext, set_block_1, setup_workers, take_events, worker_pubkey, Origin, Test,
+		};
+		// Pallets
+		use crate::mock::{PhalaFatContracts, System};
+		use phala_types::messaging::BindTopic;
+
+		fn worker_report(worker: u8, report: WorkerContractReport) -> DispatchResult {
+			PhalaFatContracts::on_worker_contract_message_received(DecodedMessage {
+				sender: MessageOrigin::Worker(worker_pubkey(worker)),
+				destination: WorkerContractReport::topic().into(),
+				payload: report,
+			})
+		}
+
+		fn last_event() -> Option<crate::mock::Event> {
+			take_events().pop()
+		}
+
+		fn fat_event(event: Event<Test>) -> Option<crate::mock::Event> {
+			Some(crate::mock::Event::PhalaFatContracts(event))
+		}
+
+		#[test]
+		fn test_backfill_cluster_key_contracts() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				let cluster = ContractClusterId::from_low_u64_be;
+				let contract = ContractId::from_low_u64_be;
+				ContractClusters::<Test>::insert(cluster(1), vec![contract(1), contract(2)]);
+				ContractClusters::<Test>::insert(cluster(2), vec![contract(3), contract(4)]);
+				ClusterKeyContracts::<Test>::insert(cluster(2), contract(5));
+				ClusterWorkers::<Test>::insert(cluster(1), vec![worker_pubkey(2)]);
+
+				// No fallback for the clusters without a key contract
+				assert_noop!(
+					PhalaFatContracts::add_cluster_worker(
+						Origin::root(),
+						cluster(1),
+						worker_pubkey(1)
+					),
+					Error::<Test>::ClusterKeyNotFound
+				);
+
+				StorageVersion::new(1).put::<PhalaFatContracts>();
+				PhalaFatContracts::on_runtime_upgrade();
+				assert_eq!(
+					PhalaFatContracts::on_chain_storage_version(),
+					STORAGE_VERSION
+				);
+				assert_eq!(
+					ClusterKeyContracts::<Test>::get(cluster(1)),
+					Some(contract(1))
+				);
+				assert_eq!(
+					ClusterKeyContracts::<Test>::get(cluster(2)),
+					Some(contract(5))
+				);
+				assert_ok!(PhalaFatContracts::add_cluster_worker(
+					Origin::root(),
+					cluster(1),
+					worker_pubkey(1)
+				));
+			});
+		}
+
+		#[test]
+		fn test_add_and_remove_cluster_workers() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(3);
+				let cluster_id = ContractClusterId::from_low_u64_be(1);
+
+				assert_ok!(PhalaFatContracts::instantiate_contract(
+					Origin::signed(1),
+					CodeIndex::NativeCode(0),
+					vec![],
+					vec![],
+					DeployTarget::NewGroup(vec![worker_pubkey(1), worker_pubkey(3)]),
+					None,
+					0,
+				));
+				let key_contract = ContractClusters::<Test>::get(&cluster_id).unwrap()[0];
+				assert_eq!(
+					ClusterKeyContracts::<Test>::get(&cluster_id),
+					Some(key_contract)
+				);
+
+				// Nothing else can change the cluster while a worker joins
+				assert_ok!(PhalaFatContracts::add_cluster_worker(
+					Origin::root(),
+					cluster_id,
+					worker_pubkey(2)
+				));
+				assert_eq!(
+					ClusterJoining::<Test>::get(&cluster_id),
+					Some(worker_pubkey(2))
+				);
+				assert_noop!(
+					PhalaFatContracts::add_cluster_worker(
+						Origin::root(),
+						cluster_id,
+						worker_pubkey(2)
+					),
+					Error::<Test>::DuplicatedClusterWorker
+				);
+				assert_noop!(
+					PhalaFatContracts::instantiate_contract(
+						Origin::signed(1),
+						CodeIndex::NativeCode(0),
+						vec![],
+						vec![1],
+						DeployTarget::Cluster(cluster_id),
+						None,
+						0,
+					),
+					Error::<Test>::ClusterJoinPending
+				);
+				assert_noop!(
+					PhalaFatContracts::remove_cluster_worker(
+						Origin::root(),
+						cluster_id,
+						worker_pubkey(1)
+					),
+					Error::<Test>::ClusterJoinPending
+				);
+
+				// The members take turns to send the state
+				assert_noop!(
+					worker_report(
+						3,
+						WorkerContractReport::ClusterStateRequested { cluster_id }
+					),
+					Error::<Test>::InvalidSender
+				);
+				assert_ok!(worker_report(
+					2,
+					WorkerContractReport::ClusterStateRequested { cluster_id }
+				));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::ClusterStateTransferring(
+						cluster_id,
+						worker_pubkey(1),
+						worker_pubkey(2)
+					))
+				);
+				assert_ok!(worker_report(
+					2,
+					WorkerContractReport::ClusterStateRejected {
+						cluster_id,
+						source: worker_pubkey(1),
+					}
+				));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::ClusterStateTransferring(
+						cluster_id,
+						worker_pubkey(3),
+						worker_pubkey(2)
+					))
+				);
+				assert_ok!(worker_report(
+					2,
+					WorkerContractReport::ClusterStateRejected {
+						cluster_id,
+						source: worker_pubkey(3),
+					}
+				));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::ClusterStateTransferring(
+						cluster_id,
+						worker_pubkey(1),
+						worker_pubkey(2)
+					))
+				);
+				assert_ok!(worker_report(
+					2,
+					WorkerContractReport::ClusterJoined { cluster_id }
+				));
+				assert_eq!(ClusterJoining::<Test>::get(&cluster_id), None);
+
+				// Remove down to the last worker
+				assert_ok!(PhalaFatContracts::remove_cluster_worker(
+					Origin::root(),
+					cluster_id,
+					worker_pubkey(1)
+				));
+				assert_ok!(PhalaFatContracts::remove_cluster_worker(
+					Origin::root(),
+					cluster_id,
+					worker_pubkey(3)
+				));
+				assert_noop!(
+					PhalaFatContracts::remove_cluster_worker(
+						Origin::root(),
+						cluster_id,
+						worker_pubkey(2)
+					),
+					Error::<Test>::CannotRemoveLastClusterWorker
+				);
+				assert_eq!(
+					ClusterWorkers::<Test>::get(&cluster_id),
+					Some(vec![worker_pubkey(2)])
+				);
+			});
+		}
+
+		#[test]
+		fn test_cancel_cluster_join() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(2);
+				let cluster_id = ContractClusterId::from_low_u64_be(1);
+
+				assert_ok!(PhalaFatContracts::instantiate_contract(
+					Origin::signed(1),
+					CodeIndex::NativeCode(0),
+					vec![],
+					vec![],
+					DeployTarget::NewGroup(vec![worker_pubkey(1)]),
+					None,
+					0,
+				));
+				assert_noop!(
+					PhalaFatContracts::cancel_cluster_join(Origin::root(), cluster_id),
+					Error::<Test>::NoClusterJoinPending
+				);
+				assert_ok!(PhalaFatContracts::add_cluster_worker(
+					Origin::root(),
+					cluster_id,
+					worker_pubkey(2)
+				));
+				assert_ok!(PhalaFatContracts::cancel_cluster_join(
+					Origin::root(),
+					cluster_id
+				));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::ClusterJoinCancelled(cluster_id, worker_pubkey(2)))
+				);
+				assert_eq!(ClusterJoining::<Test>::get(&cluster_id), None);
+				assert_eq!(
+					ClusterWorkers::<Test>::get(&cluster_id),
+					Some(vec![worker_pubkey(1)])
+				);
+				assert_noop!(
+					worker_report(2, WorkerContractReport::ClusterJoined { cluster_id }),
+					Error::<Test>::InvalidSender
+				);
+
+				// A member fails the join if the state is too large to send
+				assert_ok!(PhalaFatContracts::add_cluster_worker(
+					Origin::root(),
+					cluster_id,
+					worker_pubkey(2)
+				));
+				let too_large = || WorkerContractReport::ClusterStateTooLarge {
+					cluster_id,
+					size: 1 << 30,
+				};
+				assert_noop!(worker_report(2, too_large()), Error::<Test>::InvalidSender);
+				take_events();
+				assert_ok!(worker_report(1, too_large()));
+				assert_eq!(
+					take_events(),
+					vec![
+						crate::mock::Event::PhalaFatContracts(Event::ClusterStateTooLarge(
+							cluster_id,
+							worker_pubkey(1),
+							1 << 30
+						)),
+						crate::mock::Event::PhalaFatContracts(Event::ClusterJoinCancelled(
+							cluster_id,
+							worker_pubkey(2)
+						)),
+					]
+				);
+				assert_eq!(ClusterJoining::<Test>::get(&cluster_id), None);
+				assert_eq!(
+					ClusterWorkers::<Test>::get(&cluster_id),
+					Some(vec![worker_pubkey(1)])
+				);
+			});
+		}
+
+		#[test]
+		fn test_contract_expiration() {
+			use frame_support::traits::OnFinalize;
+
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				let cluster_id = ContractClusterId::from_low_u64_be(1);
+
+				assert_noop!(
+					PhalaFatContracts::instantiate_contract(
+						Origin::signed(1),
+						CodeIndex::NativeCode(0),
+						vec![],
+						vec![],
+						DeployTarget::NewGroup(vec![worker_pubkey(1)]),
+						Some(0),
+						0,
+					),
+					Error::<Test>::InvalidExpiration
+				);
+				assert_ok!(PhalaFatContracts::instantiate_contract(
+					Origin::signed(1),
+					CodeIndex::NativeCode(0),
+					vec![],
+					vec![],
+					DeployTarget::NewGroup(vec![worker_pubkey(1)]),
+					Some(10),
+					0,
+				));
+				let contract_id = ContractClusters::<Test>::get(&cluster_id).unwrap()[0];
+				assert_eq!(ExpiringContracts::<Test>::get(10), vec![contract_id]);
+
+				// Only the deployer or the admin can renew
+				assert_noop!(
+					PhalaFatContracts::renew_contract(Origin::signed(2), contract_id, Some(20)),
+					Error::<Test>::NotContractAdmin
+				);
+				assert_ok!(PhalaFatContracts::renew_contract(
+					Origin::signed(1),
+					contract_id,
+					Some(20)
+				));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::Renewed(contract_id, cluster_id, Some(20)))
+				);
+				assert_eq!(ContractExpiration::<Test>::get(&contract_id), Some(20));
+				assert!(ExpiringContracts::<Test>::get(10).is_empty());
+				assert_eq!(ExpiringContracts::<Test>::get(20), vec![contract_id]);
+				PhalaFatContracts::on_finalize(10);
+				assert!(Contracts::<Test>::contains_key(&contract_id));
+
+				// Removed from the chain after the last valid block
+				PhalaFatContracts::on_finalize(20);
+				assert_eq!(
+					last_event(),
+					fat_event(Event::Expired(contract_id, cluster_id))
+				);
+				assert!(!Contracts::<Test>::contains_key(&contract_id));
+				assert_eq!(ContractExpiration::<Test>::get(&contract_id), None);
+				assert_eq!(ContractClusters::<Test>::get(&cluster_id), Some(vec![]));
+				assert_noop!(
+					PhalaFatContracts::renew_contract(Origin::signed(1), contract_id, None),
+					Error::<Test>::ContractNotFound
+				);
+			});
+		}
+
+		#[test]
+		fn test_cluster_state_roots() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(3);
+				let cluster_id = ContractClusterId::from_low_u64_be(1);
+				let report = |worker: u8, block_number: u32, root: u64| {
+					worker_report(
+						worker,
+						WorkerContractReport::ClusterStateRoot {
+							cluster_id,
+							block_number,
+							root: H256::from_low_u64_be(root),
+						},
+					)
+				};
+
+				assert_ok!(PhalaFatContracts::instantiate_contract(
+					Origin::signed(1),
+					CodeIndex::NativeCode(0),
+					vec![],
+					vec![],
+					DeployTarget::NewGroup(vec![worker_pubkey(1), worker_pubkey(2)]),
+					None,
+					0,
+				));
+				assert_noop!(report(3, 1, 1), Error::<Test>::ClusterWorkerNotFound);
+
+				// Each report is compared to the first one of the same block
+				assert_ok!(report(1, 100, 1));
+				assert_ok!(report(2, 100, 1));
+				assert_ok!(report(1, 200, 2));
+				take_events();
+				assert_ok!(report(2, 100, 3));
+				assert_eq!(
+					last_event(),
+					fat_event(Event::ClusterStateDiverged(
+						cluster_id,
+						100,
+						worker_pubkey(1),
+						H256::from_low_u64_be(1),
+						worker_pubkey(2),
+						H256::from_low_u64_be(3),
+					))
+				);
+				assert_eq!(DivergedClusters::<Test>::get(&cluster_id), Some(100));
+				assert_ok!(report(2, 200, 2));
+				assert_eq!(last_event(), None);
+				assert_ok!(PhalaFatContracts::clear_cluster_divergence(
+					Origin::root(),
+					cluster_id
+				));
+
+				// The roots of the old blocks are pruned, and late reports ignored
+				System::set_block_number(200 + CLUSTER_STATE_ROOT_RETENTION);
+				assert_ok!(report(1, 300, 4));
+				assert_eq!(
+					ClusterStateRoots::<Test>::iter_key_prefix(&cluster_id).count(),
+					2
+				);
+				assert_ok!(report(2, 100, 5));
+				assert!(!ClusterStateRoots::<Test>::contains_key(&cluster_id, 100));
+				assert_eq!(DivergedClusters::<Test>::get(&cluster_id), None);
+			});
+		}
+	}
 }
@@ -1,6 +1,6 @@
 use crate::{
 	attestation::{Attestation, AttestationValidator, Error as AttestationError, IasFields},
-	mining, mq, ott, registry, stakepool,
+	fat, mining, mq, ott, registry, stakepool,
 };
 
 use frame_support::{
@@ -39,6 +39,7 @@ frame_support::construct_runtime!(
 		PhalaMining: mining::{Pallet, Event<T>, Storage, Config},
 		PhalaStakePool: stakepool::{Pallet, Event<T>},
 		PhalaOneshotTransfer: ott::{Pallet, Event<T>},
+		PhalaFatContracts: fat::{Pallet, Event<T>, Storage},
 	}
 );
 
@@ -161,6 +162,10 @@ impl ott::Config for Test {
 	type Currency = Balances;
 }
 
+impl fat::Config for Test {
+	type Event = Event;
+}
+
 pub struct MockValidator;
 impl AttestationValidator for MockValidator {
 	fn validate(
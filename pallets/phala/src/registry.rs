@@ -8,6 +8,7 @@ pub mod pallet {
 		dispatch::DispatchResult,
 		pallet_prelude::*,
 		traits::{Currency, StorageVersion, UnixTime},
+		transactional,
 	};
 	use frame_system::pallet_prelude::*;
 	use scale_info::TypeInfo;
@@ -32,8 +33,19 @@ pub mod pallet {
 	bind_topic!(RegistryEvent, b"^phala/registry/event");
 	#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
 	pub enum RegistryEvent {
-		BenchReport { start_time: u64, iterations: u64 },
-		MasterPubkey { master_pubkey: MasterPublicKey },
+		BenchReport {
+			start_time: u64,
+			iterations: u64,
+		},
+		MasterPubkey {
+			master_pubkey: MasterPublicKey,
+		},
+		RotatedMasterPubkey {
+			rotation_id: u64,
+			master_pubkey: MasterPublicKey,
+		},
+		/// The gatekeeper has wiped its master key after being unregistered
+		GatekeeperDemoted,
 	}
 
 	#[pallet::config]
@@ -60,6 +72,12 @@ pub mod pallet {
 
 	const STORAGE_VERSION: StorageVersion = StorageVersion::new(1);
 
+	/// The number of blocks the replaced master key is still accepted for after a rotation.
+	///
+	/// The gatekeepers only switch to the new master key once its pubkey is published on chain, so
+	/// the messages signed before that are still on their way.
+	const MASTER_KEY_ROTATION_GRACE_PERIOD: u32 = 100;
+
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
 	#[pallet::storage_version(STORAGE_VERSION)]
@@ -73,6 +91,21 @@ pub mod pallet {
 	#[pallet::storage]
 	pub type GatekeeperMasterPubkey<T: Config> = StorageValue<_, MasterPublicKey>;
 
+	/// The id of the latest requested master key rotation
+	#[pallet::storage]
+	pub type MasterKeyRotationId<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Mapping from rotation id to the master pubkey it published
+	///
+	/// The initial master pubkey is kept under id 0. A rotation is still in progress while its id
+	/// is missing here, and an aborted rotation keeps the master pubkey it would have replaced.
+	#[pallet::storage]
+	pub type MasterPubkeyHistory<T: Config> = StorageMap<_, Twox64Concat, u64, MasterPublicKey>;
+
+	/// The block the master pubkey was last rotated in
+	#[pallet::storage]
+	pub type MasterPubkeyRotatedAt<T: Config> = StorageValue<_, T::BlockNumber>;
+
 	/// Mapping from worker pubkey to WorkerInfo
 	#[pallet::storage]
 	pub type Workers<T: Config> =
@@ -108,6 +141,11 @@ pub mod pallet {
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event<T: Config> {
 		GatekeeperAdded(WorkerPublicKey),
+		GatekeeperRemoved(WorkerPublicKey),
+		GatekeeperDemoted(WorkerPublicKey),
+		MasterKeyRotationRequested(u64),
+		MasterPubkeyRotated(u64, MasterPublicKey),
+		MasterKeyRotationAborted(u64),
 	}
 
 	#[pallet::error]
@@ -137,6 +175,10 @@ pub mod pallet {
 		InvalidMasterPubkey,
 		MasterKeyMismatch,
 		MasterKeyUninitialized,
+		MasterKeyInRotation,
+		MasterKeyNotInRotation,
+		CannotRemoveLastGatekeeper,
+		InvalidMasterKeyRotation,
 		// GenesisBlockHash related
 		GenesisBlockHashRejected,
 		GenesisBlockHashAlreadyExists,
@@ -240,19 +282,78 @@ pub mod pallet {
 			Ok(())
 		}
 
-		/// Unregister a gatekeeper, must be called by gatekeeper himself
+		/// Unregister a gatekeeper.
 		///
-		/// Requirements:
-		//  1. `sig` is the valid signature of specific unregister message
-		#[allow(unused_variables)]
-		#[pallet::weight(0)]
+		/// The removed gatekeeper wipes its master key, and the master key is rotated among the
+		/// remaining gatekeepers if it's already generated.
+		///
+		/// Must be called by the Root origin.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(3))]
+		#[transactional]
 		pub fn unregister_gatekeeper(
 			origin: OriginFor<T>,
 			gatekeeper: WorkerPublicKey,
-			sig: [u8; 64],
 		) -> DispatchResult {
-			// TODO.shelven
-			panic!("unimpleneted");
+			T::GovernanceOrigin::ensure_origin(origin)?;
+
+			let mut gatekeepers = Gatekeeper::<T>::get();
+			ensure!(
+				gatekeepers.contains(&gatekeeper),
+				Error::<T>::InvalidGatekeeper
+			);
+			ensure!(
+				gatekeepers.len() > 1,
+				Error::<T>::CannotRemoveLastGatekeeper
+			);
+			// A pending rotation would share the new master key with the removed gatekeeper
+			ensure!(
+				!Self::master_key_in_rotation(),
+				Error::<T>::MasterKeyInRotation
+			);
+
+			gatekeepers.retain(|pubkey| pubkey != &gatekeeper);
+			Gatekeeper::<T>::put(gatekeepers);
+			Self::push_message(GatekeeperChange::gatekeeper_unregistered(gatekeeper));
+			Self::deposit_event(Event::<T>::GatekeeperRemoved(gatekeeper));
+
+			if GatekeeperMasterPubkey::<T>::get().is_some() {
+				Self::do_rotate_master_key()?;
+			}
+			Ok(())
+		}
+
+		/// Ask the gatekeepers to replace the master key with a newly generated one.
+		///
+		/// The old master keys are kept by the gatekeepers to decrypt the historical data. The new
+		/// master pubkey is published once a gatekeeper reports it.
+		///
+		/// Must be called by the Root origin.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(2))]
+		pub fn rotate_master_key(origin: OriginFor<T>) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			Self::do_rotate_master_key()
+		}
+
+		/// Abort the pending master key rotation and keep the current master key.
+		///
+		/// The new master pubkey of the aborted rotation is rejected if reported later, and the
+		/// gatekeepers discard the new master key they received.
+		///
+		/// Must be called by the Root origin.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(2))]
+		pub fn abort_master_key_rotation(origin: OriginFor<T>) -> DispatchResult {
+			T::GovernanceOrigin::ensure_origin(origin)?;
+			ensure!(
+				Self::master_key_in_rotation(),
+				Error::<T>::MasterKeyNotInRotation
+			);
+			let master_pubkey =
+				GatekeeperMasterPubkey::<T>::get().ok_or(Error::<T>::MasterKeyUninitialized)?;
+			let rotation_id = MasterKeyRotationId::<T>::get();
+			MasterPubkeyHistory::<T>::insert(rotation_id, master_pubkey);
+			Self::push_message(GatekeeperLaunch::master_key_rotation_aborted(rotation_id));
+			Self::deposit_event(Event::<T>::MasterKeyRotationAborted(rotation_id));
+			Ok(())
 		}
 
 		/// (called by anyone on behalf of a worker)
@@ -412,6 +513,46 @@ pub mod pallet {
 	where
 		T: crate::mq::Config,
 	{
+		fn master_key_in_rotation() -> bool {
+			let rotation_id = MasterKeyRotationId::<T>::get();
+			rotation_id != 0 && !MasterPubkeyHistory::<T>::contains_key(rotation_id)
+		}
+
+		fn do_rotate_master_key() -> DispatchResult {
+			let master_pubkey =
+				GatekeeperMasterPubkey::<T>::get().ok_or(Error::<T>::MasterKeyUninitialized)?;
+			ensure!(
+				!Self::master_key_in_rotation(),
+				Error::<T>::MasterKeyInRotation
+			);
+			let last_rotation_id = MasterKeyRotationId::<T>::get();
+			if last_rotation_id == 0 {
+				// The master pubkey may be published before the history is recorded
+				MasterPubkeyHistory::<T>::insert(0, master_pubkey);
+			}
+
+			let gk_identities = Gatekeeper::<T>::get()
+				.into_iter()
+				.map(|gatekeeper| {
+					let worker_info =
+						Workers::<T>::try_get(&gatekeeper).or(Err(Error::<T>::WorkerNotFound))?;
+					Ok(messaging::NewGatekeeperEvent {
+						pubkey: gatekeeper,
+						ecdh_pubkey: worker_info.ecdh_pubkey,
+					})
+				})
+				.collect::<Result<Vec<_>, Error<T>>>()?;
+
+			let rotation_id = last_rotation_id + 1;
+			MasterKeyRotationId::<T>::put(rotation_id);
+			Self::push_message(GatekeeperLaunch::rotate_master_key(
+				rotation_id,
+				gk_identities,
+			));
+			Self::deposit_event(Event::<T>::MasterKeyRotationRequested(rotation_id));
+			Ok(())
+		}
+
 		pub fn check_message(message: &SignedMessage) -> DispatchResult {
 			let pubkey_copy: ContractPublicKey;
 			let pubkey = match &message.message.sender {
@@ -424,13 +565,34 @@ pub mod pallet {
 					// GatekeeperMasterPubkey should not be None
 					pubkey_copy = GatekeeperMasterPubkey::<T>::get()
 						.ok_or(Error::<T>::MasterKeyUninitialized)?;
-					&pubkey_copy
+					let result = Self::verify_signature(&pubkey_copy, message);
+					if result.is_err() {
+						if let Some(retired_pubkey) = Self::retired_master_pubkey() {
+							return Self::verify_signature(&retired_pubkey, message);
+						}
+					}
+					return result;
 				}
 				_ => return Err(Error::<T>::CannotHandleUnknownMessage.into()),
 			};
 			Self::verify_signature(pubkey, message)
 		}
 
+		/// The master pubkey replaced by the last rotation if it's still in the grace period
+		fn retired_master_pubkey() -> Option<MasterPublicKey> {
+			let rotated_at = MasterPubkeyRotatedAt::<T>::get()?;
+			let now = frame_system::Pallet::<T>::block_number();
+			if now > rotated_at + MASTER_KEY_ROTATION_GRACE_PERIOD.into() {
+				return None;
+			}
+			let master_pubkey = GatekeeperMasterPubkey::<T>::get()?;
+			// Aborted rotations repeat the master pubkey they would have replaced
+			(0..MasterKeyRotationId::<T>::get())
+				.rev()
+				.filter_map(MasterPubkeyHistory::<T>::get)
+				.find(|pubkey| *pubkey != master_pubkey)
+		}
+
 		fn verify_signature(pubkey: &WorkerPublicKey, message: &SignedMessage) -> DispatchResult {
 			let raw_sig = &message.signature;
 			ensure!(raw_sig.len() == 64, Error::<T>::InvalidSignatureLength);
@@ -493,12 +655,59 @@ pub mod pallet {
 						}
 						_ => {
 							GatekeeperMasterPubkey::<T>::put(master_pubkey);
+							MasterPubkeyHistory::<T>::insert(0, master_pubkey);
 							Self::push_message(GatekeeperLaunch::master_pubkey_on_chain(
 								master_pubkey,
 							));
 						}
 					}
 				}
+				RegistryEvent::RotatedMasterPubkey {
+					rotation_id,
+					master_pubkey,
+				} => {
+					let gatekeepers = Gatekeeper::<T>::get();
+					ensure!(
+						gatekeepers.contains(worker_pubkey),
+						Error::<T>::InvalidGatekeeper
+					);
+					match MasterPubkeyHistory::<T>::try_get(rotation_id) {
+						Ok(saved_pubkey) => {
+							// Reported by the other gatekeepers
+							ensure!(
+								saved_pubkey.0 == master_pubkey.0,
+								Error::<T>::MasterKeyMismatch
+							);
+						}
+						_ => {
+							ensure!(
+								rotation_id == MasterKeyRotationId::<T>::get(),
+								Error::<T>::InvalidMasterKeyRotation
+							);
+							GatekeeperMasterPubkey::<T>::put(master_pubkey);
+							MasterPubkeyHistory::<T>::insert(rotation_id, master_pubkey);
+							MasterPubkeyRotatedAt::<T>::put(
+								frame_system::Pallet::<T>::block_number(),
+							);
+							Self::push_message(GatekeeperLaunch::master_pubkey_rotated(
+								rotation_id,
+								master_pubkey,
+							));
+							Self::deposit_event(Event::<T>::MasterPubkeyRotated(
+								rotation_id,
+								master_pubkey,
+							));
+						}
+					}
+				}
+				RegistryEvent::GatekeeperDemoted => {
+					let gatekeepers = Gatekeeper::<T>::get();
+					ensure!(
+						!gatekeepers.contains(worker_pubkey),
+						Error::<T>::InvalidGatekeeper
+					);
+					Self::deposit_event(Event::<T>::GatekeeperDemoted(*worker_pubkey));
+				}
 			}
 			Ok(())
 		}
@@ -798,5 +1007,200 @@ pub mod pallet {
 				assert_eq!(RelaychainGenesisBlockHashAllowList::<Test>::get().len(), 0);
 			});
 		}
+
+		#[test]
+		fn test_rotate_master_key() {
+			use phala_types::messaging::Topic;
+
+			fn gatekeeper_say(event: RegistryEvent) -> DispatchResult {
+				PhalaRegistry::on_message_received(DecodedMessage::<RegistryEvent> {
+					sender: MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32])),
+					destination: Topic::new(*b"^phala/registry/event"),
+					payload: event,
+				})
+			}
+
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				let master_pubkey = |i: u8| sp_core::sr25519::Public::from_raw([i; 32]);
+
+				assert_noop!(
+					PhalaRegistry::rotate_master_key(Origin::root()),
+					Error::<Test>::MasterKeyUninitialized
+				);
+				assert_ok!(gatekeeper_say(RegistryEvent::MasterPubkey {
+					master_pubkey: master_pubkey(1),
+				}));
+
+				assert_ok!(PhalaRegistry::rotate_master_key(Origin::root()));
+				assert_eq!(MasterKeyRotationId::<Test>::get(), 1);
+				assert_noop!(
+					PhalaRegistry::rotate_master_key(Origin::root()),
+					Error::<Test>::MasterKeyInRotation
+				);
+
+				// Only the pending rotation can be published
+				assert_noop!(
+					gatekeeper_say(RegistryEvent::RotatedMasterPubkey {
+						rotation_id: 2,
+						master_pubkey: master_pubkey(2),
+					}),
+					Error::<Test>::InvalidMasterKeyRotation
+				);
+				assert_ok!(gatekeeper_say(RegistryEvent::RotatedMasterPubkey {
+					rotation_id: 1,
+					master_pubkey: master_pubkey(2),
+				}));
+				assert_eq!(
+					GatekeeperMasterPubkey::<Test>::get(),
+					Some(master_pubkey(2))
+				);
+				assert_eq!(MasterPubkeyHistory::<Test>::get(0), Some(master_pubkey(1)));
+				assert_eq!(MasterPubkeyHistory::<Test>::get(1), Some(master_pubkey(2)));
+				assert_noop!(
+					gatekeeper_say(RegistryEvent::RotatedMasterPubkey {
+						rotation_id: 1,
+						master_pubkey: master_pubkey(3),
+					}),
+					Error::<Test>::MasterKeyMismatch
+				);
+
+				// Ready for the next rotation
+				assert_ok!(PhalaRegistry::rotate_master_key(Origin::root()));
+				assert_eq!(MasterKeyRotationId::<Test>::get(), 2);
+			});
+		}
+
+		#[test]
+		fn test_abort_master_key_rotation() {
+			use phala_types::messaging::Topic;
+			use sp_core::Pair;
+
+			fn gatekeeper_say(event: RegistryEvent) -> DispatchResult {
+				PhalaRegistry::on_message_received(DecodedMessage::<RegistryEvent> {
+					sender: MessageOrigin::Worker(sp_core::sr25519::Public::from_raw([0u8; 32])),
+					destination: Topic::new(*b"^phala/registry/event"),
+					payload: event,
+				})
+			}
+
+			fn signed_by(master_key: &sp_core::sr25519::Pair) -> SignedMessage {
+				let mut message = SignedMessage {
+					message: phala_types::messaging::Message {
+						sender: MessageOrigin::Gatekeeper,
+						destination: Topic::new(*b"^phala/registry/event"),
+						payload: vec![],
+					},
+					sequence: 0,
+					signature: vec![],
+				};
+				message.signature = master_key.sign(&message.data_be_signed()).0.to_vec();
+				message
+			}
+
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				let master_key = |i: u8| sp_core::sr25519::Pair::from_seed(&[i; 32]);
+
+				assert_ok!(gatekeeper_say(RegistryEvent::MasterPubkey {
+					master_pubkey: master_key(1).public(),
+				}));
+				assert_noop!(
+					PhalaRegistry::abort_master_key_rotation(Origin::root()),
+					Error::<Test>::MasterKeyNotInRotation
+				);
+
+				// The replaced master key is accepted for a while
+				assert_ok!(PhalaRegistry::rotate_master_key(Origin::root()));
+				assert_ok!(gatekeeper_say(RegistryEvent::RotatedMasterPubkey {
+					rotation_id: 1,
+					master_pubkey: master_key(2).public(),
+				}));
+				assert_ok!(PhalaRegistry::check_message(&signed_by(&master_key(2))));
+				assert_ok!(PhalaRegistry::check_message(&signed_by(&master_key(1))));
+				assert_noop!(
+					PhalaRegistry::check_message(&signed_by(&master_key(3))),
+					Error::<Test>::InvalidSignature
+				);
+
+				// Aborting a rotation keeps the master key and its grace period
+				assert_ok!(PhalaRegistry::rotate_master_key(Origin::root()));
+				assert_ok!(PhalaRegistry::abort_master_key_rotation(Origin::root()));
+				assert_eq!(
+					MasterPubkeyHistory::<Test>::get(2),
+					Some(master_key(2).public())
+				);
+				assert_noop!(
+					gatekeeper_say(RegistryEvent::RotatedMasterPubkey {
+						rotation_id: 2,
+						master_pubkey: master_key(3).public(),
+					}),
+					Error::<Test>::MasterKeyMismatch
+				);
+				assert_eq!(
+					GatekeeperMasterPubkey::<Test>::get(),
+					Some(master_key(2).public())
+				);
+				assert_ok!(PhalaRegistry::check_message(&signed_by(&master_key(1))));
+
+				crate::mock::System::set_block_number(
+					1 + MASTER_KEY_ROTATION_GRACE_PERIOD as u64 + 1,
+				);
+				assert_noop!(
+					PhalaRegistry::check_message(&signed_by(&master_key(1))),
+					Error::<Test>::InvalidSignature
+				);
+				assert_ok!(PhalaRegistry::check_message(&signed_by(&master_key(2))));
+
+				// Ready for the next rotation
+				assert_ok!(PhalaRegistry::rotate_master_key(Origin::root()));
+				assert_eq!(MasterKeyRotationId::<Test>::get(), 3);
+			});
+		}
+
+		#[test]
+		fn test_unregister_gatekeeper() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				let genesis_gatekeeper = sp_core::sr25519::Public::from_raw([0u8; 32]);
+				assert_ok!(PhalaRegistry::force_register_worker(
+					Origin::root(),
+					worker_pubkey(1),
+					ecdh_pubkey(1),
+					None
+				));
+
+				assert_noop!(
+					PhalaRegistry::unregister_gatekeeper(Origin::root(), worker_pubkey(1)),
+					Error::<Test>::InvalidGatekeeper
+				);
+				assert_noop!(
+					PhalaRegistry::unregister_gatekeeper(Origin::root(), genesis_gatekeeper),
+					Error::<Test>::CannotRemoveLastGatekeeper
+				);
+
+				GatekeeperMasterPubkey::<Test>::put(sp_core::sr25519::Public::from_raw([1u8; 32]));
+				assert_ok!(PhalaRegistry::register_gatekeeper(
+					Origin::root(),
+					worker_pubkey(1)
+				));
+				assert_ok!(PhalaRegistry::unregister_gatekeeper(
+					Origin::root(),
+					genesis_gatekeeper
+				));
+				assert_eq!(Gatekeeper::<Test>::get(), vec![worker_pubkey(1)]);
+				// Re-keyed among the remaining gatekeepers
+				assert_eq!(MasterKeyRotationId::<Test>::get(), 1);
+
+				// The removed gatekeeper reports its demotion
+				assert_ok!(PhalaRegistry::on_message_received(DecodedMessage::<
+					RegistryEvent,
+				> {
+					sender: MessageOrigin::Worker(genesis_gatekeeper),
+					destination: phala_types::messaging::Topic::new(*b"^phala/registry/event"),
+					payload: RegistryEvent::GatekeeperDemoted,
+				}));
+			});
+		}
 	}
 }
@@ -86,6 +86,8 @@ enum PinkCommand {
     Command {
         id: String,
         message: String,
+        #[structopt(long, default_value = "1000000000000")]
+        gas_limit: u64,
     },
 }
 
@@ -231,7 +233,11 @@ async fn handle_pink_command(command: PinkCommand) {
 
     #[derive(Debug, Encode, Decode)]
     pub enum Command {
-        InkMessage { nonce: Vec<u8>, message: Vec<u8> },
+        InkMessage {
+            nonce: Vec<u8>,
+            message: Vec<u8>,
+            gas_limit: u64,
+        },
     }
 
     #[derive(Debug, Encode, Decode)]
@@ -268,7 +274,11 @@ async fn handle_pink_command(command: PinkCommand) {
                 }
             }
         }
-        PinkCommand::Command { id, message }  => {
+        PinkCommand::Command {
+            id,
+            message,
+            gas_limit,
+        } => {
             #[derive(Encode)]
             enum Payload<T> {
                 Plain(T)
@@ -278,7 +288,11 @@ async fn handle_pink_command(command: PinkCommand) {
             let id = ContractId::decode(&mut &id[..]).expect("Bad contract id");
             let message = decode_hex(&message);
             let nonce = vec![];
-            let command = Command::InkMessage{ nonce, message };
+            let command = Command::InkMessage {
+                nonce,
+                message,
+                gas_limit,
+            };
             let mq_payload = Payload::Plain(command);
             println!("topic: (0x{})", hex::encode(phala_types::contract::command_topic(id)));
             println!("command: (0x{})", hex::encode(mq_payload.encode()));
@@ -0,0 +1,320 @@
+//! The on-disk cache of the finalized blocks fetched from the nodes.
+//!
+//! The cached data is stored in files named by the block hash, with an index from the block
+//! number to the hash for each chain. Only finalized blocks are cached, so the index never
+//! changes once written. It allows pherry to resume syncing after restarts and pRuntime errors
+//! without downloading the blocks again.
+//!
+//! The genesis hashes of the chains are recorded in the cache directory, so that a directory
+//! filled from another network is never served to the bridge.
+
+use anyhow::{anyhow, Context, Result};
+use codec::{Decode, Encode};
+use log::warn;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::types::{BlockNumber, BlockWithChanges, Hash, Header};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chain {
+    /// The relaychain in parachain mode, whose blocks are cached without storage changes
+    Relay,
+    /// The chain the storage changes are dispatched from
+    Para,
+}
+
+impl Chain {
+    pub const ALL: [Chain; 2] = [Chain::Relay, Chain::Para];
+
+    fn dir_name(&self) -> &'static str {
+        match self {
+            Chain::Relay => "relaychain",
+            Chain::Para => "parachain",
+        }
+    }
+}
+
+impl FromStr for Chain {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "relay" | "relaychain" => Ok(Chain::Relay),
+            "para" | "parachain" => Ok(Chain::Para),
+            _ => Err(anyhow!("Unknown chain {}, expect relay or para", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Block,
+    Header,
+}
+
+impl Kind {
+    fn dir_name(&self) -> &'static str {
+        match self {
+            Kind::Block => "blocks",
+            Kind::Header => "headers",
+        }
+    }
+}
+
+/// The cached items of a chain
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub blocks: usize,
+    pub headers: usize,
+    pub lowest: Option<BlockNumber>,
+    pub highest: Option<BlockNumber>,
+    pub bytes: u64,
+}
+
+/// The file recording the genesis hashes of the cached chains
+const GENESIS_FILE: &str = "genesis";
+
+#[derive(Clone)]
+pub struct BlockCache {
+    dir: PathBuf,
+}
+
+impl BlockCache {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for chain in Chain::ALL.iter() {
+            for kind in [Kind::Block, Kind::Header].iter() {
+                fs::create_dir_all(dir.join(chain.dir_name()).join(kind.dir_name()))
+                    .with_context(|| format!("Failed to create cache dir {}", dir.display()))?;
+            }
+        }
+        Ok(Self { dir })
+    }
+
+    /// Open the cache of the chains with the given genesis hashes, failing if the directory holds
+    /// the cache of other chains.
+    pub fn open_for_chains(
+        dir: impl AsRef<Path>,
+        relay_genesis: &Hash,
+        para_genesis: &Hash,
+    ) -> Result<Self> {
+        let cache = Self::open(dir)?;
+        let genesis = (relay_genesis, para_genesis).encode();
+        let path = cache.dir.join(GENESIS_FILE);
+        match fs::read(&path) {
+            Ok(recorded) if recorded == genesis => {}
+            Ok(_) => {
+                return Err(anyhow!(
+                    "Cache dir {} belongs to other chains",
+                    cache.dir.display()
+                ))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => write_atomically(&path, &genesis)?,
+            Err(err) => return Err(err.into()),
+        }
+        Ok(cache)
+    }
+
+    fn kind_dir(&self, chain: Chain, kind: Kind) -> PathBuf {
+        self.dir.join(chain.dir_name()).join(kind.dir_name())
+    }
+
+    fn index_path(&self, chain: Chain, kind: Kind, number: BlockNumber) -> PathBuf {
+        self.kind_dir(chain, kind).join(format!("{}.idx", number))
+    }
+
+    fn data_path(&self, chain: Chain, kind: Kind, hash: &Hash) -> PathBuf {
+        self.kind_dir(chain, kind).join(hex::encode(hash))
+    }
+
+    fn get<T: Decode>(&self, chain: Chain, kind: Kind, number: BlockNumber) -> Option<T> {
+        let hash = fs::read(self.index_path(chain, kind, number)).ok()?;
+        let hash = Hash::decode(&mut &hash[..]).ok()?;
+        let data = fs::read(self.data_path(chain, kind, &hash)).ok()?;
+        match T::decode(&mut &data[..]) {
+            Ok(item) => Some(item),
+            Err(err) => {
+                warn!(
+                    "Ignoring corrupted cache of {:?} {} {:?}: {:?}",
+                    chain, number, kind, err
+                );
+                None
+            }
+        }
+    }
+
+    fn put(
+        &self,
+        chain: Chain,
+        kind: Kind,
+        number: BlockNumber,
+        hash: &Hash,
+        item: &impl Encode,
+    ) -> Result<()> {
+        // Write the data before the index, both atomically, so that a crash never leaves an
+        // index pointing to missing or partial data.
+        write_atomically(&self.data_path(chain, kind, hash), &item.encode())?;
+        write_atomically(&self.index_path(chain, kind, number), &hash.encode())
+    }
+
+    pub fn get_block(&self, chain: Chain, number: BlockNumber) -> Option<BlockWithChanges> {
+        self.get(chain, Kind::Block, number)
+    }
+
+    /// Cache a block, which must have been finalized.
+    pub fn put_block(&self, chain: Chain, block: &BlockWithChanges) -> Result<()> {
+        let header = &block.block.block.header;
+        self.put(chain, Kind::Block, header.number, &header.hash(), block)
+    }
+
+    pub fn get_header(&self, chain: Chain, number: BlockNumber) -> Option<Header> {
+        self.get(chain, Kind::Header, number)
+    }
+
+    /// Cache a header, which must have been finalized.
+    pub fn put_header(&self, chain: Chain, header: &Header) -> Result<()> {
+        self.put(chain, Kind::Header, header.number, &header.hash(), header)
+    }
+
+    /// Remove the cached items of the chain below the given block number, returning the number of
+    /// removed items.
+    pub fn prune(&self, chain: Chain, below: BlockNumber) -> Result<usize> {
+        let mut removed = 0;
+        for kind in [Kind::Block, Kind::Header].iter() {
+            for (number, index_path) in self.indexes(chain, *kind)? {
+                if number >= below {
+                    continue;
+                }
+                if let Ok(hash) = fs::read(&index_path) {
+                    if let Ok(hash) = Hash::decode(&mut &hash[..]) {
+                        let _ = fs::remove_file(self.data_path(chain, *kind, &hash));
+                    }
+                }
+                fs::remove_file(&index_path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn stats(&self, chain: Chain) -> Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        for kind in [Kind::Block, Kind::Header].iter() {
+            let indexes = self.indexes(chain, *kind)?;
+            match kind {
+                Kind::Block => stats.blocks = indexes.len(),
+                Kind::Header => stats.headers = indexes.len(),
+            }
+            for (number, _) in indexes {
+                stats.lowest = Some(stats.lowest.map_or(number, |n| n.min(number)));
+                stats.highest = Some(stats.highest.map_or(number, |n| n.max(number)));
+            }
+            for entry in fs::read_dir(self.kind_dir(chain, *kind))? {
+                stats.bytes += entry?.metadata()?.len();
+            }
+        }
+        Ok(stats)
+    }
+
+    fn indexes(&self, chain: Chain, kind: Kind) -> Result<Vec<(BlockNumber, PathBuf)>> {
+        let mut indexes = Vec::new();
+        for entry in fs::read_dir(self.kind_dir(chain, kind))? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("idx") {
+                continue;
+            }
+            let number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            if let Some(number) = number {
+                indexes.push((number, path));
+            }
+        }
+        Ok(indexes)
+    }
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Block, SignedBlock};
+    use sp_runtime::{traits::Header as _, OpaqueExtrinsic};
+    use tempdir::TempDir;
+
+    fn header(number: BlockNumber) -> Header {
+        Header::new(
+            number,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    fn block(number: BlockNumber) -> BlockWithChanges {
+        BlockWithChanges {
+            block: SignedBlock::<Header, OpaqueExtrinsic> {
+                block: Block {
+                    header: header(number),
+                    extrinsics: vec![],
+                },
+                justifications: None,
+            },
+            storage_changes: Default::default(),
+        }
+    }
+
+    #[test]
+    fn put_get_prune_and_stats() {
+        let dir = TempDir::new("pherry-cache").unwrap();
+        let cache = BlockCache::open(dir.path()).unwrap();
+        for number in 1..=3 {
+            cache.put_block(Chain::Para, &block(number)).unwrap();
+        }
+        cache.put_header(Chain::Para, &header(4)).unwrap();
+        cache.put_block(Chain::Relay, &block(10)).unwrap();
+
+        let cached = cache.get_block(Chain::Para, 2).unwrap();
+        assert_eq!(cached.block.block.header, header(2));
+        assert_eq!(cache.get_header(Chain::Para, 4), Some(header(4)));
+        assert!(cache.get_block(Chain::Para, 4).is_none());
+        assert!(cache.get_block(Chain::Relay, 2).is_none());
+
+        let stats = cache.stats(Chain::Para).unwrap();
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.headers, 1);
+        assert_eq!(stats.lowest, Some(1));
+        assert_eq!(stats.highest, Some(4));
+        assert!(stats.bytes > 0);
+
+        assert_eq!(cache.prune(Chain::Para, 3).unwrap(), 2);
+        assert!(cache.get_block(Chain::Para, 2).is_none());
+        assert!(cache.get_block(Chain::Para, 3).is_some());
+        let stats = cache.stats(Chain::Para).unwrap();
+        assert_eq!((stats.blocks, stats.lowest), (1, Some(3)));
+        // Other chains are left untouched
+        assert_eq!(cache.stats(Chain::Relay).unwrap().blocks, 1);
+    }
+
+    #[test]
+    fn cache_of_other_chains_is_refused() {
+        let dir = TempDir::new("pherry-cache").unwrap();
+        let relay = Hash::repeat_byte(1);
+        let para = Hash::repeat_byte(2);
+        BlockCache::open_for_chains(dir.path(), &relay, &para).unwrap();
+        assert!(BlockCache::open_for_chains(dir.path(), &relay, &para).is_ok());
+        assert!(BlockCache::open_for_chains(dir.path(), &relay, &relay).is_err());
+        assert!(BlockCache::open_for_chains(dir.path(), &para, &para).is_err());
+    }
+}
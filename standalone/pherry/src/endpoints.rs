@@ -0,0 +1,171 @@
+//! Health checks of the nodes to fail over between the endpoints of a chain.
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use phaxt::rpc::{ExtraRpcExt as _, SyncState};
+use phaxt::subxt::{self, DefaultConfig};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+
+use crate::error::Error;
+use crate::subxt_connect;
+use crate::types::{BlockNumber, Header, KhalaConfig};
+
+/// How far a node can be behind the network and still be considered synced
+const MAX_SYNC_LAG: u64 = 8;
+/// How far the finalized head of the current node can be behind another node before failing over
+const MAX_FINALIZED_LAG: BlockNumber = 8;
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn is_synced(state: &SyncState) -> bool {
+    match state.highest_block {
+        Some(highest) => highest.saturating_sub(state.current_block) <= MAX_SYNC_LAG,
+        None => false,
+    }
+}
+
+#[derive(Debug)]
+struct Health {
+    synced: bool,
+    finalized: BlockNumber,
+}
+
+impl Health {
+    /// Synced nodes go first, and then the ones with a higher finalized head
+    fn rank(&self) -> (bool, BlockNumber) {
+        (self.synced, self.finalized)
+    }
+}
+
+async fn check_health<T: subxt::Config<Header = Header>>(
+    client: &subxt::Client<T>,
+) -> Result<Health> {
+    let state = client.extra_rpc().system_sync_state().await?;
+    let hash = client.rpc().finalized_head().await?;
+    let header = client
+        .rpc()
+        .header(Some(hash))
+        .await?
+        .ok_or(Error::BlockNotFound)?;
+    Ok(Health {
+        synced: is_synced(&state),
+        finalized: header.number,
+    })
+}
+
+async fn connect_and_check<T: subxt::Config<Header = Header>>(
+    uri: &str,
+) -> Result<(subxt::Client<T>, Health)> {
+    let check = async {
+        let client = subxt_connect(uri).await?;
+        let health = check_health(&client).await?;
+        Ok((client, health))
+    };
+    timeout(CHECK_TIMEOUT, check)
+        .await
+        .map_err(|_| anyhow!("Health check timed out"))?
+}
+
+/// Connects to the healthiest node among the endpoints.
+pub async fn connect_healthiest<T: subxt::Config<Header = Header>>(
+    uris: &[String],
+) -> Result<(subxt::Client<T>, String)> {
+    if let [uri] = uris {
+        // Nothing to choose from, leave the sync state to `wait_until_synced`
+        return Ok((subxt_connect(uri).await?, uri.clone()));
+    }
+    let mut best: Option<(subxt::Client<T>, Health, &String)> = None;
+    for uri in uris {
+        match connect_and_check(uri).await {
+            Ok((client, health)) => {
+                info!("Endpoint {}: {:?}", uri, health);
+                let better = match &best {
+                    Some((_, best_health, _)) => health.rank() > best_health.rank(),
+                    None => true,
+                };
+                if better {
+                    best = Some((client, health, uri));
+                }
+            }
+            Err(err) => warn!("Endpoint {} is unavailable: {:?}", uri, err),
+        }
+    }
+    let (client, _, uri) = best.ok_or_else(|| anyhow!("No available endpoint in {:?}", uris))?;
+    Ok((client, uri.clone()))
+}
+
+/// Checks whether there is a healthier node to fail over to than the current one.
+///
+/// Returns an error if the current node is unavailable, or is lagging behind another one.
+pub async fn check_failover<T: subxt::Config<Header = Header>>(
+    current: &str,
+    uris: &[String],
+) -> Result<()> {
+    let current_health = connect_and_check::<T>(current)
+        .await
+        .map_err(|err| anyhow!("Endpoint {} is unavailable: {:?}", current, err))?
+        .1;
+    for uri in uris.iter().filter(|uri| *uri != current) {
+        let health = match connect_and_check::<T>(uri).await {
+            Ok((_, health)) => health,
+            Err(_) => continue,
+        };
+        let lagging = (health.synced && !current_health.synced)
+            || health.finalized > current_health.finalized + MAX_FINALIZED_LAG;
+        if lagging {
+            return Err(anyhow!(
+                "Endpoint {} ({:?}) is lagging behind {} ({:?})",
+                current,
+                current_health,
+                uri,
+                health
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The nodes the bridge connected to
+pub struct Connected {
+    pub relay_uri: String,
+    /// None in solochain mode, where the relaychain node serves as both
+    pub para_uri: Option<String>,
+}
+
+/// Periodically checks the nodes the bridge connected to against the other endpoints.
+///
+/// Returns when the bridge should be restarted to reconnect to the healthiest nodes. It runs
+/// beside the bridge rather than in it, so that it fails over even if the bridge is stuck in an RPC
+/// to a dead node.
+pub async fn watch_failover(
+    relay_uris: &[String],
+    para_uris: &[String],
+    interval: Duration,
+    connected: oneshot::Receiver<Connected>,
+) {
+    let connected = match connected.await {
+        Ok(connected) => connected,
+        // The bridge quit before connecting, and is going to be restarted anyway
+        Err(_) => return std::future::pending().await,
+    };
+    loop {
+        sleep(interval).await;
+        if relay_uris.len() > 1 {
+            if let Err(err) =
+                check_failover::<DefaultConfig>(&connected.relay_uri, relay_uris).await
+            {
+                warn!("{:?}, failing over...", err);
+                return;
+            }
+        }
+        if let Some(para_uri) = &connected.para_uri {
+            if para_uris.len() > 1 {
+                if let Err(err) = check_failover::<KhalaConfig>(para_uri, para_uris).await {
+                    warn!("{:?}, failing over...", err);
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -10,6 +10,8 @@ pub enum Error {
     FailedToCallRegisterWorker,
     ParachainIdNotFound,
     ParachainValidationDataNotFound,
+    NoJustificationInRange,
+    ParachainHeadNotFound,
 }
 
 impl fmt::Display for Error {
@@ -25,6 +27,8 @@ impl fmt::Display for Error {
             Error::ParachainValidationDataNotFound => {
                 write!(f, "parachain validation data not found")
             }
+            Error::NoJustificationInRange => write!(f, "no justification found in the range"),
+            Error::ParachainHeadNotFound => write!(f, "parachain head not found"),
         }
     }
 }
@@ -5,25 +5,31 @@ use std::cmp;
 use std::str::FromStr;
 use std::time::Duration;
 use structopt::StructOpt;
+use tokio::sync::oneshot;
 use tokio::time::sleep;
 
 use codec::Decode;
 use phaxt::rpc::ExtraRpcExt as _;
 use phaxt::subxt;
+use phaxt::subxt::DefaultConfig;
 use sp_core::{crypto::Pair, sr25519, storage::StorageKey};
 use sp_finality_grandpa::{AuthorityList, SetId, VersionedAuthorityList, GRANDPA_AUTHORITIES_KEY};
 
+mod cache;
+mod endpoints;
 mod error;
 mod msg_sync;
 mod notify_client;
+mod prefetch;
 
 pub mod chain_client;
 pub mod types;
 
+use crate::cache::{BlockCache, Chain as CacheChain};
 use crate::error::Error;
 use crate::types::{
-    BlockNumber, BlockWithChanges, Hash, Header, NotifyReq, NumberOrHex, ParachainApi, PrClient,
-    RelaychainApi, SignedBlock, SrSigner,
+    BlockNumber, BlockWithChanges, Hash, Header, KhalaConfig, NotifyReq, NumberOrHex, ParachainApi,
+    PrClient, RelaychainApi, SignedBlock, SrSigner,
 };
 use phactory_api::blocks::{
     self, AuthoritySet, AuthoritySetChange, BlockHeaderWithChanges, HeaderToSync, StorageProof,
@@ -33,6 +39,7 @@ use phactory_api::pruntime_client;
 
 use msg_sync::{Error as MsgSyncError, Receiver, Sender};
 use notify_client::NotifyClient;
+use prefetch::BlockPrefetcher;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "pherry")]
@@ -81,16 +88,25 @@ struct Args {
     #[structopt(
         default_value = "ws://localhost:9944",
         long,
-        help = "Substrate rpc websocket endpoint"
+        use_delimiter = true,
+        help = "Substrate rpc websocket endpoints, separated by commas. The healthiest one is used and the others are failed over to"
     )]
-    substrate_ws_endpoint: String,
+    substrate_ws_endpoint: Vec<String>,
 
     #[structopt(
         default_value = "ws://localhost:9977",
         long,
-        help = "Parachain collator rpc websocket endpoint"
+        use_delimiter = true,
+        help = "Parachain collator rpc websocket endpoints, separated by commas. The healthiest one is used and the others are failed over to"
     )]
-    collator_ws_endpoint: String,
+    collator_ws_endpoint: Vec<String>,
+
+    #[structopt(
+        default_value = "60",
+        long,
+        help = "The interval in seconds to check if the connected nodes are lagging behind the other endpoints"
+    )]
+    health_check_interval: u64,
 
     #[structopt(
         default_value = "http://localhost:8000",
@@ -125,6 +141,13 @@ struct Args {
     )]
     sync_blocks: usize,
 
+    #[structopt(
+        default_value = "1000",
+        long = "prefetch-blocks",
+        help = "The max number of blocks to be fetched in the background ahead of pRuntime."
+    )]
+    prefetch_blocks: usize,
+
     #[structopt(
         long = "operator",
         help = "The operator account to set the miner for the worker."
@@ -140,6 +163,12 @@ struct Args {
     )]
     start_header: Option<BlockNumber>,
 
+    #[structopt(
+        long,
+        help = "Initialize pRuntime at the last justified relaychain block instead of replaying the chain since the start header"
+    )]
+    warp: bool,
+
     #[structopt(long, help = "Don't wait the substrate nodes to sync blocks")]
     no_wait: bool,
 
@@ -181,6 +210,34 @@ struct Args {
 
     #[structopt(long, help = "Restart if number of rpc errors reaches the threshold")]
     restart_on_rpc_error_threshold: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Cache the fetched finalized blocks in the directory to resume syncing without downloading them again"
+    )]
+    cache_dir: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Manage the block cache in --cache-dir
+    Cache(CacheCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// Show the cached blocks and headers of each chain
+    Inspect,
+    /// Remove the cached blocks and headers below a block number
+    Prune {
+        #[structopt(long, help = "Remove the items below this block number")]
+        before: BlockNumber,
+        #[structopt(long, help = "The chain to prune (relay or para), default to both")]
+        chain: Option<CacheChain>,
+    },
 }
 
 struct RunningFlags {
@@ -254,6 +311,43 @@ pub async fn get_block_with_storage_changes(
     });
 }
 
+fn put_cache(cache: Option<&BlockCache>, put: impl FnOnce(&BlockCache) -> Result<()>) {
+    if let Some(cache) = cache {
+        // The cache is only an optimization, never stop syncing because of it
+        if let Err(err) = put(cache) {
+            warn!("Failed to write the block cache: {:?}", err);
+        }
+    }
+}
+
+/// Gets a finalized relaychain block without storage changes, from the cache if available
+async fn get_relay_block_cached(
+    cache: Option<&BlockCache>,
+    api: &RelaychainApi,
+    number: BlockNumber,
+) -> Result<BlockWithChanges> {
+    if let Some(block) = cache.and_then(|cache| cache.get_block(CacheChain::Relay, number)) {
+        return Ok(block);
+    }
+    let block = get_block_without_storage_changes(api, Some(number)).await?;
+    put_cache(cache, |cache| cache.put_block(CacheChain::Relay, &block));
+    Ok(block)
+}
+
+/// Gets a finalized block with storage changes, from the cache if available
+async fn get_para_block_cached(
+    cache: Option<&BlockCache>,
+    api: &ParachainApi,
+    number: BlockNumber,
+) -> Result<BlockWithChanges> {
+    if let Some(block) = cache.and_then(|cache| cache.get_block(CacheChain::Para, number)) {
+        return Ok(block);
+    }
+    let block = get_block_with_storage_changes(api, Some(number)).await?;
+    put_cache(cache, |cache| cache.put_block(CacheChain::Para, &block));
+    Ok(block)
+}
+
 async fn get_authority_with_proof_at(
     api: &RelaychainApi,
     hash: Hash,
@@ -417,6 +511,8 @@ async fn batch_sync_block(
     batch_window: usize,
     info: &prpc::PhactoryInfo,
     parachain: bool,
+    cache: Option<&BlockCache>,
+    para_blocks: &mut BlockPrefetcher,
 ) -> Result<usize> {
     let block_buf = &mut sync_state.blocks;
     if block_buf.is_empty() {
@@ -535,14 +631,20 @@ async fn batch_sync_block(
         next_headernum = r.synced_to + 1;
 
         if parachain {
-            let hdr_synced_to =
-                sync_parachain_header(pr, api, paraclient, last_header_hash, next_para_headernum)
-                    .await?;
+            let hdr_synced_to = sync_parachain_header(
+                pr,
+                api,
+                paraclient,
+                last_header_hash,
+                next_para_headernum,
+                cache,
+            )
+            .await?;
             next_para_headernum = hdr_synced_to + 1;
             let mut para_blocks = Vec::new();
             if next_blocknum <= hdr_synced_to {
                 for b in next_blocknum..=hdr_synced_to {
-                    let block = get_block_with_storage_changes(&paraclient, Some(b)).await?;
+                    let block = para_blocks.recv(b).await?;
                     para_blocks.push(block.clone());
                 }
             }
@@ -587,6 +689,7 @@ async fn sync_parachain_header(
     para_api: &ParachainApi,
     last_header_hash: Hash,
     next_headernum: BlockNumber,
+    cache: Option<&BlockCache>,
 ) -> Result<BlockNumber> {
     let para_id = get_paraid(para_api, None).await?;
     let para_head_storage_key = chain_client::paras_heads_key(para_id);
@@ -626,6 +729,10 @@ async fn sync_parachain_header(
     }
     let mut para_headers = Vec::new();
     for b in next_headernum..=para_fin_block_number {
+        if let Some(header) = cache.and_then(|cache| cache.get_header(CacheChain::Para, b)) {
+            para_headers.push(header);
+            continue;
+        }
         let num = subxt::BlockNumber::from(NumberOrHex::Number(b.into()));
         let hash = para_api.client.rpc().block_hash(Some(num)).await?;
         let hash = match hash {
@@ -641,6 +748,7 @@ async fn sync_parachain_header(
             .header(Some(hash))
             .await?
             .ok_or(Error::BlockNotFound)?;
+        put_cache(cache, |cache| cache.put_header(CacheChain::Para, &header));
         para_headers.push(header);
     }
     let r = req_sync_para_header(pr, para_headers, header_proof).await?;
@@ -680,6 +788,143 @@ async fn resolve_start_header(
     Ok((validation_data.relay_parent_number - 1) as BlockNumber)
 }
 
+fn grandpa_justification(
+    block: &SignedBlock<Header, sp_runtime::OpaqueExtrinsic>,
+) -> Option<Vec<u8>> {
+    block
+        .justifications
+        .clone()
+        .map(|v| v.into_justification(GRANDPA_ENGINE_ID))
+        .flatten()
+}
+
+async fn get_set_id_at(api: &RelaychainApi, number: BlockNumber) -> Result<SetId> {
+    let hash = get_header_hash(&api.client, Some(number)).await?;
+    let set_id = api
+        .storage()
+        .grandpa()
+        .current_set_id(Some(hash))
+        .await
+        .map_err(|_| Error::NoSetIdAtBlock)?;
+    Ok(set_id)
+}
+
+/// Returns the first block in `(from, to]` with a set_id no less than `set_id` by a binary search
+///
+/// The set_id must have been reached at `to`.
+async fn search_setid_change(
+    api: &RelaychainApi,
+    from: BlockNumber,
+    to: BlockNumber,
+    set_id: SetId,
+) -> Result<BlockNumber> {
+    let (mut l, mut r) = (from + 1, to);
+    while l < r {
+        let mid = l + (r - l) / 2;
+        if get_set_id_at(api, mid).await? < set_id {
+            l = mid + 1;
+        } else {
+            r = mid;
+        }
+    }
+    Ok(l)
+}
+
+/// Builds the warp point at the last justified relaychain block, for pRuntime to skip the blocks
+/// after the start header.
+///
+/// The warp point carries the header of every authority set change since the start header, each
+/// justified by the previous authority set, and the chain state at the last justified block.
+async fn get_warp_point(
+    api: &RelaychainApi,
+    para_api: &ParachainApi,
+    is_parachain: bool,
+    start_header: BlockNumber,
+) -> Result<blocks::WarpPoint> {
+    // Only some of the blocks are justified, find the last one
+    let (finalized, _) = get_block_at(&api.client, None).await?;
+    let mut warp_block = finalized;
+    while grandpa_justification(&warp_block).is_none() {
+        let number = warp_block.block.header.number - 1;
+        if number <= start_header {
+            return Err(anyhow!(Error::NoJustificationInRange));
+        }
+        warp_block = get_block_at(&api.client, Some(number)).await?.0;
+    }
+    let warp_number = warp_block.block.header.number;
+    let warp_hash = warp_block.block.header.hash();
+    info!("Warping to relaychain block {}", warp_number);
+
+    // Walk through the authority set changes
+    let mut fragments = Vec::new();
+    let mut last_change = start_header;
+    let mut set_id = get_set_id_at(api, start_header).await?;
+    let warp_set_id = get_set_id_at(api, warp_number).await?;
+    while set_id < warp_set_id {
+        set_id += 1;
+        let change_at = search_setid_change(api, last_change, warp_number, set_id).await?;
+        let (block, hash) = get_block_at(&api.client, Some(change_at)).await?;
+        let justification = grandpa_justification(&block).ok_or(Error::NoJustificationInRange)?;
+        let change = get_authority_with_proof_at(api, hash).await?;
+        info!(
+            "Authority set changed to {} at block {}",
+            change.authority_set.id, change_at
+        );
+        // The set_id may increase by more than one at a time only in forced changes, which
+        // the light client doesn't follow either.
+        set_id = change.authority_set.id;
+        fragments.push(blocks::WarpFragment {
+            header: HeaderToSync {
+                header: block.block.header,
+                justification: Some(justification),
+            },
+            authority_set_change: Some(change),
+        });
+        last_change = change_at;
+    }
+    if last_change != warp_number {
+        fragments.push(blocks::WarpFragment {
+            header: HeaderToSync {
+                justification: grandpa_justification(&warp_block),
+                header: warp_block.block.header,
+            },
+            authority_set_change: None,
+        });
+    }
+
+    let (para_header, para_header_proof, state_hash) = if is_parachain {
+        let para_id = get_paraid(para_api, None).await?;
+        let heads_key = chain_client::paras_heads_key(para_id);
+        let raw_header = api
+            .client
+            .rpc()
+            .storage(&heads_key, Some(warp_hash))
+            .await?
+            .ok_or(Error::ParachainHeadNotFound)?
+            .0;
+        let header_data = chain_client::decode_parachain_heads(raw_header)?;
+        let para_header =
+            sp_runtime::generic::Header::<BlockNumber, sp_runtime::traits::BlakeTwo256>::decode(
+                &mut header_data.as_slice(),
+            )
+            .or(Err(Error::FailedToDecode))?;
+        let proof = chain_client::read_proof(api, Some(warp_hash), heads_key).await?;
+        let para_hash = para_header.hash();
+        info!("Warping to parachain block {}", para_header.number);
+        (Some(para_header), proof, para_hash)
+    } else {
+        (None, vec![], warp_hash)
+    };
+    let state = chain_client::fetch_storage_at(para_api, state_hash).await?;
+
+    Ok(blocks::WarpPoint {
+        fragments,
+        para_header,
+        para_header_proof,
+        state,
+    })
+}
+
 async fn init_runtime(
     api: &RelaychainApi,
     para_api: &ParachainApi,
@@ -690,6 +935,7 @@ async fn init_runtime(
     operator: Option<AccountId32>,
     is_parachain: bool,
     start_header: BlockNumber,
+    warp: bool,
 ) -> Result<InitRuntimeResponse> {
     let genesis_block = get_block_at(&api.client, Some(start_header)).await?.0.block;
     let hash = api
@@ -701,7 +947,13 @@ async fn init_runtime(
         .await?
         .expect("No genesis block?");
     let set_proof = get_authority_with_proof_at(api, hash).await?;
-    let genesis_state = chain_client::fetch_genesis_storage(para_api).await?;
+    let (genesis_state, warp_point) = if warp {
+        let warp_point = get_warp_point(api, para_api, is_parachain, start_header).await?;
+        // The state at the warp point replaces the genesis state
+        (vec![], Some(warp_point))
+    } else {
+        (chain_client::fetch_genesis_storage(para_api).await?, None)
+    };
     let genesis_info = blocks::GenesisBlockInfo {
         block_header: genesis_block.header,
         authority_set: set_proof.authority_set,
@@ -728,6 +980,10 @@ async fn init_runtime(
             genesis_state,
             operator,
             is_parachain,
+            warp_point,
+            // Relaying the BEEFY finality proofs isn't supported yet, so the headers are always
+            // validated with the GRANDPA justifications.
+            None,
         ))
         .await?;
     Ok(resp)
@@ -787,10 +1043,8 @@ async fn wait_until_synced<T: subxt::Config>(client: &subxt::Client<T>) -> Resul
             "Checking synced: current={} highest={:?}",
             state.current_block, state.highest_block
         );
-        if let Some(highest) = state.highest_block {
-            if highest - state.current_block <= 8 {
-                return Ok(());
-            }
+        if endpoints::is_synced(&state) {
+            return Ok(());
         }
         sleep(Duration::from_secs(5)).await;
     }
@@ -808,22 +1062,29 @@ async fn bridge(
     args: &Args,
     flags: &mut RunningFlags,
     err_report: Sender<MsgSyncError>,
+    connected: oneshot::Sender<endpoints::Connected>,
 ) -> Result<()> {
     // Connect to substrate
 
-    let api: RelaychainApi = subxt_connect(&args.substrate_ws_endpoint).await?.into();
-    info!("Connected to relaychain at: {}", args.substrate_ws_endpoint);
+    let (relay_client, relay_uri) =
+        endpoints::connect_healthiest::<DefaultConfig>(&args.substrate_ws_endpoint).await?;
+    let api: RelaychainApi = relay_client.into();
+    info!("Connected to relaychain at: {}", relay_uri);
 
-    let para_uri: &str = if args.parachain {
-        &args.collator_ws_endpoint
+    let (para_api, para_uri): (ParachainApi, String) = if args.parachain {
+        let (client, uri) =
+            endpoints::connect_healthiest::<KhalaConfig>(&args.collator_ws_endpoint).await?;
+        (client.into(), uri)
     } else {
-        &args.substrate_ws_endpoint
+        // The same node serves as both in solochain mode
+        (subxt_connect(&relay_uri).await?.into(), relay_uri.clone())
     };
-    let para_api: ParachainApi = subxt_connect(para_uri).await?.into();
-    info!(
-        "Connected to parachain node at: {}",
-        args.collator_ws_endpoint
-    );
+    info!("Connected to parachain node at: {}", para_uri);
+    // The receiver is gone only if the bridge is being cancelled
+    let _ = connected.send(endpoints::Connected {
+        relay_uri: relay_uri.clone(),
+        para_uri: args.parachain.then(|| para_uri.clone()),
+    });
 
     if !args.no_wait {
         // Don't start our worker until the substrate node is synced
@@ -869,6 +1130,7 @@ async fn bridge(
                 operator,
                 args.parachain,
                 start_header,
+                args.warp,
             )
             .await?;
             // STATUS: pruntime_initialized = true
@@ -912,6 +1174,19 @@ async fn bridge(
         return Ok(());
     }
 
+    let cache = args
+        .cache_dir
+        .as_ref()
+        .map(|dir| {
+            BlockCache::open_for_chains(dir, api.client.genesis(), para_api.client.genesis())
+        })
+        .transpose()?;
+    // In parachain mode, the relaychain blocks are fetched in the bridge loop and the parachain
+    // blocks in batch_sync_block(). Otherwise only the latter is used in the bridge loop.
+    let mut relay_blocks = BlockPrefetcher::relaychain(&api, cache.clone(), args.prefetch_blocks);
+    let mut para_blocks =
+        BlockPrefetcher::parachain(&para_api, cache.clone(), args.prefetch_blocks);
+
     // Don't just sync message if we want to wait for some block
     let mut sync_state = BlockSyncState {
         blocks: Vec::new(),
@@ -976,22 +1251,28 @@ async fn bridge(
             }
         };
 
-        // TODO.kevin: batch request blocks and changes.
-        for b in next_block..=batch_end {
-            let block = if args.parachain {
-                get_block_without_storage_changes(&api, Some(b)).await?
+        // Take the prefetched blocks, waiting for at least one if any is missing
+        let (blocks, more_blocks) = if next_block > batch_end {
+            (Vec::new(), more_blocks)
+        } else {
+            let max_blocks = (batch_end - next_block + 1) as usize;
+            let blocks = if args.parachain {
+                relay_blocks.recv_ready(next_block, max_blocks).await?
             } else {
                 // api and para_api are connected to the same node in solochain mode
-                get_block_with_storage_changes(&para_api, Some(b)).await?
+                para_blocks.recv_ready(next_block, max_blocks).await?
             };
-
+            let more_blocks = more_blocks || blocks.len() < max_blocks;
+            (blocks, more_blocks)
+        };
+        for block in blocks {
             if block.block.justifications.is_some() {
                 debug!(
                     "block with justification at: {}",
                     block.block.block.header.number
                 );
             }
-            sync_state.blocks.push(block.clone());
+            sync_state.blocks.push(block);
         }
 
         let next_headernum = info.para_headernum;
@@ -1010,6 +1291,8 @@ async fn bridge(
             args.sync_blocks,
             &info,
             args.parachain,
+            cache.as_ref(),
+            &mut para_blocks,
         )
         .await?;
 
@@ -1070,6 +1353,33 @@ fn preprocess_args(args: &mut Args) {
     }
 }
 
+fn run_cache_command(cache_dir: Option<&String>, command: &CacheCommand) -> Result<()> {
+    let cache_dir = cache_dir.ok_or_else(|| anyhow!("--cache-dir is required"))?;
+    let cache = BlockCache::open(cache_dir)?;
+    match command {
+        CacheCommand::Inspect => {
+            for chain in CacheChain::ALL.iter() {
+                let stats = cache.stats(*chain)?;
+                println!(
+                    "{:?}: {} blocks, {} headers, range {:?}..={:?}, {} bytes",
+                    chain, stats.blocks, stats.headers, stats.lowest, stats.highest, stats.bytes
+                );
+            }
+        }
+        CacheCommand::Prune { before, chain } => {
+            let chains = match chain {
+                Some(chain) => vec![*chain],
+                None => CacheChain::ALL.to_vec(),
+            };
+            for chain in chains {
+                let removed = cache.prune(chain, *before)?;
+                println!("{:?}: removed {} items below {}", chain, removed, before);
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn collect_async_errors(
     mut threshold: Option<u64>,
     mut err_receiver: Receiver<MsgSyncError>,
@@ -1107,6 +1417,13 @@ pub async fn pherry_main() {
         .init();
 
     let mut args = Args::from_args();
+    if let Some(Command::Cache(command)) = &args.command {
+        if let Err(err) = run_cache_command(args.cache_dir.as_ref(), command) {
+            error!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
     preprocess_args(&mut args);
 
     let mut flags = RunningFlags {
@@ -1116,18 +1433,31 @@ pub async fn pherry_main() {
 
     loop {
         let (sender, receiver) = msg_sync::create_report_channel();
+        let (connected_tx, connected_rx) = oneshot::channel();
         let threshold = args.restart_on_rpc_error_threshold;
         tokio::select! {
-            res = bridge(&args, &mut flags, sender) => {
+            res = bridge(&args, &mut flags, sender, connected_tx) => {
                 if let Err(err) = res {
                     info!("bridge() exited with error: {:?}", err);
                 } else {
                     break;
                 }
             }
-            () = collect_async_errors(threshold, receiver) => ()
+            () = collect_async_errors(threshold, receiver) => (),
+            // Beside the bridge, so that an RPC stuck on a dead node can't hold up the failover
+            () = endpoints::watch_failover(
+                &args.substrate_ws_endpoint,
+                &args.collator_ws_endpoint,
+                Duration::from_secs(args.health_check_interval),
+                connected_rx,
+            ) => (),
         };
-        if !args.auto_restart || flags.restart_failure_count > args.max_restart_retries {
+        // Restarting reconnects to the healthiest nodes, so always fail over if there are any
+        // other endpoints
+        let failover = args.substrate_ws_endpoint.len() > 1 || args.collator_ws_endpoint.len() > 1;
+        if !(args.auto_restart || failover)
+            || flags.restart_failure_count > args.max_restart_retries
+        {
             std::process::exit(if flags.worker_registered { 1 } else { 2 });
         }
         flags.restart_failure_count += 1;
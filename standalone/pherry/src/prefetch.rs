@@ -0,0 +1,222 @@
+//! Fetches the finalized blocks in the background ahead of the pRuntime cursor.
+//!
+//! The blocks are streamed in order into a bounded queue, so that pRuntime keeps dispatching the
+//! fetched blocks while the next ones are being downloaded from the node.
+
+use anyhow::{anyhow, Result};
+use log::info;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::cache::BlockCache;
+use crate::error::Error;
+use crate::types::{BlockNumber, BlockWithChanges, Header, ParachainApi, RelaychainApi};
+use crate::{get_para_block_cached, get_relay_block_cached};
+use phaxt::subxt;
+
+/// How long to wait for new finalized blocks once the prefetching reaches the chain tip
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+enum Source {
+    /// The relaychain blocks, without storage changes
+    Relaychain(RelaychainApi),
+    /// The blocks to dispatch, with storage changes
+    Parachain(ParachainApi),
+}
+
+impl Source {
+    fn clone_api(&self) -> Self {
+        match self {
+            Source::Relaychain(api) => Source::Relaychain(RelaychainApi::from(api.client.clone())),
+            Source::Parachain(api) => Source::Parachain(ParachainApi::from(api.client.clone())),
+        }
+    }
+
+    async fn finalized_number(&self) -> Result<BlockNumber> {
+        match self {
+            Source::Relaychain(api) => finalized_number(&api.client).await,
+            Source::Parachain(api) => finalized_number(&api.client).await,
+        }
+    }
+
+    async fn fetch(
+        &self,
+        cache: Option<&BlockCache>,
+        number: BlockNumber,
+    ) -> Result<BlockWithChanges> {
+        match self {
+            Source::Relaychain(api) => get_relay_block_cached(cache, api, number).await,
+            Source::Parachain(api) => get_para_block_cached(cache, api, number).await,
+        }
+    }
+}
+
+async fn finalized_number<T: subxt::Config<Header = Header>>(
+    client: &subxt::Client<T>,
+) -> Result<BlockNumber> {
+    let hash = client.rpc().finalized_head().await?;
+    let header = client
+        .rpc()
+        .header(Some(hash))
+        .await?
+        .ok_or(Error::BlockNotFound)?;
+    Ok(header.number)
+}
+
+struct Running {
+    receiver: mpsc::Receiver<Result<BlockWithChanges>>,
+    /// The number of the next block in the queue
+    next_number: BlockNumber,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+pub struct BlockPrefetcher {
+    source: Source,
+    cache: Option<BlockCache>,
+    capacity: usize,
+    running: Option<Running>,
+}
+
+impl BlockPrefetcher {
+    /// Prefetches the relaychain blocks without storage changes
+    pub fn relaychain(api: &RelaychainApi, cache: Option<BlockCache>, capacity: usize) -> Self {
+        Self::new(
+            Source::Relaychain(RelaychainApi::from(api.client.clone())),
+            cache,
+            capacity,
+        )
+    }
+
+    /// Prefetches the blocks with storage changes
+    pub fn parachain(api: &ParachainApi, cache: Option<BlockCache>, capacity: usize) -> Self {
+        Self::new(
+            Source::Parachain(ParachainApi::from(api.client.clone())),
+            cache,
+            capacity,
+        )
+    }
+
+    fn new(source: Source, cache: Option<BlockCache>, capacity: usize) -> Self {
+        Self {
+            source,
+            cache,
+            capacity: capacity.max(1),
+            running: None,
+        }
+    }
+
+    /// Receives the given block, waiting for it to be fetched if necessary.
+    ///
+    /// The prefetching restarts from the block if it's not the next one in the queue.
+    pub async fn recv(&mut self, number: BlockNumber) -> Result<BlockWithChanges> {
+        let running = self.running_from(number);
+        let block = match running.receiver.recv().await {
+            Some(block) => block,
+            None => Err(anyhow!("Block prefetching stopped unexpectedly")),
+        };
+        match block {
+            Ok(block) => {
+                running.next_number += 1;
+                Ok(block)
+            }
+            Err(err) => {
+                // The prefetching task has quit after reporting the error
+                self.running = None;
+                Err(err)
+            }
+        }
+    }
+
+    /// Receives at most `max` blocks starting from the given one.
+    ///
+    /// It waits for the first block, and takes the following ones only if they are already in
+    /// the queue.
+    pub async fn recv_ready(
+        &mut self,
+        number: BlockNumber,
+        max: usize,
+    ) -> Result<Vec<BlockWithChanges>> {
+        let mut blocks = vec![self.recv(number).await?];
+        while blocks.len() < max {
+            let running = self.running_from(number + blocks.len() as BlockNumber);
+            match running.receiver.try_recv() {
+                Ok(Ok(block)) => {
+                    running.next_number += 1;
+                    blocks.push(block);
+                }
+                Ok(Err(err)) => {
+                    self.running = None;
+                    return Err(err);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.running = None;
+                    break;
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn running_from(&mut self, number: BlockNumber) -> &mut Running {
+        let restart = match &self.running {
+            Some(running) => running.next_number != number,
+            None => true,
+        };
+        if restart {
+            if self.running.is_some() {
+                info!("Restart prefetching blocks from {}", number);
+            }
+            // Drop the old task before spawning the new one
+            self.running = None;
+            let (sender, receiver) = mpsc::channel(self.capacity);
+            let handle = tokio::spawn(prefetch(
+                self.source.clone_api(),
+                self.cache.clone(),
+                number,
+                sender,
+            ));
+            self.running = Some(Running {
+                receiver,
+                next_number: number,
+                handle,
+            });
+        }
+        self.running.as_mut().expect("Prefetching started above")
+    }
+}
+
+async fn prefetch(
+    source: Source,
+    cache: Option<BlockCache>,
+    mut next_number: BlockNumber,
+    sender: mpsc::Sender<Result<BlockWithChanges>>,
+) {
+    loop {
+        let tip = match source.finalized_number().await {
+            Ok(tip) => tip,
+            Err(err) => {
+                let _ = sender.send(Err(err)).await;
+                return;
+            }
+        };
+        while next_number <= tip {
+            let block = source.fetch(cache.as_ref(), next_number).await;
+            let failed = block.is_err();
+            // Blocks here until there is room in the queue. Quit if the receiver is gone.
+            if sender.send(block).await.is_err() || failed {
+                return;
+            }
+            next_number += 1;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
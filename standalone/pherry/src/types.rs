@@ -1,3 +1,4 @@
+use codec::{Decode, Encode};
 use phactory_api::{
     blocks::{StorageChanges, StorageProof},
     pruntime_client,
@@ -18,7 +19,7 @@ pub type SrSigner = subxt::PairSigner<phaxt::KhalaConfig, phaxt::PhalaExtra, sr2
 
 pub type SignedBlock<Hdr, Ext> = SpSignedBlock<Block<Hdr, Ext>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Encode, Decode)]
 pub struct BlockWithChanges {
     pub block: SignedBlock<Header, OpaqueExtrinsic>,
     pub storage_changes: StorageChanges,
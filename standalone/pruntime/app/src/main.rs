@@ -45,7 +45,9 @@ use sgx_urts::SgxEnclave;
 use std::env;
 use std::path;
 use std::str;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use rocket::data::Data;
 use rocket::http::Method;
@@ -89,6 +91,17 @@ struct Args {
     /// Skip corrupted checkpoint, and start to sync blocks from the beginning.
     #[structopt(long)]
     skip_corrupted_checkpoint: bool,
+
+    /// Number of blocks to retain the ink! events emitted by pink contracts, which are served to
+    /// anyone over the GetContractEvents RPC
+    #[structopt(long)]
+    #[structopt(default_value = "600")]
+    ink_event_retention: u32,
+
+    /// Keep the storage of the given pink cluster on disk instead of in the memory. Takes the hex
+    /// encoded cluster id, can be specified multiple times
+    #[structopt(long = "disk-backed-cluster")]
+    disk_backed_clusters: Vec<String>,
 }
 
 static ENCLAVE_FILE: &'static str = "enclave.signed.so";
@@ -96,6 +109,10 @@ static ENCLAVE_STATE_FILE: &'static str = "enclave.token";
 
 const ENCLAVE_OUTPUT_BUF_MAX_LEN: usize = 10 * 2048 * 1024 as usize;
 
+const DISPATCH_BLOCKS_METHOD: &str = "PhactoryAPI.DispatchBlocks";
+const QUERY_POLL_METHOD: &str = "PhactoryAPI.ContractQueryPoll";
+const MAX_QUERY_POLL_TIMEOUT_MS: u32 = 60_000;
+
 lazy_static! {
     static ref ENCLAVE: RwLock<Option<SgxEnclave>> = RwLock::new(None);
     static ref ENCLAVE_STATE_FILE_PATH: &'static str = {
@@ -109,8 +126,13 @@ lazy_static! {
         env::var("ALLOW_CORS").unwrap_or_else(|_| "".to_string()) != "";
     static ref ENABLE_KICK_API: bool =
         env::var("ENABLE_KICK_API").unwrap_or_else(|_| "".to_string()) != "";
+    /// The number of the DispatchBlocks calls, to wake the long polls up.
+    static ref BLOCKS_DISPATCHED: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
 }
 
+/// The number of the query polls that can still be held, set from the number of the workers.
+static LONG_POLL_SLOTS: AtomicUsize = AtomicUsize::new(0);
+
 fn destroy_enclave() {
     let enclave = ENCLAVE.write().unwrap().take().unwrap();
     enclave.destroy();
@@ -479,18 +501,81 @@ fn kick() {
 
 #[post("/<method>", data = "<data>")]
 fn prpc_proxy(method: String, data: Data) -> Custom<Vec<u8>> {
-    let eid = crate::get_eid();
-
-    let path_bytes = method.as_bytes();
-    let path_len = path_bytes.len();
-    let path_ptr = path_bytes.as_ptr();
-
     let data = match crate::read_data(data) {
         Some(data) => data,
         None => {
             return Custom(Status::BadRequest, b"Read body failed".to_vec());
         }
     };
+    match method.as_str() {
+        QUERY_POLL_METHOD => long_poll_query(&method, &data),
+        DISPATCH_BLOCKS_METHOD => {
+            let response = call_prpc(&method, &data);
+            let (dispatched, condvar) = &*BLOCKS_DISPATCHED;
+            *dispatched.lock().unwrap() += 1;
+            condvar.notify_all();
+            response
+        }
+        _ => call_prpc(&method, &data),
+    }
+}
+
+/// Poll a query subscription, holding the request until there are updates or the timeout in the
+/// request elapses.
+///
+/// The enclave answers the polls at once, so the waiting is done here, without locking the
+/// enclave, polling again after each dispatch of blocks. Each held poll takes a worker, so when
+/// all the long poll slots are taken, the poll is answered at once.
+fn long_poll_query(method: &str, data: &[u8]) -> Custom<Vec<u8>> {
+    let slots = &LONG_POLL_SLOTS;
+    let acquired = slots
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+        .is_ok();
+    if !acquired {
+        return call_prpc(method, data);
+    }
+    let response = hold_query_poll(method, data);
+    slots.fetch_add(1, Ordering::AcqRel);
+    response
+}
+
+fn hold_query_poll(method: &str, data: &[u8]) -> Custom<Vec<u8>> {
+    use phactory_api::prpc::Message as _;
+
+    let timeout_ms = match prpc::ContractQueryPollRequest::decode(data) {
+        Ok(request) => request.timeout_ms.min(MAX_QUERY_POLL_TIMEOUT_MS),
+        Err(_) => return call_prpc(method, data),
+    };
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms as _);
+    let (dispatched, condvar) = &*BLOCKS_DISPATCHED;
+    loop {
+        let dispatched_before = *dispatched.lock().unwrap();
+        let response = call_prpc(method, data);
+        if response.0 != Status::Ok {
+            return response;
+        }
+        match prpc::ContractQueryUpdates::decode(&response.1[..]) {
+            Ok(updates) if updates.updates.is_empty() && !updates.closed => {}
+            _ => return response,
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return response;
+        }
+        let guard = dispatched.lock().unwrap();
+        let _ = condvar
+            .wait_timeout_while(guard, deadline - now, |count| *count == dispatched_before)
+            .unwrap();
+    }
+}
+
+fn call_prpc(method: &str, data: &[u8]) -> Custom<Vec<u8>> {
+    let eid = crate::get_eid();
+
+    let path_bytes = method.as_bytes();
+    let path_len = path_bytes.len();
+    let path_ptr = path_bytes.as_ptr();
+
     let data_len = data.len();
     let data_ptr = data.as_ptr();
 
@@ -598,6 +683,12 @@ fn rocket() -> rocket::Rocket {
         server = server.mount("/", routes![kick]);
     }
 
+    // Leave at least half of the workers to the requests other than the long polls.
+    let long_poll_slots = server.config().workers as usize / 2;
+    LONG_POLL_SLOTS.store(long_poll_slots, Ordering::Relaxed);
+    if long_poll_slots == 0 {
+        warn!("Too few workers to hold the long polls, the query polls are answered at once");
+    }
     server = server.mount("/prpc", routes![prpc_proxy]);
     print_rpc_methods("/prpc", prpc::phactory_api_server::supported_methods());
 
@@ -653,6 +744,8 @@ fn main() {
         enable_checkpoint: !args.disable_checkpoint,
         checkpoint_interval: args.checkpoint_interval,
         skip_corrupted_checkpoint: args.skip_corrupted_checkpoint,
+        ink_event_retention: args.ink_event_retention,
+        disk_backed_clusters: args.disk_backed_clusters,
     };
     info!("init_args: {:#?}", init_args);
     let encoded_args = init_args.encode();